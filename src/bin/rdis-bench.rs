@@ -0,0 +1,420 @@
+use rdis::rdis::protocol::read_reply;
+use rdis::rdis::stats::{format_percentiles, LatencyHistogram};
+use rdis::{ErrorT, ResultT, RESP};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// load generator for rdis, or any server speaking RESP, in the spirit of redis-benchmark:
+//
+//     rdis-bench -c 50 -n 100000 -P 16 -t set:1,get:3
+//
+// every client sends pipelines of -P commands picked at random from the weighted mix.
+// Latency is measured per pipeline and attributed to every command in it, like redis-benchmark.
+const USAGE: &str = "Usage: rdis-bench [-h host] [-p port] [-c clients] [-n requests] \
+[-P pipeline] [-d value size] [-r keyspace] [-t command[:weight],...]
+
+commands: ping, set, get, incr, lpush, rpush, lpop, rpop, mset (10 keys), lrange (first 100 elements)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Command {
+    Ping,
+    Set,
+    Get,
+    Incr,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    MSet,
+    LRange,
+}
+
+impl Command {
+    fn parse(name: &str) -> Option<Command> {
+        match name.to_lowercase().as_str() {
+            "ping" => Some(Command::Ping),
+            "set" => Some(Command::Set),
+            "get" => Some(Command::Get),
+            "incr" => Some(Command::Incr),
+            "lpush" => Some(Command::LPush),
+            "rpush" => Some(Command::RPush),
+            "lpop" => Some(Command::LPop),
+            "rpop" => Some(Command::RPop),
+            "mset" => Some(Command::MSet),
+            "lrange" => Some(Command::LRange),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "ping",
+            Command::Set => "set",
+            Command::Get => "get",
+            Command::Incr => "incr",
+            Command::LPush => "lpush",
+            Command::RPush => "rpush",
+            Command::LPop => "lpop",
+            Command::RPop => "rpop",
+            Command::MSet => "mset",
+            Command::LRange => "lrange",
+        }
+    }
+
+    // keys are shared with redis-benchmark: key:NNNNNNNNNNNN, counter:NNNNNNNNNNNN and mylist
+    fn encode(&self, key: u64, value: &[u8], out: &mut Vec<u8>) {
+        let (key, counter) = (format!("key:{:012}", key), format!("counter:{:012}", key));
+        if let Command::MSet = self {
+            let keys: Vec<String> = (0..10).map(|i| format!("{}{}", key, i)).collect();
+            let mut args: Vec<&[u8]> = vec![b"MSET"];
+            for k in keys.iter() {
+                args.push(k.as_bytes());
+                args.push(value);
+            }
+            return encode(&args, out);
+        }
+        let args: Vec<&[u8]> = match self {
+            Command::Ping => vec![b"PING"],
+            Command::Set => vec![b"SET", key.as_bytes(), value],
+            Command::Get => vec![b"GET", key.as_bytes()],
+            Command::Incr => vec![b"INCR", counter.as_bytes()],
+            Command::LPush => vec![b"LPUSH", b"mylist", value],
+            Command::RPush => vec![b"RPUSH", b"mylist", value],
+            Command::LPop => vec![b"LPOP", b"mylist"],
+            Command::RPop => vec![b"RPOP", b"mylist"],
+            Command::LRange => vec![b"LRANGE", b"mylist", b"0", b"99"],
+            Command::MSet => unreachable!(),
+        };
+        encode(&args, out)
+    }
+}
+
+fn encode(args: &[&[u8]], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: u64,
+    pipeline: u64,
+    data_size: usize,
+    keyspace: u64,
+    // (command, weight)
+    mix: Vec<(Command, u32)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            host: "127.0.0.1".to_owned(),
+            port: 6379,
+            clients: 50,
+            requests: 100_000,
+            pipeline: 1,
+            data_size: 3,
+            keyspace: 10_000,
+            mix: vec![(Command::Set, 1), (Command::Get, 1)],
+        }
+    }
+}
+
+impl Options {
+    fn from_args<I: Iterator<Item = String>>(mut args: I) -> ResultT<Options> {
+        let mut options = Options::default();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ErrorT::from(format!("Missing value for {}", flag)))
+            };
+            match flag.as_str() {
+                "-h" => options.host = value()?,
+                "-p" => options.port = value()?.parse()?,
+                "-c" => options.clients = value()?.parse()?,
+                "-n" => options.requests = value()?.parse()?,
+                "-P" => options.pipeline = value()?.parse()?,
+                "-d" => options.data_size = value()?.parse()?,
+                "-r" => options.keyspace = value()?.parse()?,
+                "-t" => options.mix = parse_mix(&value()?)?,
+                _ => return Err(ErrorT::from(format!("Unknown option {}", flag))),
+            }
+        }
+        if options.clients == 0 || options.pipeline == 0 || options.keyspace == 0 {
+            return Err(ErrorT::from(
+                "clients, pipeline and keyspace must be positive",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+// set:1,get:3 or set,get (weight 1)
+fn parse_mix(mix: &str) -> ResultT<Vec<(Command, u32)>> {
+    let mut commands = Vec::new();
+    for item in mix.split(',').filter(|i| !i.is_empty()) {
+        let (name, weight) = match item.split_once(':') {
+            Some((name, weight)) => (name, weight.parse()?),
+            None => (item, 1),
+        };
+        let command = Command::parse(name)
+            .ok_or_else(|| ErrorT::from(format!("Unsupported command {}", name)))?;
+        if weight > 0 {
+            commands.push((command, weight));
+        }
+    }
+    if commands.is_empty() {
+        return Err(ErrorT::from("Empty command mix"));
+    }
+    Ok(commands)
+}
+
+// xorshift64, good enough to spread keys and pick commands
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick(&mut self, mix: &[(Command, u32)], total_weight: u64) -> Command {
+        let mut n = self.next() % total_weight;
+        for (command, weight) in mix {
+            if n < *weight as u64 {
+                return *command;
+            }
+            n -= *weight as u64;
+        }
+        mix[0].0
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    requests: u64,
+    errors: u64,
+    // latency in nanoseconds
+    latency: LatencyHistogram,
+    commands: BTreeMap<Command, LatencyHistogram>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.latency.merge(&other.latency);
+        for (command, latency) in other.commands {
+            self.commands.entry(command).or_default().merge(&latency);
+        }
+    }
+
+    fn print(&self, options: &Options, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        println!("====== rdis-bench ======");
+        println!(
+            "  {} requests completed in {:.2} seconds",
+            self.requests, secs
+        );
+        println!(
+            "  {} parallel clients, pipeline {}, {} bytes payload, keyspace {}",
+            options.clients, options.pipeline, options.data_size, options.keyspace
+        );
+        println!();
+        println!(
+            "throughput: {:.2} requests per second",
+            self.requests as f64 / secs
+        );
+        println!("errors: {}", self.errors);
+        println!("latency (usec):");
+        println!("  all: {}", format_percentiles(&self.latency));
+        for (command, latency) in self.commands.iter() {
+            println!(
+                "  {}: {} ({} requests)",
+                command.name(),
+                format_percentiles(latency),
+                latency.total()
+            );
+        }
+    }
+}
+
+// takes up to pipeline requests from the shared budget, 0 once it is exhausted
+fn claim(remaining: &AtomicU64, pipeline: u64) -> u64 {
+    match remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| {
+        r.checked_sub(1).map(|_| r.saturating_sub(pipeline))
+    }) {
+        Ok(previous) => previous.min(pipeline),
+        Err(_) => 0,
+    }
+}
+
+async fn run_client(
+    options: Arc<Options>,
+    remaining: Arc<AtomicU64>,
+    seed: u64,
+) -> ResultT<Report> {
+    let stream = TcpStream::connect((options.host.as_str(), options.port)).await?;
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut rng = Rng(seed);
+    let value = vec![b'x'; options.data_size];
+    let total_weight = options.mix.iter().map(|(_, w)| *w as u64).sum();
+    let mut report = Report::default();
+    let mut buf = Vec::new();
+    let mut batch = Vec::with_capacity(options.pipeline as usize);
+    loop {
+        let count = claim(&remaining, options.pipeline);
+        if count == 0 {
+            return Ok(report);
+        }
+        buf.clear();
+        batch.clear();
+        for _ in 0..count {
+            let command = rng.pick(&options.mix, total_weight);
+            command.encode(rng.next() % options.keyspace, &value, &mut buf);
+            batch.push(command);
+        }
+        let start = Instant::now();
+        writer.write_all(&buf).await?;
+        for _ in 0..count {
+            if let RESP::Error(_, _) = read_reply(&mut reader).await? {
+                report.errors += 1;
+            }
+        }
+        let elapsed = start.elapsed().as_nanos() as u64;
+        for command in batch.iter() {
+            report.latency.record(elapsed);
+            report.commands.entry(*command).or_default().record(elapsed);
+        }
+        report.requests += count;
+    }
+}
+
+async fn run(options: Arc<Options>) -> ResultT<(Report, Duration)> {
+    let remaining = Arc::new(AtomicU64::new(options.requests));
+    let start = Instant::now();
+    let handles: Vec<_> = (0..options.clients as u64)
+        .map(|idx| {
+            // xorshift needs a non zero seed
+            let seed = 0x9E37_79B9_7F4A_7C15 ^ (idx + 1);
+            tokio::spawn(run_client(options.clone(), remaining.clone(), seed))
+        })
+        .collect();
+    let mut report = Report::default();
+    for handle in handles {
+        report.merge(handle.await??);
+    }
+    Ok((report, start.elapsed()))
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let options = Arc::new(options);
+    match run(options.clone()).await {
+        Ok((report, elapsed)) => report.print(&options, elapsed),
+        Err(err) => {
+            eprintln!(
+                "Benchmark against {}:{} failed: {}",
+                options.host, options.port, err
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdis::rdis::systemd::Supervised;
+    use rdis::RdisServerBuilder;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(str::to_owned)
+    }
+
+    #[test]
+    pub fn test_options() -> ResultT<()> {
+        let options = Options::from_args(args("-p 7000 -c 4 -n 10 -P 8 -t set:1,get:3,ping"))?;
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.clients, 4);
+        assert_eq!(options.pipeline, 8);
+        assert_eq!(
+            options.mix,
+            vec![(Command::Set, 1), (Command::Get, 3), (Command::Ping, 1)]
+        );
+        assert!(Options::from_args(args("-t hset")).is_err());
+        assert!(Options::from_args(args("-c")).is_err());
+        assert!(Options::from_args(args("-P 0")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_claim() {
+        let remaining = AtomicU64::new(10);
+        assert_eq!(claim(&remaining, 4), 4);
+        assert_eq!(claim(&remaining, 4), 4);
+        assert_eq!(claim(&remaining, 4), 2);
+        assert_eq!(claim(&remaining, 4), 0);
+    }
+
+    #[test]
+    pub fn test_encode() {
+        let mut out = Vec::new();
+        Command::Set.encode(42, b"v", &mut out);
+        assert_eq!(
+            out,
+            b"*3\r\n$3\r\nSET\r\n$16\r\nkey:000000000042\r\n$1\r\nv\r\n".to_vec()
+        );
+        out.clear();
+        Command::MSet.encode(42, b"v", &mut out);
+        assert!(out.starts_with(b"*21\r\n$4\r\nMSET\r\n$17\r\nkey:0000000000420\r\n"));
+    }
+
+    #[tokio::test]
+    pub async fn test_run() -> ResultT<()> {
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await?;
+        let port = server.local_addr()?.port();
+        let shutdown = server.shutdown_handle();
+        let handle = tokio::spawn(server.serve());
+
+        let options =
+            Options::from_args(args("-c 3 -n 100 -P 7 -t set,get,lpush,rpop,mset,lrange"))?;
+        let (report, _) = run(Arc::new(Options { port, ..options })).await?;
+        assert_eq!(report.requests, 100);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.latency.total(), 100);
+        assert_eq!(
+            report.commands.values().map(|l| l.total()).sum::<u64>(),
+            100
+        );
+
+        shutdown.shutdown();
+        handle.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use rdis::rdis::protocol::read_reply;
+use rdis::rdis::recorder::read_entry;
+use rdis::{ErrorT, ResultT, RESP};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+// feeds a file written with the record-file directive to rdis, or any server speaking RESP:
+//
+//     rdis-replay -p 6380 -s 10 /var/lib/rdis/writes.resp
+//
+// commands are sent one at a time, spaced like they were recorded divided by the speed.
+// With -s 0 they are sent as fast as the server replies. A crash of rdis while writing can
+// leave a partial entry at the end of the file: -t discards it, like aof-load-truncated.
+// -u and -o recover the keyspace as it was at a point in time, say before a bad deploy: the
+// replay stops at the first entry recorded after the time (milliseconds since the epoch or
+// RFC 3339) or not ending within the first bytes of the file, reporting its offset.
+const USAGE: &str =
+    "Usage: rdis-replay [-h host] [-p port] [-s speed] [-t] [-u time] [-o offset] file";
+
+#[derive(Debug)]
+struct Options {
+    host: String,
+    port: u16,
+    speed: f64,
+    // a partial entry at the end of the file is discarded instead of failing the replay
+    truncated: bool,
+    until: Option<u64>,
+    until_offset: Option<u64>,
+    file: String,
+}
+
+// milliseconds since the epoch or an RFC 3339 date
+fn parse_time(value: &str) -> ResultT<u64> {
+    match value.parse() {
+        Ok(t) => Ok(t),
+        Err(_) => match chrono::DateTime::parse_from_rfc3339(value) {
+            Ok(date) if date.timestamp_millis() >= 0 => Ok(date.timestamp_millis() as u64),
+            _ => Err(ErrorT::from(format!("Invalid time {}", value))),
+        },
+    }
+}
+
+impl Options {
+    fn from_args<I: Iterator<Item = String>>(mut args: I) -> ResultT<Options> {
+        let mut options = Options {
+            host: "127.0.0.1".to_owned(),
+            port: 6379,
+            speed: 1.0,
+            truncated: false,
+            until: None,
+            until_offset: None,
+            file: String::new(),
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ErrorT::from(format!("Missing value for {}", flag)))
+            };
+            match flag.as_str() {
+                "-h" => options.host = value()?,
+                "-p" => options.port = value()?.parse()?,
+                "-s" => options.speed = value()?.parse()?,
+                "-t" => options.truncated = true,
+                "-u" => options.until = Some(parse_time(&value()?)?),
+                "-o" => options.until_offset = Some(value()?.parse()?),
+                _ if flag.starts_with('-') => {
+                    return Err(ErrorT::from(format!("Unknown option {}", flag)))
+                }
+                _ if options.file.is_empty() => options.file = flag,
+                _ => return Err(ErrorT::from("Only one file can be replayed")),
+            }
+        }
+        if options.file.is_empty() {
+            return Err(ErrorT::from("Missing file"));
+        }
+        if !(options.speed >= 0.0 && options.speed.is_finite()) {
+            return Err(ErrorT::from("speed must be a non negative number"));
+        }
+        Ok(options)
+    }
+
+    // when the command recorded elapsed millis after the first one is due
+    fn delay(&self, elapsed: u64) -> Duration {
+        if self.speed == 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(elapsed).div_f64(self.speed)
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Report {
+    commands: u64,
+    errors: u64,
+    // bytes of the truncated entry at the end of the file
+    discarded: u64,
+    // offset of the first entry past -u or -o
+    stopped_at: Option<u64>,
+}
+
+async fn replay(options: &Options) -> ResultT<Report> {
+    let file = File::open(&options.file).await?;
+    let len = file.metadata().await?.len();
+    let mut entries = BufReader::new(file);
+    let stream = TcpStream::connect((options.host.as_str(), options.port)).await?;
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut report = Report::default();
+    let start = Instant::now();
+    let mut first = None;
+    loop {
+        let offset = entries.stream_position().await?;
+        let (t, command) = match read_entry(&mut entries).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            // the entry runs until the end of the file, it wasn't fully written
+            Err(e) if options.truncated && entries.fill_buf().await?.is_empty() => {
+                report.discarded = len - offset;
+                eprintln!(
+                    "Discarded {} bytes of a truncated entry at the end of the file: {}",
+                    report.discarded, e
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let end = entries.stream_position().await?;
+        if options.until.is_some_and(|until| t > until)
+            || options.until_offset.is_some_and(|until| end > until)
+        {
+            report.stopped_at = Some(offset);
+            break;
+        }
+        // the clock may go backwards between restarts appending to the same file
+        let elapsed = t.saturating_sub(*first.get_or_insert(t));
+        tokio::time::sleep_until(start + options.delay(elapsed)).await;
+        RESP::Array(command).write_async(&mut writer, true).await?;
+        if let RESP::Error(kind, msg) = read_reply(&mut reader).await? {
+            eprintln!("Command {} failed: {} {}", report.commands, kind, msg);
+            report.errors += 1;
+        }
+        report.commands += 1;
+    }
+    Ok(report)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let start = Instant::now();
+    match replay(&options).await {
+        Ok(report) => {
+            println!(
+                "{} commands replayed in {:.2} seconds, {} errors, {} bytes discarded",
+                report.commands,
+                start.elapsed().as_secs_f64(),
+                report.errors,
+                report.discarded
+            );
+            if let Some(offset) = report.stopped_at {
+                println!("Stopped at the entry at offset {}", offset);
+            }
+        }
+        Err(err) => {
+            eprintln!(
+                "Replay of {} against {}:{} failed: {}",
+                options.file, options.host, options.port, err
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdis::rdis::recorder::Recorder;
+    use rdis::rdis::systemd::Supervised;
+    use rdis::RdisServerBuilder;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(str::to_owned)
+    }
+
+    #[test]
+    pub fn test_options() -> ResultT<()> {
+        let options = Options::from_args(args("-p 7000 -s 4 writes.resp"))?;
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.file, "writes.resp");
+        assert_eq!(options.delay(1000), Duration::from_millis(250));
+        assert!(!options.truncated);
+        let options = Options::from_args(args("-s 0 -t writes.resp"))?;
+        assert_eq!(options.delay(1000), Duration::ZERO);
+        assert!(options.truncated);
+        let options = Options::from_args(args("-u 1650000000000 -o 4096 writes.resp"))?;
+        assert_eq!(options.until, Some(1_650_000_000_000));
+        assert_eq!(options.until_offset, Some(4096));
+        let options = Options::from_args(args("-u 2022-04-15T05:20:00.5Z writes.resp"))?;
+        assert_eq!(options.until, Some(1_650_000_000_500));
+        assert!(Options::from_args(args("-u yesterday writes.resp")).is_err());
+        assert!(Options::from_args(args("-s 1")).is_err());
+        assert!(Options::from_args(args("-s -1 writes.resp")).is_err());
+        assert!(Options::from_args(args("a.resp b.resp")).is_err());
+        assert!(Options::from_args(args("-x a.resp")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_record_and_replay() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-replay-{}.resp", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .record_file(&path)
+            .build()
+            .await?;
+        let shutdown = server.shutdown_handle();
+        let client = server.client();
+        let handle = tokio::spawn(server.serve());
+        client.set("a", "1").await?;
+        client.set("b", "2").await?;
+        client.incr("a").await?;
+        client.rpush("l", "x").await?;
+        client.del(&["b"]).await?;
+        // reads and pops on empty lists aren't recorded
+        client.get("a").await?;
+        client.lpop("empty").await?;
+        drop(client);
+        shutdown.shutdown();
+        handle.await??;
+        // a SET cut while being written
+        let partial = b"*5\r\n:1650000000000\r\n$3\r\nSET\r\n$1\r\nc";
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        std::io::Write::write_all(&mut file, partial)?;
+
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await?;
+        let port = server.local_addr()?.port();
+        let shutdown = server.shutdown_handle();
+        let client = server.client();
+        let handle = tokio::spawn(server.serve());
+        let options = Options::from_args(args(&format!("-p {} -s 0 {}", port, path)))?;
+        // the commands before the partial entry are sent either way, replaying them twice ends
+        // with the same keyspace but for the list
+        assert!(replay(&options).await.is_err());
+        let options = Options::from_args(args(&format!("-p {} -s 0 -t {}", port, path)))?;
+        let report = replay(&options).await?;
+        assert_eq!(
+            report,
+            Report {
+                commands: 5,
+                errors: 0,
+                discarded: partial.len() as u64,
+                stopped_at: None,
+            }
+        );
+        assert_eq!(client.get("a").await?, Some(b"2".to_vec()));
+        assert_eq!(client.get("b").await?, None);
+        assert_eq!(client.lpop("l").await?, Some(b"x".to_vec()));
+        assert_eq!(client.lpop("l").await?, Some(b"x".to_vec()));
+        assert_eq!(client.lpop("l").await?, None);
+        drop(client);
+        shutdown.shutdown();
+        handle.await??;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_bounded_replay() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-bounded-{}.resp", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+        let (recorder, handle) = Recorder::open(&path).await?;
+        for (t, v) in [(1_000, "1"), (2_000, "2"), (3_000, "3")] {
+            recorder.record(t, b"SET", &[RESP::from("a"), RESP::from(v)]);
+        }
+        drop(recorder);
+        handle.await?;
+
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await?;
+        let port = server.local_addr()?.port();
+        let shutdown = server.shutdown_handle();
+        let client = server.client();
+        let handle = tokio::spawn(server.serve());
+        let replay_with = |bound: String| {
+            let options = Options::from_args(args(&format!("-p {} -s 0 {} {}", port, bound, path)));
+            async move { replay(&options?).await }
+        };
+        let report = replay_with("-u 2000".to_owned()).await?;
+        assert_eq!(report.commands, 2);
+        assert_eq!(client.get("a").await?, Some(b"2".to_vec()));
+        let third = report.stopped_at.unwrap();
+        // the second entry ends at the offset of the third one
+        let report = replay_with(format!("-o {}", third - 1)).await?;
+        assert_eq!(report.commands, 1);
+        assert_eq!(client.get("a").await?, Some(b"1".to_vec()));
+        let report = replay_with(format!("-o {}", third)).await?;
+        assert_eq!((report.commands, report.stopped_at), (2, Some(third)));
+        drop(client);
+        shutdown.shutdown();
+        handle.await??;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
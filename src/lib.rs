@@ -0,0 +1,14 @@
+pub mod rdis;
+
+pub use crate::rdis::client::RdisClient;
+pub use crate::rdis::config::Config;
+pub use crate::rdis::convert::Fields;
+pub use crate::rdis::error::RdisError;
+pub use crate::rdis::module::{CommandContext, CustomCommand};
+pub use crate::rdis::protocol::RESP;
+pub use crate::rdis::readthrough::{LoadFuture, Loader, ReadThroughOptions};
+pub use crate::rdis::server::{RdisServer, RdisServerBuilder, ShutdownHandle};
+pub use crate::rdis::types::{ErrorT, ResultT};
+pub use crate::rdis::writebehind::{
+    MirroredValue, Mutation, SinkFuture, WriteBehindOptions, WriteSink,
+};
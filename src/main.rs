@@ -1,45 +1,74 @@
-use crate::rdis::engine::RedisEngine;
-use tokio::net::TcpSocket;
+use log::{error, info};
+use rdis::rdis::daemon::{self, Pidfile};
+use rdis::rdis::logging::{self, FileLogger};
+use rdis::rdis::memory::TrackingAllocator;
+use rdis::{Config, RdisServerBuilder, ResultT};
+use tokio::signal::unix::{signal, SignalKind};
 
-mod rdis;
-use log::{info, LevelFilter};
-use rdis::types::*;
-use simple_logger::SimpleLogger;
-use std::sync::Arc;
-use tokio::sync::mpsc;
+// counts the allocated bytes for INFO memory, jemalloc wins if both features are enabled
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: TrackingAllocator<tikv_jemallocator::Jemalloc> =
+    TrackingAllocator::new(tikv_jemallocator::Jemalloc);
 
-#[tokio::main(worker_threads = 4)]
-async fn main() -> ResultT<()> {
-    let logger = SimpleLogger::new().with_level(LevelFilter::Info);
-    logger.init()?;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: TrackingAllocator<mimalloc::MiMalloc> = TrackingAllocator::new(mimalloc::MiMalloc);
 
-    let addr = "127.0.0.1:6379".parse()?;
-    let socket = TcpSocket::new_v4()?;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+#[global_allocator]
+static GLOBAL: TrackingAllocator<std::alloc::System> = TrackingAllocator::new(std::alloc::System);
 
-    socket.set_reuseaddr(true)?;
-    socket.bind(addr)?;
-    info!("Bound socket to addr {}", addr);
-
-    let listener = socket.listen(1024)?;
-
-    let server = RedisServer::new(listener);
-    let (sender, receiver) = mpsc::channel(4096);
-    let api = Arc::new(RedisEngineApi::new(sender));
-
-    let _server_handle = tokio::spawn(async move {
-        let mut engine = RedisEngine::new(receiver);
-        engine.start_loop().await
-    });
-
-    accept_connections(server, api).await;
+fn main() -> ResultT<()> {
+    let config = Config::from_args(std::env::args().skip(1))?;
+    if config.daemonize {
+        daemon::daemonize()?;
+    }
+    let file_logger = logging::init(&config.log)?;
+    // removed when main returns, after the runtime is dropped
+    let _pidfile = match config.pidfile_path() {
+        Some(path) => Some(Pidfile::create(&path)?),
+        None => None,
+    };
 
-    Ok(())
+    let runtime = config.runtime.build()?;
+    match config.deterministic_seed {
+        Some(seed) => info!("Starting deterministic runtime, seed {}", seed),
+        None => info!(
+            "Starting runtime with {} worker threads",
+            config.runtime.worker_threads
+        ),
+    }
+    runtime.block_on(async move {
+        let server = RdisServerBuilder::from_config(config).build().await?;
+        let shutdown = server.shutdown_handle();
+        if let Some(logger) = file_logger {
+            tokio::spawn(reopen_on_sighup(logger));
+        }
+        // init scripts stop daemons with SIGTERM
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            let received = tokio::select! {
+                result = tokio::signal::ctrl_c() => result.is_ok(),
+                signal = terminate.recv() => signal.is_some(),
+            };
+            if received {
+                info!("Received shutdown signal");
+                shutdown.shutdown();
+            }
+        });
+        server.serve().await
+    })
 }
 
-async fn accept_connections(server: RedisServer, api: Arc<RedisEngineApi>) {
-    while let Ok((stream, _)) = server.listener.accept().await {
-        server.add_handle(tokio::spawn(
-            server.client_connection(api.clone(), stream).start_loop(),
-        ));
+// logrotate style rotation: the file is moved away, then rdis is told to open a new one
+async fn reopen_on_sighup(logger: &'static FileLogger) -> ResultT<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match logger.reopen() {
+            Ok(()) => info!("Reopened the log file"),
+            Err(e) => error!("Failed to reopen the log file: {}", e),
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,277 @@
+use super::metrics::{Metrics, Snapshot};
+use super::protocol::{ClientReq, RESP};
+use super::registry::ClientRegistry;
+use super::types::{ErrorT, RedisEngineApi, ResultT};
+use bytes::Bytes;
+use log::{debug, info};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// client_epoch used for the requests sent by the admin server, never assigned to a connection
+pub const ADMIN_CLIENT: usize = usize::MAX;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn json(body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+}
+
+pub struct AdminState {
+    pub api: Arc<RedisEngineApi>,
+    pub registry: Arc<ClientRegistry>,
+    pub metrics: Arc<Metrics>,
+}
+
+// minimal HTTP/1.1 server for probes and dashboards, every connection serves a single request:
+// /livez, /readyz, /stats, /info (INFO rendered as JSON) and /metrics (prometheus)
+pub async fn serve(listener: TcpListener, state: AdminState) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Admin endpoint listening on http://{}", addr);
+    }
+    let state = Arc::new(state);
+    while let Ok((stream, addr)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &state).await {
+                debug!("Admin request from {} failed {}", addr, err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, state: &AdminState) -> ResultT<()> {
+    let mut buf = Vec::with_capacity(1024);
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if stream.read_buf(&mut buf).await? == 0 || buf.len() > MAX_REQUEST_SIZE {
+            return Err(ErrorT::from("Incomplete or too large http request"));
+        }
+    }
+    let request_line = String::from_utf8_lossy(&buf);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(path, state).await,
+        _ => Response::text("405 Method Not Allowed", "method not allowed"),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn route(path: &str, state: &AdminState) -> Response {
+    let api = &state.api;
+    match path {
+        "/livez" => Response::text("200 OK", "ok"),
+        "/metrics" => {
+            let command_latency = match engine_command(api, &["INFO", "latencystats"]).await {
+                Ok(RESP::BulkString(info)) => parse_info(&String::from_utf8_lossy(&info))
+                    .into_iter()
+                    .flat_map(|(_, fields)| fields)
+                    .flat_map(|(key, value)| parse_latency(&key, &value))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let snapshot = Snapshot {
+                connected_clients: state.registry.len(),
+                connections_total: state.registry.total_connections(),
+                command_latency,
+            };
+            Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: state.metrics.render(&snapshot),
+            }
+        }
+        "/readyz" => {
+            let ping = engine_command(api, &["PING"]);
+            match tokio::time::timeout(READY_TIMEOUT, ping).await {
+                Ok(Ok(_)) => Response::text("200 OK", "ok"),
+                _ => Response::text("503 Service Unavailable", "engine not responding"),
+            }
+        }
+        "/info" | "/stats" => match engine_command(api, &["INFO"]).await {
+            Ok(RESP::BulkString(info)) => {
+                let sections = parse_info(&String::from_utf8_lossy(&info));
+                if path == "/info" {
+                    Response::json(sections_to_json(&sections))
+                } else {
+                    let stats: Vec<(String, String)> = sections
+                        .into_iter()
+                        .filter(|(name, _)| name == "clients" || name == "stats")
+                        .flat_map(|(_, fields)| fields)
+                        .collect();
+                    Response::json(fields_to_json(&stats))
+                }
+            }
+            _ => Response::text("503 Service Unavailable", "engine not responding"),
+        },
+        _ => Response::text("404 Not Found", "not found"),
+    }
+}
+
+async fn engine_command(api: &RedisEngineApi, args: &[&str]) -> ResultT<RESP> {
+    let cmd = RESP::Array(
+        args.iter()
+            .map(|a| RESP::BulkString(Bytes::copy_from_slice(a.as_bytes())))
+            .collect(),
+    );
+    match api.request(ADMIN_CLIENT, ClientReq::Single(cmd)).await? {
+        ClientReq::Single(resp) => Ok(resp),
+        ClientReq::Pipeline(_) => Err(ErrorT::from("Unexpected pipeline response")),
+    }
+}
+
+type InfoSection = (String, Vec<(String, String)>);
+
+fn parse_info(info: &str) -> Vec<InfoSection> {
+    let mut sections: Vec<InfoSection> = Vec::new();
+    for line in info.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(name) = line.strip_prefix('#') {
+            sections.push((name.trim().to_lowercase(), Vec::new()));
+        } else if let (Some((key, value)), Some(section)) =
+            (line.split_once(':'), sections.last_mut())
+        {
+            section.1.push((key.to_owned(), value.to_owned()));
+        }
+    }
+    sections
+}
+
+// latency_percentiles_usec_get:p50=1.003,p99.9=2.007 into (get, 0.5, 1.003), (get, 0.999, 2.007)
+fn parse_latency(key: &str, value: &str) -> Vec<(String, f64, f64)> {
+    let command = match key.strip_prefix("latency_percentiles_usec_") {
+        Some(command) => command,
+        None => return Vec::new(),
+    };
+    value
+        .split(',')
+        .filter_map(|p| {
+            let (percentile, usec) = p.strip_prefix('p')?.split_once('=')?;
+            // rounded so that p99.9 is rendered as 0.999
+            let quantile = (percentile.parse::<f64>().ok()? * 100.0).round() / 10_000.0;
+            Some((command.to_owned(), quantile, usec.parse().ok()?))
+        })
+        .collect()
+}
+
+fn sections_to_json(sections: &[InfoSection]) -> String {
+    let mut out = String::from("{");
+    for (idx, (name, fields)) in sections.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}:{}", json_string(name), fields_to_json(fields));
+    }
+    out.push('}');
+    out
+}
+
+fn fields_to_json(fields: &[(String, String)]) -> String {
+    let mut out = String::from("{");
+    for (idx, (key, value)) in fields.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}:{}", json_string(key), json_value(value));
+    }
+    out.push('}');
+    out
+}
+
+// numbers are kept as numbers, everything else is a string
+fn json_value(value: &str) -> String {
+    let is_number = value.parse::<i64>().is_ok()
+        || (!value.contains(char::is_alphabetic) && value.parse::<f64>().is_ok());
+    if is_number {
+        value.to_owned()
+    } else {
+        json_string(value)
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_info_to_json() {
+        let info = "# Server\r\nredis_version:6.0.0\r\nuptime_in_seconds:10\r\n\r\n\
+                    # Keyspace\r\ndb0:keys=1,expires=0,avg_ttl=0\r\n";
+        assert_eq!(
+            sections_to_json(&parse_info(info)),
+            "{\"server\":{\"redis_version\":\"6.0.0\",\"uptime_in_seconds\":10},\
+             \"keyspace\":{\"db0\":\"keys=1,expires=0,avg_ttl=0\"}}"
+        );
+    }
+
+    #[test]
+    pub fn test_parse_latency() {
+        assert_eq!(
+            parse_latency("latency_percentiles_usec_get", "p50=1.003,p99.9=2.5"),
+            vec![
+                ("get".to_owned(), 0.5, 1.003),
+                ("get".to_owned(), 0.999, 2.5)
+            ]
+        );
+        assert!(parse_latency("db0", "keys=1").is_empty());
+    }
+
+    #[test]
+    pub fn test_json_value() {
+        assert_eq!(json_value("-12"), "-12");
+        assert_eq!(json_value("0.75"), "0.75");
+        assert_eq!(json_value("inf"), "\"inf\"");
+        assert_eq!(json_value("NaN"), "\"NaN\"");
+        assert_eq!(json_value("a\"b\n"), "\"a\\\"b\\n\"");
+    }
+}
@@ -0,0 +1,120 @@
+use super::types::{ErrorT, ResultT};
+
+// CPU pinning of the engine thread and of the runtime workers, configured with the
+// engine-cpulist and worker-cpulist directives. Lists use the redis server_cpulist syntax:
+//
+//     0,2,4-7
+//
+// keeping the engine on a core of its own, close to the memory it touches, avoids the cache
+// thrashing of the scheduler moving it around on NUMA machines. Linux only.
+
+// the size of cpu_set_t
+const MAX_CPUS: usize = 1024;
+
+pub fn parse_cpu_list(value: &str) -> ResultT<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in value.split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>()?, last.parse::<usize>()?),
+            None => {
+                let cpu = range.parse::<usize>()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last || last >= MAX_CPUS {
+            return Err(ErrorT::from(format!("Invalid CPU range {}", range)));
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+// the inverse of parse_cpu_list, consecutive CPUs are collapsed in ranges
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == *cpu => *last = *cpu,
+            _ => ranges.push((*cpu, *cpu)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{}-{}", first, last),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpus: &[usize]) -> ResultT<()> {
+    // the zeroed set is empty, CPU_ZERO only clears it
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    let result = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// the CPUs the calling thread may run on
+#[cfg(target_os = "linux")]
+pub fn current_thread_cpus() -> ResultT<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok((0..MAX_CPUS)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpus: &[usize]) -> ResultT<()> {
+    Err(ErrorT::from("CPU affinity is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_thread_cpus() -> ResultT<Vec<usize>> {
+    Err(ErrorT::from("CPU affinity is only supported on linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_cpu_list() -> ResultT<()> {
+        assert_eq!(parse_cpu_list("3")?, vec![3]);
+        assert_eq!(parse_cpu_list("4-6,0,2,5")?, vec![0, 2, 4, 5, 6]);
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0-1024").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert_eq!(format_cpu_list(&[0, 2, 4, 5, 6]), "0,2,4-6");
+        assert_eq!(format_cpu_list(&[]), "");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn test_pin_current_thread() -> ResultT<()> {
+        // a thread of its own, the test threads are reused
+        std::thread::spawn(|| {
+            let cpu = current_thread_cpus()?[0];
+            pin_current_thread(&[cpu])?;
+            assert_eq!(current_thread_cpus()?, vec![cpu]);
+            Ok(())
+        })
+        .join()
+        .unwrap()
+    }
+}
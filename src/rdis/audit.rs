@@ -0,0 +1,193 @@
+use super::types::{ErrorT, ResultT};
+use hmac::{Hmac, KeyInit, Mac};
+use log::*;
+use sha2::Sha256;
+use std::io;
+use std::net::SocketAddr;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const HASH_FIELD: &str = " hash=";
+
+// audit log of the administrative commands run by the clients, enabled by audit-log, see
+// CommandTable::audited. Lines are logfmt and end with hash=, the HMAC-SHA256 in hex, keyed with
+// audit-log-key, of the hash of the previous line (64 zeros for the first one) followed by the
+// line up to " hash=":
+//
+//     ts=2022-04-15T10:00:00.000Z client=7 addr=127.0.0.1:50000 name="" command="CLIENT" args="KILL ID 8" outcome=ok hash=...
+//
+// Without the key a line can't be edited, removed or added without breaking the chain from there
+// on, see verify, only cutting the end of the file goes unnoticed. The engine hands the lines to
+// a task appending them to the file, flushed whenever the queue is empty, like the Recorder.
+pub struct AuditLog {
+    key: Vec<u8>,
+    last_hash: String,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+pub struct AuditEntry<'a> {
+    pub client: usize,
+    // None once the client disconnected
+    pub addr: Option<SocketAddr>,
+    pub name: Option<&'a str>,
+    pub command: &'a str,
+    pub args: &'a str,
+    // ok or the kind of the error reply
+    pub outcome: &'a str,
+}
+
+fn chain(key: &[u8], previous: &str, body: &str) -> String {
+    // any key length is valid for an HMAC
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(previous.as_bytes());
+    mac.update(body.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn genesis() -> String {
+    "0".repeat(64)
+}
+
+impl AuditLog {
+    // an existing file is appended to, continuing its chain. The handle completes once the
+    // engine is dropped
+    pub async fn open(path: &str, key: &[u8]) -> ResultT<(AuditLog, JoinHandle<()>)> {
+        let cannot_open = |err: io::Error| ErrorT::from(format!("Cannot open {}: {}", path, err));
+        let last_hash = match fs::read_to_string(path).await {
+            Ok(content) => match content.lines().last() {
+                Some(line) => match line.rsplit_once(HASH_FIELD) {
+                    Some((_, hash)) => hash.to_owned(),
+                    None => return Err(ErrorT::from(format!("{} is not an audit log", path))),
+                },
+                None => genesis(),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => genesis(),
+            Err(err) => return Err(cannot_open(err)),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(cannot_open)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let path = path.to_owned();
+        let handle = tokio::spawn(async move {
+            if let Err(err) = write_lines(file, receiver).await {
+                error!("Failed to write to the audit log {}: {}", path, err);
+            }
+        });
+        let audit = AuditLog {
+            key: key.to_owned(),
+            last_hash,
+            sender,
+        };
+        Ok((audit, handle))
+    }
+
+    pub fn record(&mut self, entry: &AuditEntry) {
+        let addr = entry.addr.map(|addr| addr.to_string()).unwrap_or_default();
+        let body = format!(
+            "ts={} client={} addr={} name={:?} command={:?} args={:?} outcome={}",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            entry.client,
+            addr,
+            entry.name.unwrap_or(""),
+            entry.command,
+            entry.args,
+            entry.outcome
+        );
+        self.last_hash = chain(&self.key, &self.last_hash, &body);
+        // the writer is gone only after an io error, already logged
+        let _ = self
+            .sender
+            .send(format!("{}{}{}\n", body, HASH_FIELD, self.last_hash));
+    }
+}
+
+async fn write_lines(file: File, mut receiver: mpsc::UnboundedReceiver<String>) -> ResultT<()> {
+    let mut writer = BufWriter::new(file);
+    while let Some(line) = receiver.recv().await {
+        writer.write_all(line.as_bytes()).await?;
+        if receiver.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// the number of lines of an intact log, or the number of the first line breaking the chain
+pub fn verify(content: &str, key: &[u8]) -> Result<usize, usize> {
+    let mut previous = genesis();
+    for (idx, line) in content.lines().enumerate() {
+        match line.rsplit_once(HASH_FIELD) {
+            Some((body, hash)) if chain(key, &previous, body) == hash => previous = hash.to_owned(),
+            _ => return Err(idx + 1),
+        }
+    }
+    Ok(content.lines().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    fn entry<'a>(command: &'a str, args: &'a str) -> AuditEntry<'a> {
+        AuditEntry {
+            client: 7,
+            addr: Some("127.0.0.1:50000".parse().unwrap()),
+            name: Some("admin"),
+            command,
+            args,
+            outcome: "ok",
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_chain() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-audit-{}.log", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path).await;
+        let (mut audit, handle) = AuditLog::open(&path, KEY).await?;
+        audit.record(&entry("CLIENT", "KILL ID 8"));
+        audit.record(&entry("CONFIG", "SET \"maxmemory\" 1mb"));
+        drop(audit);
+        handle.await?;
+        // reopening continues the chain
+        let (mut audit, handle) = AuditLog::open(&path, KEY).await?;
+        audit.record(&entry("FLUSHALL", ""));
+        drop(audit);
+        handle.await?;
+        let content = fs::read_to_string(&path).await?;
+        assert_eq!(verify(&content, KEY), Ok(3));
+        assert!(content.contains("client=7 addr=127.0.0.1:50000 name=\"admin\" command=\"CLIENT\""));
+        assert_eq!(verify(&content, b"guessed"), Err(1));
+
+        let lines: Vec<&str> = content.lines().collect();
+        let edited = content.replace("KILL ID 8", "KILL ID 9");
+        assert_eq!(verify(&edited, KEY), Err(1));
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        assert_eq!(verify(&removed, KEY), Err(2));
+        // the end of the file is not covered by the chain
+        let truncated = format!("{}\n{}\n", lines[0], lines[1]);
+        assert_eq!(verify(&truncated, KEY), Ok(2));
+        fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    // rewriting the whole file with a plain SHA-256 chain is no longer enough
+    #[test]
+    pub fn test_forged_chain() {
+        let body = "ts=2022-04-15T10:00:00.000Z client=1 addr= name=\"\" command=\"FLUSHALL\" args=\"\" outcome=ok";
+        let forged = format!("{}{}{}\n", body, HASH_FIELD, chain(b"", &genesis(), body));
+        assert_eq!(verify(&forged, KEY), Err(1));
+        let keyed = format!("{}{}{}\n", body, HASH_FIELD, chain(KEY, &genesis(), body));
+        assert_eq!(verify(&keyed, KEY), Ok(1));
+    }
+}
@@ -0,0 +1,170 @@
+use super::protocol::{ClientReq, RESP};
+use super::types::{ErrorT, RdisError, RedisEngineApi, ResultT};
+use bytes::Bytes;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+// client_epoch used by the embedded clients, never assigned to a connection
+pub const EMBEDDED_CLIENT: usize = usize::MAX - 1;
+
+// sends commands straight to the engine, without sockets and RESP encoding.
+// Error replies are returned as Err.
+#[derive(Clone)]
+pub struct RdisClient {
+    api: Arc<RedisEngineApi>,
+}
+
+impl RdisClient {
+    pub fn new(api: Arc<RedisEngineApi>) -> RdisClient {
+        RdisClient { api }
+    }
+
+    pub async fn command<A: AsRef<[u8]>>(&self, args: &[A]) -> ResultT<RESP> {
+        let cmd = RESP::Array(
+            args.iter()
+                .map(|a| RESP::BulkString(Bytes::copy_from_slice(a.as_ref())))
+                .collect(),
+        );
+        match self
+            .api
+            .request(EMBEDDED_CLIENT, ClientReq::Single(cmd))
+            .await?
+        {
+            ClientReq::Single(RESP::Error(kind, msg)) => Err(RdisError::from_reply(&kind, &msg)),
+            ClientReq::Single(resp) => Ok(resp),
+            ClientReq::Pipeline(_) => Err(ErrorT::from("Unexpected pipeline response")),
+        }
+    }
+
+    pub async fn ping(&self) -> ResultT<()> {
+        self.command(&["PING"]).await.map(|_| ())
+    }
+
+    pub async fn get<K: AsRef<[u8]>>(&self, key: K) -> ResultT<Option<Vec<u8>>> {
+        Option::try_from(self.command(&[b"GET", key.as_ref()]).await?)
+    }
+
+    pub async fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> ResultT<()> {
+        self.command(&[b"SET", key.as_ref(), value.as_ref()])
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn incr<K: AsRef<[u8]>>(&self, key: K) -> ResultT<i64> {
+        i64::try_from(self.command(&[b"INCR", key.as_ref()]).await?)
+    }
+
+    // None for the missing keys
+    pub async fn mget<K: AsRef<[u8]>>(&self, keys: &[K]) -> ResultT<Vec<Option<Vec<u8>>>> {
+        let mut args = vec![b"MGET".as_ref()];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        Vec::try_from(self.command(&args).await?)
+    }
+
+    // number of keys removed
+    pub async fn del<K: AsRef<[u8]>>(&self, keys: &[K]) -> ResultT<i64> {
+        let mut args = vec![b"DEL".as_ref()];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        i64::try_from(self.command(&args).await?)
+    }
+
+    // length of the list after the push
+    pub async fn lpush<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> ResultT<i64> {
+        i64::try_from(
+            self.command(&[b"LPUSH", key.as_ref(), value.as_ref()])
+                .await?,
+        )
+    }
+
+    // length of the list after the push
+    pub async fn rpush<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> ResultT<i64> {
+        i64::try_from(
+            self.command(&[b"RPUSH", key.as_ref(), value.as_ref()])
+                .await?,
+        )
+    }
+
+    pub async fn lpop<K: AsRef<[u8]>>(&self, key: K) -> ResultT<Option<Vec<u8>>> {
+        self.pop(b"LPOP", key.as_ref()).await
+    }
+
+    pub async fn rpop<K: AsRef<[u8]>>(&self, key: K) -> ResultT<Option<Vec<u8>>> {
+        self.pop(b"RPOP", key.as_ref()).await
+    }
+
+    // true if the lock was free, it's released after ttl unless unlocked before, see LOCK
+    pub async fn lock<K: AsRef<[u8]>, T: AsRef<[u8]>>(
+        &self,
+        key: K,
+        token: T,
+        ttl: Duration,
+    ) -> ResultT<bool> {
+        let ttl = ttl.as_millis().to_string();
+        let resp = self
+            .command(&[b"LOCK", key.as_ref(), token.as_ref(), ttl.as_bytes()])
+            .await?;
+        Ok(resp != RESP::Null)
+    }
+
+    // false if the lock expired or is held with another token
+    pub async fn unlock<K: AsRef<[u8]>, T: AsRef<[u8]>>(&self, key: K, token: T) -> ResultT<bool> {
+        bool::try_from(
+            self.command(&[b"UNLOCK", key.as_ref(), token.as_ref()])
+                .await?,
+        )
+    }
+
+    async fn pop(&self, cmd: &[u8], key: &[u8]) -> ResultT<Option<Vec<u8>>> {
+        Option::try_from(self.command(&[cmd, key]).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::config::Config;
+    use crate::rdis::engine::RedisEngine;
+    use crate::rdis::metrics::Metrics;
+    use crate::rdis::registry::ClientRegistry;
+    use crate::rdis::storage::RedisData;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    pub async fn test_embedded_client() -> ResultT<()> {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut engine = RedisEngine::new(
+            receiver,
+            Arc::new(ClientRegistry::new()),
+            Arc::new(Metrics::new()),
+            Box::new(RedisData::new()),
+            &Config::default(),
+        );
+        let engine_handle = tokio::spawn(async move { engine.start_loop().await });
+        let client = RdisClient::new(Arc::new(RedisEngineApi::new(sender)));
+
+        client.ping().await?;
+        assert_eq!(client.get("k").await?, None);
+        client.set("k", "1").await?;
+        assert_eq!(client.get("k").await?, Some(b"1".to_vec()));
+        assert_eq!(client.incr("k").await?, 2);
+        assert_eq!(
+            client.mget(&["k", "missing"]).await?,
+            vec![Some(b"2".to_vec()), None]
+        );
+        assert_eq!(client.del(&["k", "missing"]).await?, 1);
+        client.rpush("l", "a").await?;
+        assert_eq!(client.rpush("l", "b").await?, 2);
+        assert_eq!(client.lpop("l").await?, Some(b"a".to_vec()));
+        assert!(client.command(&["GET"]).await.is_err());
+        assert!(client.lock("lock", "a", Duration::from_secs(10)).await?);
+        assert!(!client.lock("lock", "b", Duration::from_secs(10)).await?);
+        assert!(!client.unlock("lock", "b").await?);
+        assert!(client.unlock("lock", "a").await?);
+        assert!(client.lock("lock", "b", Duration::from_secs(10)).await?);
+
+        drop(client);
+        engine_handle.await?;
+        Ok(())
+    }
+}
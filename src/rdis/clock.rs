@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// time source of the engine, in unix millis. Expiration is computed from it, so tests can
+// drive it with a ManualClock instead of sleeping.
+pub trait Clock: Send {
+    fn now_millis(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+// only moves when told to, clones share the same time
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(millis: u64) -> ManualClock {
+        ManualClock {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.millis.fetch_add(millis, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::Relaxed)
+    }
+}
+
+// used by deterministic-seed: starts at the seed and moves one millisecond every time it is
+// read, that is once per batch of requests dequeued by the engine. Runs sending the same
+// requests in the same order see the same times, whatever the speed of the machine.
+#[derive(Clone, Default)]
+pub struct SteppingClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl SteppingClock {
+    pub fn new(seed: u64) -> SteppingClock {
+        SteppingClock {
+            millis: Arc::new(AtomicU64::new(seed)),
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_manual_clock() {
+        let clock = ManualClock::new(10);
+        let shared = clock.clone();
+        shared.advance(5);
+        assert_eq!(clock.now_millis(), 15);
+        shared.set(3);
+        assert_eq!(clock.now_millis(), 3);
+        assert!(SystemClock.now_millis() > 1_600_000_000_000);
+    }
+
+    #[test]
+    pub fn test_stepping_clock() {
+        let clock = SteppingClock::new(100);
+        assert_eq!(clock.now_millis(), 100);
+        assert_eq!(clock.clone().now_millis(), 101);
+        assert_eq!(clock.now_millis(), 102);
+    }
+}
@@ -0,0 +1,80 @@
+// redis cluster key distribution: 16384 slots, the slot of a key is the CRC16 (XMODEM) of the
+// key, or of its hash tag, modulo the number of slots
+pub const SLOTS: u16 = 16384;
+
+// CRC16-CCITT (XMODEM): polynomial 0x1021, initial value 0
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// the part of the key between the first { and the following }, when not empty.
+// Keys sharing a hash tag are stored in the same slot: {user1000}.following, {user1000}.followers
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(start) = key.iter().position(|c| *c == b'{') {
+        if let Some(len) = key[start + 1..].iter().position(|c| *c == b'}') {
+            if len > 0 {
+                return &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) % SLOTS
+}
+
+// false when the keys hash to more than one slot, commands are then replied with CROSSSLOT
+pub fn same_slot<'a, I: IntoIterator<Item = &'a [u8]>>(keys: I) -> bool {
+    let mut slots = keys.into_iter().map(key_slot);
+    match slots.next() {
+        Some(first) => slots.all(|slot| slot == first),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    pub fn test_key_slot() {
+        // values returned by CLUSTER KEYSLOT on redis
+        assert_eq!(key_slot(b"somekey"), 11058);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b""), 0);
+        assert_eq!(key_slot(b"{foo}bar"), key_slot(b"foo"));
+    }
+
+    #[test]
+    pub fn test_hash_tag() {
+        assert_eq!(hash_tag(b"{user1000}.following"), b"user1000");
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+        assert!(same_slot(vec![
+            &b"{user1000}.following"[..],
+            b"{user1000}.followers"
+        ]));
+        assert!(!same_slot(vec![&b"foo"[..], b"bar"]));
+        assert!(same_slot(Vec::<&[u8]>::new()));
+    }
+}
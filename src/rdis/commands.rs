@@ -0,0 +1,371 @@
+use super::protocol::RESP;
+use super::types::{ErrorT, ResultT};
+use std::collections::{HashMap, HashSet};
+
+// commands implemented by the engine with their arity, anything else is replied with unknown
+// command. The arity counts the name too, a negative one is the minimum number of arguments
+const COMMANDS: &[(&str, i32)] = &[
+    ("PING", 1),
+    ("COMMAND", -1),
+    ("CLIENT", -2),
+    ("CLUSTER", -2),
+    ("CONFIG", -2),
+    ("DBSIZE", 1),
+    ("DEBUG", -2),
+    ("INFO", -1),
+    ("MEMORY", -2),
+    ("TYPE", 2),
+    ("STRLEN", 2),
+    ("LLEN", 2),
+    ("GET", 2),
+    ("INCR", 2),
+    ("INCRBY", 3),
+    ("DEL", -2),
+    ("UNLINK", -2),
+    ("EXISTS", -2),
+    ("EXPIRE", -3),
+    ("PEXPIRE", -3),
+    ("EXPIREAT", -3),
+    ("PEXPIREAT", -3),
+    ("PERSIST", 2),
+    ("TTL", 2),
+    ("PTTL", 2),
+    ("EXPIRETIME", 2),
+    ("PEXPIRETIME", 2),
+    ("LOCK", 4),
+    ("UNLOCK", 3),
+    ("RATELIMIT", -4),
+    ("MCFLAGS", -2),
+    ("SCAN", -2),
+    ("KEYS", 2),
+    ("HSCAN", -3),
+    ("SSCAN", -3),
+    ("ZSCAN", -3),
+    ("LPOP", 2),
+    ("RPOP", 2),
+    ("SET", -3),
+    ("MSET", -3),
+    ("MGET", -2),
+    ("LPUSH", -3),
+    ("RPUSH", -3),
+    ("LRANGE", 4),
+    ("HSET", -4),
+    ("HGET", 3),
+    ("HMGET", -3),
+    ("HDEL", -3),
+    ("HGETALL", 2),
+    ("HEXISTS", 3),
+    ("HLEN", 2),
+    ("SADD", -3),
+    ("SREM", -3),
+    ("SMEMBERS", 2),
+    ("SISMEMBER", 3),
+    ("SCARD", 2),
+    ("SPOP", -2),
+    ("ZADD", -4),
+    ("ZREM", -3),
+    ("ZSCORE", 3),
+    ("ZRANK", 3),
+    ("ZRANGE", -4),
+    ("ZCARD", 2),
+    ("XADD", -5),
+    ("XRANGE", -4),
+    ("XREVRANGE", -4),
+    ("XLEN", 2),
+    ("XREAD", -4),
+    ("MULTI", 1),
+    ("EXEC", 1),
+    ("DISCARD", 1),
+];
+
+// commands modifying their first argument, they publish Event::KeyWritten.
+// DEL, UNLINK and MSET publish an event for every key instead.
+const WRITE_COMMANDS: &[&str] = &[
+    "INCR",
+    "INCRBY",
+    "LPOP",
+    "RPOP",
+    "SET",
+    "LPUSH",
+    "RPUSH",
+    "HSET",
+    "HDEL",
+    "SADD",
+    "SREM",
+    "SPOP",
+    "ZADD",
+    "ZREM",
+    "XADD",
+    "EXPIRE",
+    "PEXPIRE",
+    "EXPIREAT",
+    "PEXPIREAT",
+    "PERSIST",
+    "LOCK",
+    "UNLOCK",
+    "RATELIMIT",
+];
+
+// keys of the commands touching more than one key, they must share a slot in cluster mode
+pub fn multi_keys<'a>(cmd: &[u8], args: &'a [RESP]) -> Vec<&'a [u8]> {
+    let (args, step) = match cmd {
+        b"DEL" | b"UNLINK" | b"EXISTS" | b"MGET" => (args, 1),
+        b"MSET" => (args, 2),
+        // the keys follow STREAMS, then come their ids
+        b"XREAD" => {
+            let streams = args.iter().position(
+                |arg| matches!(arg, RESP::BulkString(a) if a.eq_ignore_ascii_case(b"STREAMS")),
+            );
+            match streams {
+                Some(at) => {
+                    let streams = &args[at + 1..];
+                    (&streams[..streams.len() / 2], 1)
+                }
+                None => return Vec::new(),
+            }
+        }
+        _ => return Vec::new(),
+    };
+    args.iter()
+        .step_by(step)
+        .filter_map(|arg| match arg {
+            RESP::BulkString(k) => Some(k.as_ref()),
+            _ => None,
+        })
+        .collect()
+}
+
+// commands written to the audit log, with the subcommand when only some of them are audited
+const AUDITED_COMMANDS: &[(&str, Option<&str>)] =
+    &[("CLIENT", Some("KILL")), ("DEBUG", Some("FAULT"))];
+
+// run as soon as they are received inside a transaction instead of being queued
+const TRANSACTION_COMMANDS: &[&str] = &["EXEC", "DISCARD"];
+
+// refused inside a transaction, the refusal discards it
+const NO_MULTI_COMMANDS: &[&str] = &["MULTI"];
+
+// commands whose first argument is their only key
+const KEY_COMMANDS: &[&str] = &[
+    "TYPE",
+    "STRLEN",
+    "LLEN",
+    "GET",
+    "INCR",
+    "INCRBY",
+    "EXPIRE",
+    "PEXPIRE",
+    "EXPIREAT",
+    "PEXPIREAT",
+    "PERSIST",
+    "TTL",
+    "PTTL",
+    "EXPIRETIME",
+    "PEXPIRETIME",
+    "LOCK",
+    "UNLOCK",
+    "RATELIMIT",
+    "MCFLAGS",
+    "LPOP",
+    "RPOP",
+    "SET",
+    "LPUSH",
+    "RPUSH",
+    "LRANGE",
+    "HSET",
+    "HGET",
+    "HMGET",
+    "HDEL",
+    "HGETALL",
+    "HEXISTS",
+    "HLEN",
+    "HSCAN",
+    "SADD",
+    "SREM",
+    "SMEMBERS",
+    "SISMEMBER",
+    "SCARD",
+    "SPOP",
+    "SSCAN",
+    "ZADD",
+    "ZREM",
+    "ZSCORE",
+    "ZRANK",
+    "ZRANGE",
+    "ZCARD",
+    "ZSCAN",
+    "XADD",
+    "XRANGE",
+    "XREVRANGE",
+    "XLEN",
+];
+
+// keys of any command, counted by hotkeys-sample-rate. Custom commands have no known keys
+pub fn keys<'a>(cmd: &[u8], args: &'a [RESP]) -> Vec<&'a [u8]> {
+    match args.first() {
+        Some(RESP::BulkString(k)) if KEY_COMMANDS.iter().any(|c| c.as_bytes() == cmd) => {
+            vec![k.as_ref()]
+        }
+        _ => multi_keys(cmd, args),
+    }
+}
+
+// resolves the name sent by the client to the command executed by the engine.
+// Built once at startup from the rename-command directives.
+#[derive(Debug, Default)]
+pub struct CommandTable {
+    aliases: HashMap<Vec<u8>, Vec<u8>>,
+    hidden: HashSet<Vec<u8>>,
+    // registered by CustomCommand implementations
+    custom: HashSet<Vec<u8>>,
+}
+
+impl CommandTable {
+    pub fn new(renames: &[(Vec<u8>, Vec<u8>)]) -> CommandTable {
+        let mut table = CommandTable::default();
+        for (from, to) in renames {
+            table.hidden.insert(from.clone());
+            if !to.is_empty() {
+                table.aliases.insert(to.clone(), from.clone());
+            }
+        }
+        table
+    }
+
+    pub fn register(&mut self, name: &str) -> ResultT<()> {
+        let upper = name.to_ascii_uppercase();
+        if COMMANDS.iter().any(|(c, _)| *c == upper) || !self.custom.insert(upper.into_bytes()) {
+            return Err(ErrorT::from(format!("Command {} is already defined", name)));
+        }
+        Ok(())
+    }
+
+    // cmd is the resolved name
+    pub fn is_write(&self, cmd: &[u8]) -> bool {
+        WRITE_COMMANDS.iter().any(|c| c.as_bytes() == cmd)
+    }
+
+    // the write commands plus the multi-key ones, recorded by record-file.
+    // Custom commands are never recorded, their effects are unknown
+    pub fn modifies_keyspace(&self, cmd: &[u8]) -> bool {
+        self.is_write(cmd) || cmd == b"DEL" || cmd == b"UNLINK" || cmd == b"MSET"
+    }
+
+    pub fn audited(&self, cmd: &[u8], args: &[RESP]) -> bool {
+        let sub = match args.first() {
+            Some(RESP::BulkString(sub)) => sub.to_ascii_uppercase(),
+            _ => Vec::new(),
+        };
+        AUDITED_COMMANDS
+            .iter()
+            .any(|(c, s)| c.as_bytes() == cmd && s.is_none_or(|s| s.as_bytes() == sub.as_slice()))
+    }
+
+    pub fn is_queued(&self, cmd: &[u8]) -> bool {
+        !TRANSACTION_COMMANDS.iter().any(|c| c.as_bytes() == cmd)
+    }
+
+    pub fn allowed_in_multi(&self, cmd: &[u8]) -> bool {
+        !NO_MULTI_COMMANDS.iter().any(|c| c.as_bytes() == cmd)
+    }
+
+    // argc counts the name. Custom commands check their own arguments
+    pub fn arity_ok(&self, cmd: &[u8], argc: usize) -> bool {
+        match COMMANDS.iter().find(|(c, _)| c.as_bytes() == cmd) {
+            Some((_, arity)) if *arity < 0 => argc >= arity.unsigned_abs() as usize,
+            Some((_, arity)) => argc == *arity as usize,
+            None => true,
+        }
+    }
+
+    // None when the command is unknown, renamed or disabled
+    pub fn resolve(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let upper = name.to_ascii_uppercase();
+        match self.aliases.get(&upper) {
+            Some(original) => Some(original.clone()),
+            None if self.hidden.contains(&upper) => None,
+            None if COMMANDS
+                .iter()
+                .any(|(c, _)| c.as_bytes() == upper.as_slice()) =>
+            {
+                Some(upper)
+            }
+            None if self.custom.contains(&upper) => Some(upper),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_resolve() {
+        let table = CommandTable::new(&[
+            (b"FLUSHALL".to_vec(), b"".to_vec()),
+            (b"GET".to_vec(), b"FETCH".to_vec()),
+        ]);
+        assert_eq!(table.resolve(b"flushall"), None);
+        assert_eq!(table.resolve(b"GET"), None);
+        assert_eq!(table.resolve(b"fetch"), Some(b"GET".to_vec()));
+        assert_eq!(table.resolve(b"set"), Some(b"SET".to_vec()));
+        assert_eq!(table.resolve(b"unknown"), None);
+    }
+
+    #[test]
+    pub fn test_register() {
+        let mut table = CommandTable::new(&[]);
+        assert!(table.register("hello").is_ok());
+        assert!(table.register("HELLO").is_err());
+        assert!(table.register("get").is_err());
+        assert_eq!(table.resolve(b"Hello"), Some(b"HELLO".to_vec()));
+    }
+
+    #[test]
+    pub fn test_audited() {
+        let table = CommandTable::new(&[]);
+        let args = |args: &[&str]| args.iter().map(|a| RESP::from(*a)).collect::<Vec<_>>();
+        assert!(table.audited(b"CLIENT", &args(&["kill", "id", "3"])));
+        assert!(!table.audited(b"CLIENT", &args(&["list"])));
+        assert!(table.audited(b"DEBUG", &args(&["FAULT", "latency", "5"])));
+        assert!(!table.audited(b"DEBUG", &args(&["HOTKEYS"])));
+        assert!(!table.audited(b"GET", &args(&["k"])));
+        assert!(!table.audited(b"CLIENT", &[]));
+    }
+
+    #[test]
+    pub fn test_multi_flags() {
+        let table = CommandTable::new(&[]);
+        assert!(table.is_queued(b"SET"));
+        assert!(!table.is_queued(b"EXEC"));
+        assert!(!table.allowed_in_multi(b"MULTI"));
+        assert!(table.allowed_in_multi(b"GET"));
+        assert!(table.arity_ok(b"GET", 2));
+        assert!(!table.arity_ok(b"GET", 1));
+        assert!(!table.arity_ok(b"GET", 3));
+        assert!(table.arity_ok(b"DEL", 4));
+        assert!(!table.arity_ok(b"DEL", 1));
+        assert!(table.arity_ok(b"CUSTOM", 7));
+    }
+
+    #[test]
+    pub fn test_multi_keys() {
+        let args: Vec<RESP> = ["a", "1", "b", "2"]
+            .iter()
+            .map(|a| RESP::from(*a))
+            .collect();
+        assert_eq!(multi_keys(b"MSET", &args), vec![&b"a"[..], b"b"]);
+        assert_eq!(multi_keys(b"DEL", &args).len(), 4);
+        assert_eq!(multi_keys(b"MGET", &args).len(), 4);
+        assert!(multi_keys(b"GET", &args).is_empty());
+        let xread: Vec<RESP> = ["COUNT", "2", "STREAMS", "a", "b", "0", "0"]
+            .iter()
+            .map(|a| RESP::from(*a))
+            .collect();
+        assert_eq!(multi_keys(b"XREAD", &xread), vec![&b"a"[..], b"b"]);
+        assert_eq!(keys(b"GET", &args), vec![&b"a"[..]]);
+        assert_eq!(keys(b"MSET", &args), vec![&b"a"[..], b"b"]);
+        assert!(keys(b"PING", &args).is_empty());
+    }
+}
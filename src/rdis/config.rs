@@ -0,0 +1,805 @@
+use super::affinity::{format_cpu_list, parse_cpu_list, pin_current_thread};
+use super::logging::parse_facility;
+use super::systemd::Supervised;
+use super::types::{ErrorT, ResultT};
+use log::{error, LevelFilter};
+use std::fs;
+use std::time::Duration;
+use tokio::runtime::{self, Runtime};
+
+const DEFAULT_PIDFILE: &str = "/var/run/rdis.pid";
+const DEFAULT_PIPELINE_SLICE: usize = 1024;
+
+// server configuration, read from a redis.conf style file and/or the command line.
+// Command line directives are applied after the file, like redis-server does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    // http admin endpoint, disabled when 0
+    pub admin_port: u16,
+    // memcached text protocol listener, disabled when 0
+    pub memcached_port: u16,
+    // multi-key commands must touch a single slot
+    pub cluster_enabled: bool,
+    // GETs are replied by the connections from a cache of the strings, see ReadCache
+    pub read_fast_path: bool,
+    // the default storage keeps an index of the keys by prefix, see RedisData::with_prefix_index
+    pub keyspace_prefix_index: bool,
+    // long commands checking it are aborted with -TIMEOUT once they run for longer
+    pub command_time_budget: Option<Duration>,
+    // commands of a pipeline run before the requests of the other clients, 0 runs pipelines
+    // to the end
+    pub pipeline_slice: usize,
+    // the keys of one command in n are counted for DEBUG HOTKEYS, 0 disables the tracking
+    pub hotkeys_sample_rate: u64,
+    // commands modifying the keyspace are appended to this file, see rdis-replay
+    pub record_file: Option<String>,
+    // commands run at startup, before accepting clients, see preload
+    pub preload: Option<String>,
+    // administrative commands are appended to this file, see AuditLog
+    pub audit_log: Option<String>,
+    // key of the HMAC chaining the lines of the audit log, required with audit-log
+    pub audit_log_key: Option<String>,
+    // single threaded runtime and an engine clock starting at the seed, see SteppingClock
+    pub deterministic_seed: Option<u64>,
+    // (original, new name), an empty new name disables the command
+    pub rename_commands: Vec<(Vec<u8>, Vec<u8>)>,
+    pub limits: ClientLimits,
+    pub defrag: DefragConfig,
+    pub tiering: TieringConfig,
+    pub supervised: Supervised,
+    // fork and detach at startup, see daemon::daemonize
+    pub daemonize: bool,
+    pub pidfile: Option<String>,
+    pub io_backend: IoBackend,
+    pub runtime: RuntimeConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    // (module path, level), overrides the global level for that module and its children
+    pub module_levels: Vec<(String, LevelFilter)>,
+    // per-command trace records are emitted for one command out of sample_rate
+    pub sample_rate: u64,
+    // logfmt lines appended to this file instead of stdout, see FileLogger
+    pub file: Option<String>,
+    pub rotation: LogRotation,
+    pub syslog: SyslogConfig,
+    pub wire_trace: WireTraceConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyslogConfig {
+    pub enabled: bool,
+    pub ident: String,
+    // LOG_LOCAL0 by default, see logging::parse_facility
+    pub facility: i32,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        SyslogConfig {
+            enabled: false,
+            ident: "rdis".to_owned(),
+            facility: libc::LOG_LOCAL0,
+        }
+    }
+}
+
+// frames exchanged with the clients, see wiretrace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WireTraceConfig {
+    // every client is traced from the start, CLIENT TRACE toggles a single one
+    pub enabled: bool,
+    // bytes of a frame logged, the rest is only counted
+    pub max_bytes: usize,
+}
+
+impl Default for WireTraceConfig {
+    fn default() -> Self {
+        WireTraceConfig {
+            enabled: false,
+            max_bytes: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRotation {
+    // the log file is rotated before growing over max_size bytes
+    pub max_size: Option<usize>,
+    // or once it has been open for interval
+    pub interval: Option<Duration>,
+    // rotated files kept, the file is truncated when 0
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation {
+            max_size: None,
+            interval: None,
+            keep: 5,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            module_levels: Vec::new(),
+            sample_rate: 1,
+            file: None,
+            rotation: LogRotation::default(),
+            syslog: SyslogConfig::default(),
+            wire_trace: WireTraceConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeConfig {
+    pub worker_threads: usize,
+    pub thread_name: String,
+    pub max_blocking_threads: usize,
+    // tokio default when None
+    pub thread_stack_size: Option<usize>,
+    // everything runs on the main thread, worker_threads is ignored
+    pub current_thread: bool,
+    // the engine runs on a thread of its own instead of a runtime worker
+    pub engine_thread: bool,
+    // CPUs the engine thread is pinned to, implies engine_thread
+    pub engine_cpus: Vec<usize>,
+    // CPUs the runtime workers and blocking threads are pinned to
+    pub worker_cpus: Vec<usize>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            worker_threads: 4,
+            thread_name: "rdis-worker".to_owned(),
+            max_blocking_threads: 512,
+            thread_stack_size: None,
+            current_thread: false,
+            engine_thread: false,
+            engine_cpus: Vec::new(),
+            worker_cpus: Vec::new(),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn build(&self) -> ResultT<Runtime> {
+        let mut builder = if self.current_thread {
+            runtime::Builder::new_current_thread()
+        } else {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(self.worker_threads);
+            builder
+        };
+        builder
+            .enable_all()
+            .thread_name(self.thread_name.clone())
+            .max_blocking_threads(self.max_blocking_threads);
+        if let Some(size) = self.thread_stack_size {
+            builder.thread_stack_size(size);
+        }
+        if !self.worker_cpus.is_empty() {
+            let cpus = self.worker_cpus.clone();
+            builder.on_thread_start(move || {
+                if let Err(e) = pin_current_thread(&cpus) {
+                    error!(
+                        "Failed to pin worker to CPUs {}: {}",
+                        format_cpu_list(&cpus),
+                        e
+                    );
+                }
+            });
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoBackend {
+    Tokio,
+    // requires the io-uring feature
+    Uring,
+}
+
+impl IoBackend {
+    pub fn parse(value: &str) -> ResultT<IoBackend> {
+        match value.to_lowercase().as_str() {
+            "tokio" => Ok(IoBackend::Tokio),
+            "uring" if cfg!(feature = "io-uring") => Ok(IoBackend::Uring),
+            "uring" => Err(ErrorT::from("rdis was built without the io-uring feature")),
+            other => Err(ErrorT::from(format!("Invalid io backend {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientLimits {
+    // max bytes of unparsed input kept for a single client
+    pub query_buffer_limit: usize,
+    // 0 means unlimited
+    pub max_requests_per_sec: u64,
+    // bytes held by the buffers of all the clients together, 0 means unlimited
+    pub maxmemory_clients: usize,
+    // connections not sending a complete request this long after being accepted are closed
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        ClientLimits {
+            query_buffer_limit: 1024 * 1024 * 1024,
+            max_requests_per_sec: 0,
+            maxmemory_clients: 0,
+            handshake_timeout: None,
+        }
+    }
+}
+
+// active defragmentation, see defrag::ActiveDefrag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DefragConfig {
+    pub enabled: bool,
+    // a pass starts when the fragmentation is over both thresholds, in percent and in bytes
+    pub threshold_lower: u64,
+    pub ignore_bytes: usize,
+}
+
+impl Default for DefragConfig {
+    fn default() -> Self {
+        DefragConfig {
+            enabled: false,
+            threshold_lower: 10,
+            ignore_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+// idle strings moved to disk, see tiered::Tiering
+#[derive(Debug, Clone, PartialEq)]
+pub struct TieringConfig {
+    // disabled when None
+    pub file: Option<String>,
+    pub idle: Duration,
+    // smaller strings stay in memory
+    pub min_value_size: usize,
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        TieringConfig {
+            file: None,
+            idle: Duration::from_secs(3600),
+            min_value_size: 64,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_owned(),
+            port: 6379,
+            admin_port: 0,
+            memcached_port: 0,
+            cluster_enabled: false,
+            read_fast_path: false,
+            keyspace_prefix_index: false,
+            command_time_budget: None,
+            pipeline_slice: DEFAULT_PIPELINE_SLICE,
+            hotkeys_sample_rate: 0,
+            record_file: None,
+            preload: None,
+            audit_log: None,
+            audit_log_key: None,
+            deterministic_seed: None,
+            rename_commands: Vec::new(),
+            limits: ClientLimits::default(),
+            defrag: DefragConfig::default(),
+            tiering: TieringConfig::default(),
+            supervised: Supervised::Auto,
+            daemonize: false,
+            pidfile: None,
+            io_backend: IoBackend::Tokio,
+            runtime: RuntimeConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    // rdis [/path/to/rdis.conf] [--directive arg ...]
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> ResultT<Config> {
+        let mut config = Config::default();
+        let mut directives: Vec<Vec<String>> = Vec::new();
+        for arg in &mut args {
+            if let Some(name) = arg.strip_prefix("--") {
+                directives.push(vec![name.to_owned()]);
+            } else if let Some(last) = directives.last_mut() {
+                last.push(arg);
+            } else {
+                config.load_file(&arg)?;
+            }
+        }
+        for d in directives {
+            config.apply(&d[0], &d[1..])?;
+        }
+        Ok(config)
+    }
+
+    pub fn load_file(&mut self, path: &str) -> ResultT<()> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| ErrorT::from(format!("Cannot read config file {}: {}", path, err)))?;
+        self.load_str(&content)
+    }
+
+    pub fn load_str(&mut self, content: &str) -> ResultT<()> {
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens = split_line(line)
+                .ok_or_else(|| ErrorT::from(format!("Unbalanced quotes at line {}", idx + 1)))?;
+            self.apply(&tokens[0], &tokens[1..])
+                .map_err(|err| ErrorT::from(format!("Error at line {}: {}", idx + 1, err)))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, directive: &str, args: &[String]) -> ResultT<()> {
+        match (directive.to_lowercase().as_str(), args) {
+            ("bind", [addr]) => self.bind = addr.clone(),
+            ("port", [port]) => self.port = port.parse()?,
+            ("admin-port", [port]) => {
+                self.admin_port = port.parse()?;
+                if self.admin_port != 0 && !cfg!(feature = "admin") {
+                    return Err(ErrorT::from("rdis was built without the admin feature"));
+                }
+            }
+            ("memcached-port", [port]) => {
+                self.memcached_port = port.parse()?;
+                if self.memcached_port != 0 && !cfg!(feature = "memcached") {
+                    return Err(ErrorT::from("rdis was built without the memcached feature"));
+                }
+            }
+            ("cluster-enabled", [flag]) => self.cluster_enabled = parse_bool(flag)?,
+            ("read-fast-path", [flag]) => self.read_fast_path = parse_bool(flag)?,
+            ("keyspace-prefix-index", [flag]) => self.keyspace_prefix_index = parse_bool(flag)?,
+            // in millis, 0 disables the budget
+            ("command-time-budget", [ms]) => {
+                self.command_time_budget = match ms.parse()? {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                }
+            }
+            ("pipeline-slice", [n]) => self.pipeline_slice = n.parse()?,
+            ("hotkeys-sample-rate", [n]) => self.hotkeys_sample_rate = n.parse()?,
+            ("record-file", [path]) => self.record_file = Some(path.clone()),
+            ("preload", [path]) => self.preload = Some(path.clone()).filter(|p| !p.is_empty()),
+            ("audit-log", [path]) => {
+                self.audit_log = Some(path.clone()).filter(|path| !path.is_empty())
+            }
+            ("audit-log-key", [key]) => {
+                self.audit_log_key = Some(key.clone()).filter(|key| !key.is_empty())
+            }
+            ("deterministic-seed", [seed]) => {
+                self.deterministic_seed = Some(seed.parse()?);
+                self.runtime.current_thread = true;
+            }
+            ("rename-command", [from, to]) => self.rename_commands.push((
+                from.to_uppercase().into_bytes(),
+                to.to_uppercase().into_bytes(),
+            )),
+            ("supervised", [mode]) => self.supervised = Supervised::parse(mode)?,
+            ("daemonize", [flag]) => self.daemonize = parse_bool(flag)?,
+            ("pidfile", [path]) => self.pidfile = Some(path.clone()).filter(|p| !p.is_empty()),
+            ("io-backend", [backend]) => self.io_backend = IoBackend::parse(backend)?,
+            ("worker-threads", [n]) => self.runtime.worker_threads = parse_positive(n)?,
+            ("thread-name", [name]) => self.runtime.thread_name = name.clone(),
+            ("max-blocking-threads", [n]) => self.runtime.max_blocking_threads = parse_positive(n)?,
+            ("engine-thread", [flag]) => self.runtime.engine_thread = parse_bool(flag)?,
+            ("engine-cpulist", [cpus]) => {
+                self.runtime.engine_cpus = parse_cpu_list(cpus)?;
+                self.runtime.engine_thread = true;
+            }
+            ("worker-cpulist", [cpus]) => self.runtime.worker_cpus = parse_cpu_list(cpus)?,
+            ("thread-stack-size", [size]) => {
+                self.runtime.thread_stack_size = Some(parse_memory(size)?)
+            }
+            ("client-query-buffer-limit", [limit]) => {
+                self.limits.query_buffer_limit = parse_memory(limit)?
+            }
+            ("maxmemory-clients", [limit]) => self.limits.maxmemory_clients = parse_memory(limit)?,
+            ("activedefrag", [flag]) => self.defrag.enabled = parse_bool(flag)?,
+            ("active-defrag-threshold-lower", [percent]) => {
+                self.defrag.threshold_lower = percent.parse()?
+            }
+            ("active-defrag-ignore-bytes", [size]) => {
+                self.defrag.ignore_bytes = parse_memory(size)?
+            }
+            ("tiering-file", [path]) => {
+                self.tiering.file = Some(path.clone()).filter(|p| !p.is_empty())
+            }
+            ("tiering-idle-seconds", [secs]) => {
+                self.tiering.idle = Duration::from_secs(secs.parse()?)
+            }
+            ("tiering-min-value-size", [size]) => self.tiering.min_value_size = parse_memory(size)?,
+            ("client-max-requests-per-sec", [max]) => {
+                self.limits.max_requests_per_sec = max.parse()?
+            }
+            // in millis, 0 disables the timeout
+            ("client-handshake-timeout", [ms]) => {
+                self.limits.handshake_timeout = match ms.parse()? {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                }
+            }
+            ("loglevel", [level]) => self.log.level = parse_log_level(level)?,
+            ("log-module-level", [module, level]) => self
+                .log
+                .module_levels
+                .push((module.clone(), parse_log_level(level)?)),
+            ("log-sample-rate", [rate]) => self.log.sample_rate = parse_positive(rate)? as u64,
+            // an empty path logs to stdout, like redis
+            ("logfile", [path]) => self.log.file = Some(path.clone()).filter(|p| !p.is_empty()),
+            ("logfile-max-size", [size]) => self.log.rotation.max_size = Some(parse_memory(size)?),
+            ("logfile-rotate-interval", [secs]) => {
+                self.log.rotation.interval = Some(Duration::from_secs(parse_positive(secs)? as u64))
+            }
+            ("logfile-keep", [n]) => self.log.rotation.keep = n.parse()?,
+            ("syslog-enabled", [flag]) => self.log.syslog.enabled = parse_bool(flag)?,
+            ("syslog-ident", [ident]) => self.log.syslog.ident = ident.clone(),
+            ("syslog-facility", [name]) => self.log.syslog.facility = parse_facility(name)?,
+            ("wire-trace", [flag]) => self.log.wire_trace.enabled = parse_bool(flag)?,
+            ("wire-trace-max-bytes", [size]) => self.log.wire_trace.max_bytes = parse_memory(size)?,
+            (other, _) => {
+                return Err(ErrorT::from(format!(
+                    "Bad directive or wrong number of arguments: {}",
+                    other
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    pub fn admin_addr(&self) -> Option<String> {
+        match self.admin_port {
+            0 => None,
+            port => Some(format!("{}:{}", self.bind, port)),
+        }
+    }
+
+    // reported by CONFIG GET, redis-benchmark reads save and appendonly at startup
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("appendonly", "no".to_owned()),
+            ("bind", self.bind.clone()),
+            ("databases", "1".to_owned()),
+            ("port", self.port.to_string()),
+            ("save", String::new()),
+        ]
+    }
+
+    // a daemonized server always writes its pid, like redis
+    pub fn pidfile_path(&self) -> Option<String> {
+        match (&self.pidfile, self.daemonize) {
+            (Some(path), _) => Some(path.clone()),
+            (None, true) => Some(DEFAULT_PIDFILE.to_owned()),
+            (None, false) => None,
+        }
+    }
+
+    // deterministic runs keep the engine on the runtime thread
+    pub fn dedicated_engine_thread(&self) -> bool {
+        self.runtime.engine_thread && self.deterministic_seed.is_none()
+    }
+
+    pub fn memcached_addr(&self) -> Option<String> {
+        match self.memcached_port {
+            0 => None,
+            port => Some(format!("{}:{}", self.bind, port)),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> ResultT<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        other => Err(ErrorT::from(format!(
+            "argument must be 'yes' or 'no', got {}",
+            other
+        ))),
+    }
+}
+
+fn parse_positive(value: &str) -> ResultT<usize> {
+    match value.parse()? {
+        0 => Err(ErrorT::from("value must be greater than 0")),
+        n => Ok(n),
+    }
+}
+
+// redis level names are accepted along with the log crate ones
+fn parse_log_level(value: &str) -> ResultT<LevelFilter> {
+    match value.to_lowercase().as_str() {
+        "verbose" => Ok(LevelFilter::Debug),
+        "notice" => Ok(LevelFilter::Info),
+        "warning" => Ok(LevelFilter::Warn),
+        "nothing" => Ok(LevelFilter::Off),
+        other => other
+            .parse()
+            .map_err(|_| ErrorT::from(format!("Invalid log level {}", value))),
+    }
+}
+
+// 1gb, 512mb, 64kb or plain bytes
+pub fn parse_memory(value: &str) -> ResultT<usize> {
+    let lower = value.to_lowercase();
+    let units: [(&str, usize); 6] = [
+        ("gb", 1024 * 1024 * 1024),
+        ("mb", 1024 * 1024),
+        ("kb", 1024),
+        ("g", 1000 * 1000 * 1000),
+        ("m", 1000 * 1000),
+        ("k", 1000),
+    ];
+    for (suffix, mul) in units.iter() {
+        if let Some(n) = lower.strip_suffix(suffix) {
+            return Ok(n.parse::<usize>()? * mul);
+        }
+    }
+    Ok(lower.parse()?)
+}
+
+// splits on whitespace, "double" and 'single' quoted tokens can contain spaces or be empty
+fn split_line(line: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quote = match chars.peek() {
+            None => return Some(tokens),
+            Some('"') | Some('\'') => chars.next(),
+            Some(_) => None,
+        };
+        let mut token = String::new();
+        loop {
+            match (chars.next(), quote) {
+                (None, Some(_)) => return None,
+                (None, None) => break,
+                (Some(c), Some(q)) if c == q => break,
+                (Some(c), None) if c.is_whitespace() => break,
+                (Some(c), _) => token.push(c),
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_split_line() {
+        assert_eq!(
+            split_line("rename-command FLUSHALL \"\"").unwrap(),
+            vec!["rename-command", "FLUSHALL", ""]
+        );
+        assert_eq!(
+            split_line("  bind   '0.0.0.0' ").unwrap(),
+            vec!["bind", "0.0.0.0"]
+        );
+        assert!(split_line("bind \"0.0.0.0").is_none());
+    }
+
+    #[test]
+    pub fn test_load_str() -> ResultT<()> {
+        let mut config = Config::default();
+        config.load_str("# comment\nport 7000\n\nrename-command flushall \"\"\n")?;
+        assert_eq!(config.port, 7000);
+        assert_eq!(
+            config.rename_commands,
+            vec![(b"FLUSHALL".to_vec(), b"".to_vec())]
+        );
+        assert!(config.load_str("port").is_err());
+        assert!(config.load_str("unknown-directive 1").is_err());
+        config.load_str("cluster-enabled YES")?;
+        assert!(config.cluster_enabled);
+        config.load_str("read-fast-path yes")?;
+        assert!(config.read_fast_path);
+        config.load_str("keyspace-prefix-index yes")?;
+        assert!(config.keyspace_prefix_index);
+        config.load_str("command-time-budget 50")?;
+        assert_eq!(config.command_time_budget, Some(Duration::from_millis(50)));
+        config.load_str("command-time-budget 0")?;
+        assert_eq!(config.command_time_budget, None);
+        assert_eq!(config.pipeline_slice, DEFAULT_PIPELINE_SLICE);
+        config.load_str("pipeline-slice 0")?;
+        assert_eq!(config.pipeline_slice, 0);
+        config.load_str("activedefrag yes\nactive-defrag-ignore-bytes 1mb")?;
+        assert!(config.defrag.enabled);
+        assert_eq!(config.defrag.ignore_bytes, 1024 * 1024);
+        config.load_str("tiering-file /tmp/cold.dat\ntiering-idle-seconds 60")?;
+        assert_eq!(config.tiering.file.as_deref(), Some("/tmp/cold.dat"));
+        assert_eq!(config.tiering.idle, Duration::from_secs(60));
+        config.load_str("maxmemory-clients 64kb")?;
+        assert_eq!(config.limits.maxmemory_clients, 64 * 1024);
+        config.load_str("client-handshake-timeout 500")?;
+        assert_eq!(
+            config.limits.handshake_timeout,
+            Some(Duration::from_millis(500))
+        );
+        config.load_str("hotkeys-sample-rate 10")?;
+        assert_eq!(config.hotkeys_sample_rate, 10);
+        assert!(config.load_str("cluster-enabled maybe").is_err());
+        config.load_str("record-file /tmp/rdis.resp")?;
+        assert_eq!(config.record_file.as_deref(), Some("/tmp/rdis.resp"));
+        config.load_str("preload fixtures.json")?;
+        assert_eq!(config.preload.as_deref(), Some("fixtures.json"));
+        config.load_str("audit-log /tmp/rdis-audit.log")?;
+        assert_eq!(config.audit_log.as_deref(), Some("/tmp/rdis-audit.log"));
+        config.load_str("audit-log \"\"")?;
+        assert_eq!(config.audit_log, None);
+        config.load_str("audit-log-key s3cret")?;
+        assert_eq!(config.audit_log_key.as_deref(), Some("s3cret"));
+        assert_eq!(config.pidfile_path(), None);
+        config.load_str("daemonize yes")?;
+        assert_eq!(config.pidfile_path().as_deref(), Some("/var/run/rdis.pid"));
+        config.load_str("pidfile /tmp/rdis.pid\ndaemonize no")?;
+        assert_eq!(config.pidfile_path().as_deref(), Some("/tmp/rdis.pid"));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_memory() -> ResultT<()> {
+        assert_eq!(parse_memory("1gb")?, 1 << 30);
+        assert_eq!(parse_memory("64KB")?, 64 * 1024);
+        assert_eq!(parse_memory("2m")?, 2_000_000);
+        assert_eq!(parse_memory("100")?, 100);
+        assert!(parse_memory("mb").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_runtime_directives() -> ResultT<()> {
+        let mut config = Config::default();
+        config.load_str("worker-threads 8\nthread-name rdis\nthread-stack-size 4mb\n")?;
+        assert_eq!(config.runtime.worker_threads, 8);
+        assert_eq!(config.runtime.thread_name, "rdis");
+        assert_eq!(config.runtime.thread_stack_size, Some(4 * 1024 * 1024));
+        assert_eq!(config.runtime.max_blocking_threads, 512);
+        assert!(config.load_str("worker-threads 0").is_err());
+        assert!(!config.runtime.current_thread);
+        config.load_str("deterministic-seed 42")?;
+        assert_eq!(config.deterministic_seed, Some(42));
+        assert!(config.runtime.current_thread);
+        assert!(config.runtime.build().is_ok());
+
+        let mut config = Config::default();
+        config.load_str("engine-thread yes\nworker-cpulist 0-2,5")?;
+        assert!(config.runtime.engine_thread);
+        assert_eq!(config.runtime.worker_cpus, vec![0, 1, 2, 5]);
+        let mut config = Config::default();
+        config.load_str("engine-cpulist 3")?;
+        assert!(config.runtime.engine_thread);
+        assert_eq!(config.runtime.engine_cpus, vec![3]);
+        assert!(config.load_str("engine-cpulist 2-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_log_directives() -> ResultT<()> {
+        let mut config = Config::default();
+        config.load_str(
+            "loglevel warning\nlog-module-level rdis::rdis::engine trace\nlog-sample-rate 100\n",
+        )?;
+        assert_eq!(config.log.level, LevelFilter::Warn);
+        assert_eq!(
+            config.log.module_levels,
+            vec![("rdis::rdis::engine".to_owned(), LevelFilter::Trace)]
+        );
+        assert_eq!(config.log.sample_rate, 100);
+        assert!(config.load_str("loglevel loud").is_err());
+        assert!(config.load_str("log-sample-rate 0").is_err());
+
+        config.load_str(
+            "logfile /var/log/rdis.log\nlogfile-max-size 10mb\nlogfile-rotate-interval 3600\n",
+        )?;
+        assert_eq!(config.log.file.as_deref(), Some("/var/log/rdis.log"));
+        assert_eq!(
+            config.log.rotation,
+            LogRotation {
+                max_size: Some(10 * 1024 * 1024),
+                interval: Some(Duration::from_secs(3600)),
+                keep: 5
+            }
+        );
+        config.load_str("logfile \"\"\nlogfile-keep 0")?;
+        assert_eq!(config.log.file, None);
+        assert_eq!(config.log.rotation.keep, 0);
+
+        assert!(!config.log.syslog.enabled);
+        config.load_str("syslog-enabled yes\nsyslog-ident cache\nsyslog-facility LOCAL3")?;
+        assert_eq!(
+            config.log.syslog,
+            SyslogConfig {
+                enabled: true,
+                ident: "cache".to_owned(),
+                facility: libc::LOG_LOCAL3
+            }
+        );
+        assert!(config.load_str("syslog-facility kern").is_err());
+
+        config.load_str("wire-trace yes\nwire-trace-max-bytes 1kb")?;
+        assert_eq!(
+            config.log.wire_trace,
+            WireTraceConfig {
+                enabled: true,
+                max_bytes: 1024
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_io_backend() -> ResultT<()> {
+        assert_eq!(IoBackend::parse("Tokio")?, IoBackend::Tokio);
+        assert_eq!(
+            IoBackend::parse("uring").is_ok(),
+            cfg!(feature = "io-uring")
+        );
+        assert!(IoBackend::parse("epoll").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_admin_feature() {
+        let mut config = Config::default();
+        assert!(config.load_str("admin-port 0").is_ok());
+        assert_eq!(
+            config.load_str("admin-port 9121").is_ok(),
+            cfg!(feature = "admin")
+        );
+    }
+
+    #[test]
+    pub fn test_memcached_feature() {
+        let mut config = Config::default();
+        assert!(config.load_str("memcached-port 0").is_ok());
+        assert_eq!(config.memcached_addr(), None);
+        assert_eq!(
+            config.load_str("memcached-port 11211").is_ok(),
+            cfg!(feature = "memcached")
+        );
+    }
+
+    #[test]
+    pub fn test_from_args() -> ResultT<()> {
+        let args = vec!["--port", "7001", "--rename-command", "GET", "FETCH"];
+        let config = Config::from_args(args.into_iter().map(String::from))?;
+        assert_eq!(config.port, 7001);
+        assert_eq!(
+            config.rename_commands,
+            vec![(b"GET".to_vec(), b"FETCH".to_vec())]
+        );
+        assert_eq!(config.addr(), "127.0.0.1:7001");
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use super::protocol::RESP;
+use super::types::{ErrorT, RdisError, ResultT};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+// conversions between rust values and RESP, for custom commands and embedded clients.
+// Strings and floats are encoded as bulk strings and maps as flat field/value arrays, like
+// redis does with RESP2. Decoding accepts both bulk and simple strings, an error reply is
+// decoded as the corresponding RdisError.
+
+impl From<&str> for RESP {
+    fn from(s: &str) -> Self {
+        RESP::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl From<String> for RESP {
+    fn from(s: String) -> Self {
+        RESP::BulkString(Bytes::from(s.into_bytes()))
+    }
+}
+
+impl From<&[u8]> for RESP {
+    fn from(s: &[u8]) -> Self {
+        RESP::BulkString(Bytes::copy_from_slice(s))
+    }
+}
+
+impl From<Bytes> for RESP {
+    fn from(s: Bytes) -> Self {
+        RESP::BulkString(s)
+    }
+}
+
+impl From<i64> for RESP {
+    fn from(i: i64) -> Self {
+        RESP::Integer(i)
+    }
+}
+
+impl From<usize> for RESP {
+    fn from(i: usize) -> Self {
+        RESP::Integer(i as i64)
+    }
+}
+
+impl From<bool> for RESP {
+    fn from(b: bool) -> Self {
+        RESP::Integer(b as i64)
+    }
+}
+
+impl From<f64> for RESP {
+    fn from(f: f64) -> Self {
+        RESP::from(f.to_string())
+    }
+}
+
+impl<T: Into<RESP>> From<Option<T>> for RESP {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(RESP::Null, Into::into)
+    }
+}
+
+impl<T: Into<RESP>> From<Vec<T>> for RESP {
+    fn from(v: Vec<T>) -> Self {
+        RESP::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<RESP>, V: Into<RESP>> From<HashMap<K, V>> for RESP {
+    fn from(m: HashMap<K, V>) -> Self {
+        RESP::map(m)
+    }
+}
+
+// errors become error replies
+impl<T: Into<RESP>> From<ResultT<T>> for RESP {
+    fn from(r: ResultT<T>) -> Self {
+        r.map_or_else(|err| err.to_resp(), Into::into)
+    }
+}
+
+impl RESP {
+    // flat field/value array, the encoding of maps and of structs:
+    //
+    //     RESP::map(vec![("name", RESP::from(name)), ("age", RESP::from(age))])
+    pub fn map<K: Into<RESP>, V: Into<RESP>, I: IntoIterator<Item = (K, V)>>(pairs: I) -> RESP {
+        let mut items = Vec::new();
+        for (k, v) in pairs {
+            items.push(k.into());
+            items.push(v.into());
+        }
+        RESP::Array(items)
+    }
+}
+
+fn unexpected(resp: RESP, expected: &str) -> ErrorT {
+    match resp {
+        RESP::Error(kind, msg) => RdisError::from_reply(&kind, &msg),
+        other => ErrorT::from(format!("Expected {}, got {:?}", expected, other)),
+    }
+}
+
+impl TryFrom<RESP> for Vec<u8> {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        match resp {
+            RESP::BulkString(s) => Ok(Vec::from(s)),
+            RESP::SimpleString(s) => Ok(s),
+            other => Err(unexpected(other, "a string")),
+        }
+    }
+}
+
+impl TryFrom<RESP> for String {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        Ok(String::from_utf8(Vec::try_from(resp)?)?)
+    }
+}
+
+impl TryFrom<RESP> for i64 {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        match resp {
+            RESP::Integer(i) => Ok(i),
+            s @ RESP::BulkString(_) | s @ RESP::SimpleString(_) => Ok(String::try_from(s)?
+                .parse()
+                .map_err(|_| RdisError::NotInteger)?),
+            other => Err(unexpected(other, "an integer")),
+        }
+    }
+}
+
+impl TryFrom<RESP> for f64 {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        match resp {
+            RESP::Integer(i) => Ok(i as f64),
+            s @ RESP::BulkString(_) | s @ RESP::SimpleString(_) => {
+                Ok(String::try_from(s)?.parse()?)
+            }
+            other => Err(unexpected(other, "a float")),
+        }
+    }
+}
+
+impl TryFrom<RESP> for bool {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        Ok(i64::try_from(resp)? != 0)
+    }
+}
+
+impl<T: TryFrom<RESP, Error = RdisError>> TryFrom<RESP> for Option<T> {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        match resp {
+            RESP::Null => Ok(None),
+            other => T::try_from(other).map(Some),
+        }
+    }
+}
+
+impl<T: TryFrom<RESP, Error = RdisError>> TryFrom<RESP> for Vec<T> {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        match resp {
+            RESP::Array(items) => items.into_iter().map(T::try_from).collect(),
+            other => Err(unexpected(other, "an array")),
+        }
+    }
+}
+
+impl<K, V> TryFrom<RESP> for HashMap<K, V>
+where
+    K: TryFrom<RESP, Error = RdisError> + Eq + Hash,
+    V: TryFrom<RESP, Error = RdisError>,
+{
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        let mut map = HashMap::new();
+        for (k, v) in pairs(resp)? {
+            map.insert(K::try_from(k)?, V::try_from(v)?);
+        }
+        Ok(map)
+    }
+}
+
+fn pairs(resp: RESP) -> ResultT<Vec<(RESP, RESP)>> {
+    let items = match resp {
+        RESP::Array(items) if items.len() % 2 == 0 => items,
+        other => return Err(unexpected(other, "a field/value array")),
+    };
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut iter = items.into_iter();
+    while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+        pairs.push((k, v));
+    }
+    Ok(pairs)
+}
+
+// decodes a struct encoded with RESP::map, one field at a time:
+//
+//     let mut fields = Fields::try_from(resp)?;
+//     let user = User { name: fields.take("name")?, age: fields.take("age")? };
+pub struct Fields {
+    fields: HashMap<Vec<u8>, RESP>,
+}
+
+impl TryFrom<RESP> for Fields {
+    type Error = RdisError;
+
+    fn try_from(resp: RESP) -> ResultT<Self> {
+        let mut fields = HashMap::new();
+        for (k, v) in pairs(resp)? {
+            fields.insert(Vec::try_from(k)?, v);
+        }
+        Ok(Fields { fields })
+    }
+}
+
+impl Fields {
+    // a missing field is decoded from Null, so it's accepted only by Option fields
+    pub fn take<T: TryFrom<RESP, Error = RdisError>>(&mut self, name: &str) -> ResultT<T> {
+        let value = self.fields.remove(name.as_bytes()).unwrap_or(RESP::Null);
+        T::try_from(value).map_err(|err| ErrorT::from(format!("Field {}: {}", name, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: i64,
+        email: Option<String>,
+    }
+
+    impl From<User> for RESP {
+        fn from(u: User) -> Self {
+            RESP::map(vec![
+                ("name", RESP::from(u.name)),
+                ("age", RESP::from(u.age)),
+                ("email", RESP::from(u.email)),
+            ])
+        }
+    }
+
+    impl TryFrom<RESP> for User {
+        type Error = RdisError;
+
+        fn try_from(resp: RESP) -> ResultT<Self> {
+            let mut fields = Fields::try_from(resp)?;
+            Ok(User {
+                name: fields.take("name")?,
+                age: fields.take("age")?,
+                email: fields.take("email")?,
+            })
+        }
+    }
+
+    #[test]
+    pub fn test_scalars() -> ResultT<()> {
+        assert_eq!(RESP::from("v"), RESP::BulkString(Bytes::from_static(b"v")));
+        assert_eq!(i64::try_from(RESP::from(-3i64))?, -3);
+        assert_eq!(i64::try_from(RESP::SimpleString(b"12".to_vec()))?, 12);
+        assert!(matches!(
+            i64::try_from(RESP::from("x")),
+            Err(RdisError::NotInteger)
+        ));
+        assert_eq!(f64::try_from(RESP::from(1.5))?, 1.5);
+        assert!(bool::try_from(RESP::from(true))?);
+        assert_eq!(String::try_from(RESP::from("s".to_owned()))?, "s");
+        assert_eq!(Option::<String>::try_from(RESP::Null)?, None);
+        assert!(matches!(
+            String::try_from(RdisError::WrongType.to_resp()),
+            Err(RdisError::WrongType)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_collections() -> ResultT<()> {
+        let list = RESP::from(vec![Some(1i64), None]);
+        assert_eq!(list, RESP::Array(vec![RESP::Integer(1), RESP::Null]));
+        assert_eq!(Vec::<Option<i64>>::try_from(list)?, vec![Some(1), None]);
+
+        let mut map = HashMap::new();
+        map.insert("a".to_owned(), 1i64);
+        map.insert("b".to_owned(), 2i64);
+        assert_eq!(HashMap::try_from(RESP::from(map.clone()))?, map);
+        assert!(HashMap::<String, i64>::try_from(RESP::Array(vec![RESP::Null])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_struct() -> ResultT<()> {
+        let user = User {
+            name: "ada".to_owned(),
+            age: 36,
+            email: None,
+        };
+        let resp = RESP::from(user);
+        assert_eq!(
+            User::try_from(resp)?,
+            User {
+                name: "ada".to_owned(),
+                age: 36,
+                email: None
+            }
+        );
+        let missing = RESP::map(vec![("name", "ada")]);
+        assert!(User::try_from(missing).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use super::types::ResultT;
+use log::warn;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// detaches from the terminal like redis does with daemonize yes: the parent exits, the child
+// starts a new session and its standard streams are redirected to /dev/null.
+// Threads don't survive fork, this must run before the runtime is built.
+pub fn daemonize() -> ResultT<()> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => (),
+        _ => std::process::exit(0),
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+// holds the pid of the server, the file is removed when dropped
+pub struct Pidfile {
+    path: String,
+}
+
+impl Pidfile {
+    pub fn create(path: &str) -> ResultT<Pidfile> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Pidfile {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove the pid file {}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_pidfile() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+        let pidfile = Pidfile::create(path)?;
+        assert_eq!(
+            fs::read_to_string(path)?,
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(fs::metadata(path).is_err());
+        assert!(Pidfile::create("/nonexistent/rdis.pid").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use super::config::DefragConfig;
+use super::memory::MemoryStats;
+use super::storage::Storage;
+
+// keys visited at every cron tick while a pass runs
+const KEYS_PER_STEP: usize = 1000;
+
+// active defragmentation, enabled by activedefrag yes. When the allocator wastes more than
+// both thresholds, the engine cron walks the keyspace a step at a time and moves the values
+// to new allocations, which the allocator places in its fuller pages, letting it release the
+// sparse ones. Tables left sparse by deletions are shrunk at the end of the pass. The effect of
+// the last pass is the drop of the fragmentation, as reported by INFO memory.
+#[derive(Debug)]
+pub struct ActiveDefrag {
+    config: DefragConfig,
+    running: bool,
+    cursor: u64,
+    // fragmentation bytes when the current or the last pass started
+    started_with: usize,
+    pub hits: u64,
+    pub passes: u64,
+    pub last_reclaimed: usize,
+}
+
+impl ActiveDefrag {
+    pub fn new(config: DefragConfig) -> ActiveDefrag {
+        ActiveDefrag {
+            config,
+            running: false,
+            cursor: 0,
+            started_with: 0,
+            hits: 0,
+            passes: 0,
+            last_reclaimed: 0,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    // keys visited by the current pass
+    pub fn scanned(&self) -> u64 {
+        self.cursor
+    }
+
+    pub fn cron(&mut self, data: &mut dyn Storage) {
+        let fragmentation = MemoryStats::collect().fragmentation();
+        self.step(data, fragmentation);
+    }
+
+    // fragmentation as (bytes, percent), see MemoryStats::fragmentation
+    fn step(&mut self, data: &mut dyn Storage, fragmentation: Option<(usize, f64)>) {
+        if !self.running {
+            match fragmentation {
+                Some((bytes, percent))
+                    if bytes >= self.config.ignore_bytes
+                        && percent >= self.config.threshold_lower as f64 =>
+                {
+                    self.running = true;
+                    self.started_with = bytes;
+                }
+                _ => return,
+            }
+        }
+        let (next, moved) = data.defrag(self.cursor, KEYS_PER_STEP);
+        self.hits += moved as u64;
+        self.cursor = next;
+        if next == 0 {
+            self.running = false;
+            self.passes += 1;
+            let left = fragmentation.map(|(bytes, _)| bytes).unwrap_or(0);
+            self.last_reclaimed = self.started_with.saturating_sub(left);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::storage::RedisData;
+    use bytes::Bytes;
+
+    #[test]
+    pub fn test_step() {
+        let mut data = RedisData::new();
+        for i in 0..2500 {
+            data.set(
+                Bytes::from(format!("k{}", i)),
+                Bytes::from(b"v".to_vec()),
+                None,
+            );
+        }
+        let mut defrag = ActiveDefrag::new(DefragConfig {
+            enabled: true,
+            threshold_lower: 10,
+            ignore_bytes: 1024,
+        });
+        // under either threshold nothing runs
+        defrag.step(&mut data, None);
+        defrag.step(&mut data, Some((512, 50.0)));
+        defrag.step(&mut data, Some((1 << 20, 5.0)));
+        assert!(!defrag.running());
+        defrag.step(&mut data, Some((1 << 20, 50.0)));
+        assert!(defrag.running());
+        assert_eq!(defrag.scanned(), 1000);
+        // a started pass goes on whatever the fragmentation
+        defrag.step(&mut data, None);
+        defrag.step(&mut data, Some((1 << 19, 25.0)));
+        assert!(!defrag.running());
+        assert_eq!(defrag.passes, 1);
+        assert_eq!(defrag.hits, 2500);
+        assert_eq!(defrag.last_reclaimed, 1 << 19);
+    }
+}
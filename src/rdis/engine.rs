@@ -1,207 +1,3268 @@
+use super::affinity::{current_thread_cpus, format_cpu_list};
+use super::audit::{AuditEntry, AuditLog};
+use super::clock::{Clock, SystemClock};
+use super::cluster;
+use super::commands::{self, CommandTable};
+use super::config::Config;
+use super::defrag::ActiveDefrag;
+use super::events::{Event, EventBus};
+#[cfg(feature = "fault-injection")]
+use super::faults::Faults;
+use super::glob;
+use super::hotkeys::{HotKeys, MAX_CANDIDATES};
+use super::memory::{human_bytes, MemoryStats, ALLOCATOR};
+use super::metrics::Metrics;
+use super::module::{CommandContext, CustomCommand};
 use super::protocol::RESP;
+use super::ratelimit;
+use super::readcache::ReadCache;
+use super::readthrough::{Loaded, ReadThrough};
+use super::recorder::Recorder;
+use super::registry::{ClientKind, ClientRegistry, KillFilter};
+use super::session::ConnectionState;
+use super::stats::{format_percentiles, InfoBuilder, Stats};
+use super::storage::{range_bounds, Key, RawValue, Storage};
+use super::stream::{self, NewId, StreamEntry, StreamId};
 use crate::rdis::protocol::ClientReq;
+use bytes::Bytes;
 use log::*;
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use RESP::*;
 
-type RawValue = Vec<u8>;
-use super::types::ResultT;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-type Key = Arc<RawValue>;
-// contains the common data structures
-struct RedisData {
-    single_map: HashMap<Key, Arc<RawValue>>,
-    list_map: HashMap<Key, VecDeque<Arc<RawValue>>>,
-    eviction: BTreeMap<u64, HashSet<Key>>,
-    last_evicted_t: u64,
+use super::types::{EngineRequest, EngineResponse, RdisError, ResponseSender, ResultT};
+use super::writebehind::{MirroredValue, Mutation, WriteBehind};
+
+// like the default hz 10 of redis
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+// requests dequeued at every wakeup of the loop, they share a single timestamp
+const MAX_BATCH: usize = 64;
+// elements copied by LRANGE between two checks of the time budget
+const LRANGE_CHUNK: usize = 1024;
+// hot keys listed by INFO, DEBUG HOTKEYS lists up to MAX_CANDIDATES
+const INFO_HOT_KEYS: usize = 5;
+
+// options of SET, evict_at from EX, PX, EXAT or PXAT
+#[derive(Debug, Default)]
+struct SetOptions {
+    evict_at: Option<u64>,
+    nx: bool,
+    xx: bool,
+    keep_ttl: bool,
+    get: bool,
+}
+
+// options of SCAN, HSCAN, SSCAN and ZSCAN, no_values from NOVALUES and NOSCORES
+#[derive(Debug)]
+struct ScanOptions {
+    cursor: u64,
+    count: usize,
+    pattern: Option<RawValue>,
+    kind: Option<String>,
+    no_values: bool,
+}
+
+// a pipeline longer than pipeline-slice, resumed after the requests of the other clients
+struct Parked {
+    seq: u64,
+    commands: std::vec::IntoIter<RESP>,
+    len: usize,
+    responses: Vec<RESP>,
+    state: ConnectionState,
+    sender: ResponseSender,
 }
 
-const DEFAULT_CAPACITY: usize = 4096;
-const DEFAULT_LIST_CAPACITY: usize = 8;
+pub struct RedisEngine {
+    data: Box<dyn Storage>,
+    receiver: mpsc::Receiver<EngineRequest>,
+    registry: Arc<ClientRegistry>,
+    commands: CommandTable,
+    custom_commands: HashMap<Vec<u8>, Box<dyn CustomCommand>>,
+    stats: Stats,
+    metrics: Arc<Metrics>,
+    log_sample_rate: u64,
+    port: u16,
+    clock: Box<dyn Clock>,
+    events: EventBus,
+    config_params: Vec<(&'static str, String)>,
+    cluster_enabled: bool,
+    command_time_budget: Option<Duration>,
+    pipeline_slice: usize,
+    parked: VecDeque<Parked>,
+    maxmemory_clients: usize,
+    // of the command being run, when it has a time budget
+    deadline: Option<Instant>,
+    engine_thread: bool,
+    worker_cpus: Vec<usize>,
+    recorder: Option<Recorder>,
+    write_behind: Option<WriteBehind>,
+    audit: Option<AuditLog>,
+    read_cache: Option<Arc<ReadCache>>,
+    read_through: Option<ReadThrough>,
+    hot_keys: Option<HotKeys>,
+    defrag: Option<ActiveDefrag>,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
+}
+
+impl RedisEngine {
+    pub fn new(
+        receiver: mpsc::Receiver<EngineRequest>,
+        registry: Arc<ClientRegistry>,
+        metrics: Arc<Metrics>,
+        data: Box<dyn Storage>,
+        config: &Config,
+    ) -> RedisEngine {
+        RedisEngine {
+            data,
+            receiver,
+            registry,
+            commands: CommandTable::new(&config.rename_commands),
+            custom_commands: HashMap::new(),
+            stats: Stats::new(),
+            metrics,
+            log_sample_rate: config.log.sample_rate,
+            port: config.port,
+            clock: Box::new(SystemClock),
+            events: EventBus::default(),
+            config_params: config.params(),
+            cluster_enabled: config.cluster_enabled,
+            command_time_budget: config.command_time_budget,
+            pipeline_slice: config.pipeline_slice,
+            parked: VecDeque::new(),
+            maxmemory_clients: config.limits.maxmemory_clients,
+            deadline: None,
+            engine_thread: config.dedicated_engine_thread(),
+            worker_cpus: config.runtime.worker_cpus.clone(),
+            recorder: None,
+            write_behind: None,
+            audit: None,
+            read_cache: None,
+            read_through: None,
+            hot_keys: match config.hotkeys_sample_rate {
+                0 => None,
+                n => Some(HotKeys::new(n, 0)),
+            },
+            defrag: Some(ActiveDefrag::new(config.defrag)).filter(|_| config.defrag.enabled),
+            #[cfg(feature = "fault-injection")]
+            faults: Faults::default(),
+        }
+    }
+
+    // fails if the name is already used by another command
+    pub fn register_command(&mut self, command: Box<dyn CustomCommand>) -> ResultT<()> {
+        self.commands.register(command.name())?;
+        let name = command.name().to_ascii_uppercase().into_bytes();
+        self.custom_commands.insert(name, command);
+        Ok(())
+    }
+
+    // SystemClock by default
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // key events are published on the bus
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    // successful writes are appended to the record file
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    // successful writes are mirrored into its sink, see WriteSink
+    pub fn set_write_behind(&mut self, write_behind: WriteBehind) {
+        self.write_behind = Some(write_behind);
+    }
+
+    // administrative commands are appended to the audit log
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    // shared with the connections, see ReadCache
+    pub fn set_read_cache(&mut self, cache: Arc<ReadCache>) {
+        self.read_cache = Some(cache);
+    }
+
+    // GET misses are loaded through it, see Loader
+    pub fn set_read_through(&mut self, read_through: ReadThrough) {
+        self.read_through = Some(read_through);
+    }
+
+    // rules added with DEBUG FAULT, shared with the connections
+    #[cfg(feature = "fault-injection")]
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+    }
+
+    // runs the fixtures of the preload directive before the loop starts, see preload. The
+    // first command failing fails the startup
+    pub fn preload(&mut self, commands: Vec<Vec<RESP>>) -> ResultT<()> {
+        let mut state = ConnectionState::new(0);
+        let t = self.clock.now_millis();
+        for (i, command) in commands.into_iter().enumerate() {
+            if let Error(kind, msg) = self.execute(&mut state, &Array(command), 1, t) {
+                let msg = format!("Preloaded command {} failed: {} {}", i + 1, kind, msg);
+                return Err(RdisError::from(msg));
+            }
+        }
+        self.update_keyspace_metrics();
+        Ok(())
+    }
+
+    pub async fn start_loop(&mut self) {
+        let mut cron = tokio::time::interval(CRON_INTERVAL);
+        let mut batch = Vec::with_capacity(MAX_BATCH);
+        loop {
+            // a slice of every parked pipeline runs after each batch, and on its own while no
+            // request is waiting
+            tokio::select! {
+                biased;
+                _ = cron.tick() => self.cron(),
+                n = self.receiver.recv_many(&mut batch, MAX_BATCH) => match n {
+                    0 => {
+                        info!("No senders, loop terminated");
+                        break;
+                    }
+                    _ => {
+                        self.process_batch(&mut batch);
+                        self.resume_parked();
+                    }
+                },
+                _ = std::future::ready(()), if !self.parked.is_empty() => self.resume_parked(),
+                (k, value) = RedisEngine::next_load(&mut self.read_through) => {
+                    self.finish_load(k, value);
+                }
+            }
+        }
+    }
+
+    async fn next_load(read_through: &mut Option<ReadThrough>) -> Loaded {
+        match read_through {
+            Some(read_through) => read_through.next().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    // the loaded value is stored unless the key was written while loading, the GETs waiting
+    // for it are replied with the key
+    fn finish_load(&mut self, k: Key, value: ResultT<Option<RawValue>>) {
+        let read_through = match self.read_through.as_mut() {
+            Some(read_through) => read_through,
+            None => return,
+        };
+        let waiters = read_through.waiters(&k);
+        let ttl = read_through.ttl().as_millis() as u64;
+        let t = self.clock.now_millis();
+        let resp = match value {
+            Ok(value) => {
+                if let (Some(v), Ok(None)) = (value, self.data.get(&k, t)) {
+                    self.data.set(k.clone(), v, Some(t.saturating_add(ttl)));
+                }
+                RESP::from(self.data.get(&k, t))
+            }
+            Err(err) => {
+                warn!("Cannot load {}: {}", String::from_utf8_lossy(&k), err);
+                Error("ERR".into(), format!("cannot load the key: {}", err))
+            }
+        };
+        for (seq, state, sender) in waiters {
+            let resp = ClientReq::Single(resp.clone());
+            RedisEngine::reply(seq, resp, state, &sender);
+        }
+        self.update_keyspace_metrics();
+    }
+
+    // a GET sent on its own missing a key to load
+    fn read_through_key(&self, req: &RESP) -> Option<Key> {
+        let read_through = self.read_through.as_ref()?;
+        match req {
+            Array(args) => match args.as_slice() {
+                [BulkString(name), BulkString(k)]
+                    if self.commands.resolve(name).as_deref() == Some(b"GET")
+                        && read_through.loads(k) =>
+                {
+                    Some(k.clone())
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn process_batch(&mut self, batch: &mut Vec<EngineRequest>) {
+        self.metrics.engine_batch_size.record(batch.len() as f64);
+        let t = self.clock.now_millis();
+        for (seq, req, state, sender) in batch.drain(..) {
+            self.process(seq, req, state, sender, t);
+        }
+        self.update_keyspace_metrics();
+    }
+
+    fn process(
+        &mut self,
+        seq: u64,
+        req: ClientReq,
+        mut state: ConnectionState,
+        sender: ResponseSender,
+        t: u64,
+    ) {
+        match req {
+            ClientReq::Single(r) => {
+                let resp = self.execute(&mut state, &r, 1, t);
+                let load = match resp {
+                    Null => self.read_through_key(&r),
+                    _ => None,
+                };
+                match (load, self.read_through.as_mut()) {
+                    (Some(k), Some(read_through)) => read_through.park(k, (seq, state, sender)),
+                    _ => RedisEngine::reply(seq, ClientReq::Single(resp), state, &sender),
+                }
+            }
+            ClientReq::Pipeline(rs) => {
+                let parked = Parked {
+                    seq,
+                    len: rs.len(),
+                    responses: Vec::with_capacity(rs.len()),
+                    commands: rs.into_iter(),
+                    state,
+                    sender,
+                };
+                self.run_slice(parked, t);
+            }
+        }
+    }
+
+    // the pipeline is parked again if it has more than pipeline-slice commands left
+    fn run_slice(&mut self, mut parked: Parked, t: u64) {
+        let slice = match self.pipeline_slice {
+            0 => usize::MAX,
+            n => n,
+        };
+        for r in parked.commands.by_ref().take(slice) {
+            let resp = self.execute(&mut parked.state, &r, parked.len, t);
+            parked.responses.push(resp);
+        }
+        if parked.commands.len() > 0 {
+            self.parked.push_back(parked);
+        } else {
+            let resp = ClientReq::Pipeline(parked.responses);
+            RedisEngine::reply(parked.seq, resp, parked.state, &parked.sender);
+        }
+    }
+
+    fn resume_parked(&mut self) {
+        if self.parked.is_empty() {
+            return;
+        }
+        let t = self.clock.now_millis();
+        for _ in 0..self.parked.len() {
+            let parked = self.parked.pop_front().unwrap();
+            // the rest of the pipeline of a killed client is dropped
+            if parked.sender.is_closed() {
+                debug!(
+                    "Client {} dropped with a pipeline running",
+                    parked.state.client_id
+                );
+                continue;
+            }
+            self.run_slice(parked, t);
+        }
+        self.update_keyspace_metrics();
+    }
+
+    fn reply(seq: u64, resp: ClientReq, state: ConnectionState, sender: &ResponseSender) {
+        let client = state.client_id;
+        // the receiver is gone if the client was killed while waiting
+        if sender.send(EngineResponse { seq, resp, state }).is_err() {
+            debug!("Client {} dropped before receiving the response", client);
+        }
+    }
+
+    // periodic tasks, run between requests
+    fn cron(&mut self) {
+        let now = tokio::time::Instant::now();
+        let stats = &mut self.stats;
+        stats
+            .instantaneous_ops
+            .sample(stats.total_commands_processed, now);
+        let input = self.metrics.net_input_bytes.load(Ordering::Relaxed);
+        stats.instantaneous_input.sample(input, now);
+        let output = self.metrics.net_output_bytes.load(Ordering::Relaxed);
+        stats.instantaneous_output.sample(output, now);
+        if self.maxmemory_clients > 0 {
+            let evicted = self.registry.evict_clients(self.maxmemory_clients);
+            self.stats.evicted_clients += evicted as u64;
+        }
+        if let Some(defrag) = self.defrag.as_mut() {
+            defrag.cron(self.data.as_mut());
+        }
+        self.data.cron(self.clock.now_millis());
+    }
+
+    fn execute(
+        &mut self,
+        state: &mut ConnectionState,
+        req: &RESP,
+        pipeline: usize,
+        t: u64,
+    ) -> RESP {
+        let started = Instant::now();
+        let resp = self.handle_request(state, req, t);
+        let elapsed = started.elapsed();
+        self.metrics.command_duration.observe(elapsed);
+        let processed = self.metrics.commands_total.fetch_add(1, Ordering::Relaxed);
+        if let Error(error_type, _) = &resp {
+            self.stats.record_error(error_type);
+            self.metrics
+                .command_errors_total
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        if processed.is_multiple_of(self.log_sample_rate) && log_enabled!(Level::Trace) {
+            let (command, args) = req.describe_command();
+            let outcome = match &resp {
+                Error(kind, _) => kind.as_str(),
+                _ => "ok",
+            };
+            trace!(
+                "client={} command={} args={} pipeline={} elapsed_us={} outcome={}",
+                state.client_id,
+                command,
+                args,
+                pipeline,
+                elapsed.as_micros(),
+                outcome
+            );
+        }
+        match state.resp_version {
+            2 => resp.without_attributes(),
+            _ => resp,
+        }
+    }
+
+    fn update_keyspace_metrics(&self) {
+        let keys = self.data.keys_count() as u64;
+        self.metrics.keys.store(keys, Ordering::Relaxed);
+        let expires = self.data.expires_count() as u64;
+        self.metrics.expires.store(expires, Ordering::Relaxed);
+    }
+
+    fn handle_request(&mut self, state: &mut ConnectionState, req: &RESP, t: u64) -> RESP {
+        match req {
+            Array(commands) => match commands.split_first() {
+                None => Error("ERR".into(), "empty command".into()),
+                Some((BulkString(name), args)) => self.dispatch(state, name, args, t),
+                // inline commands are parsed as simple strings
+                Some((SimpleString(_), _)) => {
+                    let bulk = commands.iter().map(RedisEngine::to_bulk).collect();
+                    self.handle_request(state, &Array(bulk), t)
+                }
+                Some(_) => RedisEngine::not_bulk(),
+            },
+            other => self.handle_request(state, &Array(vec![other.clone()]), t),
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        state: &mut ConnectionState,
+        name: &RawValue,
+        args: &[RESP],
+        t: u64,
+    ) -> RESP {
+        let cmd = match self.commands.resolve(name) {
+            Some(cmd) => cmd,
+            None => {
+                state.multi_failed = state.multi.is_some();
+                return RedisEngine::unknown_command(name);
+            }
+        };
+        if self.cluster_enabled && !cluster::same_slot(commands::multi_keys(&cmd, args)) {
+            state.multi_failed = state.multi.is_some();
+            return Error(
+                "CROSSSLOT".into(),
+                "Keys in request don't hash to the same slot".into(),
+            );
+        }
+        if state.multi.is_some() && self.commands.is_queued(&cmd) {
+            return self.queue(state, &cmd, name, args);
+        }
+        self.stats.total_commands_processed += 1;
+        if let Some(hot_keys) = &mut self.hot_keys {
+            if hot_keys.sample() {
+                for k in commands::keys(&cmd, args) {
+                    hot_keys.record(k, t);
+                }
+            }
+        }
+        let started = Instant::now();
+        self.deadline = self.command_time_budget.map(|budget| started + budget);
+        let old_values = match &self.write_behind {
+            Some(_) if self.commands.modifies_keyspace(&cmd) => self.mirrored_values(&cmd, args, t),
+            _ => Vec::new(),
+        };
+        let resp = self.run(state, &cmd, args, t);
+        let failed = matches!(resp, Error(_, _));
+        self.stats.record_command(&cmd, started.elapsed(), failed);
+        self.propagate_expired(t);
+        self.update_read_cache(&cmd, args, failed, t);
+        match (cmd.as_slice(), &resp) {
+            (b"GET", BulkString(_)) => self.stats.keyspace_hits += 1,
+            (b"GET", Null) => self.stats.keyspace_misses += 1,
+            (b"MGET", Array(values)) => {
+                let hits = values.iter().filter(|v| **v != Null).count() as u64;
+                self.stats.keyspace_hits += hits;
+                self.stats.keyspace_misses += values.len() as u64 - hits;
+            }
+            _ => (),
+        }
+        self.audit(state, &cmd, args, &resp);
+        // a pop on an empty list doesn't modify anything, nor an expiration not set
+        let unchanged = match (cmd.as_slice(), &resp) {
+            (
+                b"EXPIRE" | b"PEXPIRE" | b"EXPIREAT" | b"PEXPIREAT" | b"PERSIST" | b"UNLOCK",
+                Integer(0),
+            ) => true,
+            // a denied request isn't counted
+            (b"RATELIMIT", Array(reply)) => reply.first() == Some(&Integer(0)),
+            _ => false,
+        };
+        let modified = match (cmd.as_slice(), args) {
+            (b"SET", [_, _, options @ ..]) => {
+                !failed && RedisEngine::set_modified(options, &resp, t)
+            }
+            _ => !failed && resp != Null && !unchanged,
+        };
+        if let Some(recorder) = &self.recorder {
+            match (cmd.as_slice(), &resp, args.first()) {
+                // the members popped at random are recorded, a replay removes the same ones
+                (b"SPOP", BulkString(member), Some(k)) => {
+                    recorder.record(t, b"SREM", &[k.clone(), BulkString(member.clone())])
+                }
+                (b"SPOP", Array(members), Some(k)) if !members.is_empty() => {
+                    let mut srem = vec![k.clone()];
+                    srem.extend(members.iter().cloned());
+                    recorder.record(t, b"SREM", &srem)
+                }
+                (b"SPOP", _, _) => (),
+                // with the generated id, a replay adds the entry with the same one
+                (b"XADD", BulkString(id), _) => {
+                    let mut xadd = args.to_vec();
+                    xadd[1] = BulkString(id.clone());
+                    recorder.record(t, b"XADD", &xadd)
+                }
+                _ if modified && self.commands.modifies_keyspace(&cmd) => {
+                    recorder.record(t, &cmd, args)
+                }
+                _ => (),
+            }
+        }
+        if modified && !old_values.is_empty() {
+            self.write_behind(&cmd, args, old_values, t);
+        }
+        if modified && self.commands.is_write(&cmd) {
+            if let Some(BulkString(key)) = args.first() {
+                self.events.publish(|| Event::KeyWritten {
+                    key: key.clone(),
+                    command: String::from_utf8_lossy(&cmd).to_lowercase(),
+                });
+            }
+        }
+        resp
+    }
+
+    // the keys of the command with their values, see Mutation
+    fn mirrored_values(
+        &mut self,
+        cmd: &[u8],
+        args: &[RESP],
+        t: u64,
+    ) -> Vec<(Key, Option<MirroredValue>)> {
+        commands::keys(cmd, args)
+            .into_iter()
+            .map(|k| {
+                let k = Key::copy_from_slice(k);
+                let v = match self.data.get(&k, t) {
+                    Ok(v) => v.map(MirroredValue::String),
+                    // not a string
+                    Err(_) => self
+                        .data
+                        .info(&k, t)
+                        .map(|info| MirroredValue::Unsupported(info.kind)),
+                };
+                (k, v)
+            })
+            .collect()
+    }
+
+    fn write_behind(
+        &mut self,
+        cmd: &[u8],
+        args: &[RESP],
+        old_values: Vec<(Key, Option<MirroredValue>)>,
+        t: u64,
+    ) {
+        let new_values = self.mirrored_values(cmd, args, t);
+        let command = String::from_utf8_lossy(cmd).to_lowercase();
+        if let Some(write_behind) = &mut self.write_behind {
+            for ((key, old), (_, new)) in old_values.into_iter().zip(new_values) {
+                write_behind.send(Mutation {
+                    key,
+                    command: command.clone(),
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    // commands are queued as they were sent, EXEC resolves them again
+    // refused commands are not queued, they discard the whole transaction at EXEC
+    fn queue(
+        &self,
+        state: &mut ConnectionState,
+        cmd: &[u8],
+        name: &RawValue,
+        args: &[RESP],
+    ) -> RESP {
+        let refused = if !self.commands.allowed_in_multi(cmd) {
+            Some(Error(
+                "ERR".into(),
+                "Command not allowed inside a transaction".into(),
+            ))
+        } else if !self.commands.arity_ok(cmd, args.len() + 1) {
+            Some(RedisEngine::wrong_arity(cmd))
+        } else {
+            None
+        };
+        if let Some(refused) = refused {
+            state.multi_failed = true;
+            return refused;
+        }
+        let mut req = Vec::with_capacity(args.len() + 1);
+        req.push(BulkString(name.clone()));
+        req.extend_from_slice(args);
+        if let Some(queued) = state.multi.as_mut() {
+            queued.push(Array(req));
+        }
+        SimpleString("QUEUED".into())
+    }
+
+    // the queued commands run one after the other, nothing else runs in between
+    fn exec(&mut self, state: &mut ConnectionState, t: u64) -> RESP {
+        let queued = match state.multi.take() {
+            Some(queued) => queued,
+            None => return Error("ERR".into(), "EXEC without MULTI".into()),
+        };
+        if std::mem::take(&mut state.multi_failed) {
+            return Error(
+                "EXECABORT".into(),
+                "Transaction discarded because of previous errors.".into(),
+            );
+        }
+        let replies = queued
+            .iter()
+            .map(|req| self.handle_request(state, req, t))
+            .collect();
+        Array(replies)
+    }
+
+    // keys expired while running a command are recorded and mirrored as DELs ahead of the
+    // command, a replay then ends with the same keyspace whatever the timing of the target server
+    fn propagate_expired(&mut self, t: u64) {
+        for key in self.data.take_expired() {
+            self.stats.expired_keys += 1;
+            if let Some(recorder) = &self.recorder {
+                recorder.record(t, b"DEL", &[BulkString(key.clone())]);
+            }
+            if let Some(write_behind) = &mut self.write_behind {
+                write_behind.send(Mutation {
+                    key: key.clone(),
+                    command: "del".to_owned(),
+                    old: None,
+                    new: None,
+                });
+            }
+            if let Some(cache) = &self.read_cache {
+                cache.remove(&key);
+            }
+            self.events.publish(|| Event::KeyExpired { key });
+        }
+    }
+
+    // failed attempts are audited too, with the kind of the error
+    fn audit(&mut self, state: &ConnectionState, cmd: &[u8], args: &[RESP], resp: &RESP) {
+        let audit = match &mut self.audit {
+            Some(audit) if self.commands.audited(cmd, args) => audit,
+            _ => return,
+        };
+        let args = args
+            .iter()
+            .map(|a| match a {
+                BulkString(a) => String::from_utf8_lossy(a).into_owned(),
+                _ => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        audit.record(&AuditEntry {
+            client: state.client_id,
+            addr: self.registry.info(state.client_id).map(|info| info.addr),
+            name: state.name.as_deref(),
+            command: &String::from_utf8_lossy(cmd),
+            args: &args,
+            outcome: match resp {
+                Error(kind, _) => kind,
+                _ => "ok",
+            },
+        });
+    }
+
+    // the cache is updated before the reply is sent, a client reads its own writes. Dropping
+    // every argument of the commands modifying the keyspace drops values too, which is harmless
+    fn update_read_cache(&self, cmd: &[u8], args: &[RESP], failed: bool, t: u64) {
+        let cache = match &self.read_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        match (cmd, args) {
+            (b"SET", [BulkString(k), BulkString(v), options @ ..]) if !failed => {
+                match RedisEngine::set_options(options, t) {
+                    // with NX or XX the value may not be set, with KEEPTTL its expiration
+                    // is unknown
+                    Ok(o) if !o.nx && !o.xx && !o.keep_ttl => {
+                        cache.insert(k.clone(), v.clone(), o.evict_at)
+                    }
+                    _ => cache.remove(k),
+                }
+            }
+            (b"MSET", pairs) if !failed => {
+                for pair in pairs.chunks(2) {
+                    if let [BulkString(k), BulkString(v)] = pair {
+                        cache.insert(k.clone(), v.clone(), None);
+                    }
+                }
+            }
+            _ if self.commands.modifies_keyspace(cmd) => {
+                for arg in args {
+                    if let BulkString(k) = arg {
+                        cache.remove(k);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn run(&mut self, state: &mut ConnectionState, cmd: &[u8], args: &[RESP], t: u64) -> RESP {
+        if let Some(custom) = self.custom_commands.get_mut(cmd) {
+            let mut bulk_args = Vec::with_capacity(args.len());
+            for arg in args {
+                match arg {
+                    BulkString(a) => bulk_args.push(a.clone()),
+                    _ => return RedisEngine::not_bulk(),
+                }
+            }
+            let mut ctx =
+                CommandContext::new(self.data.as_mut(), state.client_id, t, self.deadline);
+            let resp = custom.execute(&mut ctx, &bulk_args);
+            if let Some(cache) = &self.read_cache {
+                for k in ctx.into_touched() {
+                    cache.remove(&k);
+                }
+            }
+            return resp;
+        }
+        match (cmd, args) {
+            (b"PING", []) => SimpleString("PONG".into()),
+            (b"COMMAND", _) => RedisEngine::ok(),
+            (b"CLIENT", args) => self.client_command(state, args),
+            // slots are computed even when cluster mode is disabled
+            (b"CLUSTER", [BulkString(sub), BulkString(key)])
+                if sub.eq_ignore_ascii_case(b"KEYSLOT") =>
+            {
+                Integer(cluster::key_slot(key) as i64)
+            }
+            (b"INFO", []) => self.info(None),
+            (b"MEMORY", [BulkString(sub)]) if sub.eq_ignore_ascii_case(b"STATS") => {
+                RedisEngine::memory_stats()
+            }
+            // the usage is computed exactly, SAMPLES is accepted for redis-cli --memkeys
+            (b"MEMORY", [BulkString(sub), BulkString(k), samples @ ..])
+                if sub.eq_ignore_ascii_case(b"USAGE") && matches!(samples.len(), 0 | 2) =>
+            {
+                RESP::from(self.data.info(k, t).map(|info| info.memory))
+            }
+            (b"DEBUG", [BulkString(sub), count @ ..]) if sub.eq_ignore_ascii_case(b"BIGKEYS") => {
+                match count {
+                    [] => self.big_keys(3, t),
+                    [BulkString(count)] => match parse_int(count) {
+                        Ok(n) if n > 0 => self.big_keys(n as usize, t),
+                        _ => Error("ERR".into(), "count should be greater than 0".into()),
+                    },
+                    _ => RedisEngine::wrong_arity(b"debug|bigkeys"),
+                }
+            }
+            (b"DEBUG", [BulkString(sub), count @ ..]) if sub.eq_ignore_ascii_case(b"HOTKEYS") => {
+                match count {
+                    [] => self.hot_keys_report(10, t),
+                    [BulkString(count)] => match parse_int(count) {
+                        Ok(n) if n > 0 => self.hot_keys_report(n as usize, t),
+                        _ => Error("ERR".into(), "count should be greater than 0".into()),
+                    },
+                    _ => RedisEngine::wrong_arity(b"debug|hotkeys"),
+                }
+            }
+            #[cfg(feature = "fault-injection")]
+            (b"DEBUG", [BulkString(sub), args @ ..]) if sub.eq_ignore_ascii_case(b"FAULT") => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|a| match a {
+                        BulkString(a) => String::from_utf8_lossy(a).into_owned(),
+                        _ => String::new(),
+                    })
+                    .collect();
+                self.faults.command(&args)
+            }
+            (b"MULTI", []) => {
+                state.multi = Some(Vec::new());
+                RedisEngine::ok()
+            }
+            (b"EXEC", []) => self.exec(state, t),
+            (b"DISCARD", []) => match state.multi.take() {
+                Some(_) => {
+                    state.multi_failed = false;
+                    RedisEngine::ok()
+                }
+                None => Error("ERR".into(), "DISCARD without MULTI".into()),
+            },
+            (b"DBSIZE", []) => RESP::from(self.data.keys_count()),
+            (b"TYPE", [BulkString(k)]) => {
+                let kind = self.data.info(k, t).map_or("none", |info| info.kind);
+                SimpleString(kind.into())
+            }
+            (b"STRLEN", [BulkString(k)]) => self.length(k, "string", t),
+            (b"LLEN", [BulkString(k)]) => self.length(k, "list", t),
+            (b"INFO", [BulkString(section)]) => self.info(Some(section)),
+            (b"GET", [BulkString(k)]) => RESP::from(self.data.get(k, t)),
+            (b"INCR", [BulkString(k)]) => RESP::from(self.data.incr_by(k.clone(), 1, t)),
+            (b"INCRBY", [BulkString(k), BulkString(by)]) => match parse_int(by) {
+                Ok(by) => RESP::from(self.data.incr_by(k.clone(), by, t)),
+                Err(err) => err.to_resp(),
+            },
+            // values are freed right away, none is large enough to stall the engine
+            (b"DEL" | b"UNLINK", keys) if !keys.is_empty() => self.del(keys, t),
+            (b"EXISTS", keys) if !keys.is_empty() => self.exists(keys, t),
+            (
+                b"EXPIRE" | b"PEXPIRE" | b"EXPIREAT" | b"PEXPIREAT",
+                [BulkString(k), BulkString(time), options @ ..],
+            ) => self.expire(cmd, k, time, options, t),
+            (b"LOCK", [BulkString(k), BulkString(token), BulkString(ttl)]) => {
+                self.lock(k, token, ttl, t)
+            }
+            (b"UNLOCK", [BulkString(k), BulkString(token)]) => self.unlock(k, token, t),
+            (b"RATELIMIT", [BulkString(k), BulkString(limit), BulkString(window), cost @ ..]) => {
+                self.rate_limit(k, limit, window, cost, t)
+            }
+            // the flags of the memcached items, see memcached::Connection::set
+            (b"MCFLAGS", [BulkString(k)]) => Integer(self.data.client_flags(k, t) as i64),
+            (b"MCFLAGS", [BulkString(k), BulkString(flags)]) => {
+                match parse_int(flags).map(u32::try_from) {
+                    Ok(Ok(flags)) => Integer(self.data.set_client_flags(k, flags, t) as i64),
+                    _ => RdisError::NotInteger.to_resp(),
+                }
+            }
+            (b"PERSIST", [BulkString(k)]) => Integer(self.data.persist(k, t) as i64),
+            (b"TTL", [BulkString(k)]) => self.ttl(k, false, false, t),
+            (b"PTTL", [BulkString(k)]) => self.ttl(k, true, false, t),
+            (b"EXPIRETIME", [BulkString(k)]) => self.ttl(k, false, true, t),
+            (b"PEXPIRETIME", [BulkString(k)]) => self.ttl(k, true, true, t),
+            (b"SCAN", [BulkString(cursor), options @ ..]) => self.scan(cursor, options, t),
+            (b"KEYS", [BulkString(pattern)]) => self.keys(pattern, t),
+            (b"HSCAN" | b"SSCAN" | b"ZSCAN", [BulkString(k), BulkString(cursor), options @ ..]) => {
+                self.scan_members(cmd, k, cursor, options, t)
+            }
+            (b"LPOP", [BulkString(k)]) => RESP::from(self.data.l_pop(k, t)),
+            (b"RPOP", [BulkString(k)]) => RESP::from(self.data.r_pop(k, t)),
+            (b"SET", [BulkString(k), BulkString(v), options @ ..]) => {
+                match RedisEngine::set_options(options, t) {
+                    Ok(options) => self.set(k, v, options, t),
+                    Err(err) => err,
+                }
+            }
+            (b"LPUSH", [BulkString(k), values @ ..]) if !values.is_empty() => {
+                self.push(k, values, true, t)
+            }
+            (b"RPUSH", [BulkString(k), values @ ..]) if !values.is_empty() => {
+                self.push(k, values, false, t)
+            }
+            (b"LRANGE", [BulkString(k), BulkString(start), BulkString(stop)]) => {
+                match (parse_int(start), parse_int(stop)) {
+                    (Ok(start), Ok(stop)) => self.l_range(k, start, stop, t),
+                    (Err(err), _) | (_, Err(err)) => err.to_resp(),
+                }
+            }
+            (b"MSET", pairs) => self.mset(pairs),
+            (b"MGET", keys) if !keys.is_empty() => self.mget(keys, t),
+            (b"HSET", [BulkString(k), pairs @ ..]) => self.h_set(k, pairs, t),
+            (b"HGET", [BulkString(k), BulkString(field)]) => {
+                let values = self.data.h_get(k, std::slice::from_ref(field), t);
+                RESP::from(values.map(|mut values| values.pop().flatten()))
+            }
+            (b"HMGET", [BulkString(k), fields @ ..]) if !fields.is_empty() => {
+                match RedisEngine::bulk_args(fields) {
+                    Some(fields) => RESP::from(self.data.h_get(k, &fields, t)),
+                    None => RedisEngine::not_bulk(),
+                }
+            }
+            (b"HDEL", [BulkString(k), fields @ ..]) if !fields.is_empty() => {
+                match RedisEngine::bulk_args(fields) {
+                    Some(fields) => RESP::from(self.data.h_del(k, &fields, t)),
+                    None => RedisEngine::not_bulk(),
+                }
+            }
+            (b"HGETALL", [BulkString(k)]) => match self.data.h_get_all(k, t) {
+                Ok(pairs) => RESP::map(pairs),
+                Err(err) => err.to_resp(),
+            },
+            (b"HEXISTS", [BulkString(k), BulkString(field)]) => {
+                let values = self.data.h_get(k, std::slice::from_ref(field), t);
+                RESP::from(values.map(|values| values[0].is_some()))
+            }
+            (b"HLEN", [BulkString(k)]) => self.length(k, "hash", t),
+            (b"SADD", [BulkString(k), members @ ..]) if !members.is_empty() => {
+                match RedisEngine::bulk_args(members) {
+                    Some(members) => RESP::from(self.data.s_add(k.clone(), members, t)),
+                    None => RedisEngine::not_bulk(),
+                }
+            }
+            (b"SREM", [BulkString(k), members @ ..]) if !members.is_empty() => {
+                match RedisEngine::bulk_args(members) {
+                    Some(members) => RESP::from(self.data.s_rem(k, &members, t)),
+                    None => RedisEngine::not_bulk(),
+                }
+            }
+            (b"SMEMBERS", [BulkString(k)]) => RESP::from(self.data.s_members(k, t)),
+            (b"SISMEMBER", [BulkString(k), BulkString(member)]) => {
+                RESP::from(self.data.s_is_member(k, member, t))
+            }
+            (b"SCARD", [BulkString(k)]) => self.length(k, "set", t),
+            // without a count the reply is a single member
+            (b"SPOP", [BulkString(k)]) => {
+                RESP::from(self.data.s_pop(k, 1, t).map(|mut popped| popped.pop()))
+            }
+            (b"SPOP", [BulkString(k), BulkString(count)]) => match parse_int(count) {
+                Ok(count) if count >= 0 => RESP::from(self.data.s_pop(k, count as usize, t)),
+                Ok(_) => Error(
+                    "ERR".into(),
+                    "value is out of range, must be positive".into(),
+                ),
+                Err(err) => err.to_resp(),
+            },
+            (b"ZADD", [BulkString(k), pairs @ ..]) => self.z_add(k, pairs, t),
+            (b"ZREM", [BulkString(k), members @ ..]) if !members.is_empty() => {
+                match RedisEngine::bulk_args(members) {
+                    Some(members) => RESP::from(self.data.z_rem(k, &members, t)),
+                    None => RedisEngine::not_bulk(),
+                }
+            }
+            (b"ZSCORE", [BulkString(k), BulkString(member)]) => {
+                RESP::from(self.data.z_score(k, member, t))
+            }
+            (b"ZRANK", [BulkString(k), BulkString(member)]) => {
+                RESP::from(self.data.z_rank(k, member, t))
+            }
+            (b"ZRANGE", [BulkString(k), BulkString(start), BulkString(stop), options @ ..]) => {
+                let with_scores = match options {
+                    [] => false,
+                    [BulkString(o)] if o.eq_ignore_ascii_case(b"WITHSCORES") => true,
+                    _ => return Error("ERR".into(), "syntax error".into()),
+                };
+                let range = match (parse_int(start), parse_int(stop)) {
+                    (Ok(start), Ok(stop)) => self.data.z_range(k, start, stop, t),
+                    (Err(err), _) | (_, Err(err)) => Err(err),
+                };
+                match range {
+                    Ok(range) if with_scores => RESP::map(range),
+                    Ok(range) => RESP::from(range.into_iter().map(|(m, _)| m).collect::<Vec<_>>()),
+                    Err(err) => err.to_resp(),
+                }
+            }
+            (b"ZCARD", [BulkString(k)]) => self.length(k, "zset", t),
+            (b"XADD", [BulkString(k), BulkString(id), pairs @ ..]) => self.x_add(k, id, pairs, t),
+            (b"XRANGE", [BulkString(k), BulkString(start), BulkString(end), options @ ..]) => {
+                self.x_range(k, start, end, options, false, t)
+            }
+            (b"XREVRANGE", [BulkString(k), BulkString(end), BulkString(start), options @ ..]) => {
+                self.x_range(k, start, end, options, true, t)
+            }
+            (b"XLEN", [BulkString(k)]) => self.length(k, "stream", t),
+            (b"XREAD", args) => self.x_read(args, t),
+            (b"CONFIG", [BulkString(sub), patterns @ ..])
+                if sub.eq_ignore_ascii_case(b"GET") && !patterns.is_empty() =>
+            {
+                self.config_get(patterns)
+            }
+            _ => RedisEngine::wrong_arity(cmd),
+        }
+    }
+
+    // NX, XX and GET are checked against the value before the SET. With GET the reply is the
+    // old value whether the key was set or not
+    fn set(&mut self, k: &RawValue, v: &RawValue, options: SetOptions, t: u64) -> RESP {
+        let (old, exists) = if options.get {
+            match self.data.get(k, t) {
+                Ok(old) => {
+                    let exists = old.is_some();
+                    (RESP::from(old), exists)
+                }
+                Err(err) => return err.to_resp(),
+            }
+        } else {
+            (RedisEngine::ok(), self.data.info(k, t).is_some())
+        };
+        if (options.nx && exists) || (options.xx && !exists) {
+            return if options.get { old } else { Null };
+        }
+        let evict_at = if options.keep_ttl {
+            self.data.expires_at(k, t).flatten()
+        } else {
+            options.evict_at
+        };
+        self.data.set(k.clone(), v.clone(), evict_at);
+        old
+    }
+
+    // EX seconds | PX milliseconds | EXAT timestamp | PXAT timestamp-ms | KEEPTTL, NX | XX, GET
+    fn set_options(options: &[RESP], t: u64) -> Result<SetOptions, RESP> {
+        let syntax_error = || Error("ERR".into(), "syntax error".into());
+        let invalid = || Error("ERR".into(), "invalid expire time in 'set' command".into());
+        let mut parsed = SetOptions::default();
+        let mut expiration = false;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = match option {
+                BulkString(option) => option.to_ascii_uppercase(),
+                _ => return Err(syntax_error()),
+            };
+            match option.as_slice() {
+                b"NX" if !parsed.xx => parsed.nx = true,
+                b"XX" if !parsed.nx => parsed.xx = true,
+                b"GET" => parsed.get = true,
+                b"KEEPTTL" if !expiration => parsed.keep_ttl = true,
+                b"EX" | b"PX" | b"EXAT" | b"PXAT" if !expiration && !parsed.keep_ttl => {
+                    let value = match options.next() {
+                        Some(BulkString(value)) => parse_int(value).map_err(|e| e.to_resp())?,
+                        _ => return Err(syntax_error()),
+                    };
+                    if value <= 0 {
+                        return Err(invalid());
+                    }
+                    let evict_at = match option.as_slice() {
+                        b"EX" => (value as u64)
+                            .checked_mul(1000)
+                            .and_then(|ms| ms.checked_add(t)),
+                        b"PX" => (value as u64).checked_add(t),
+                        b"EXAT" => (value as u64).checked_mul(1000),
+                        _ => Some(value as u64),
+                    };
+                    parsed.evict_at = Some(evict_at.ok_or_else(invalid)?);
+                    expiration = true;
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    // a SET with NX or XX may not set the key, then the reply tells whether it was
+    fn set_modified(options: &[RESP], resp: &RESP, t: u64) -> bool {
+        match RedisEngine::set_options(options, t) {
+            // with GET the reply is the old value: NX sets the key only if there was none
+            Ok(o) if o.get && o.nx => *resp == Null,
+            Ok(o) if o.get && !o.xx => true,
+            Ok(_) => *resp != Null,
+            Err(_) => false,
+        }
+    }
+
+    // LOCK key token ttl-ms is SET key token NX PX ttl-ms: OK when the lock was free, nil when
+    // held. UNLOCK key token deletes the key only if it holds the token, like the release
+    // script of redlock, so a client never frees a lock expired and taken by another one
+    fn lock(&mut self, k: &RawValue, token: &RawValue, ttl: &RawValue, t: u64) -> RESP {
+        let evict_at = match parse_int(ttl) {
+            Ok(ttl) if ttl > 0 => t.checked_add(ttl as u64),
+            Ok(_) => None,
+            Err(err) => return err.to_resp(),
+        };
+        let evict_at = match evict_at {
+            Some(evict_at) => evict_at,
+            None => return Error("ERR".into(), "invalid expire time in 'lock' command".into()),
+        };
+        if self.data.info(k, t).is_some() {
+            return Null;
+        }
+        self.data.set(k.clone(), token.clone(), Some(evict_at));
+        RedisEngine::ok()
+    }
+
+    fn unlock(&mut self, k: &RawValue, token: &RawValue, t: u64) -> RESP {
+        match self.data.get(k, t) {
+            Ok(Some(v)) if v == token => Integer(self.data.del(k, t) as i64),
+            Ok(_) => Integer(0),
+            Err(err) => err.to_resp(),
+        }
+    }
+
+    // RATELIMIT key limit window-ms [COST n] replies [1 or 0 if denied, remaining, retry after
+    // ms], see ratelimit::check. The counts of the two fixed windows are the fields of a hash
+    // named by the start of the window, expiring with the next one
+    fn rate_limit(
+        &mut self,
+        k: &RawValue,
+        limit: &RawValue,
+        window: &RawValue,
+        cost: &[RESP],
+        t: u64,
+    ) -> RESP {
+        let cost = match cost {
+            [] => Ok(1),
+            [BulkString(option), BulkString(cost)] if option.eq_ignore_ascii_case(b"COST") => {
+                parse_int(cost)
+            }
+            _ => return Error("ERR".into(), "syntax error".into()),
+        };
+        let (limit, window, cost) = match (parse_int(limit), parse_int(window), cost) {
+            (Ok(limit), Ok(window), Ok(cost)) if limit > 0 && window > 0 && cost > 0 => {
+                (limit as u64, window as u64, cost as u64)
+            }
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return err.to_resp(),
+            _ => {
+                return Error(
+                    "ERR".into(),
+                    "limit, window and cost must be positive".into(),
+                )
+            }
+        };
+        let start = t - t % window;
+        let field = |start: u64| Bytes::from(start.to_string());
+        let fields = [field(start), field(start.wrapping_sub(window))];
+        let counts = match self.data.h_get(k, &fields, t) {
+            Ok(counts) => counts,
+            Err(err) => return err.to_resp(),
+        };
+        let mut counts = counts.iter().map(|count| match count {
+            Some(count) => parse_int(count).map(|count| count.max(0) as u64),
+            None => Ok(0),
+        });
+        let (current, previous) = match (counts.next(), counts.next()) {
+            (Some(Ok(current)), Some(Ok(previous))) => (current, previous),
+            _ => return RdisError::NotInteger.to_resp(),
+        };
+        let decision = ratelimit::check(limit, window, t - start, previous, current, cost);
+        if decision.allowed {
+            let count = Bytes::from((current + cost).to_string());
+            let stale = [field(start.wrapping_sub(2 * window))];
+            let updated = self
+                .data
+                .h_set(k.clone(), vec![(fields[0].clone(), count)], t)
+                .and_then(|_| self.data.h_del(k, &stale, t));
+            if let Err(err) = updated {
+                return err.to_resp();
+            }
+            self.data.expire(k, start.saturating_add(2 * window), t);
+        }
+        Array(vec![
+            Integer(decision.allowed as i64),
+            Integer(decision.remaining as i64),
+            Integer(decision.retry_after as i64),
+        ])
+    }
+
+    // EXPIRE and PEXPIRE take a time to live, EXPIREAT and PEXPIREAT a unix time, in ms for the
+    // P ones. NX sets the expiration only when the key has none, XX only when it has one, GT
+    // and LT only when it moves later or earlier, no expiration counting as infinite
+    fn expire(
+        &mut self,
+        cmd: &[u8],
+        k: &RawValue,
+        time: &RawValue,
+        options: &[RESP],
+        t: u64,
+    ) -> RESP {
+        let time = match parse_int(time) {
+            Ok(time) => time,
+            Err(err) => return err.to_resp(),
+        };
+        let ms = if cmd.starts_with(b"P") {
+            Some(time)
+        } else {
+            time.checked_mul(1000)
+        };
+        let evict_at = if cmd.ends_with(b"AT") {
+            ms
+        } else {
+            ms.and_then(|ms| ms.checked_add(t as i64))
+        };
+        let evict_at = match evict_at {
+            Some(evict_at) => evict_at.max(0) as u64,
+            None => {
+                let cmd = String::from_utf8_lossy(cmd).to_lowercase();
+                return Error(
+                    "ERR".into(),
+                    format!("invalid expire time in '{}' command", cmd),
+                );
+            }
+        };
+        let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+        for option in options {
+            let option = match option {
+                BulkString(option) => option,
+                _ => return RedisEngine::not_bulk(),
+            };
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" => nx = true,
+                b"XX" => xx = true,
+                b"GT" => gt = true,
+                b"LT" => lt = true,
+                _ => {
+                    return Error(
+                        "ERR".into(),
+                        format!("Unsupported option {}", String::from_utf8_lossy(option)),
+                    )
+                }
+            }
+        }
+        if nx && (xx || gt || lt) {
+            return Error(
+                "ERR".into(),
+                "NX and XX, GT or LT options at the same time are not compatible".into(),
+            );
+        }
+        if gt && lt {
+            return Error(
+                "ERR".into(),
+                "GT and LT options at the same time are not compatible".into(),
+            );
+        }
+        let allowed = match self.data.expires_at(k, t) {
+            None => return Integer(0),
+            Some(None) => !xx && !gt,
+            Some(Some(current)) => {
+                !nx && (!gt || evict_at > current) && (!lt || evict_at < current)
+            }
+        };
+        Integer((allowed && self.data.expire(k, evict_at, t)) as i64)
+    }
+
+    // -2 for a missing key, -1 for one without expiration. The time left, or the unix time of
+    // the expiration if absolute, in seconds rounded like redis unless ms
+    fn ttl(&mut self, k: &RawValue, ms: bool, absolute: bool, t: u64) -> RESP {
+        let evict_at = match self.data.expires_at(k, t) {
+            None => return Integer(-2),
+            Some(None) => return Integer(-1),
+            Some(Some(evict_at)) => evict_at,
+        };
+        let ttl = if absolute {
+            evict_at
+        } else {
+            evict_at.saturating_sub(t)
+        };
+        Integer(if ms { ttl } else { (ttl + 500) / 1000 } as i64)
+    }
+
+    // values are pushed one at a time, the reply is the length of the list
+    fn push(&mut self, k: &RawValue, values: &[RESP], front: bool, t: u64) -> RESP {
+        let mut len = 0;
+        for v in values {
+            let v = match v {
+                BulkString(v) => v.clone(),
+                _ => return RedisEngine::not_bulk(),
+            };
+            let pushed = if front {
+                self.data.l_push(k.clone(), v, None, t)
+            } else {
+                self.data.r_push(k.clone(), v, None, t)
+            };
+            match pushed {
+                Ok(l) => len = l,
+                Err(err) => return err.to_resp(),
+            }
+        }
+        RESP::from(len)
+    }
+
+    // publishes Event::KeyWritten for every key, like DEL does for deletions
+    fn mset(&mut self, pairs: &[RESP]) -> RESP {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return RedisEngine::wrong_arity(b"mset");
+        }
+        let pairs = match RedisEngine::bulk_args(pairs) {
+            Some(pairs) => pairs,
+            None => return RedisEngine::not_bulk(),
+        };
+        for pair in pairs.chunks(2) {
+            self.data.set(pair[0].clone(), pair[1].clone(), None);
+            let key = pair[0].clone();
+            self.events.publish(|| Event::KeyWritten {
+                key,
+                command: "mset".to_owned(),
+            });
+        }
+        RedisEngine::ok()
+    }
+
+    // keys holding another kind of value are nil, like the missing ones
+    fn mget(&mut self, keys: &[RESP], t: u64) -> RESP {
+        let keys = match RedisEngine::bulk_args(keys) {
+            Some(keys) => keys,
+            None => return RedisEngine::not_bulk(),
+        };
+        let values = keys.iter().map(|k| self.data.get(k, t).unwrap_or(None));
+        RESP::from(values.collect::<Vec<_>>())
+    }
+
+    fn h_set(&mut self, k: &RawValue, pairs: &[RESP], t: u64) -> RESP {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return RedisEngine::wrong_arity(b"hset");
+        }
+        let pairs = match RedisEngine::bulk_args(pairs) {
+            Some(pairs) => pairs,
+            None => return RedisEngine::not_bulk(),
+        };
+        let pairs = pairs
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        RESP::from(self.data.h_set(k.clone(), pairs, t))
+    }
+
+    // score member pairs
+    fn z_add(&mut self, k: &RawValue, pairs: &[RESP], t: u64) -> RESP {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return RedisEngine::wrong_arity(b"zadd");
+        }
+        let pairs = match RedisEngine::bulk_args(pairs) {
+            Some(pairs) => pairs,
+            None => return RedisEngine::not_bulk(),
+        };
+        let mut members = Vec::with_capacity(pairs.len() / 2);
+        for pair in pairs.chunks(2) {
+            match parse_float(&pair[0]) {
+                Ok(score) => members.push((score, pair[1].clone())),
+                Err(err) => return err.to_resp(),
+            }
+        }
+        RESP::from(self.data.z_add(k.clone(), members, t))
+    }
+
+    // field value pairs after the id, the reply is the id of the entry
+    fn x_add(&mut self, k: &RawValue, id: &RawValue, pairs: &[RESP], t: u64) -> RESP {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return RedisEngine::wrong_arity(b"xadd");
+        }
+        let id = match NewId::parse(id) {
+            Some(id) => id,
+            None => return RedisEngine::invalid_stream_id(),
+        };
+        let pairs = match RedisEngine::bulk_args(pairs) {
+            Some(pairs) => pairs,
+            None => return RedisEngine::not_bulk(),
+        };
+        let fields = pairs
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        match self.data.x_add(k.clone(), id, fields, t) {
+            Ok(id) => RESP::from(id.to_string()),
+            Err(err) => err.to_resp(),
+        }
+    }
+
+    // [COUNT count]
+    fn x_range(
+        &mut self,
+        k: &RawValue,
+        start: &RawValue,
+        end: &RawValue,
+        options: &[RESP],
+        rev: bool,
+        t: u64,
+    ) -> RESP {
+        let count = match options {
+            [] => None,
+            [BulkString(o), BulkString(count)] if o.eq_ignore_ascii_case(b"COUNT") => {
+                match parse_int(count) {
+                    Ok(count) => Some(count.max(0) as usize),
+                    Err(err) => return err.to_resp(),
+                }
+            }
+            _ => return Error("ERR".into(), "syntax error".into()),
+        };
+        let (start, end) = match (
+            stream::parse_bound(start, true),
+            stream::parse_bound(end, false),
+        ) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return RedisEngine::invalid_stream_id(),
+        };
+        match self.data.x_range(k, start, end, count, rev, t) {
+            Ok(entries) => RedisEngine::stream_entries(entries),
+            Err(err) => err.to_resp(),
+        }
+    }
 
-impl RedisData {
-    fn new() -> RedisData {
-        RedisData {
-            single_map: HashMap::with_capacity(DEFAULT_CAPACITY),
-            list_map: HashMap::with_capacity(DEFAULT_CAPACITY),
-            eviction: BTreeMap::new(),
-            last_evicted_t: 0,
+    // [COUNT count] STREAMS key [key ...] id [id ...], the entries after the ids. Nothing waits
+    // for new entries: BLOCK is refused and $, the last id, never has entries after it
+    fn x_read(&mut self, args: &[RESP], t: u64) -> RESP {
+        let args = match RedisEngine::bulk_args(args) {
+            Some(args) => args,
+            None => return RedisEngine::not_bulk(),
+        };
+        let mut count = None;
+        let mut options = args.iter();
+        loop {
+            match options.next().map(|o| o.to_ascii_uppercase()).as_deref() {
+                Some(b"STREAMS") => break,
+                Some(b"COUNT") => match options.next().map(|c| parse_int(c)) {
+                    Some(Ok(c)) => count = Some(c.max(0) as usize),
+                    Some(Err(err)) => return err.to_resp(),
+                    None => return Error("ERR".into(), "syntax error".into()),
+                },
+                Some(b"BLOCK") => {
+                    return Error("ERR".into(), "XREAD BLOCK is not supported".into());
+                }
+                _ => return Error("ERR".into(), "syntax error".into()),
+            }
+        }
+        let streams = options.as_slice();
+        if streams.is_empty() || !streams.len().is_multiple_of(2) {
+            return Error(
+                "ERR".into(),
+                "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".into(),
+            );
+        }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+        let mut replies = Vec::new();
+        for (k, id) in keys.iter().zip(ids) {
+            if id.as_ref() == b"$" {
+                continue;
+            }
+            let start = match StreamId::parse(id, 0).and_then(StreamId::next) {
+                Some(start) => start,
+                None => return RedisEngine::invalid_stream_id(),
+            };
+            match self.data.x_range(k, start, StreamId::MAX, count, false, t) {
+                Ok(entries) if entries.is_empty() => (),
+                Ok(entries) => replies.push(Array(vec![
+                    BulkString(k.clone()),
+                    RedisEngine::stream_entries(entries),
+                ])),
+                Err(err) => return err.to_resp(),
+            }
+        }
+        if replies.is_empty() {
+            Null
+        } else {
+            Array(replies)
         }
     }
 
-    fn evict_if_needed(&mut self, t: u64) {
-        let to_remove: Vec<u64> = self
-            .eviction
-            .range(self.last_evicted_t..t)
-            .map(|(k, _)| *k)
+    // every entry is an array of its id and of its fields and values
+    fn stream_entries(entries: Vec<StreamEntry>) -> RESP {
+        let entries = entries
+            .into_iter()
+            .map(|(id, fields)| Array(vec![RESP::from(id.to_string()), RESP::map(fields)]))
             .collect();
-        self.last_evicted_t = t;
+        Array(entries)
+    }
+
+    fn invalid_stream_id() -> RESP {
+        Error(
+            "ERR".into(),
+            "Invalid stream ID specified as stream command argument".into(),
+        )
+    }
 
-        for k in to_remove {
-            if let Some(values) = self.eviction.remove(&k) {
-                for v in values {
-                    self.single_map.remove(&v);
+    // None if any of the arguments is not a bulk string
+    fn bulk_args(args: &[RESP]) -> Option<Vec<RawValue>> {
+        args.iter()
+            .map(|arg| match arg {
+                BulkString(arg) => Some(arg.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // only exact names and * are matched, tools mostly ask for single parameters
+    fn config_get(&self, patterns: &[RESP]) -> RESP {
+        let mut matches = Vec::new();
+        for pattern in patterns {
+            let pattern = match pattern {
+                BulkString(p) => p,
+                _ => return RedisEngine::not_bulk(),
+            };
+            for (name, value) in self.config_params.iter() {
+                if glob::matches(pattern, name.as_bytes(), true) {
+                    matches.push((*name, value.as_str()));
                 }
             }
         }
+        RESP::map(matches)
     }
 
-    fn insert_eviction(&mut self, k: Key, t: u64) {
-        let set = match self.eviction.get_mut(&t) {
-            Some(l) => l,
-            None => {
-                let s = HashSet::new();
-                self.eviction.insert(t, s);
-                self.eviction.get_mut(&t).unwrap()
+    // cursor [MATCH pattern] [COUNT count], then [TYPE type] for SCAN, [NOVALUES] for HSCAN and
+    // [NOSCORES] for ZSCAN
+    fn scan_options(cmd: &[u8], cursor: &[u8], options: &[RESP]) -> Result<ScanOptions, RESP> {
+        let cursor = match std::str::from_utf8(cursor).map(str::parse::<u64>) {
+            Ok(Ok(c)) => c,
+            _ => return Err(Error("ERR".into(), "invalid cursor".into())),
+        };
+        let syntax_error = || Error("ERR".into(), "syntax error".into());
+        let mut parsed = ScanOptions {
+            cursor,
+            count: 10,
+            pattern: None,
+            kind: None,
+            no_values: false,
+        };
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let name = match option {
+                BulkString(name) => name.to_ascii_uppercase(),
+                _ => return Err(syntax_error()),
+            };
+            let mut value = || match options.next() {
+                Some(BulkString(value)) => Ok(value.clone()),
+                _ => Err(syntax_error()),
+            };
+            match (name.as_slice(), cmd) {
+                (b"COUNT", _) => match parse_int(&value()?) {
+                    Ok(c) if c > 0 => parsed.count = c as usize,
+                    Ok(_) => return Err(syntax_error()),
+                    Err(err) => return Err(err.to_resp()),
+                },
+                // * matches every key, the empty one included
+                (b"MATCH", _) => parsed.pattern = Some(value()?).filter(|p| p.as_ref() != b"*"),
+                (b"TYPE", b"SCAN") => {
+                    parsed.kind = Some(String::from_utf8_lossy(&value()?).to_lowercase())
+                }
+                (b"NOVALUES", b"HSCAN") | (b"NOSCORES", b"ZSCAN") => parsed.no_values = true,
+                _ => return Err(syntax_error()),
             }
+        }
+        Ok(parsed)
+    }
+
+    fn scan(&mut self, cursor: &[u8], options: &[RESP], t: u64) -> RESP {
+        let options = match RedisEngine::scan_options(b"SCAN", cursor, options) {
+            Ok(options) => options,
+            Err(err) => return err,
+        };
+        let pattern = options.pattern.as_deref();
+        let kind = options.kind.as_deref();
+        let (next, keys) = self
+            .data
+            .scan(options.cursor, options.count, pattern, kind, t);
+        Array(vec![RESP::from(next.to_string()), RESP::from(keys)])
+    }
+
+    // HSCAN, SSCAN and ZSCAN: the fields of a hash followed by their values, the members of a
+    // set, the members of a sorted set followed by their scores
+    fn scan_members(
+        &mut self,
+        cmd: &[u8],
+        k: &RawValue,
+        cursor: &[u8],
+        options: &[RESP],
+        t: u64,
+    ) -> RESP {
+        let options = match RedisEngine::scan_options(cmd, cursor, options) {
+            Ok(options) => options,
+            Err(err) => return err,
+        };
+        let (cursor, count) = (options.cursor, options.count);
+        let pattern = options.pattern.as_deref();
+        let page = match cmd {
+            b"HSCAN" => self
+                .data
+                .h_scan(k, cursor, count, pattern, t)
+                .map(|(next, pairs)| (next, RedisEngine::pairs(pairs, options.no_values))),
+            b"ZSCAN" => self
+                .data
+                .z_scan(k, cursor, count, pattern, t)
+                .map(|(next, pairs)| (next, RedisEngine::pairs(pairs, options.no_values))),
+            _ => self
+                .data
+                .s_scan(k, cursor, count, pattern, t)
+                .map(|(next, members)| (next, RESP::from(members))),
         };
-        set.insert(k);
+        match page {
+            Ok((next, members)) => Array(vec![RESP::from(next.to_string()), members]),
+            Err(err) => err.to_resp(),
+        }
     }
 
-    fn set(&mut self, k: Arc<RawValue>, v: Arc<RawValue>, evict_at: Option<u64>) {
-        self.single_map.insert(k.clone(), v);
-        if let Some(t) = evict_at {
-            self.insert_eviction(k, t)
+    // a flat array of the pairs, or of their first elements alone
+    fn pairs<V: Into<RESP>>(pairs: Vec<(RawValue, V)>, first_only: bool) -> RESP {
+        if first_only {
+            RESP::from(pairs.into_iter().map(|(k, _)| k).collect::<Vec<_>>())
+        } else {
+            RESP::map(pairs)
         }
     }
 
-    fn get(&mut self, k: &RawValue, t: u64) -> Option<Arc<RawValue>> {
-        self.evict_if_needed(t);
-        self.single_map.get(k).clone().map(|el| el.clone())
+    // every key matching the pattern at once, blocking the engine like in redis. With the
+    // prefix index a pattern starting with literal bytes only walks the keys with that prefix
+    fn keys(&mut self, pattern: &[u8], t: u64) -> RESP {
+        let prefix = glob::literal_prefix(pattern);
+        let indexed = if prefix.is_empty() {
+            None
+        } else {
+            self.data.keys_with_prefix(&prefix, t)
+        };
+        let keys = match indexed {
+            Some(keys) => keys
+                .into_iter()
+                .filter(|k| glob::matches(pattern, k, false))
+                .collect(),
+            None => {
+                let pattern = Some(pattern).filter(|p| *p != b"*");
+                self.data.scan(0, usize::MAX, pattern, None, t).1
+            }
+        };
+        RESP::from(keys)
+    }
+
+    fn del(&mut self, keys: &[RESP], t: u64) -> RESP {
+        let mut removed = 0;
+        for k in keys {
+            match k {
+                BulkString(k) => {
+                    if self.data.del(k, t) {
+                        removed += 1;
+                        let key = k.clone();
+                        self.events.publish(|| Event::KeyDeleted { key });
+                    }
+                }
+                _ => return RedisEngine::not_bulk(),
+            }
+        }
+        Integer(removed)
     }
 
-    fn incr(&mut self, k: &RawValue, t: u64) -> ResultT<Option<i64>> {
-        self.evict_if_needed(t);
-        match self.single_map.get(k) {
-            None => Ok(None),
-            Some(int_raw) => {
-                let i_decimal: i64 = String::from_utf8(int_raw.clone().to_vec())?.parse()?;
-                Ok(Some(i_decimal + 1))
+    // a key given twice counts twice
+    fn exists(&mut self, keys: &[RESP], t: u64) -> RESP {
+        let mut found = 0;
+        for k in keys {
+            match k {
+                BulkString(k) => found += self.data.info(k, t).is_some() as i64,
+                _ => return RedisEngine::not_bulk(),
             }
         }
+        Integer(found)
     }
 
-    fn l_push(&mut self, k: Arc<RawValue>, v: Arc<RawValue>, evict_at: Option<u64>) {
-        evict_at.map(|t| self.insert_eviction(k.clone(), t));
-        let deq = self
-            .list_map
-            .entry(k)
-            .or_insert(VecDeque::with_capacity(DEFAULT_LIST_CAPACITY));
-        deq.push_front(v);
+    fn to_bulk(resp: &RESP) -> RESP {
+        match resp {
+            SimpleString(s) => BulkString(Bytes::from(s.clone())),
+            other => other.clone(),
+        }
     }
 
-    fn r_push(&mut self, k: Arc<RawValue>, v: Arc<RawValue>, evict_at: Option<u64>) {
-        if let Some(t) = evict_at {
-            self.insert_eviction(k.clone(), t)
+    fn info(&self, section: Option<&[u8]>) -> RESP {
+        let mut info = InfoBuilder::new(section);
+        if info.section("Server") {
+            info.field("redis_version", "6.0.0");
+            info.field("rdis_version", env!("CARGO_PKG_VERSION"));
+            info.field("process_id", std::process::id());
+            info.field("tcp_port", self.port);
+            info.field("uptime_in_seconds", self.stats.uptime_secs());
+            info.field(
+                "engine_thread",
+                if self.engine_thread { "yes" } else { "no" },
+            );
+            // read from the thread running the engine
+            if let Ok(cpus) = current_thread_cpus() {
+                info.field("engine_cpulist", format_cpu_list(&cpus));
+            }
+            if !self.worker_cpus.is_empty() {
+                info.field("worker_cpulist", format_cpu_list(&self.worker_cpus));
+            }
+        }
+        if info.section("Clients") {
+            info.field("connected_clients", self.registry.len());
+        }
+        if info.section("Memory") {
+            let memory = MemoryStats::collect();
+            info.field("used_memory", memory.used);
+            info.field("used_memory_human", human_bytes(memory.used));
+            info.field("used_memory_rss", memory.rss);
+            info.field("used_memory_rss_human", human_bytes(memory.rss));
+            if let Some((allocated, active, resident)) = memory.allocator {
+                info.field("allocator_allocated", allocated);
+                info.field("allocator_active", active);
+                info.field("allocator_resident", resident);
+            }
+            if let Some(ratio) = memory.allocator_fragmentation_ratio() {
+                info.field("allocator_frag_ratio", format!("{:.2}", ratio));
+            }
+            info.field(
+                "mem_fragmentation_ratio",
+                format!("{:.2}", memory.fragmentation_ratio()),
+            );
+            info.field("mem_clients_normal", self.registry.clients_memory());
+            info.field("maxmemory_clients", self.maxmemory_clients);
+            info.field("mem_allocator", ALLOCATOR);
+            let defrag = self.defrag.as_ref();
+            info.field("active_defrag_enabled", defrag.is_some() as u8);
+            info.field(
+                "active_defrag_running",
+                defrag.is_some_and(|d| d.running()) as u8,
+            );
+            info.field("active_defrag_scanned", defrag.map_or(0, |d| d.scanned()));
+            info.field("active_defrag_hits", defrag.map_or(0, |d| d.hits));
+            info.field("active_defrag_passes", defrag.map_or(0, |d| d.passes));
+            info.field(
+                "active_defrag_last_reclaimed",
+                defrag.map_or(0, |d| d.last_reclaimed),
+            );
+            if let Some(tiering) = self.data.tiering_stats() {
+                info.field("tiered_keys", tiering.keys);
+                info.field("tiered_bytes", tiering.bytes);
+                info.field("tiered_file_bytes", tiering.file_bytes);
+                info.field("tiered_spills", tiering.spills);
+                info.field("tiered_loads", tiering.loads);
+                info.field("tiered_compactions", tiering.compactions);
+            }
+        }
+        if info.section("Stats") {
+            info.field(
+                "total_connections_received",
+                self.registry.total_connections(),
+            );
+            info.field(
+                "total_commands_processed",
+                self.stats.total_commands_processed,
+            );
+            info.field("total_error_replies", self.stats.total_error_replies);
+            info.field("expired_keys", self.stats.expired_keys);
+            let stale = match self.data.expires_count() {
+                0 => 0.0,
+                expires => {
+                    let stale = self.data.expired_stale_count(self.clock.now_millis());
+                    stale as f64 * 100.0 / expires as f64
+                }
+            };
+            info.field("expired_stale_perc", format!("{:.2}", stale));
+            // there is no maxmemory eviction of keys
+            info.field("evicted_keys", 0);
+            info.field("evicted_clients", self.stats.evicted_clients);
+            // GETs served by the read fast path are hits
+            let fast_path_hits = self.read_cache.as_ref().map_or(0, |cache| cache.hits());
+            info.field("keyspace_hits", self.stats.keyspace_hits + fast_path_hits);
+            info.field("keyspace_misses", self.stats.keyspace_misses);
+            if self.read_cache.is_some() {
+                info.field("read_fast_path_hits", fast_path_hits);
+            }
+            info.field(
+                "instantaneous_ops_per_sec",
+                self.stats.instantaneous_ops.rate().round(),
+            );
+            info.field(
+                "total_net_input_bytes",
+                self.metrics.net_input_bytes.load(Ordering::Relaxed),
+            );
+            info.field(
+                "total_net_output_bytes",
+                self.metrics.net_output_bytes.load(Ordering::Relaxed),
+            );
+            info.field(
+                "instantaneous_input_kbps",
+                format!("{:.2}", self.stats.instantaneous_input.rate() / 1024.0),
+            );
+            info.field(
+                "instantaneous_output_kbps",
+                format!("{:.2}", self.stats.instantaneous_output.rate() / 1024.0),
+            );
         }
-        let deq = self
-            .list_map
-            .entry(k)
-            .or_insert_with(|| VecDeque::with_capacity(DEFAULT_LIST_CAPACITY));
-        deq.push_back(v);
+        if info.section("Commandstats") {
+            for (name, stats) in self.stats.commands.iter() {
+                info.field(&format!("cmdstat_{}", name), stats.describe());
+            }
+        }
+        if info.section("Errorstats") {
+            for (error_type, count) in self.stats.errors.iter() {
+                info.field(
+                    &format!("errorstat_{}", error_type),
+                    format!("count={}", count),
+                );
+            }
+        }
+        if info.section("Latencystats") {
+            for (name, stats) in self.stats.commands.iter() {
+                let key = format!("latency_percentiles_usec_{}", name);
+                info.field(&key, format_percentiles(&stats.latency));
+            }
+        }
+        // counters kept by the storage, nothing is scanned
+        if info.section("Keyspace") && self.data.keys_count() > 0 {
+            info.field(
+                "db0",
+                format!(
+                    "keys={},expires={},avg_ttl={}",
+                    self.data.keys_count(),
+                    self.data.expires_count(),
+                    self.data.avg_ttl(self.clock.now_millis())
+                ),
+            );
+        }
+        if info.section("Hotkeys") {
+            if let Some(hot_keys) = &self.hot_keys {
+                let top = hot_keys.top(INFO_HOT_KEYS, self.clock.now_millis());
+                for (i, (k, qps)) in top.iter().enumerate() {
+                    info.field(
+                        &format!("hotkey{}", i),
+                        format!("key={},qps={:.2}", String::from_utf8_lossy(k), qps),
+                    );
+                }
+            }
+        }
+        BulkString(Bytes::from(info.build().into_bytes()))
     }
 
-    fn l_pop(&mut self, k: &RawValue) -> Option<Arc<RawValue>> {
-        self.list_map.get_mut(k).and_then(|list| list.pop_front())
+    // 0 for a missing key
+    // with a time budget the range is copied LRANGE_CHUNK elements at a time, checking the
+    // deadline in between
+    fn l_range(&mut self, k: &RawValue, start: i64, stop: i64, t: u64) -> RESP {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return RESP::from(self.data.l_range(k, start, stop, t)),
+        };
+        let len = match self.data.info(k, t) {
+            None => return Array(Vec::new()),
+            Some(info) if info.kind == "list" => info.len,
+            Some(_) => return RdisError::WrongType.to_resp(),
+        };
+        let (start, stop) = match range_bounds(start, stop, len) {
+            Some(bounds) => bounds,
+            None => return Array(Vec::new()),
+        };
+        let mut values = Vec::with_capacity(stop - start + 1);
+        let mut from = start;
+        while from <= stop {
+            if Instant::now() >= deadline {
+                return RdisError::Timeout.to_resp();
+            }
+            let to = (from + LRANGE_CHUNK - 1).min(stop);
+            match self.data.l_range(k, from as i64, to as i64, t) {
+                Ok(chunk) => values.extend(chunk),
+                Err(err) => return err.to_resp(),
+            }
+            from = to + 1;
+        }
+        RESP::from(values)
     }
 
-    fn r_pop(&mut self, k: &RawValue) -> Option<Arc<RawValue>> {
-        self.list_map.get_mut(k).and_then(|list| list.pop_back())
+    fn length(&mut self, k: &RawValue, kind: &str, t: u64) -> RESP {
+        match self.data.info(k, t) {
+            None => Integer(0),
+            Some(info) if info.kind == kind => RESP::from(info.len),
+            Some(_) => RdisError::WrongType.to_resp(),
+        }
     }
-}
 
-pub struct RedisEngine {
-    data: RedisData,
-    receiver: mpsc::Receiver<(ClientReq, oneshot::Sender<ClientReq>)>,
-}
+    // the largest keys of every kind, one per line:
+    // list mylist memory=2312 len=100
+    fn big_keys(&mut self, count: usize, t: u64) -> RESP {
+        let mut out = String::new();
+        for (k, info) in self.data.big_keys(count, t) {
+            out.push_str(&format!(
+                "{} {} memory={} len={}\n",
+                info.kind,
+                String::from_utf8_lossy(&k),
+                info.memory,
+                info.len
+            ));
+        }
+        BulkString(Bytes::from(out.into_bytes()))
+    }
 
-impl RedisEngine {
-    pub fn new(receiver: mpsc::Receiver<(ClientReq, oneshot::Sender<ClientReq>)>) -> RedisEngine {
-        let data = RedisData::new();
-        RedisEngine { data, receiver }
+    // the hottest keys, one per line with the estimated commands per second:
+    // mykey qps=1200.50
+    fn hot_keys_report(&self, count: usize, t: u64) -> RESP {
+        let hot_keys = match &self.hot_keys {
+            Some(hot_keys) => hot_keys,
+            None => {
+                return Error(
+                    "ERR".into(),
+                    "hot keys aren't tracked, see hotkeys-sample-rate".into(),
+                )
+            }
+        };
+        let mut out = String::new();
+        for (k, qps) in hot_keys.top(count.min(MAX_CANDIDATES), t) {
+            out.push_str(&format!("{} qps={:.2}\n", String::from_utf8_lossy(&k), qps));
+        }
+        BulkString(Bytes::from(out.into_bytes()))
     }
 
-    fn current_time() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
+    // a subset of the fields of redis, with the same names
+    fn memory_stats() -> RESP {
+        let memory = MemoryStats::collect();
+        let mut fields = vec![
+            ("total.allocated", RESP::from(memory.used)),
+            ("rss", RESP::from(memory.rss)),
+            ("fragmentation", RESP::from(memory.fragmentation_ratio())),
+            ("allocator", RESP::from(ALLOCATOR)),
+        ];
+        if let Some((allocated, active, resident)) = memory.allocator {
+            fields.push(("allocator.allocated", RESP::from(allocated)));
+            fields.push(("allocator.active", RESP::from(active)));
+            fields.push(("allocator.resident", RESP::from(resident)));
+        }
+        if let Some(ratio) = memory.allocator_fragmentation_ratio() {
+            fields.push(("allocator-fragmentation.ratio", RESP::from(ratio)));
+        }
+        RESP::map(fields)
     }
 
-    pub async fn start_loop(&mut self)  {
-        loop {
-            match self.receiver.recv().await {
-                Some((req, channel)) => {
-                    let t = RedisEngine::current_time();
-                    match req {
-                        ClientReq::Single(r) => channel
-                            .send(ClientReq::Single(self.handle_request(&r, t)))
-                            .unwrap(),
-                        ClientReq::Pipeline(rs) => {
-                            let mut resp = Vec::with_capacity(rs.len());
-                            for r in rs.iter() {
-                                resp.push(self.handle_request(r, t));
-                            }
-                            channel.send(ClientReq::Pipeline(resp)).unwrap()
-                        }
+    fn client_command(&self, state: &mut ConnectionState, args: &[RESP]) -> RESP {
+        match args {
+            [BulkString(sub)] => match sub.to_ascii_uppercase().as_slice() {
+                b"ID" => Integer(state.client_id as i64),
+                b"GETNAME" => state.name.as_ref().map_or(RESP::Null, |name| {
+                    BulkString(Bytes::from(name.clone().into_bytes()))
+                }),
+                b"INFO" => match self.registry.info(state.client_id) {
+                    Some(info) => {
+                        BulkString(Bytes::from(format!("{}\n", info.describe()).into_bytes()))
+                    }
+                    None => Error("ERR".into(), "No such client".into()),
+                },
+                b"LIST" => {
+                    let mut out = String::new();
+                    for info in self.registry.list() {
+                        out.push_str(&info.describe());
+                        out.push('\n');
                     }
+                    BulkString(Bytes::from(out.into_bytes()))
                 }
-                None => {
-                    // TODO stay alive
-                    warn!("No senders, loop terminated");
+                _ => Error(
+                    "ERR".into(),
+                    format!(
+                        "unknown subcommand '{}'. Try CLIENT HELP.",
+                        String::from_utf8_lossy(sub)
+                    ),
+                ),
+            },
+            [BulkString(sub), BulkString(name)] if sub.eq_ignore_ascii_case(b"SETNAME") => {
+                if name.iter().any(|c| *c <= b' ' || *c > b'~') {
+                    return Error(
+                        "ERR".into(),
+                        "Client names cannot contain spaces, newlines or special characters."
+                            .into(),
+                    );
+                }
+                state.name = if name.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(name).into_owned())
+                };
+                RedisEngine::ok()
+            }
+            [BulkString(sub), BulkString(flag)] if sub.eq_ignore_ascii_case(b"TRACE") => {
+                match flag.to_ascii_uppercase().as_slice() {
+                    b"ON" => state.wire_trace = true,
+                    b"OFF" => state.wire_trace = false,
+                    _ => return Error("ERR".into(), "syntax error".into()),
+                }
+                RedisEngine::ok()
+            }
+            // old form, CLIENT KILL addr:port
+            [BulkString(sub), BulkString(addr)] if sub.eq_ignore_ascii_case(b"KILL") => {
+                let addr = String::from_utf8_lossy(addr);
+                if self.registry.kill_addr(&addr) {
+                    RedisEngine::ok()
+                } else {
+                    Error("ERR".into(), "No such client".into())
+                }
+            }
+            [BulkString(sub), filters @ ..] if sub.eq_ignore_ascii_case(b"KILL") => {
+                match RedisEngine::kill_filter(state, filters) {
+                    Ok(filter) => Integer(self.registry.kill(&filter) as i64),
+                    Err(err) => err,
                 }
             }
+            _ => RedisEngine::wrong_arity(b"client"),
         }
     }
 
-    fn handle_request(&mut self, req: &RESP, t: u64) -> RESP {
-        match req {
-            Array(commands) => match commands.as_slice() {
-                [] => Error("todo".into(), "empty command".into()),
-                [BulkString(single)] => match single.as_slice() {
-                    b"PING" => SimpleString("PONG".into()),
-                    b"COMMAND" => SimpleString("OK".into()),
-                    _ => RedisEngine::error_resp(),
-                },
-                [BulkString(cmd), BulkString(k)] => match cmd.as_slice() {
-                    b"GET" => self.data.get(k, t).map_or(RESP::Null, BulkString),
-                    b"INCR" => match self.data.incr(k, t) {
-                        Ok(res) => res.map_or(RESP::Null, |i| SimpleString(i.to_string().into())),
-                        Err(err) => Error("WRONG_TYPE".into(), err.to_string()),
-                    },
-                    b"LPOP" => self.data.l_pop(k).map_or(RESP::Null, BulkString),
-                    b"RPOP" => self.data.r_pop(k).map_or(RESP::Null, BulkString),
-                    _ => RedisEngine::error_resp(),
-                },
-                [BulkString(cmd), BulkString(k), BulkString(v)] => match cmd.as_slice() {
-                    b"SET" => {
-                        self.data.set(k.clone(), v.clone(), None);
-                        RedisEngine::ok()
-                    }
-                    b"LPUSH" => {
-                        self.data.l_push(k.clone(), v.clone(), None);
-                        RedisEngine::ok()
+    // CLIENT KILL <filter> <value> [<filter> <value> ...], SKIPME is yes by default
+    fn kill_filter(state: &ConnectionState, filters: &[RESP]) -> Result<KillFilter, RESP> {
+        let syntax_error = || Error("ERR".into(), "syntax error".into());
+        if filters.is_empty() || !filters.len().is_multiple_of(2) {
+            return Err(syntax_error());
+        }
+        let mut filter = KillFilter {
+            skip: Some(state.client_id),
+            ..KillFilter::default()
+        };
+        for pair in filters.chunks(2) {
+            let (name, value) = match pair {
+                [BulkString(name), BulkString(value)] => {
+                    (name.to_ascii_uppercase(), String::from_utf8_lossy(value))
+                }
+                _ => return Err(syntax_error()),
+            };
+            match name.as_slice() {
+                b"ID" => match value.parse() {
+                    Ok(id) => filter.id = Some(id),
+                    Err(_) => {
+                        return Err(Error(
+                            "ERR".into(),
+                            "client-id should be greater than 0".into(),
+                        ))
                     }
-                    b"RPUSH" => {
-                        self.data.r_push(k.clone(), v.clone(), None);
-                        RedisEngine::ok()
+                },
+                b"ADDR" => filter.addr = Some(value.into_owned()),
+                b"LADDR" => filter.laddr = Some(value.into_owned()),
+                b"TYPE" => match ClientKind::parse(&value) {
+                    Some(kind) => filter.kind = Some(kind),
+                    None => {
+                        return Err(Error(
+                            "ERR".into(),
+                            format!("Unknown client type '{}'", value),
+                        ))
                     }
-                    _ => RedisEngine::error_resp(),
                 },
-                _ => RedisEngine::error_resp(),
-            },
-            other => self.handle_request(&Array(vec![other.clone()]), t),
+                b"MAXAGE" => match value.parse() {
+                    Ok(secs) => filter.max_age = Some(Duration::from_secs(secs)),
+                    Err(_) => return Err(syntax_error()),
+                },
+                b"SKIPME" => match value.to_lowercase().as_str() {
+                    "yes" => filter.skip = Some(state.client_id),
+                    "no" => filter.skip = None,
+                    _ => return Err(syntax_error()),
+                },
+                _ => return Err(syntax_error()),
+            }
         }
+        Ok(filter)
+    }
+
+    fn unknown_command(name: &[u8]) -> RESP {
+        Error(
+            "ERR".into(),
+            format!("unknown command '{}'", String::from_utf8_lossy(name)),
+        )
+    }
+
+    fn wrong_arity(cmd: &[u8]) -> RESP {
+        RdisError::WrongArity(String::from_utf8_lossy(cmd).to_lowercase()).to_resp()
     }
 
-    fn error_resp() -> RESP {
-        Error("Error".into(), "too many arguments".into())
+    // arguments are bulk strings, redis refuses anything else while parsing the request
+    fn not_bulk() -> RESP {
+        RdisError::Protocol("expected bulk string arguments".into()).to_resp()
     }
 
     fn ok() -> RESP {
         SimpleString("OK".into())
     }
 }
+
+fn parse_int(raw: &[u8]) -> ResultT<i64> {
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(RdisError::NotInteger)
+}
+
+// inf and -inf are valid scores, nan is not
+fn parse_float(raw: &[u8]) -> ResultT<f64> {
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| !f.is_nan())
+        .ok_or(RdisError::NotFloat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::audit::verify;
+    use crate::rdis::clock::{ManualClock, SteppingClock};
+    use crate::rdis::recorder::read_entry;
+    use crate::rdis::storage::RedisData;
+    use crate::rdis::writebehind::{SinkFuture, WriteBehindOptions, WriteSink};
+
+    fn engine(config: &Config) -> RedisEngine {
+        let (_, receiver) = mpsc::channel(1);
+        RedisEngine::new(
+            receiver,
+            Arc::new(ClientRegistry::new()),
+            Arc::new(Metrics::new()),
+            Box::new(RedisData::new()),
+            config,
+        )
+    }
+
+    fn cmd(args: &[&str]) -> RESP {
+        Array(
+            args.iter()
+                .map(|a| BulkString(Bytes::copy_from_slice(a.as_bytes())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    pub fn test_renamed_commands() {
+        let config = Config {
+            rename_commands: vec![
+                (b"SET".to_vec(), b"STORE".to_vec()),
+                (b"PING".to_vec(), b"".to_vec()),
+            ],
+            ..Config::default()
+        };
+        let mut engine = engine(&config);
+        let mut state = ConnectionState::new(0);
+        assert!(matches!(
+            engine.handle_request(&mut state, &cmd(&["SET", "k", "v"]), 0),
+            Error(_, _)
+        ));
+        assert!(matches!(
+            engine.handle_request(&mut state, &cmd(&["ping"]), 0),
+            Error(_, _)
+        ));
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["store", "k", "v"]), 0),
+            RedisEngine::ok()
+        );
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["get", "k"]), 0),
+            BulkString(Bytes::from_static(b"v"))
+        );
+    }
+
+    #[test]
+    pub fn test_wrong_arity() {
+        let mut engine = engine(&Config::default());
+        let arity = |cmd: &str| {
+            Error(
+                "ERR".into(),
+                format!("wrong number of arguments for '{}' command", cmd),
+            )
+        };
+        assert_eq!(request(&mut engine, &["get"]), arity("get"));
+        assert_eq!(request(&mut engine, &["LPOP", "l", "2"]), arity("lpop"));
+        assert_eq!(request(&mut engine, &["MSET", "a"]), arity("mset"));
+        assert_eq!(
+            request(&mut engine, &["DEBUG", "HOTKEYS", "1", "2"]),
+            arity("debug|hotkeys")
+        );
+        assert!(matches!(
+            engine.handle_request(&mut ConnectionState::new(0), &Array(vec![Integer(1)]), 0),
+            Error(kind, msg) if kind == "ERR" && msg.starts_with("Protocol error")
+        ));
+    }
+
+    #[test]
+    pub fn test_info() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        engine.handle_request(&mut state, &cmd(&["SET", "k", "v"]), 0);
+        let info = match engine.handle_request(&mut state, &cmd(&["INFO"]), 0) {
+            BulkString(s) => String::from_utf8(s.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(info.contains("# Server\r\n"));
+        assert!(info.contains("total_commands_processed:2\r\n"));
+        assert!(info.contains("db0:keys=1,expires=0,avg_ttl=0\r\n"));
+        assert!(info.contains("latency_percentiles_usec_set:p50="));
+        assert!(info.contains("cmdstat_set:calls=1,usec="));
+        assert!(info.contains("# Memory\r\nused_memory:"));
+        assert!(info.contains("\r\nmem_allocator:"));
+        match engine.handle_request(&mut state, &cmd(&["MEMORY", "STATS"]), 0) {
+            Array(fields) => assert_eq!(fields[0], RESP::from("total.allocated")),
+            other => panic!("unexpected {:?}", other),
+        }
+        let clients = engine.handle_request(&mut state, &cmd(&["info", "clients"]), 0);
+        assert_eq!(
+            clients,
+            BulkString(Bytes::from_static(b"# Clients\r\nconnected_clients:0\r\n"))
+        );
+    }
+
+    // the engine reads the time from the clock when processing a request
+    fn request(engine: &mut RedisEngine, args: &[&str]) -> RESP {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let req = ClientReq::Single(cmd(args));
+        engine.process_batch(&mut vec![(0, req, ConnectionState::new(0), sender)]);
+        match receiver.try_recv() {
+            Ok(EngineResponse {
+                resp: ClientReq::Single(resp),
+                ..
+            }) => resp,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    // runs the requests against an engine recording them to a temporary file, the entries read
+    // back from it
+    async fn recorded(
+        name: &str,
+        clock: &ManualClock,
+        run: impl FnOnce(&mut RedisEngine),
+    ) -> ResultT<Vec<(u64, Vec<RESP>)>> {
+        let path = std::env::temp_dir().join(format!("rdis-{}-{}.resp", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        let (recorder, handle) = Recorder::open(path).await?;
+        engine.set_recorder(recorder);
+        run(&mut engine);
+        drop(engine);
+        handle.await?;
+
+        let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+        let mut entries = Vec::new();
+        while let Some(entry) = read_entry(&mut reader).await? {
+            entries.push(entry);
+        }
+        tokio::fs::remove_file(path).await?;
+        Ok(entries)
+    }
+
+    #[test]
+    pub fn test_expiration_with_manual_clock() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        engine.data.set(
+            Bytes::from_static(b"k"),
+            Bytes::from_static(b"v"),
+            Some(1_500),
+        );
+        assert_eq!(
+            request(&mut engine, &["GET", "k"]),
+            BulkString(Bytes::from_static(b"v"))
+        );
+        clock.advance(500);
+        assert_eq!(
+            request(&mut engine, &["GET", "k"]),
+            BulkString(Bytes::from_static(b"v"))
+        );
+        clock.advance(1);
+        assert_eq!(request(&mut engine, &["GET", "k"]), Null);
+    }
+
+    #[test]
+    pub fn test_expiration_stats() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        for k in ["a", "b", "c", "d"] {
+            request(&mut engine, &["SET", k, "v", "PX", "100"]);
+        }
+        request(&mut engine, &["SET", "e", "v", "PX", "1000"]);
+        request(&mut engine, &["GET", "a"]);
+        request(&mut engine, &["GET", "missing"]);
+        clock.advance(500);
+        let section = |engine: &mut RedisEngine, name| match request(engine, &["INFO", name]) {
+            BulkString(s) => String::from_utf8(s.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        let stats = |engine: &mut RedisEngine| section(engine, "stats");
+        let keyspace = |engine: &mut RedisEngine| section(engine, "keyspace");
+        // expired keys stay until the next command touching the keyspace, lowering avg_ttl
+        assert!(keyspace(&mut engine).contains("db0:keys=5,expires=5,avg_ttl=0\r\n"));
+        let info = stats(&mut engine);
+        assert!(info.contains("expired_keys:0\r\nexpired_stale_perc:80.00\r\n"));
+        assert!(info.contains("evicted_keys:0\r\n"));
+        assert!(info.contains("keyspace_hits:1\r\nkeyspace_misses:1\r\n"));
+        assert_eq!(request(&mut engine, &["GET", "a"]), Null);
+        let info = stats(&mut engine);
+        assert!(info.contains("expired_keys:4\r\nexpired_stale_perc:0.00\r\n"));
+        assert!(info.contains("keyspace_hits:1\r\nkeyspace_misses:2\r\n"));
+        assert!(keyspace(&mut engine).contains("db0:keys=1,expires=1,avg_ttl=500\r\n"));
+    }
+
+    #[test]
+    pub fn test_command_time_budget() {
+        let config = Config {
+            command_time_budget: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let mut engine = engine(&config);
+        let mut state = ConnectionState::new(0);
+        for i in 0..2500 {
+            engine
+                .data
+                .r_push(
+                    Bytes::from_static(b"l"),
+                    Bytes::from(i.to_string()),
+                    None,
+                    0,
+                )
+                .unwrap();
+        }
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        // the range is copied in chunks
+        match run(&["LRANGE", "l", "-2000", "-1"]) {
+            Array(values) => {
+                assert_eq!(values.len(), 2000);
+                assert_eq!(values[0], RESP::from("500"));
+                assert_eq!(values[1999], RESP::from("2499"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(run(&["LRANGE", "l", "5", "1"]), Array(Vec::new()));
+        // a negative stop past the head selects nothing
+        assert_eq!(run(&["LRANGE", "l", "0", "-3000"]), Array(Vec::new()));
+        assert_eq!(run(&["LRANGE", "missing", "0", "-1"]), Array(Vec::new()));
+        run(&["SET", "s", "v"]);
+        assert_eq!(
+            run(&["LRANGE", "s", "0", "-1"]),
+            RdisError::WrongType.to_resp()
+        );
+
+        engine.command_time_budget = Some(Duration::ZERO);
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["LRANGE", "l", "0", "-1"]), 0),
+            RESP::Error(
+                "TIMEOUT".into(),
+                "command aborted after exceeding its time budget".into()
+            )
+        );
+    }
+
+    // the expired key is deleted before INCR creates it again
+    #[tokio::test]
+    pub async fn test_expirations_are_recorded() -> ResultT<()> {
+        let clock = ManualClock::new(1_000);
+        let entries = recorded("expired", &clock, |engine| {
+            request(engine, &["SET", "k", "v", "PX", "10"]);
+            clock.advance(20);
+            request(engine, &["INCR", "k"]);
+        })
+        .await?;
+        let entry = |t, args: &[&str]| (t, args.iter().map(|a| RESP::from(*a)).collect());
+        assert_eq!(
+            entries,
+            vec![
+                entry(1_000, &["SET", "k", "v", "PX", "10"]),
+                entry(1_020, &["DEL", "k"]),
+                entry(1_020, &["INCR", "k"]),
+            ]
+        );
+        Ok(())
+    }
+
+    // a replay removes the members popped at random
+    #[tokio::test]
+    pub async fn test_spop_is_recorded_as_srem() -> ResultT<()> {
+        let entries = recorded("spop", &ManualClock::new(1_000), |engine| {
+            request(engine, &["SADD", "s", "a"]);
+            request(engine, &["SPOP", "s"]);
+            request(engine, &["SPOP", "s"]);
+            request(engine, &["SADD", "s", "b"]);
+            request(engine, &["SPOP", "s", "2"]);
+            request(engine, &["SPOP", "s", "2"]);
+        })
+        .await?;
+        let entry = |args: &[&str]| (1_000, args.iter().map(|a| RESP::from(*a)).collect());
+        assert_eq!(
+            entries,
+            vec![
+                entry(&["SADD", "s", "a"]),
+                entry(&["SREM", "s", "a"]),
+                entry(&["SADD", "s", "b"]),
+                entry(&["SREM", "s", "b"]),
+            ]
+        );
+        Ok(())
+    }
+
+    struct Collect(Arc<std::sync::Mutex<Vec<Mutation>>>);
+
+    impl WriteSink for Collect {
+        fn write<'a>(&'a mut self, batch: &'a [Mutation]) -> SinkFuture<'a> {
+            self.0.lock().unwrap().extend_from_slice(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_write_behind() -> ResultT<()> {
+        let mutations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = engine(&Config::default());
+        let clock = ManualClock::new(1_000);
+        engine.set_clock(Box::new(clock.clone()));
+        let sink = Box::new(Collect(mutations.clone()));
+        let (write_behind, handle) = WriteBehind::spawn(sink, WriteBehindOptions::default());
+        engine.set_write_behind(write_behind);
+        request(&mut engine, &["SET", "a", "1"]);
+        request(&mut engine, &["GET", "a"]);
+        request(&mut engine, &["INCR", "a"]);
+        // nothing popped
+        request(&mut engine, &["LPOP", "l"]);
+        request(&mut engine, &["RPUSH", "l", "x"]);
+        request(&mut engine, &["MSET", "a", "3", "b", "4"]);
+        request(&mut engine, &["DEL", "a", "l", "c"]);
+        request(&mut engine, &["SET", "e", "5", "PX", "10"]);
+        clock.advance(20);
+        request(&mut engine, &["INCR", "e"]);
+        drop(engine);
+        handle.await?;
+
+        let mutation = |k: &str, command: &str, old, new| Mutation {
+            key: Bytes::copy_from_slice(k.as_bytes()),
+            command: command.to_owned(),
+            old,
+            new,
+        };
+        let string = |v: &str| Some(MirroredValue::String(Bytes::copy_from_slice(v.as_bytes())));
+        // only the type of a list is mirrored
+        let list = Some(MirroredValue::Unsupported("list"));
+        assert_eq!(
+            *mutations.lock().unwrap(),
+            vec![
+                mutation("a", "set", None, string("1")),
+                mutation("a", "incr", string("1"), string("2")),
+                mutation("l", "rpush", None, list.clone()),
+                mutation("a", "mset", string("2"), string("3")),
+                mutation("b", "mset", None, string("4")),
+                mutation("a", "del", string("3"), None),
+                mutation("l", "del", list, None),
+                mutation("c", "del", None, None),
+                mutation("e", "set", None, string("5")),
+                // the expired key goes ahead of the command finding it missing
+                mutation("e", "del", None, None),
+                mutation("e", "incr", None, string("1")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_streams() -> ResultT<()> {
+        let entries = recorded("xadd", &ManualClock::new(1_000), |engine| {
+            let id = |id: &str| RESP::from(id);
+            assert_eq!(request(engine, &["XADD", "x", "*", "f", "1"]), id("1000-0"));
+            assert_eq!(
+                request(engine, &["XADD", "x", "*", "f", "2", "g", "3"]),
+                id("1000-1")
+            );
+            assert!(matches!(
+                request(engine, &["XADD", "x", "999-*", "f", "4"]),
+                Error(_, _)
+            ));
+            assert!(matches!(
+                request(engine, &["XADD", "y", "0", "f", "4"]),
+                Error(_, _)
+            ));
+            assert_eq!(request(engine, &["XLEN", "y"]), Integer(0));
+
+            let entry = |id: &str, fields: &[&str]| {
+                Array(vec![RESP::from(id), RESP::from(fields.to_vec())])
+            };
+            assert_eq!(
+                request(engine, &["XRANGE", "x", "-", "+"]),
+                Array(vec![
+                    entry("1000-0", &["f", "1"]),
+                    entry("1000-1", &["f", "2", "g", "3"])
+                ])
+            );
+            assert_eq!(
+                request(engine, &["XREVRANGE", "x", "+", "-", "COUNT", "1"]),
+                Array(vec![entry("1000-1", &["f", "2", "g", "3"])])
+            );
+            assert_eq!(
+                request(engine, &["XREAD", "STREAMS", "x", "y", "1000-0", "0"]),
+                Array(vec![Array(vec![
+                    RESP::from("x"),
+                    Array(vec![entry("1000-1", &["f", "2", "g", "3"])])
+                ])])
+            );
+            assert_eq!(request(engine, &["XREAD", "STREAMS", "x", "$"]), Null);
+            assert!(matches!(
+                request(engine, &["XREAD", "BLOCK", "0", "STREAMS", "x", "$"]),
+                Error(_, _)
+            ));
+        })
+        .await?;
+
+        // the generated ids are recorded
+        let entry = |args: &[&str]| (1_000, args.iter().map(|a| RESP::from(*a)).collect());
+        assert_eq!(
+            entries,
+            vec![
+                entry(&["XADD", "x", "1000-0", "f", "1"]),
+                entry(&["XADD", "x", "1000-1", "f", "2", "g", "3"]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_audit_log() -> ResultT<()> {
+        let path =
+            std::env::temp_dir().join(format!("rdis-audit-engine-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut engine = engine(&Config::default());
+        let (audit, handle) = AuditLog::open(path, b"key").await?;
+        engine.set_audit_log(audit);
+        request(&mut engine, &["CLIENT", "KILL", "127.0.0.1:1"]);
+        request(&mut engine, &["client", "kill", "ID", "12"]);
+        request(&mut engine, &["CLIENT", "LIST"]);
+        request(&mut engine, &["SET", "k", "v"]);
+        drop(engine);
+        handle.await?;
+
+        let content = std::fs::read_to_string(path)?;
+        assert_eq!(verify(&content, b"key"), Ok(2));
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].contains(
+            "client=0 addr= name=\"\" command=\"CLIENT\" args=\"KILL 127.0.0.1:1\" outcome=ERR "
+        ));
+        assert!(lines[1].contains("command=\"CLIENT\" args=\"kill ID 12\" outcome=ok "));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_multi() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        let queued = || SimpleString("QUEUED".into());
+        assert_eq!(run(&["MULTI"]), RedisEngine::ok());
+        assert!(matches!(run(&["MULTI"]), Error(_, msg) if msg.contains("not allowed")));
+        assert!(matches!(run(&["EXEC"]), Error(kind, _) if kind == "EXECABORT"));
+        run(&["MULTI"]);
+        assert_eq!(run(&["SET", "k", "v"]), queued());
+        assert_eq!(run(&["GET", "k"]), queued());
+        assert_eq!(
+            run(&["EXEC"]),
+            Array(vec![RedisEngine::ok(), RESP::from("v")])
+        );
+        assert!(matches!(run(&["EXEC"]), Error(_, msg) if msg == "EXEC without MULTI"));
+
+        // refused commands discard the transaction, errors of queued commands don't
+        run(&["MULTI"]);
+        assert_eq!(run(&["SET", "k", "w"]), queued());
+        // refused as unknown until pub/sub is implemented
+        assert!(matches!(run(&["SUBSCRIBE", "c"]), Error(_, _)));
+        assert!(matches!(run(&["EXEC"]), Error(kind, _) if kind == "EXECABORT"));
+        run(&["MULTI"]);
+        assert_eq!(run(&["SET", "k", "w"]), queued());
+        assert!(matches!(run(&["GET"]), Error(_, msg) if msg.contains("'get'")));
+        assert!(matches!(run(&["LRANGE", "k", "0"]), Error(_, _)));
+        assert!(matches!(run(&["EXEC"]), Error(kind, _) if kind == "EXECABORT"));
+        assert_eq!(run(&["GET", "k"]), RESP::from("v"));
+        run(&["MULTI"]);
+        run(&["SADD", "k", "a"]);
+        run(&["SET", "k", "x"]);
+        assert!(matches!(&run(&["EXEC"]), Array(replies) if matches!(replies[0], Error(_, _))));
+        assert_eq!(run(&["GET", "k"]), RESP::from("x"));
+
+        run(&["MULTI"]);
+        run(&["DEL", "k"]);
+        assert_eq!(run(&["DISCARD"]), RedisEngine::ok());
+        assert_eq!(run(&["GET", "k"]), RESP::from("x"));
+        assert!(matches!(run(&["DISCARD"]), Error(_, _)));
+    }
+
+    #[test]
+    pub fn test_pipeline_slices() {
+        let config = Config {
+            pipeline_slice: 2,
+            ..Config::default()
+        };
+        let mut engine = engine(&config);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let incrs = ClientReq::Pipeline(vec![cmd(&["INCR", "k"]); 5]);
+        let get = ClientReq::Single(cmd(&["GET", "k"]));
+        engine.process_batch(&mut vec![
+            (0, incrs, ConnectionState::new(1), sender.clone()),
+            (0, get, ConnectionState::new(2), sender.clone()),
+        ]);
+        // the GET runs after the first slice of the pipeline
+        let response = receiver.try_recv().unwrap();
+        assert_eq!(response.state.client_id, 2);
+        assert_eq!(response.resp, ClientReq::Single(RESP::from("2")));
+        assert!(receiver.try_recv().is_err());
+        engine.resume_parked();
+        assert!(receiver.try_recv().is_err());
+        engine.resume_parked();
+        let response = receiver.try_recv().unwrap();
+        let expected = (1..=5).map(Integer).collect();
+        assert_eq!(response.resp, ClientReq::Pipeline(expected));
+        assert!(engine.parked.is_empty());
+
+        // the rest of the pipeline of a dropped client doesn't run
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let incrs = ClientReq::Pipeline(vec![cmd(&["INCR", "k"]); 5]);
+        engine.process_batch(&mut vec![(0, incrs, ConnectionState::new(1), sender)]);
+        drop(receiver);
+        engine.resume_parked();
+        assert!(engine.parked.is_empty());
+        assert_eq!(request(&mut engine, &["GET", "k"]), RESP::from("7"));
+    }
+
+    // a batch reads the clock once, its requests see the same time
+    #[test]
+    pub fn test_batch_shares_timestamp() {
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(SteppingClock::new(1_000)));
+        // responses are tagged with the sequence number of their request
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let set = ClientReq::Single(cmd(&["SET", "k", "v", "PX", "1"]));
+        let get = ClientReq::Single(cmd(&["GET", "k"]));
+        let mut batch = vec![
+            (7, set, ConnectionState::new(1), sender.clone()),
+            (8, get, ConnectionState::new(2), sender),
+        ];
+        engine.process_batch(&mut batch);
+        assert!(batch.is_empty());
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(EngineResponse {
+                seq: 7,
+                resp: ClientReq::Single(SimpleString(_)),
+                ..
+            })
+        ));
+        let response = receiver.try_recv().unwrap();
+        assert_eq!(response.seq, 8);
+        assert_eq!(
+            response.resp,
+            ClientReq::Single(BulkString(Bytes::from_static(b"v")))
+        );
+        assert_eq!(response.state.client_id, 2);
+        // every following batch runs one millisecond later
+        request(&mut engine, &["GET", "k"]);
+        assert_eq!(request(&mut engine, &["GET", "k"]), Null);
+    }
+
+    #[test]
+    pub fn test_client_name() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(7);
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["CLIENT", "GETNAME"]), 0),
+            Null
+        );
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["CLIENT", "SETNAME", "worker"]), 0),
+            RedisEngine::ok()
+        );
+        assert_eq!(state.name.as_deref(), Some("worker"));
+        assert_eq!(
+            engine.handle_request(&mut state, &cmd(&["client", "getname"]), 0),
+            BulkString(Bytes::from_static(b"worker"))
+        );
+        assert!(matches!(
+            engine.handle_request(&mut state, &cmd(&["CLIENT", "SETNAME", "a b"]), 0),
+            Error(_, _)
+        ));
+        engine.handle_request(&mut state, &cmd(&["CLIENT", "SETNAME", ""]), 0);
+        assert_eq!(state.name, None);
+    }
+
+    struct Tagged;
+
+    impl CustomCommand for Tagged {
+        fn name(&self) -> &str {
+            "tagged"
+        }
+
+        fn execute(&mut self, _ctx: &mut CommandContext, _args: &[RawValue]) -> RESP {
+            Integer(1).with_attributes(vec![(RESP::from("hint"), Integer(2))])
+        }
+    }
+
+    #[test]
+    pub fn test_attributes_dropped_for_resp2() -> ResultT<()> {
+        let mut engine = engine(&Config::default());
+        engine.register_command(Box::new(Tagged))?;
+        let mut state = ConnectionState::new(0);
+        assert_eq!(
+            engine.execute(&mut state, &cmd(&["TAGGED"]), 1, 0),
+            Integer(1)
+        );
+        state.resp_version = 3;
+        assert_eq!(
+            engine.execute(&mut state, &cmd(&["TAGGED"]), 1, 0),
+            Integer(1).with_attributes(vec![(RESP::from("hint"), Integer(2))])
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_client_trace() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(7);
+        let mut run = |state: &mut ConnectionState, args: &[&str]| {
+            engine.handle_request(state, &cmd(args), 0)
+        };
+        assert_eq!(
+            run(&mut state, &["CLIENT", "TRACE", "on"]),
+            RedisEngine::ok()
+        );
+        assert!(state.wire_trace);
+        assert!(matches!(
+            run(&mut state, &["CLIENT", "TRACE", "maybe"]),
+            Error(_, _)
+        ));
+        assert!(state.wire_trace);
+        assert_eq!(
+            run(&mut state, &["CLIENT", "TRACE", "OFF"]),
+            RedisEngine::ok()
+        );
+        assert!(!state.wire_trace);
+    }
+
+    #[test]
+    pub fn test_counters_and_del() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        assert_eq!(run(&["INCR", "n"]), Integer(1));
+        assert_eq!(run(&["INCRBY", "n", "-11"]), Integer(-10));
+        assert_eq!(run(&["GET", "n"]), BulkString(Bytes::from_static(b"-10")));
+        assert!(matches!(run(&["INCRBY", "n", "x"]), Error(_, _)));
+        run(&["SET", "s", "v"]);
+        run(&["RPUSH", "l", "a"]);
+        assert_eq!(run(&["EXISTS", "n", "s", "l", "n", "missing"]), Integer(4));
+        assert_eq!(run(&["DEL", "n", "s", "missing"]), Integer(2));
+        assert_eq!(run(&["GET", "n"]), Null);
+        assert_eq!(run(&["UNLINK", "l", "l"]), Integer(1));
+        assert_eq!(run(&["EXISTS", "n", "l"]), Integer(0));
+        assert_eq!(run(&["TYPE", "l"]), SimpleString("none".into()));
+        for args in [&["DEL"][..], &["UNLINK"], &["EXISTS"]].iter() {
+            assert!(matches!(run(args), Error(_, _)), "{:?}", args);
+        }
+    }
+
+    #[test]
+    pub fn test_locks() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        let mut run = |args: &[&str]| request(&mut engine, args);
+        assert_eq!(run(&["LOCK", "l", "a", "100"]), RedisEngine::ok());
+        assert_eq!(run(&["LOCK", "l", "b", "100"]), Null);
+        assert_eq!(run(&["PTTL", "l"]), Integer(100));
+        clock.advance(101);
+        // the lock of a expired, b takes it and a can no longer release it
+        assert_eq!(run(&["LOCK", "l", "b", "100"]), RedisEngine::ok());
+        assert_eq!(run(&["UNLOCK", "l", "a"]), Integer(0));
+        assert_eq!(run(&["UNLOCK", "l", "b"]), Integer(1));
+        assert_eq!(run(&["UNLOCK", "l", "b"]), Integer(0));
+        run(&["RPUSH", "list", "a"]);
+        assert_eq!(run(&["LOCK", "list", "a", "100"]), Null);
+        for args in [
+            &["UNLOCK", "list", "a"][..],
+            &["LOCK", "l", "a", "0"],
+            &["LOCK", "l", "a", "x"],
+            &["LOCK", "l", "a"],
+        ]
+        .iter()
+        {
+            assert!(matches!(run(args), Error(_, _)), "{:?}", args);
+        }
+    }
+
+    #[test]
+    pub fn test_rate_limit() {
+        let clock = ManualClock::new(10_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        let reply = |allowed, remaining, retry_after| {
+            Array(vec![
+                Integer(allowed),
+                Integer(remaining),
+                Integer(retry_after),
+            ])
+        };
+        let limit = &["RATELIMIT", "r", "3", "1000"];
+        assert_eq!(request(&mut engine, limit), reply(1, 2, 0));
+        assert_eq!(
+            request(&mut engine, &["RATELIMIT", "r", "3", "1000", "COST", "2"]),
+            reply(1, 0, 0)
+        );
+        clock.advance(500);
+        assert_eq!(request(&mut engine, limit), reply(0, 0, 500));
+        // the 3 requests of the previous window weigh 2.25 at 250
+        clock.advance(750);
+        assert_eq!(request(&mut engine, limit), reply(0, 0, 84));
+        clock.advance(84);
+        assert_eq!(request(&mut engine, limit), reply(1, 0, 0));
+        assert_eq!(request(&mut engine, &["HLEN", "r"]), Integer(2));
+        assert_eq!(request(&mut engine, &["PTTL", "r"]), Integer(1666));
+        // the key expires once both windows are over
+        clock.advance(1667);
+        assert_eq!(request(&mut engine, &["HLEN", "r"]), Integer(0));
+        for args in [
+            &["RATELIMIT", "r", "0", "1000"][..],
+            &["RATELIMIT", "r", "3", "x"],
+            &["RATELIMIT", "r", "3", "1000", "COST"],
+            &["RATELIMIT", "r", "3", "1000", "COST", "-1"],
+            &["RATELIMIT", "r", "3"],
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(request(&mut engine, args), Error(_, _)),
+                "{:?}",
+                args
+            );
+        }
+        request(&mut engine, &["SET", "s", "v"]);
+        assert!(matches!(
+            request(&mut engine, &["RATELIMIT", "s", "3", "1000"]),
+            Error(_, _)
+        ));
+    }
+
+    #[test]
+    pub fn test_expire_and_ttl() {
+        let clock = ManualClock::new(10_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        let mut run = |args: &[&str]| request(&mut engine, args);
+        assert_eq!(run(&["TTL", "k"]), Integer(-2));
+        assert_eq!(run(&["EXPIRE", "k", "10"]), Integer(0));
+        run(&["SET", "k", "v"]);
+        assert_eq!(run(&["TTL", "k"]), Integer(-1));
+        assert_eq!(run(&["PEXPIRETIME", "k"]), Integer(-1));
+        assert_eq!(run(&["EXPIRE", "k", "10", "XX"]), Integer(0));
+        assert_eq!(run(&["EXPIRE", "k", "10", "GT"]), Integer(0));
+        assert_eq!(run(&["PEXPIRE", "k", "1499", "NX"]), Integer(1));
+        assert_eq!(run(&["PTTL", "k"]), Integer(1_499));
+        // seconds are rounded
+        assert_eq!(run(&["TTL", "k"]), Integer(1));
+        assert_eq!(run(&["EXPIRETIME", "k"]), Integer(11));
+        assert_eq!(run(&["EXPIRE", "k", "1", "LT"]), Integer(1));
+        assert_eq!(run(&["EXPIRE", "k", "2", "lt"]), Integer(0));
+        assert_eq!(run(&["EXPIRE", "k", "2", "XX", "GT"]), Integer(1));
+        assert_eq!(run(&["PEXPIREAT", "k", "15000"]), Integer(1));
+        assert_eq!(run(&["EXPIREAT", "k", "20"]), Integer(1));
+        assert_eq!(run(&["PTTL", "k"]), Integer(10_000));
+        assert_eq!(run(&["PERSIST", "k"]), Integer(1));
+        assert_eq!(run(&["PERSIST", "k"]), Integer(0));
+        assert_eq!(run(&["TTL", "k"]), Integer(-1));
+        for args in [
+            &["EXPIRE", "k", "x"][..],
+            &["EXPIRE", "k", "1", "NX", "XX"],
+            &["EXPIRE", "k", "1", "GT", "LT"],
+            &["EXPIRE", "k", "1", "KEEP"],
+            &["EXPIRE", "k", "9223372036854775807"],
+            &["TTL"],
+        ]
+        .iter()
+        {
+            assert!(matches!(run(args), Error(_, _)), "{:?}", args);
+        }
+        // a time in the past deletes the key
+        assert_eq!(run(&["EXPIRE", "k", "-1"]), Integer(1));
+        assert_eq!(run(&["GET", "k"]), Null);
+        run(&["SET", "k", "v"]);
+        assert_eq!(run(&["EXPIRE", "k", "1"]), Integer(1));
+        clock.advance(1_001);
+        assert_eq!(run(&["TTL", "k"]), Integer(-2));
+    }
+
+    #[test]
+    pub fn test_set_options() {
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(ManualClock::new(1_000)));
+        let mut run = |args: &[&str]| request(&mut engine, args);
+        let v = |v: &str| BulkString(Bytes::copy_from_slice(v.as_bytes()));
+        assert_eq!(run(&["SET", "k", "a", "XX"]), Null);
+        assert_eq!(
+            run(&["SET", "k", "a", "nx", "PX", "100"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(run(&["SET", "k", "b", "NX"]), Null);
+        assert_eq!(run(&["SET", "k", "b", "XX", "KEEPTTL"]), RedisEngine::ok());
+        assert_eq!(run(&["PTTL", "k"]), Integer(100));
+        assert_eq!(run(&["SET", "k", "c", "GET"]), v("b"));
+        assert_eq!(run(&["PTTL", "k"]), Integer(-1));
+        // with GET the old value is replied even when the key is not set
+        assert_eq!(run(&["SET", "k", "d", "NX", "GET"]), v("c"));
+        assert_eq!(run(&["SET", "n", "d", "XX", "GET"]), Null);
+        assert_eq!(run(&["SET", "n", "d", "GET", "EX", "1"]), Null);
+        assert_eq!(run(&["GET", "k"]), v("c"));
+        assert_eq!(run(&["GET", "n"]), v("d"));
+        run(&["RPUSH", "l", "a"]);
+        assert!(matches!(run(&["SET", "l", "a", "GET"]), Error(_, _)));
+        assert_eq!(run(&["SET", "l", "a", "NX"]), Null);
+        assert_eq!(run(&["SET", "l", "a"]), RedisEngine::ok());
+        for args in [
+            &["SET", "k", "v", "NX", "XX"][..],
+            &["SET", "k", "v", "EX", "1", "PX", "1"],
+            &["SET", "k", "v", "EX", "1", "KEEPTTL"],
+            &["SET", "k", "v", "KEEPTTL", "PXAT", "1"],
+            &["SET", "k", "v", "GET", "EX"],
+            &["SET", "k", "v", "EX", "-1"],
+        ]
+        .iter()
+        {
+            assert!(matches!(run(args), Error(_, _)), "{:?}", args);
+        }
+    }
+
+    // a SET not setting the key is not recorded
+    #[tokio::test]
+    pub async fn test_set_options_are_recorded() -> ResultT<()> {
+        let entries = recorded("set", &ManualClock::new(1_000), |engine| {
+            request(engine, &["SET", "k", "a", "GET"]);
+            request(engine, &["SET", "k", "b", "NX", "GET"]);
+            request(engine, &["SET", "k", "c", "XX", "GET"]);
+            request(engine, &["SET", "j", "c", "XX"]);
+            request(engine, &["SET", "j", "d", "NX"]);
+        })
+        .await?;
+        let entry = |args: &[&str]| (1_000, args.iter().map(|a| RESP::from(*a)).collect());
+        assert_eq!(
+            entries,
+            vec![
+                entry(&["SET", "k", "a", "GET"]),
+                entry(&["SET", "k", "c", "XX", "GET"]),
+                entry(&["SET", "j", "d", "NX"]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_set_expiration() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = engine(&Config::default());
+        engine.set_clock(Box::new(clock.clone()));
+        assert_eq!(
+            request(&mut engine, &["SET", "a", "v", "EX", "2"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(
+            request(&mut engine, &["SET", "b", "v", "px", "500"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(
+            request(&mut engine, &["SET", "c", "v", "PXAT", "1200"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(
+            request(&mut engine, &["SET", "d", "v", "EXAT", "2"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(engine.data.expires_count(), 4);
+        for args in [
+            &["SET", "e", "v", "EX", "0"][..],
+            &["SET", "e", "v", "EX", "x"],
+            &["SET", "e", "v", "KEEP", "1"],
+            &["SET", "e", "v", "EX"],
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(request(&mut engine, args), Error(_, _)),
+                "{:?}",
+                args
+            );
+        }
+        clock.advance(500);
+        assert_eq!(request(&mut engine, &["GET", "c"]), Null);
+        assert_eq!(
+            request(&mut engine, &["GET", "b"]),
+            BulkString(Bytes::from_static(b"v"))
+        );
+        clock.advance(1);
+        assert_eq!(request(&mut engine, &["GET", "b"]), Null);
+        clock.advance(1_500);
+        assert_eq!(request(&mut engine, &["GET", "a"]), Null);
+        assert_eq!(request(&mut engine, &["GET", "d"]), Null);
+        assert_eq!(engine.data.keys_count(), 0);
+    }
+
+    #[test]
+    pub fn test_benchmark_commands() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        let bulk = |s: &str| BulkString(Bytes::copy_from_slice(s.as_bytes()));
+        assert_eq!(run(&["LPUSH", "l", "b", "a"]), Integer(2));
+        assert_eq!(run(&["RPUSH", "l", "c"]), Integer(3));
+        assert_eq!(
+            run(&["LRANGE", "l", "0", "-2"]),
+            Array(vec![bulk("a"), bulk("b")])
+        );
+        assert!(matches!(run(&["LRANGE", "l", "x", "1"]), Error(_, _)));
+        assert_eq!(run(&["MSET", "k1", "v1", "k2", "v2"]), RedisEngine::ok());
+        assert_eq!(run(&["GET", "k2"]), bulk("v2"));
+        assert!(matches!(run(&["MSET", "k1"]), Error(_, _)));
+        run(&["SADD", "s", "a"]);
+        assert_eq!(
+            run(&["MGET", "k1", "missing", "s", "k2"]),
+            Array(vec![bulk("v1"), Null, Null, bulk("v2")])
+        );
+        assert!(matches!(run(&["MGET"]), Error(_, _)));
+        assert_eq!(
+            run(&["CONFIG", "GET", "save", "APPENDONLY", "unknown"]),
+            Array(vec![bulk("save"), bulk(""), bulk("appendonly"), bulk("no")])
+        );
+        assert!(matches!(run(&["CONFIG", "GET", "*"]), Array(params) if params.len() > 4));
+        assert_eq!(
+            run(&["CONFIG", "GET", "APPEND*"]),
+            Array(vec![bulk("appendonly"), bulk("no")])
+        );
+    }
+
+    #[test]
+    pub fn test_scan() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        for i in 0..25 {
+            let key = format!("k{}", i);
+            engine.handle_request(&mut state, &cmd(&["SET", &key, "v"]), 0);
+        }
+        engine.handle_request(&mut state, &cmd(&["RPUSH", "l", "v"]), 0);
+        let mut scan = |args: &[&str]| match engine.handle_request(&mut state, &cmd(args), 0) {
+            Array(reply) => match reply.as_slice() {
+                [BulkString(cursor), Array(keys)] => {
+                    (String::from_utf8_lossy(cursor).into_owned(), keys.len())
+                }
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        };
+        let (mut cursor, mut total) = ("0".to_owned(), 0);
+        loop {
+            let (next, len) = scan(&["SCAN", &cursor, "COUNT", "4"]);
+            assert!(len <= 4);
+            total += len;
+            cursor = next;
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(total, 26);
+        assert_eq!(
+            scan(&["SCAN", "0", "count", "100", "TYPE", "list"]),
+            ("0".to_owned(), 1)
+        );
+        assert_eq!(
+            scan(&["SCAN", "0", "COUNT", "100", "MATCH", "k1*"]),
+            ("0".to_owned(), 11)
+        );
+        assert_eq!(
+            scan(&["SCAN", "0", "MATCH", "*", "COUNT", "100"]),
+            ("0".to_owned(), 26)
+        );
+        let mut error = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        assert!(matches!(error(&["SCAN", "x"]), Error(_, _)));
+        assert!(matches!(error(&["SCAN", "0", "COUNT", "0"]), Error(_, _)));
+        assert!(matches!(error(&["SCAN", "0", "MATCH"]), Error(_, _)));
+        assert!(matches!(error(&["SCAN", "0", "COUNT"]), Error(_, _)));
+        assert!(matches!(error(&["SCAN", "0", "NOVALUES"]), Error(_, _)));
+    }
+
+    #[test]
+    pub fn test_scan_members() {
+        let mut engine = engine(&Config::default());
+        request(&mut engine, &["HSET", "h", "a", "1", "b", "2", "c", "3"]);
+        request(&mut engine, &["SADD", "s", "a", "b", "c"]);
+        request(&mut engine, &["ZADD", "z", "1", "a", "2.5", "b"]);
+        request(&mut engine, &["SET", "k", "v"]);
+        let mut scan = |args: &[&str]| match request(&mut engine, args) {
+            Array(reply) => match reply.as_slice() {
+                [BulkString(cursor), Array(items)] => {
+                    assert_eq!(cursor.as_ref(), b"0");
+                    let mut items: Vec<String> = items
+                        .iter()
+                        .map(|i| match i {
+                            BulkString(i) => String::from_utf8_lossy(i).into_owned(),
+                            other => panic!("unexpected {:?}", other),
+                        })
+                        .collect();
+                    items.sort();
+                    items
+                }
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(scan(&["HSCAN", "h", "0"]), ["1", "2", "3", "a", "b", "c"]);
+        assert_eq!(
+            scan(&["HSCAN", "h", "0", "MATCH", "[ab]"]),
+            ["1", "2", "a", "b"]
+        );
+        assert_eq!(scan(&["HSCAN", "h", "0", "novalues"]), ["a", "b", "c"]);
+        assert_eq!(scan(&["SSCAN", "s", "0", "COUNT", "100"]), ["a", "b", "c"]);
+        assert_eq!(scan(&["ZSCAN", "z", "0"]), ["1", "2.5", "a", "b"]);
+        assert_eq!(scan(&["ZSCAN", "z", "0", "NOSCORES"]), ["a", "b"]);
+        assert!(scan(&["SSCAN", "missing", "0"]).is_empty());
+        let mut error = |args: &[&str]| request(&mut engine, args);
+        assert!(matches!(error(&["SSCAN", "k", "0"]), Error(kind, _) if kind == "WRONGTYPE"));
+        assert!(matches!(error(&["SSCAN", "s", "x"]), Error(_, _)));
+        assert!(matches!(
+            error(&["SSCAN", "s", "0", "NOVALUES"]),
+            Error(_, _)
+        ));
+        assert!(matches!(
+            error(&["HSCAN", "h", "0", "TYPE", "hash"]),
+            Error(_, _)
+        ));
+        assert!(matches!(
+            error(&["ZSCAN", "z", "0", "COUNT", "0"]),
+            Error(_, _)
+        ));
+    }
+
+    #[test]
+    pub fn test_keys() {
+        for data in [RedisData::new(), RedisData::with_prefix_index()] {
+            let clock = ManualClock::new(1_000);
+            let mut engine = engine(&Config::default());
+            engine.data = Box::new(data);
+            engine.set_clock(Box::new(clock.clone()));
+            for k in [
+                "user:1",
+                "user:2",
+                "user:10",
+                "users",
+                "session:1",
+                "a*b",
+                "",
+            ] {
+                request(&mut engine, &["SET", k, "v"]);
+            }
+            request(&mut engine, &["SET", "user:3", "v", "PX", "10"]);
+            request(&mut engine, &["RPUSH", "user:4", "v"]);
+            let mut keys = |pattern: &str| match request(&mut engine, &["KEYS", pattern]) {
+                Array(keys) => {
+                    let mut keys: Vec<String> = keys
+                        .into_iter()
+                        .map(|k| match k {
+                            BulkString(k) => String::from_utf8_lossy(&k).into_owned(),
+                            other => panic!("unexpected {:?}", other),
+                        })
+                        .collect();
+                    keys.sort();
+                    keys
+                }
+                other => panic!("unexpected {:?}", other),
+            };
+            assert_eq!(keys("user:?"), ["user:1", "user:2", "user:3", "user:4"]);
+            // expired keys are left out
+            clock.advance(11);
+            assert_eq!(keys("user:?"), ["user:1", "user:2", "user:4"]);
+            assert_eq!(keys("user:[^1]*"), ["user:2", "user:4"]);
+            assert_eq!(keys("a\\*b"), ["a*b"]);
+            assert_eq!(keys("nope*"), Vec::<String>::new());
+            // * matches the empty key too
+            assert_eq!(keys("*").len(), 8);
+        }
+    }
+
+    #[test]
+    pub fn test_cluster_slots() {
+        let config = Config {
+            cluster_enabled: true,
+            ..Config::default()
+        };
+        let mut engine = engine(&config);
+        let mut state = ConnectionState::new(0);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        assert_eq!(run(&["CLUSTER", "KEYSLOT", "somekey"]), Integer(11058));
+        assert_eq!(
+            run(&["MSET", "{u1}.a", "1", "{u1}.b", "2"]),
+            RedisEngine::ok()
+        );
+        assert_eq!(run(&["DEL", "{u1}.a", "{u1}.b"]), Integer(2));
+        assert!(matches!(
+            run(&["MSET", "a", "1", "b", "2"]),
+            Error(kind, _) if kind == "CROSSSLOT"
+        ));
+        assert!(matches!(run(&["DEL", "a", "b"]), Error(kind, _) if kind == "CROSSSLOT"));
+        assert_eq!(run(&["GET", "a"]), Null);
+    }
+
+    #[test]
+    pub fn test_key_inspection() {
+        let mut engine = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 0);
+        run(&["SET", "s", "hello"]);
+        run(&["RPUSH", "l", "a", "b"]);
+        assert_eq!(run(&["DBSIZE"]), Integer(2));
+        assert_eq!(run(&["TYPE", "s"]), SimpleString(b"string".to_vec()));
+        assert_eq!(run(&["TYPE", "l"]), SimpleString(b"list".to_vec()));
+        assert_eq!(run(&["TYPE", "missing"]), SimpleString(b"none".to_vec()));
+        assert_eq!(run(&["STRLEN", "s"]), Integer(5));
+        assert_eq!(run(&["LLEN", "l"]), Integer(2));
+        assert_eq!(run(&["LLEN", "missing"]), Integer(0));
+        assert!(matches!(run(&["STRLEN", "l"]), Error(kind, _) if kind == "WRONGTYPE"));
+        assert!(matches!(run(&["MEMORY", "USAGE", "s"]), Integer(m) if m > 5));
+        assert!(matches!(
+            run(&["MEMORY", "USAGE", "s", "SAMPLES", "0"]),
+            Integer(_)
+        ));
+        assert_eq!(run(&["MEMORY", "USAGE", "missing"]), Null);
+        let report = match run(&["DEBUG", "BIGKEYS", "1"]) {
+            BulkString(report) => String::from_utf8_lossy(&report).into_owned(),
+            other => panic!("unexpected {:?}", other),
+        };
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("list l memory="));
+        assert!(lines[1].ends_with(" len=5"));
+        assert!(matches!(run(&["DEBUG", "BIGKEYS", "0"]), Error(_, _)));
+    }
+
+    #[test]
+    pub fn test_hot_keys() {
+        let mut disabled = engine(&Config::default());
+        let mut state = ConnectionState::new(0);
+        assert!(matches!(
+            disabled.handle_request(&mut state, &cmd(&["DEBUG", "HOTKEYS"]), 0),
+            Error(_, _)
+        ));
+        let config = Config {
+            hotkeys_sample_rate: 1,
+            ..Config::default()
+        };
+        let mut engine = engine(&config);
+        let clock = ManualClock::new(1_000);
+        engine.set_clock(Box::new(clock.clone()));
+        for i in 0..100 {
+            engine.handle_request(&mut state, &cmd(&["INCR", "hot"]), 1_000);
+            if i % 4 == 0 {
+                engine.handle_request(&mut state, &cmd(&["MSET", "a", "1", "b", "2"]), 1_000);
+            }
+        }
+        clock.set(6_000);
+        let mut run = |args: &[&str]| engine.handle_request(&mut state, &cmd(args), 6_000);
+        assert_eq!(
+            run(&["DEBUG", "HOTKEYS", "2"]),
+            RESP::from("hot qps=10.00\na qps=2.50\n")
+        );
+        assert!(matches!(run(&["DEBUG", "HOTKEYS", "0"]), Error(_, _)));
+        let info = match run(&["INFO", "hotkeys"]) {
+            BulkString(info) => String::from_utf8_lossy(&info).into_owned(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert!(info.contains("hotkey0:key=hot,qps=10.00\r\n"));
+        assert!(info.contains("hotkey2:key=b,qps=2.50\r\n"));
+    }
+}
@@ -0,0 +1,137 @@
+use super::protocol::RESP;
+use std::error::Error;
+use std::io;
+use thiserror::Error;
+
+// errors of the server and of the embedding api. The variant tells the connection whether the
+// client gets an error reply or the connection is closed, see RdisError::closes_connection.
+#[derive(Debug, Error)]
+pub enum RdisError {
+    // malformed or oversized request, replied with -ERR before closing the connection
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    // operation against a key holding another kind of value
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("value is not an integer or out of range")]
+    NotInteger,
+    #[error("value is not a valid float")]
+    NotFloat,
+    // the lowercase name of the command, subcommands as in redis: 'client|kill'
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    // the command ran longer than command-time-budget and was aborted
+    #[error("command aborted after exceeding its time budget")]
+    Timeout,
+    // the engine loop terminated or dropped the request
+    #[error("Engine error: {0}")]
+    Engine(String),
+    // configuration, startup and everything else
+    #[error(transparent)]
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl RdisError {
+    pub fn closes_connection(&self) -> bool {
+        matches!(
+            self,
+            RdisError::Protocol(_) | RdisError::Io(_) | RdisError::Engine(_)
+        )
+    }
+
+    // inverse of to_resp, for replies received by clients
+    pub fn from_reply(kind: &str, msg: &str) -> RdisError {
+        match kind {
+            "WRONGTYPE" => RdisError::WrongType,
+            "TIMEOUT" => RdisError::Timeout,
+            _ if msg == RdisError::NotInteger.to_string() => RdisError::NotInteger,
+            _ if msg == RdisError::NotFloat.to_string() => RdisError::NotFloat,
+            _ => RdisError::from(format!("{} {}", kind, msg)),
+        }
+    }
+
+    // the error reply sent to the client, redis style
+    pub fn to_resp(&self) -> RESP {
+        let kind = match self {
+            RdisError::WrongType => "WRONGTYPE",
+            RdisError::Timeout => "TIMEOUT",
+            _ => "ERR",
+        };
+        RESP::Error(kind.to_owned(), self.to_string())
+    }
+}
+
+impl From<&str> for RdisError {
+    fn from(msg: &str) -> Self {
+        RdisError::Other(msg.into())
+    }
+}
+
+impl From<String> for RdisError {
+    fn from(msg: String) -> Self {
+        RdisError::Other(msg.into())
+    }
+}
+
+macro_rules! other_from {
+    ($($err:ty),*) => {
+        $(impl From<$err> for RdisError {
+            fn from(err: $err) -> Self {
+                RdisError::Other(Box::new(err))
+            }
+        })*
+    };
+}
+
+other_from!(
+    std::num::ParseIntError,
+    std::num::ParseFloatError,
+    std::str::Utf8Error,
+    std::string::FromUtf8Error,
+    std::net::AddrParseError,
+    tokio::task::JoinError,
+    tokio::sync::broadcast::error::RecvError,
+    log::SetLoggerError
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_to_resp() {
+        assert_eq!(
+            RdisError::WrongType.to_resp(),
+            RESP::Error(
+                "WRONGTYPE".into(),
+                "Operation against a key holding the wrong kind of value".into()
+            )
+        );
+        let protocol = RdisError::Protocol("invalid multibulk length".into());
+        assert_eq!(
+            protocol.to_resp(),
+            RESP::Error(
+                "ERR".into(),
+                "Protocol error: invalid multibulk length".into()
+            )
+        );
+        assert!(protocol.closes_connection());
+        assert!(!RdisError::NotInteger.closes_connection());
+        assert_eq!(
+            RdisError::WrongArity("get".into()).to_resp(),
+            RESP::Error(
+                "ERR".into(),
+                "wrong number of arguments for 'get' command".into()
+            )
+        );
+        assert!(matches!(
+            RdisError::from_reply("TIMEOUT", "aborted"),
+            RdisError::Timeout
+        ));
+        assert_eq!(RdisError::from("boom").to_string(), "boom");
+        let parse: RdisError = "x".parse::<i64>().unwrap_err().into();
+        assert!(!parse.closes_connection());
+    }
+}
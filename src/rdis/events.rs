@@ -0,0 +1,75 @@
+use super::storage::Key;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+// events are dropped for subscribers lagging behind by more than this
+pub const EVENTS_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // command is the lowercase name of the command that modified the key
+    KeyWritten { key: Key, command: String },
+    KeyDeleted { key: Key },
+    KeyExpired { key: Key },
+    ClientConnected { id: usize, addr: SocketAddr },
+    ClientDisconnected { id: usize },
+}
+
+// server events fanned out to any number of subscribers: keyspace notifications,
+// replication, metrics or hooks of an embedding application. Publishing never blocks,
+// slow subscribers get RecvError::Lagged.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(EVENTS_CAPACITY)
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> EventBus {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    // the event is built only if someone is listening
+    pub fn publish<F: FnOnce() -> Event>(&self, event: F) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    pub fn test_event_bus() {
+        let bus = EventBus::new(2);
+        bus.publish(|| panic!("no subscribers, the event is not built"));
+        let mut receiver = bus.subscribe();
+        let key = Bytes::from_static(b"k");
+        bus.publish(|| Event::KeyExpired { key: key.clone() });
+        assert_eq!(receiver.try_recv().unwrap(), Event::KeyExpired { key });
+        for id in 0..3 {
+            bus.publish(|| Event::ClientDisconnected { id });
+        }
+        assert!(matches!(
+            receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(1))
+        ));
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            Event::ClientDisconnected { id: 1 }
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use super::protocol::{ClientReq, RESP};
+use super::types::{ErrorT, ResultT};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// chaos testing, built with the fault-injection feature. Rules are added with the handle
+// returned by RdisServer::faults or with DEBUG FAULT, and apply to the requests read from the
+// matching connections:
+//
+//     DEBUG FAULT LATENCY 200 COMMAND get   GETs wait 200ms before reaching the engine
+//     DEBUG FAULT DROP CLIENT 12            the commands of client 12 run but aren't replied
+//     DEBUG FAULT DISCONNECT COMMAND incr   the connection is closed before INCR runs
+//     DEBUG FAULT RESET
+//
+// a pipeline waits, or is disconnected, when any of its commands matches a rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Latency(Duration),
+    DropResponse,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub fault: Fault,
+    // uppercase name as sent by the client, every command when None
+    pub command: Option<String>,
+    // every client when None
+    pub client: Option<usize>,
+}
+
+impl FaultRule {
+    pub fn new(fault: Fault) -> FaultRule {
+        FaultRule {
+            fault,
+            command: None,
+            client: None,
+        }
+    }
+
+    pub fn command(mut self, name: &str) -> Self {
+        self.command = Some(name.to_uppercase());
+        self
+    }
+
+    pub fn client(mut self, client_id: usize) -> Self {
+        self.client = Some(client_id);
+        self
+    }
+
+    // the arguments of DEBUG FAULT: LATENCY ms|DROP|DISCONNECT [COMMAND name] [CLIENT id]
+    pub fn parse(args: &[String]) -> ResultT<FaultRule> {
+        let (fault, mut rest) = match args {
+            [kind, ms, rest @ ..] if kind.eq_ignore_ascii_case("latency") => {
+                (Fault::Latency(Duration::from_millis(ms.parse()?)), rest)
+            }
+            [kind, rest @ ..] if kind.eq_ignore_ascii_case("drop") => (Fault::DropResponse, rest),
+            [kind, rest @ ..] if kind.eq_ignore_ascii_case("disconnect") => {
+                (Fault::Disconnect, rest)
+            }
+            _ => return Err(ErrorT::from("fault must be LATENCY ms, DROP or DISCONNECT")),
+        };
+        let mut rule = FaultRule::new(fault);
+        while let [option, value, tail @ ..] = rest {
+            rule = match option.to_lowercase().as_str() {
+                "command" => rule.command(value),
+                "client" => rule.client(value.parse()?),
+                _ => break,
+            };
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(ErrorT::from("syntax error"));
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, client_id: usize, command: &str) -> bool {
+        self.client.is_none_or(|c| c == client_id)
+            && self.command.as_ref().is_none_or(|name| name == command)
+    }
+}
+
+// the effect of the matching rules on a request, latencies add up.
+// Latency and disconnects apply to the whole pipeline, dropped replies to the matching commands.
+#[derive(Debug, Default, PartialEq)]
+pub struct Injected {
+    pub latency: Duration,
+    // indexes in the pipeline of the commands whose reply is dropped
+    pub dropped: Vec<usize>,
+    pub disconnect: bool,
+}
+
+impl Injected {
+    pub fn drop_responses(&self, responses: &mut Vec<RESP>) {
+        let mut idx = 0;
+        responses.retain(|_| {
+            idx += 1;
+            !self.dropped.contains(&(idx - 1))
+        });
+    }
+}
+
+// shared by the engine, handling DEBUG FAULT, and the connections
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    rules: Arc<Mutex<Vec<FaultRule>>>,
+}
+
+impl Faults {
+    pub fn add(&self, rule: FaultRule) {
+        self.rules.lock().unwrap().push(rule);
+    }
+
+    pub fn reset(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    pub fn check(&self, client_id: usize, req: &ClientReq) -> Injected {
+        let rules = self.rules.lock().unwrap();
+        let mut injected = Injected::default();
+        if rules.is_empty() {
+            return injected;
+        }
+        let commands: Vec<String> = match req {
+            ClientReq::Single(r) => vec![r.describe_command().0],
+            ClientReq::Pipeline(rs) => rs.iter().map(|r| r.describe_command().0).collect(),
+        };
+        for rule in rules.iter() {
+            let matching: Vec<usize> = commands
+                .iter()
+                .enumerate()
+                .filter(|(_, name)| rule.matches(client_id, name))
+                .map(|(idx, _)| idx)
+                .collect();
+            if matching.is_empty() {
+                continue;
+            }
+            match rule.fault {
+                Fault::Latency(latency) => injected.latency += latency,
+                Fault::DropResponse => injected.dropped.extend(matching),
+                Fault::Disconnect => injected.disconnect = true,
+            }
+        }
+        injected.dropped.sort_unstable();
+        injected.dropped.dedup();
+        injected
+    }
+
+    // DEBUG FAULT
+    pub fn command(&self, args: &[String]) -> RESP {
+        match args {
+            [reset] if reset.eq_ignore_ascii_case("reset") => self.reset(),
+            args => match FaultRule::parse(args) {
+                Ok(rule) => self.add(rule),
+                Err(e) => return RESP::Error("ERR".into(), e.to_string()),
+            },
+        }
+        RESP::SimpleString("OK".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    fn request(commands: &[&str]) -> ClientReq {
+        let commands = commands
+            .iter()
+            .map(|c| RESP::Array(vec![RESP::from(*c), RESP::from("k")]))
+            .collect();
+        ClientReq::Pipeline(commands)
+    }
+
+    #[test]
+    pub fn test_parse() -> ResultT<()> {
+        assert_eq!(
+            FaultRule::parse(&args("latency 200 command get client 3"))?,
+            FaultRule::new(Fault::Latency(Duration::from_millis(200)))
+                .command("GET")
+                .client(3)
+        );
+        assert_eq!(
+            FaultRule::parse(&args("DROP"))?,
+            FaultRule::new(Fault::DropResponse)
+        );
+        assert!(FaultRule::parse(&args("latency")).is_err());
+        assert!(FaultRule::parse(&args("latency x")).is_err());
+        assert!(FaultRule::parse(&args("crash")).is_err());
+        assert!(FaultRule::parse(&args("drop command")).is_err());
+        assert!(FaultRule::parse(&args("drop client x")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_check() {
+        let faults = Faults::default();
+        assert_eq!(faults.check(1, &request(&["get"])), Injected::default());
+        faults.add(FaultRule::new(Fault::Latency(Duration::from_millis(10))).command("get"));
+        faults.add(FaultRule::new(Fault::Latency(Duration::from_millis(5))));
+        faults.add(FaultRule::new(Fault::DropResponse).client(2));
+        faults.add(FaultRule::new(Fault::Disconnect).command("incr").client(1));
+
+        let injected = faults.check(1, &request(&["set", "get", "get"]));
+        assert_eq!(injected.latency, Duration::from_millis(15));
+        assert!(injected.dropped.is_empty() && !injected.disconnect);
+        let injected = faults.check(2, &request(&["incr"]));
+        assert_eq!(injected.latency, Duration::from_millis(5));
+        assert_eq!(injected.dropped, vec![0]);
+        assert!(!injected.disconnect);
+        assert!(faults.check(1, &request(&["incr"])).disconnect);
+
+        faults.add(FaultRule::new(Fault::DropResponse).command("set"));
+        let injected = faults.check(1, &request(&["set", "get", "set"]));
+        assert_eq!(injected.dropped, vec![0, 2]);
+        let mut responses = vec![RESP::from("a"), RESP::from("b"), RESP::from("c")];
+        injected.drop_responses(&mut responses);
+        assert_eq!(responses, vec![RESP::from("b")]);
+
+        assert_eq!(
+            faults.command(&args("reset")),
+            RESP::SimpleString("OK".into())
+        );
+        assert_eq!(faults.check(1, &request(&["get"])), Injected::default());
+        assert!(matches!(
+            faults.command(&args("explode")),
+            RESP::Error(_, _)
+        ));
+    }
+}
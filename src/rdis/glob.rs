@@ -0,0 +1,233 @@
+// glob-style patterns of redis (stringmatchlen), matched against any bytes:
+//   *        any sequence of bytes
+//   ?        any single byte
+//   [abc]    one of the bytes, [^abc] none of them, [a-z] a range, \ escapes inside the class
+//   \x       the byte x
+// A class left open extends to the end of the pattern. Every element but * matches exactly one
+// byte, so the last * is the only backtracking point and matching is O(pattern * string).
+
+fn eq(a: u8, b: u8, nocase: bool) -> bool {
+    a == b || (nocase && a.eq_ignore_ascii_case(&b))
+}
+
+pub fn matches(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    // like redis, only the empty pattern matches the empty string
+    if string.is_empty() {
+        return pattern.is_empty();
+    }
+    let (mut p, mut s) = (0, 0);
+    // pattern index after the last *, string index it's being tried from
+    let mut backtrack = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            backtrack = Some((p, s));
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, string[s], nocase) {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match backtrack {
+            Some((star, from)) => {
+                backtrack = Some((star, from + 1));
+                p = star;
+                s = from + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// the pattern index after the element at p if it matches c
+fn match_one(pattern: &[u8], p: usize, c: u8, nocase: bool) -> Option<usize> {
+    match pattern[p..] {
+        [] => None,
+        [b'?', ..] => Some(p + 1),
+        [b'[', ..] => match_class(pattern, p + 1, c, nocase),
+        [b'\\', escaped, ..] => eq(escaped, c, nocase).then_some(p + 2),
+        [literal, ..] => eq(literal, c, nocase).then_some(p + 1),
+    }
+}
+
+fn match_class(pattern: &[u8], mut i: usize, c: u8, nocase: bool) -> Option<usize> {
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    loop {
+        match pattern[i..] {
+            [] => break,
+            // escapes ignore nocase, like in redis
+            [b'\\', escaped, ..] => {
+                matched |= escaped == c;
+                i += 2;
+            }
+            [b']', ..] => {
+                i += 1;
+                break;
+            }
+            [start, b'-', end, ..] => {
+                let (mut start, mut end, mut c) = (start.min(end), start.max(end), c);
+                if nocase {
+                    start = start.to_ascii_lowercase();
+                    end = end.to_ascii_lowercase();
+                    c = c.to_ascii_lowercase();
+                }
+                matched |= start <= c && c <= end;
+                i += 3;
+            }
+            [literal, ..] => {
+                matched |= eq(literal, c, nocase);
+                i += 1;
+            }
+        }
+    }
+    (matched != negated).then_some(i)
+}
+
+// the bytes every string matched by pattern starts with, case sensitive
+pub fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut i = 0;
+    loop {
+        match pattern[i..] {
+            [] | [b'*', ..] | [b'?', ..] | [b'[', ..] => return prefix,
+            [b'\\', escaped, ..] => {
+                prefix.push(escaped);
+                i += 2;
+            }
+            [literal, ..] => {
+                prefix.push(literal);
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, string: &str) -> bool {
+        matches(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    pub fn test_literals_and_wildcards() {
+        assert!(check("hello", "hello"));
+        assert!(!check("hello", "hell"));
+        assert!(!check("hell", "hello"));
+        assert!(check("h?llo", "hallo"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(check("*", "anything"));
+        assert!(check("h*llo", "hllo"));
+        assert!(check("h*llo", "heeeello"));
+        assert!(check("h**llo", "hello"));
+        assert!(!check("h*llo", "hellox"));
+        assert!(check("*llo", "hello"));
+        assert!(check("he*", "he"));
+        assert!(check("a*b*c", "aXbYbZc"));
+        assert!(!check("a*b*c", "aXbYbZ"));
+        assert!(check("*a*", "bab"));
+        assert!(check("?*", "x"));
+        assert!(!check("?*?", "x"));
+        assert!(check("user:*:name", "user:42:name"));
+        assert!(!check("user:*:name", "user:42:age"));
+        // backtracking past a partial match
+        assert!(check("*aab", "aaab"));
+        assert!(check("*ab*ab", "xabyabab"));
+        // only the empty pattern matches the empty string
+        assert!(check("", ""));
+        assert!(!check("*", ""));
+        assert!(!check("", "a"));
+    }
+
+    #[test]
+    pub fn test_classes() {
+        assert!(check("h[ae]llo", "hallo"));
+        assert!(check("h[ae]llo", "hello"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(check("h[a-c]llo", "hbllo"));
+        assert!(!check("h[a-c]llo", "hdllo"));
+        // reversed ranges are swapped
+        assert!(check("h[c-a]llo", "hbllo"));
+        assert!(check("[a-cx-z]", "y"));
+        assert!(check("[\\]]", "]"));
+        assert!(check("[\\-]", "-"));
+        assert!(!check("[\\-]", "a"));
+        // like in redis the ] ends the range a-], so the class is left open
+        assert!(check("[a-]", "_"));
+        assert!(!check("[a-]", "-"));
+        // an empty class never matches, a negated one matches any byte
+        assert!(!check("[]", "a"));
+        assert!(check("[^]", "a"));
+        // an unterminated class extends to the end of the pattern
+        assert!(check("[abc", "b"));
+        assert!(!check("[abc", "bc"));
+        assert!(!check("[", "a"));
+        assert!(check("[^", "a"));
+        assert!(check("*[0-9]", "key7"));
+        assert!(!check("*[0-9]", "key"));
+    }
+
+    #[test]
+    pub fn test_escapes() {
+        assert!(check("\\*", "*"));
+        assert!(!check("\\*", "a"));
+        assert!(check("a\\?", "a?"));
+        assert!(!check("a\\?", "ab"));
+        assert!(check("\\[a]", "[a]"));
+        // a trailing backslash is a literal
+        assert!(check("a\\", "a\\"));
+        assert!(check("\\\\", "\\"));
+    }
+
+    #[test]
+    pub fn test_nocase() {
+        assert!(matches(b"HeLLo", b"hello", true));
+        assert!(!check("HeLLo", "hello"));
+        assert!(matches(b"h[A-C]llo", b"hbllo", true));
+        assert!(matches(b"h[a-c]llo", b"HBLLO", true));
+        assert!(matches(b"[X]", b"x", true));
+        // escaped bytes inside a class stay case sensitive
+        assert!(!matches(b"[\\X]", b"x", true));
+        assert!(matches(b"\\X", b"x", true));
+    }
+
+    #[test]
+    pub fn test_binary() {
+        assert!(matches(b"\x00*\xff", b"\x00abc\xff", false));
+        assert!(matches(b"[\x00-\x10]", b"\x05", false));
+        assert!(!matches(b"?", b"\xff\xff", false));
+    }
+
+    #[test]
+    pub fn test_many_stars() {
+        // would be exponential with naive backtracking
+        let pattern = "a*".repeat(30) + "b";
+        assert!(!check(&pattern, &"a".repeat(100)));
+        assert!(check(&pattern, &("a".repeat(100) + "b")));
+    }
+
+    #[test]
+    pub fn test_literal_prefix() {
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"user:?"), b"user:");
+        assert_eq!(literal_prefix(b"user[12]"), b"user");
+        assert_eq!(literal_prefix(b"a\\*b*"), b"a*b");
+        assert_eq!(literal_prefix(b"*"), b"");
+        assert_eq!(literal_prefix(b"exact"), b"exact");
+        assert_eq!(literal_prefix(b"a\\"), b"a\\");
+    }
+}
@@ -0,0 +1,177 @@
+use super::scan::scan_hash;
+use super::storage::Key;
+use std::collections::HashMap;
+
+// hottest keys of the engine, enabled by hotkeys-sample-rate. The keys of one command in
+// sample_rate are counted in a count-min sketch, which never underestimates and only needs a
+// fixed amount of memory whatever the number of keys. The keys with the largest estimates are
+// kept as candidates. Counts are reset every WINDOW_MS, the rate of a key is the count of the
+// current window plus the share of the previous one still overlapping the last WINDOW_MS.
+
+const WINDOW_MS: u64 = 10_000;
+const DEPTH: usize = 4;
+const WIDTH: usize = 2048;
+// keys tracked, the largest count reported by DEBUG HOTKEYS
+pub const MAX_CANDIDATES: usize = 32;
+
+#[derive(Debug)]
+pub struct HotKeys {
+    sample_rate: u64,
+    sampled: u64,
+    sketch: Vec<u32>,
+    window_start: u64,
+    // key -> estimated count in the window
+    current: HashMap<Key, u64>,
+    previous: HashMap<Key, u64>,
+    // smallest count of current once full, a key must beat it to become a candidate
+    floor: u64,
+}
+
+impl HotKeys {
+    // sample_rate > 0
+    pub fn new(sample_rate: u64, t: u64) -> HotKeys {
+        HotKeys {
+            sample_rate,
+            sampled: 0,
+            sketch: vec![0; DEPTH * WIDTH],
+            window_start: t,
+            current: HashMap::with_capacity(MAX_CANDIDATES),
+            previous: HashMap::new(),
+            floor: 0,
+        }
+    }
+
+    // whether the keys of the next command must be recorded
+    pub fn sample(&mut self) -> bool {
+        self.sampled += 1;
+        self.sampled.is_multiple_of(self.sample_rate)
+    }
+
+    pub fn record(&mut self, key: &[u8], t: u64) {
+        self.rotate(t);
+        // double hashing gives the DEPTH indexes from a single hash
+        let hash = scan_hash(key);
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let mut estimate = u32::MAX;
+        for row in 0..DEPTH {
+            let column = (h1.wrapping_add(h2.wrapping_mul(row as u64)) % WIDTH as u64) as usize;
+            let counter = &mut self.sketch[row * WIDTH + column];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        let count = estimate as u64 * self.sample_rate;
+        if let Some(c) = self.current.get_mut(key) {
+            *c = count;
+        } else if self.current.len() < MAX_CANDIDATES {
+            self.current.insert(Key::copy_from_slice(key), count);
+            if self.current.len() == MAX_CANDIDATES {
+                self.update_floor();
+            }
+        } else if count > self.floor {
+            let coldest = self
+                .current
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(k, _)| k.clone());
+            if let Some(coldest) = coldest {
+                self.current.remove(&coldest);
+            }
+            self.current.insert(Key::copy_from_slice(key), count);
+            self.update_floor();
+        }
+    }
+
+    fn update_floor(&mut self) {
+        self.floor = self.current.values().copied().min().unwrap_or(0);
+    }
+
+    fn rotate(&mut self, t: u64) {
+        if t < self.window_start + WINDOW_MS {
+            return;
+        }
+        let windows = (t - self.window_start) / WINDOW_MS;
+        self.previous = match windows {
+            1 => std::mem::take(&mut self.current),
+            _ => HashMap::new(),
+        };
+        self.current.clear();
+        self.sketch.iter_mut().for_each(|c| *c = 0);
+        self.window_start += windows * WINDOW_MS;
+        self.floor = 0;
+    }
+
+    // (key, estimated commands per second), hottest first. Windows are only rotated by record,
+    // the current one counts as the previous one once it's over
+    pub fn top(&self, count: usize, t: u64) -> Vec<(Key, f64)> {
+        let elapsed = t.saturating_sub(self.window_start);
+        let (previous, current) = match elapsed / WINDOW_MS {
+            0 => (&self.previous, Some(&self.current)),
+            1 => (&self.current, None),
+            _ => return Vec::new(),
+        };
+        let overlap = 1.0 - (elapsed % WINDOW_MS) as f64 / WINDOW_MS as f64;
+        let mut rates: HashMap<&Key, f64> = HashMap::new();
+        for (k, c) in previous.iter() {
+            *rates.entry(k).or_default() += *c as f64 * overlap;
+        }
+        for (k, c) in current.into_iter().flatten() {
+            *rates.entry(k).or_default() += *c as f64;
+        }
+        let window_secs = WINDOW_MS as f64 / 1000.0;
+        let mut top: Vec<(Key, f64)> = rates
+            .into_iter()
+            .map(|(k, c)| (k.clone(), c / window_secs))
+            .collect();
+        top.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(count);
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(top: &[(Key, f64)]) -> Vec<&[u8]> {
+        top.iter().map(|(k, _)| k.as_ref()).collect()
+    }
+
+    #[test]
+    pub fn test_top() {
+        let mut hot = HotKeys::new(1, 0);
+        for i in 0..1000 {
+            hot.record(b"hot", 1);
+            if i % 2 == 0 {
+                hot.record(b"warm", 1);
+            }
+            hot.record(format!("cold:{}", i).as_bytes(), 1);
+        }
+        let top = hot.top(2, 5_000);
+        assert_eq!(keys(&top), vec![&b"hot"[..], b"warm"]);
+        // the sketch may only overestimate
+        assert!(top[0].1 >= 100.0 && top[0].1 < 101.0);
+        assert!(top[1].1 >= 50.0 && top[1].1 < 51.0);
+        // half of the previous window still counts
+        let top = hot.top(1, 15_000);
+        assert_eq!(keys(&top), vec![&b"hot"[..]]);
+        assert!(top[0].1 >= 50.0 && top[0].1 < 51.0);
+        assert!(hot.top(1, 30_000).is_empty());
+        hot.record(b"warm", 30_000);
+        assert_eq!(keys(&hot.top(2, 30_000)), vec![&b"warm"[..]]);
+    }
+
+    #[test]
+    pub fn test_sample() {
+        let mut hot = HotKeys::new(4, 0);
+        let mut sampled = 0;
+        for _ in 0..400 {
+            if hot.sample() {
+                hot.record(b"k", 0);
+                sampled += 1;
+            }
+        }
+        assert_eq!(sampled, 100);
+        // counts are scaled by the sample rate
+        assert_eq!(hot.top(1, 0)[0].1, 40.0);
+    }
+}
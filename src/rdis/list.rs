@@ -0,0 +1,334 @@
+use super::storage::{realloc_bytes, shared_bytes, RawValue};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+// values of the list keys. Short lists keep their elements as shared buffers, like strings.
+// Past SMALL_LIST_MAX elements they are packed in a QuickList, saving the buffer header and
+// the reference count of every element, and they go back to the small representation once
+// they shrink under half of it.
+const SMALL_LIST_MAX: usize = 128;
+// bytes of elements packed in a node, larger elements get a node of their own
+const NODE_SIZE: usize = 8 * 1024;
+
+pub enum List {
+    Small(VecDeque<RawValue>),
+    Packed(QuickList),
+}
+
+impl List {
+    pub fn with_capacity(capacity: usize) -> List {
+        List::Small(VecDeque::with_capacity(capacity))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            List::Small(list) => list.len(),
+            List::Packed(list) => list.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push_front(&mut self, v: RawValue) {
+        match self {
+            List::Small(list) => list.push_front(v),
+            List::Packed(list) => list.push_front(&v),
+        }
+        self.convert();
+    }
+
+    pub fn push_back(&mut self, v: RawValue) {
+        match self {
+            List::Small(list) => list.push_back(v),
+            List::Packed(list) => list.push_back(&v),
+        }
+        self.convert();
+    }
+
+    pub fn pop_front(&mut self) -> Option<RawValue> {
+        let popped = match self {
+            List::Small(list) => list.pop_front(),
+            List::Packed(list) => list.pop_front(),
+        };
+        self.convert();
+        popped
+    }
+
+    pub fn pop_back(&mut self) -> Option<RawValue> {
+        let popped = match self {
+            List::Small(list) => list.pop_back(),
+            List::Packed(list) => list.pop_back(),
+        };
+        self.convert();
+        popped
+    }
+
+    // elements from start to stop included, both valid indexes
+    pub fn range(&self, start: usize, stop: usize) -> Vec<RawValue> {
+        match self {
+            List::Small(list) => list.range(start..=stop).cloned().collect(),
+            List::Packed(list) => list.range(start, stop),
+        }
+    }
+
+    // estimate of the heap used by the elements, see Value::memory
+    pub fn memory(&self) -> usize {
+        match self {
+            List::Small(list) => {
+                let slots = list.capacity() * size_of::<RawValue>();
+                slots + list.iter().map(shared_bytes).sum::<usize>()
+            }
+            List::Packed(list) => list.memory(),
+        }
+    }
+
+    // allocations moved, see Storage::defrag
+    pub fn defrag(&mut self) -> usize {
+        match self {
+            List::Small(list) => {
+                let moved = list.iter_mut().map(realloc_bytes).sum::<usize>();
+                let mut moved_list = VecDeque::with_capacity(list.len());
+                moved_list.extend(list.drain(..));
+                *list = moved_list;
+                moved + 1
+            }
+            List::Packed(list) => list.defrag(),
+        }
+    }
+
+    fn convert(&mut self) {
+        match self {
+            List::Small(list) if list.len() > SMALL_LIST_MAX => {
+                let mut packed = QuickList::default();
+                for v in list.drain(..) {
+                    packed.push_back(&v);
+                }
+                *self = List::Packed(packed);
+            }
+            List::Packed(list) if list.len <= SMALL_LIST_MAX / 2 => {
+                let small = match list.len {
+                    0 => VecDeque::new(),
+                    len => list.range(0, len - 1).into(),
+                };
+                *self = List::Small(small);
+            }
+            _ => (),
+        }
+    }
+}
+
+// a list of nodes packing the bytes of consecutive elements, like the quicklist of redis.
+// Elements are copied in and out of the nodes.
+#[derive(Default)]
+pub struct QuickList {
+    nodes: VecDeque<Node>,
+    len: usize,
+}
+
+// the element i is data[ends[i - 1]..ends[i]]
+struct Node {
+    data: Vec<u8>,
+    ends: Vec<u32>,
+}
+
+impl Node {
+    fn with_capacity(capacity: usize) -> Node {
+        Node {
+            data: Vec::with_capacity(capacity.max(NODE_SIZE)),
+            ends: Vec::new(),
+        }
+    }
+
+    fn get(&self, i: usize) -> &[u8] {
+        let start = match i {
+            0 => 0,
+            i => self.ends[i - 1] as usize,
+        };
+        &self.data[start..self.ends[i] as usize]
+    }
+
+    fn fits(&self, v: &[u8]) -> bool {
+        self.data.len() + v.len() <= NODE_SIZE
+    }
+
+    fn push_back(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+        self.ends.push(self.data.len() as u32);
+    }
+
+    fn push_front(&mut self, v: &[u8]) {
+        self.data.splice(0..0, v.iter().copied());
+        for end in self.ends.iter_mut() {
+            *end += v.len() as u32;
+        }
+        self.ends.insert(0, v.len() as u32);
+    }
+
+    fn pop_front(&mut self) -> Option<RawValue> {
+        if self.ends.is_empty() {
+            return None;
+        }
+        let end = self.ends.remove(0);
+        let popped = Bytes::copy_from_slice(&self.data[..end as usize]);
+        self.data.drain(..end as usize);
+        for e in self.ends.iter_mut() {
+            *e -= end;
+        }
+        Some(popped)
+    }
+
+    fn pop_back(&mut self) -> Option<RawValue> {
+        self.ends.pop()?;
+        let start = self.ends.last().copied().unwrap_or(0) as usize;
+        let popped = Bytes::copy_from_slice(&self.data[start..]);
+        self.data.truncate(start);
+        Some(popped)
+    }
+}
+
+impl QuickList {
+    fn push_back(&mut self, v: &[u8]) {
+        match self.nodes.back_mut() {
+            Some(node) if node.fits(v) => node.push_back(v),
+            _ => {
+                let mut node = Node::with_capacity(v.len());
+                node.push_back(v);
+                self.nodes.push_back(node);
+            }
+        }
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, v: &[u8]) {
+        match self.nodes.front_mut() {
+            Some(node) if node.fits(v) => node.push_front(v),
+            _ => {
+                let mut node = Node::with_capacity(v.len());
+                node.push_back(v);
+                self.nodes.push_front(node);
+            }
+        }
+        self.len += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<RawValue> {
+        let node = self.nodes.front_mut()?;
+        let popped = node.pop_front();
+        if node.ends.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.len -= 1;
+        popped
+    }
+
+    fn pop_back(&mut self) -> Option<RawValue> {
+        let node = self.nodes.back_mut()?;
+        let popped = node.pop_back();
+        if node.ends.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.len -= 1;
+        popped
+    }
+
+    // nodes before start are skipped by their count
+    fn range(&self, start: usize, stop: usize) -> Vec<RawValue> {
+        let count = stop + 1 - start;
+        let mut values = Vec::with_capacity(count);
+        let mut skip = start;
+        for node in self.nodes.iter() {
+            if skip >= node.ends.len() {
+                skip -= node.ends.len();
+                continue;
+            }
+            for i in skip..node.ends.len() {
+                if values.len() == count {
+                    return values;
+                }
+                values.push(Bytes::copy_from_slice(node.get(i)));
+            }
+            skip = 0;
+            if values.len() == count {
+                break;
+            }
+        }
+        values
+    }
+
+    fn defrag(&mut self) -> usize {
+        for node in self.nodes.iter_mut() {
+            let mut data = Vec::with_capacity(node.data.capacity());
+            data.extend_from_slice(&node.data);
+            node.data = data;
+        }
+        self.nodes.len()
+    }
+
+    fn memory(&self) -> usize {
+        let nodes = self.nodes.capacity() * size_of::<Node>();
+        nodes
+            + self
+                .nodes
+                .iter()
+                .map(|node| node.data.capacity() + node.ends.capacity() * size_of::<u32>())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(s: &str) -> RawValue {
+        Bytes::copy_from_slice(s.as_bytes())
+    }
+
+    #[test]
+    pub fn test_packed_list() {
+        let mut list = List::with_capacity(8);
+        let mut expected = VecDeque::new();
+        for i in 0..1000 {
+            let v = raw(&format!("element:{}", i));
+            if i % 3 == 0 {
+                list.push_front(v.clone());
+                expected.push_front(v);
+            } else {
+                list.push_back(v.clone());
+                expected.push_back(v);
+            }
+        }
+        // an element larger than a node
+        let big = raw(&"x".repeat(2 * NODE_SIZE));
+        list.push_back(big.clone());
+        expected.push_back(big);
+        assert!(matches!(list, List::Packed(_)));
+        assert_eq!(list.len(), expected.len());
+        let all: Vec<RawValue> = expected.iter().cloned().collect();
+        assert_eq!(list.range(0, all.len() - 1), all);
+        assert_eq!(list.range(400, 650), all[400..=650].to_vec());
+        assert_eq!(list.range(1000, 1000), vec![all[1000].clone()]);
+
+        while list.len() > 10 {
+            assert_eq!(list.pop_back(), expected.pop_back());
+            assert_eq!(list.pop_front(), expected.pop_front());
+        }
+        assert!(matches!(list, List::Small(_)));
+        let all: Vec<RawValue> = expected.iter().cloned().collect();
+        assert_eq!(list.range(0, all.len() - 1), all);
+    }
+
+    #[test]
+    pub fn test_packed_memory() {
+        let mut small = VecDeque::new();
+        let mut packed = List::with_capacity(8);
+        for i in 0..10_000 {
+            small.push_back(raw(&i.to_string()));
+            packed.push_back(raw(&i.to_string()));
+        }
+        let small = List::Small(small);
+        assert!(packed.memory() * 4 < small.memory());
+    }
+}
@@ -0,0 +1,404 @@
+use super::config::{LogConfig, LogRotation};
+use super::types::{ErrorT, ResultT};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// installs the logger: stdout by default, the logfile and/or syslog when configured. The file
+// logger is returned so that the caller can reopen it, rdis does on SIGHUP.
+pub fn init(config: &LogConfig) -> ResultT<Option<&'static FileLogger>> {
+    let file = match &config.file {
+        Some(path) => Some(&*Box::leak(Box::new(FileLogger::open(path, config)?))),
+        None => None,
+    };
+    let syslog = match config.syslog.enabled {
+        true => Some(SyslogLogger::open(config)?),
+        false => None,
+    };
+    if file.is_none() && syslog.is_none() {
+        let mut logger = SimpleLogger::new().with_level(config.level);
+        for (module, level) in config.module_levels.iter() {
+            logger = logger.with_module_level(module, *level);
+        }
+        logger.init()?;
+        return Ok(None);
+    }
+    log::set_boxed_logger(Box::new(Outputs { file, syslog }))?;
+    log::set_max_level(Levels::new(config).max());
+    Ok(file)
+}
+
+// the global level and the per module overrides
+struct Levels {
+    level: LevelFilter,
+    // sorted by decreasing length, the first matching prefix wins
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn new(config: &LogConfig) -> Levels {
+        let mut module_levels = config.module_levels.clone();
+        module_levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Levels {
+            level: config.level,
+            module_levels,
+        }
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, Ord::max)
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let level = self
+            .module_levels
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map_or(self.level, |(_, level)| *level);
+        metadata.level() <= level
+    }
+}
+
+// the file and syslog together
+struct Outputs {
+    file: Option<&'static FileLogger>,
+    syslog: Option<SyslogLogger>,
+}
+
+impl Log for Outputs {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.file.is_some_and(|file| file.enabled(metadata))
+            || self
+                .syslog
+                .as_ref()
+                .is_some_and(|syslog| syslog.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(file) = self.file {
+            file.log(record);
+        }
+        if let Some(syslog) = &self.syslog {
+            syslog.log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// writes one logfmt line per record:
+//
+//     ts=2022-04-15T10:00:00.123Z level=INFO target=rdis::rdis::server msg="Bound socket"
+//
+// the file is rotated when it grows over the max size or gets older than the interval:
+// rdis.log becomes rdis.log.1, rdis.log.1 becomes rdis.log.2 and so on, up to keep files.
+// reopen lets external tools like logrotate move the file instead.
+pub struct FileLogger {
+    levels: Levels,
+    rotation: LogRotation,
+    path: String,
+    output: Mutex<LogOutput>,
+}
+
+struct LogOutput {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl LogOutput {
+    fn open(path: &str) -> io::Result<LogOutput> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogOutput {
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+}
+
+impl FileLogger {
+    pub fn open(path: &str, config: &LogConfig) -> ResultT<FileLogger> {
+        Ok(FileLogger {
+            levels: Levels::new(config),
+            rotation: config.rotation.clone(),
+            path: path.to_owned(),
+            output: Mutex::new(LogOutput::open(path)?),
+        })
+    }
+
+    // closes the file and opens the path again, after it was moved away
+    pub fn reopen(&self) -> ResultT<()> {
+        let mut output = self.output.lock().unwrap();
+        *output = LogOutput::open(&self.path)?;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, output: &LogOutput, len: u64) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| output.size > 0 && output.size + len > max as u64);
+        let too_old = self
+            .rotation
+            .interval
+            .is_some_and(|interval| output.opened_at.elapsed() >= interval);
+        too_big || too_old
+    }
+
+    fn rotate(&self, output: &mut LogOutput) -> io::Result<()> {
+        let rotated = |n: usize| format!("{}.{}", self.path, n);
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        *output = LogOutput::open(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        let mut output = self.output.lock().unwrap();
+        if self.needs_rotation(&output, line.len() as u64) {
+            self.rotate(&mut output)?;
+        }
+        output.file.write_all(line)?;
+        output.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.levels.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(record);
+        // nowhere else to report the failure
+        if let Err(e) = self.write(line.as_bytes()) {
+            eprintln!("Failed to write to {}: {}", self.path, e);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn format_record(record: &Record) -> String {
+    format!(
+        "ts={} level={} target={} msg={:?}\n",
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        record.level(),
+        record.target(),
+        record.args().to_string()
+    )
+}
+
+// sends the records to the local syslog daemon with the configured ident and facility,
+// which adds the time and the pid
+pub struct SyslogLogger {
+    levels: Levels,
+}
+
+impl SyslogLogger {
+    // syslog is process wide, openlog keeps the ident pointer: it is leaked
+    fn open(config: &LogConfig) -> ResultT<SyslogLogger> {
+        let ident = CString::new(config.syslog.ident.as_str())
+            .map_err(|_| ErrorT::from("syslog ident contains a nul byte"))?;
+        let ident: &'static CStr = Box::leak(ident.into_boxed_c_str());
+        unsafe {
+            libc::openlog(
+                ident.as_ptr(),
+                libc::LOG_PID | libc::LOG_NDELAY,
+                config.syslog.facility,
+            )
+        };
+        Ok(SyslogLogger {
+            levels: Levels::new(config),
+        })
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.levels.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // nul bytes would truncate the message
+        let msg = format!("{}: {}", record.target(), record.args()).replace('\0', "\\0");
+        let msg = CString::new(msg).unwrap();
+        unsafe {
+            libc::syslog(
+                priority(record.level()),
+                b"%s\0".as_ptr() as *const libc::c_char,
+                msg.as_ptr(),
+            )
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+fn priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_NOTICE,
+        Level::Debug => libc::LOG_INFO,
+        Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+// the facilities accepted by redis for syslog-facility
+pub fn parse_facility(name: &str) -> ResultT<libc::c_int> {
+    let facilities = [
+        ("user", libc::LOG_USER),
+        ("local0", libc::LOG_LOCAL0),
+        ("local1", libc::LOG_LOCAL1),
+        ("local2", libc::LOG_LOCAL2),
+        ("local3", libc::LOG_LOCAL3),
+        ("local4", libc::LOG_LOCAL4),
+        ("local5", libc::LOG_LOCAL5),
+        ("local6", libc::LOG_LOCAL6),
+        ("local7", libc::LOG_LOCAL7),
+    ];
+    facilities
+        .iter()
+        .find(|(facility, _)| facility.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+        .ok_or_else(|| ErrorT::from(format!("Invalid syslog facility {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn log(logger: &FileLogger, level: Level, target: &str, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rdis-{}-{}.log", name, std::process::id()));
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    pub fn test_format_and_levels() -> ResultT<()> {
+        let path = temp_path("levels");
+        let config = LogConfig {
+            level: LevelFilter::Warn,
+            module_levels: vec![("rdis::rdis::engine".to_owned(), LevelFilter::Debug)],
+            ..LogConfig::default()
+        };
+        let logger = FileLogger::open(&path, &config)?;
+        log(&logger, Level::Info, "rdis::rdis::server", "dropped");
+        log(
+            &logger,
+            Level::Debug,
+            "rdis::rdis::engine",
+            "kept \"quoted\"",
+        );
+        log(&logger, Level::Debug, "rdis::rdis::engineering", "dropped");
+        log(&logger, Level::Error, "rdis::rdis::server", "kept");
+        let contents = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ts=20"));
+        assert!(
+            lines[0].ends_with(r#"level=DEBUG target=rdis::rdis::engine msg="kept \"quoted\"""#)
+        );
+        assert!(lines[1].ends_with("level=ERROR target=rdis::rdis::server msg=\"kept\""));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_syslog_priorities() -> ResultT<()> {
+        assert_eq!(priority(Level::Info), libc::LOG_NOTICE);
+        assert_eq!(priority(Level::Trace), libc::LOG_DEBUG);
+        assert_eq!(parse_facility("user")?, libc::LOG_USER);
+        assert_eq!(parse_facility("Local7")?, libc::LOG_LOCAL7);
+        assert!(parse_facility("local8").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rotation() -> ResultT<()> {
+        let path = temp_path("rotation");
+        let config = LogConfig {
+            rotation: LogRotation {
+                max_size: Some(100),
+                interval: None,
+                keep: 2,
+            },
+            ..LogConfig::default()
+        };
+        let logger = FileLogger::open(&path, &config)?;
+        // every line is longer than 50 bytes, each file holds a single one
+        for n in 0..4 {
+            log(&logger, Level::Info, "rdis", &format!("line {}", n));
+        }
+        let read = |path: &str| fs::read_to_string(path).unwrap();
+        assert!(read(&path).contains("line 3"));
+        assert!(read(&format!("{}.1", path)).contains("line 2"));
+        assert!(read(&format!("{}.2", path)).contains("line 1"));
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+
+        // the file was moved away, reopen creates a new one
+        fs::rename(&path, format!("{}.moved", path))?;
+        logger.reopen()?;
+        log(&logger, Level::Info, "rdis", "reopened");
+        assert!(read(&path).contains("reopened"));
+        for suffix in &["", ".1", ".2", ".moved"] {
+            fs::remove_file(format!("{}{}", path, suffix))?;
+        }
+
+        let config = LogConfig {
+            rotation: LogRotation {
+                max_size: None,
+                interval: Some(Duration::from_millis(0)),
+                keep: 0,
+            },
+            ..LogConfig::default()
+        };
+        let logger = FileLogger::open(&path, &config)?;
+        log(&logger, Level::Info, "rdis", "first");
+        log(&logger, Level::Info, "rdis", "second");
+        assert!(!read(&path).contains("first"));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
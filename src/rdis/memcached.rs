@@ -0,0 +1,513 @@
+use super::protocol::{ClientReq, RESP};
+use super::registry::{ClientGuard, ClientRegistry};
+use super::types::{ErrorT, RdisError, RedisEngineApi, ResultT};
+use bytes::Bytes;
+use log::{debug, info};
+use std::convert::TryFrom;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+
+// like the memcached limits: 250 bytes keys and 1MB items
+const MAX_KEY_SIZE: usize = 250;
+const MAX_LINE_SIZE: usize = 2048;
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+// larger expiration times are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Get(Vec<Vec<u8>>),
+    Set {
+        key: Vec<u8>,
+        flags: u32,
+        exptime: i64,
+        bytes: usize,
+        noreply: bool,
+    },
+    Delete {
+        key: Vec<u8>,
+        noreply: bool,
+    },
+    Incr {
+        key: Vec<u8>,
+        by: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    // replied with ERROR
+    Unknown,
+    // replied with CLIENT_ERROR and the message
+    Client(&'static str),
+}
+
+const BAD_FORMAT: ParseError = ParseError::Client("bad command line format");
+
+// the line excludes the trailing \r\n
+fn parse_command(line: &[u8]) -> Result<Command, ParseError> {
+    let mut tokens = line
+        .split(|c| *c == b' ')
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_vec());
+    let name = tokens.next().ok_or(ParseError::Unknown)?;
+    let mut args: Vec<Vec<u8>> = tokens.collect();
+    let noreply = args.last().map(|a| a.as_slice()) == Some(b"noreply");
+    if noreply && name.as_slice() != b"get" {
+        args.pop();
+    }
+    if args.iter().any(|a| a.len() > MAX_KEY_SIZE) {
+        return Err(BAD_FORMAT);
+    }
+    match (name.as_slice(), args.as_mut_slice()) {
+        (b"get", []) => Err(ParseError::Unknown),
+        (b"get", _) => Ok(Command::Get(args)),
+        (b"set", [key, flags, exptime, bytes]) => Ok(Command::Set {
+            key: std::mem::take(key),
+            flags: parse(flags).ok_or(BAD_FORMAT)?,
+            exptime: parse(exptime).ok_or(BAD_FORMAT)?,
+            bytes: parse(bytes).ok_or(BAD_FORMAT)?,
+            noreply,
+        }),
+        (b"delete", [key]) => Ok(Command::Delete {
+            key: std::mem::take(key),
+            noreply,
+        }),
+        (b"incr", [key, by]) => Ok(Command::Incr {
+            key: std::mem::take(key),
+            // deltas are unsigned like in memcached, but limited by the i64 counters of the engine
+            by: parse::<u64>(by)
+                .and_then(|by| i64::try_from(by).ok())
+                .ok_or(ParseError::Client("invalid numeric delta argument"))?,
+            noreply,
+        }),
+        (b"version", []) => Ok(Command::Version),
+        (b"quit", []) => Ok(Command::Quit),
+        (b"set", _) | (b"delete", _) | (b"incr", _) => Err(BAD_FORMAT),
+        _ => Err(ParseError::Unknown),
+    }
+}
+
+fn parse<T: std::str::FromStr>(raw: &[u8]) -> Option<T> {
+    std::str::from_utf8(raw).ok()?.parse().ok()
+}
+
+// SET options for a memcached expiration time, None deletes the key
+fn expiration(exptime: i64) -> Option<Vec<Vec<u8>>> {
+    match exptime {
+        0 => Some(vec![]),
+        e if e < 0 => None,
+        e if e <= MAX_RELATIVE_EXPTIME => Some(vec![b"EX".to_vec(), e.to_string().into_bytes()]),
+        e => Some(vec![b"EXAT".to_vec(), e.to_string().into_bytes()]),
+    }
+}
+
+// memcached text protocol frontend: get, set, delete, incr, version and quit are translated
+// to engine commands, so both protocols share the same keyspace. Connections are registered
+// in the ClientRegistry like the RESP ones.
+pub async fn serve(listener: TcpListener, api: Arc<RedisEngineApi>, registry: Arc<ClientRegistry>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Memcached frontend listening on {}", addr);
+    }
+    while let Ok((stream, addr)) = listener.accept().await {
+        let guard = registry.register(addr, stream.local_addr().ok());
+        let id = guard.id;
+        let connection = Connection {
+            api: api.clone(),
+            guard,
+        };
+        let handle = tokio::spawn(async move {
+            if let Err(err) = connection.start_loop(stream).await {
+                debug!("Memcached connection {} closed {}", addr, err);
+            }
+        });
+        registry.attach_handle(id, handle);
+    }
+}
+
+struct Connection {
+    api: Arc<RedisEngineApi>,
+    guard: ClientGuard,
+}
+
+impl Connection {
+    async fn start_loop(self, stream: TcpStream) -> ResultT<()> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let mut line = Vec::with_capacity(128);
+        loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(MAX_LINE_SIZE as u64)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                return Ok(());
+            }
+            if !line.ends_with(b"\n") {
+                writer.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+                writer.flush().await?;
+                return Err(ErrorT::from("Line too long"));
+            }
+            self.record_input(read);
+            let trimmed = line.strip_suffix(b"\r\n").unwrap_or(&line[..read - 1]);
+            let reply = match parse_command(trimmed) {
+                Ok(Command::Quit) => return Ok(()),
+                Ok(command) => self.execute(command, &mut reader).await?,
+                Err(ParseError::Unknown) => Some(b"ERROR\r\n".to_vec()),
+                Err(ParseError::Client(msg)) => {
+                    Some(format!("CLIENT_ERROR {}\r\n", msg).into_bytes())
+                }
+            };
+            if let Some(reply) = reply {
+                self.guard
+                    .stats
+                    .net_output_bytes
+                    .fetch_add(reply.len() as u64, Ordering::Relaxed);
+                writer.write_all(&reply).await?;
+            }
+            // pipelined commands are replied with a single write
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+
+    fn record_input(&self, bytes: usize) {
+        self.guard
+            .stats
+            .net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // None when the client asked for noreply
+    async fn execute(
+        &self,
+        command: Command,
+        reader: &mut BufReader<OwnedReadHalf>,
+    ) -> ResultT<Option<Vec<u8>>> {
+        let name = match &command {
+            Command::Get(_) => "get",
+            Command::Set { .. } => "set",
+            Command::Delete { .. } => "delete",
+            Command::Incr { .. } => "incr",
+            Command::Version | Command::Quit => "version",
+        };
+        let stats = &self.guard.stats;
+        stats.record_request(1, name.to_owned(), self.guard.connected_at);
+        let (reply, noreply) = match command {
+            Command::Get(keys) => (self.get(keys).await?, false),
+            Command::Set { bytes, noreply, .. } if bytes > MAX_ITEM_SIZE => {
+                // the data block is discarded
+                let mut data = reader.take(bytes as u64 + 2);
+                let discarded = tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+                self.record_input(discarded as usize);
+                let reply = b"SERVER_ERROR object too large for cache\r\n".to_vec();
+                (reply, noreply)
+            }
+            Command::Set {
+                key,
+                flags,
+                exptime,
+                bytes,
+                noreply,
+            } => {
+                let mut data = vec![0; bytes + 2];
+                reader.read_exact(&mut data).await?;
+                self.record_input(data.len());
+                if !data.ends_with(b"\r\n") {
+                    return Err(ErrorT::from("Bad data chunk"));
+                }
+                data.truncate(bytes);
+                (self.set(key, flags, exptime, data).await?, noreply)
+            }
+            Command::Delete { key, noreply } => (self.delete(key).await?, noreply),
+            Command::Incr { key, by, noreply } => (self.incr(key, by).await?, noreply),
+            Command::Version | Command::Quit => (
+                format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+                false,
+            ),
+        };
+        Ok(if noreply { None } else { Some(reply) })
+    }
+
+    // the value and the flags of every key
+    async fn get(&self, keys: Vec<Vec<u8>>) -> ResultT<Vec<u8>> {
+        let mut requests = Vec::with_capacity(keys.len() * 2);
+        for k in &keys {
+            requests.push(command(vec![b"GET".to_vec(), k.clone()]));
+            requests.push(command(vec![b"MCFLAGS".to_vec(), k.clone()]));
+        }
+        let replies = match self.request(ClientReq::Pipeline(requests)).await? {
+            ClientReq::Pipeline(replies) => replies,
+            ClientReq::Single(_) => return Err(ErrorT::from("Unexpected single response")),
+        };
+        let mut out = Vec::new();
+        // keys holding other kinds of values are reported as missing
+        for (key, reply) in keys.iter().zip(replies.chunks(2)) {
+            if let [RESP::BulkString(value), flags] = reply {
+                let flags = match flags {
+                    RESP::Integer(flags) => *flags,
+                    _ => 0,
+                };
+                out.extend_from_slice(b"VALUE ");
+                out.extend_from_slice(key);
+                let header = format!(" {} {}\r\n", flags, value.len());
+                out.extend_from_slice(header.as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+        out.extend_from_slice(b"END\r\n");
+        Ok(out)
+    }
+
+    async fn set(
+        &self,
+        key: Vec<u8>,
+        flags: u32,
+        exptime: i64,
+        value: Vec<u8>,
+    ) -> ResultT<Vec<u8>> {
+        let reply = match expiration(exptime) {
+            // the flags are stored with the key, a SET drops the old ones
+            Some(options) => {
+                let mut args = vec![b"SET".to_vec(), key.clone(), value];
+                args.extend(options);
+                let flags = vec![b"MCFLAGS".to_vec(), key, flags.to_string().into_bytes()];
+                let requests = vec![command(args), command(flags)];
+                match self.request(ClientReq::Pipeline(requests)).await? {
+                    ClientReq::Pipeline(mut replies) if !replies.is_empty() => replies.remove(0),
+                    _ => return Err(ErrorT::from("Unexpected response")),
+                }
+            }
+            // already expired
+            None => self.single(vec![b"DEL".to_vec(), key]).await?,
+        };
+        Ok(match reply {
+            RESP::Error(_, msg) if msg.starts_with("invalid expire time") => {
+                b"CLIENT_ERROR invalid exptime argument\r\n".to_vec()
+            }
+            RESP::Error(kind, msg) => format!("SERVER_ERROR {} {}\r\n", kind, msg).into_bytes(),
+            _ => b"STORED\r\n".to_vec(),
+        })
+    }
+
+    async fn delete(&self, key: Vec<u8>) -> ResultT<Vec<u8>> {
+        Ok(match self.single(vec![b"DEL".to_vec(), key]).await? {
+            RESP::Integer(1) => b"DELETED\r\n".to_vec(),
+            _ => b"NOT_FOUND\r\n".to_vec(),
+        })
+    }
+
+    // memcached doesn't create missing keys. The check and the increment are two engine
+    // requests, a key deleted in between is recreated starting from 0.
+    async fn incr(&self, key: Vec<u8>, by: i64) -> ResultT<Vec<u8>> {
+        match self.single(vec![b"GET".to_vec(), key.clone()]).await? {
+            RESP::BulkString(_) => (),
+            _ => return Ok(b"NOT_FOUND\r\n".to_vec()),
+        }
+        let by = by.to_string().into_bytes();
+        Ok(
+            match self.single(vec![b"INCRBY".to_vec(), key, by]).await? {
+                RESP::Integer(value) => format!("{}\r\n", value).into_bytes(),
+                RESP::Error(kind, msg) => match RdisError::from_reply(&kind, &msg) {
+                    RdisError::NotInteger => {
+                        b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec()
+                    }
+                    err => format!("SERVER_ERROR {}\r\n", err).into_bytes(),
+                },
+                other => format!("SERVER_ERROR unexpected reply {:?}\r\n", other).into_bytes(),
+            },
+        )
+    }
+
+    async fn single(&self, args: Vec<Vec<u8>>) -> ResultT<RESP> {
+        match self.request(ClientReq::Single(command(args))).await? {
+            ClientReq::Single(reply) => Ok(reply),
+            ClientReq::Pipeline(_) => Err(ErrorT::from("Unexpected pipeline response")),
+        }
+    }
+
+    async fn request(&self, req: ClientReq) -> ResultT<ClientReq> {
+        self.api.request(self.guard.id, req).await
+    }
+}
+
+fn command(args: Vec<Vec<u8>>) -> RESP {
+    RESP::Array(
+        args.into_iter()
+            .map(|a| RESP::BulkString(Bytes::from(a)))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::client::RdisClient;
+    use crate::rdis::config::Config;
+    use crate::rdis::engine::RedisEngine;
+    use crate::rdis::metrics::Metrics;
+    use crate::rdis::storage::RedisData;
+    use tokio::sync::mpsc;
+
+    async fn exchange(stream: &mut TcpStream, request: &[u8], expected: &[u8]) -> ResultT<()> {
+        stream.write_all(request).await?;
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).await?;
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_memcached_session() -> ResultT<()> {
+        let (sender, receiver) = mpsc::channel(16);
+        let registry = Arc::new(ClientRegistry::new());
+        let mut engine = RedisEngine::new(
+            receiver,
+            registry.clone(),
+            Arc::new(Metrics::new()),
+            Box::new(RedisData::new()),
+            &Config::default(),
+        );
+        let engine_handle = tokio::spawn(async move { engine.start_loop().await });
+        let api = Arc::new(RedisEngineApi::new(sender));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(serve(listener, api.clone(), registry.clone()));
+        let client = RdisClient::new(api);
+
+        let mut stream = TcpStream::connect(addr).await?;
+        exchange(&mut stream, b"set k 7 0 5\r\nhello\r\n", b"STORED\r\n").await?;
+        exchange(
+            &mut stream,
+            b"get k missing\r\n",
+            b"VALUE k 7 5\r\nhello\r\nEND\r\n",
+        )
+        .await?;
+        // both protocols share the keyspace
+        assert_eq!(client.get("k").await?, Some(b"hello".to_vec()));
+        // the flags go with the value replaced through RESP
+        client.set("k", "hello").await?;
+        exchange(
+            &mut stream,
+            b"get k\r\n",
+            b"VALUE k 0 5\r\nhello\r\nEND\r\n",
+        )
+        .await?;
+        client.set("n", "41").await?;
+        exchange(
+            &mut stream,
+            b"incr n 1\r\nincr k 1\r\nincr x 1\r\n",
+            b"42\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\nNOT_FOUND\r\n",
+        )
+        .await?;
+        exchange(
+            &mut stream,
+            b"delete n noreply\r\ndelete n\r\n",
+            b"NOT_FOUND\r\n",
+        )
+        .await?;
+        exchange(
+            &mut stream,
+            b"set e 0 -1 1\r\nv\r\nget e\r\n",
+            b"STORED\r\nEND\r\n",
+        )
+        .await?;
+        exchange(
+            &mut stream,
+            b"set t 0 100 1\r\nv\r\nbogus\r\n",
+            b"STORED\r\nERROR\r\n",
+        )
+        .await?;
+        assert_eq!(client.get("t").await?, Some(b"v".to_vec()));
+        // the engine refuses the expiration, nothing is stored
+        exchange(
+            &mut stream,
+            b"set big 3 9223372036854775807 1\r\nv\r\nget big\r\n",
+            b"CLIENT_ERROR invalid exptime argument\r\nEND\r\n",
+        )
+        .await?;
+        assert_eq!(registry.len(), 1);
+        exchange(&mut stream, b"quit\r\n", b"").await?;
+        assert_eq!(stream.read(&mut [0; 1]).await?, 0);
+
+        server.abort();
+        registry.shutdown().await;
+        drop(client);
+        engine_handle.await?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_command() {
+        assert_eq!(
+            parse_command(b"get a  b"),
+            Ok(Command::Get(vec![b"a".to_vec(), b"b".to_vec()]))
+        );
+        assert_eq!(
+            parse_command(b"set k 5 0 3 noreply"),
+            Ok(Command::Set {
+                key: b"k".to_vec(),
+                flags: 5,
+                exptime: 0,
+                bytes: 3,
+                noreply: true
+            })
+        );
+        assert_eq!(
+            parse_command(b"incr n 2"),
+            Ok(Command::Incr {
+                key: b"n".to_vec(),
+                by: 2,
+                noreply: false
+            })
+        );
+        assert_eq!(
+            parse_command(b"delete k noreply"),
+            Ok(Command::Delete {
+                key: b"k".to_vec(),
+                noreply: true
+            })
+        );
+        assert_eq!(parse_command(b"quit"), Ok(Command::Quit));
+        assert_eq!(parse_command(b""), Err(ParseError::Unknown));
+        assert_eq!(parse_command(b"get"), Err(ParseError::Unknown));
+        assert_eq!(parse_command(b"gets k"), Err(ParseError::Unknown));
+        assert_eq!(parse_command(b"set k 0 0"), Err(BAD_FORMAT));
+        assert_eq!(parse_command(b"set k 0 0 x"), Err(BAD_FORMAT));
+        for line in [
+            &b"incr n -1"[..],
+            b"incr n x",
+            b"incr n 18446744073709551615",
+        ]
+        .iter()
+        {
+            assert!(matches!(parse_command(line), Err(ParseError::Client(_))));
+        }
+        let long_key = format!("get {}", "k".repeat(MAX_KEY_SIZE + 1));
+        assert_eq!(parse_command(long_key.as_bytes()), Err(BAD_FORMAT));
+    }
+
+    #[test]
+    pub fn test_expiration() {
+        assert_eq!(expiration(0), Some(vec![]));
+        assert_eq!(expiration(-1), None);
+        assert_eq!(expiration(60), Some(vec![b"EX".to_vec(), b"60".to_vec()]));
+        assert_eq!(
+            expiration(1_700_000_000),
+            Some(vec![b"EXAT".to_vec(), b"1700000000".to_vec()])
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use super::metrics::resident_memory;
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// bytes currently allocated through TrackingAllocator
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// counts the bytes allocated by the wrapped allocator, like zmalloc does in redis.
+// The rdis binary installs it as the global allocator around the system allocator, jemalloc
+// or mimalloc depending on the features; embedding applications can do the same:
+//
+//     #[global_allocator]
+//     static GLOBAL: TrackingAllocator<System> = TrackingAllocator::new(System);
+pub struct TrackingAllocator<A> {
+    inner: A,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> TrackingAllocator<A> {
+        TrackingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+// 0 when TrackingAllocator isn't the global allocator
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+pub const ALLOCATOR: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else {
+    "libc"
+};
+
+// statistics reported by INFO memory and MEMORY STATS
+pub struct MemoryStats {
+    // bytes requested by rdis, from TrackingAllocator
+    pub used: usize,
+    // resident set size of the process, 0 when unknown
+    pub rss: usize,
+    // (allocated, active, resident) as seen by the allocator, jemalloc only
+    pub allocator: Option<(usize, usize, usize)>,
+}
+
+impl MemoryStats {
+    pub fn collect() -> MemoryStats {
+        MemoryStats {
+            used: allocated(),
+            rss: resident_memory().unwrap_or(0) as usize,
+            allocator: allocator_stats(),
+        }
+    }
+
+    // rss / used, includes the allocator fragmentation as well as code and stacks
+    pub fn fragmentation_ratio(&self) -> f64 {
+        ratio(self.rss, self.used)
+    }
+
+    // active / allocated, the fragmentation inside the allocator pages
+    pub fn allocator_fragmentation_ratio(&self) -> Option<f64> {
+        self.allocator
+            .map(|(allocated, active, _)| ratio(active, allocated))
+    }
+
+    // (bytes, percent) wasted by fragmentation, from the allocator when it reports it or else
+    // from rss and used. None when neither is known
+    pub fn fragmentation(&self) -> Option<(usize, f64)> {
+        let (used, total) = match self.allocator {
+            Some((allocated, active, _)) => (allocated, active),
+            None if self.used > 0 && self.rss > 0 => (self.used, self.rss),
+            None => return None,
+        };
+        let percent = (ratio(total, used) - 1.0).max(0.0) * 100.0;
+        Some((total.saturating_sub(used), percent))
+    }
+}
+
+fn ratio(a: usize, b: usize) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> Option<(usize, usize, usize)> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // statistics are cached by jemalloc until the epoch is advanced
+    epoch::advance().ok()?;
+    Some((
+        stats::allocated::read().ok()?,
+        stats::active::read().ok()?,
+        stats::resident::read().ok()?,
+    ))
+}
+
+#[cfg(not(feature = "jemalloc"))]
+fn allocator_stats() -> Option<(usize, usize, usize)> {
+    None
+}
+
+// 1.50M, like the _human fields of INFO memory
+pub fn human_bytes(bytes: usize) -> String {
+    let units = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    for (size, unit) in units.iter() {
+        if bytes >= *size {
+            return format!("{:.2}{}", bytes as f64 / *size as f64, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    pub fn test_tracking_allocator() {
+        let allocator = TrackingAllocator::new(System);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let before = allocated();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert_eq!(allocated(), before + 64);
+            let ptr = allocator.realloc(ptr, layout, 256);
+            assert_eq!(allocated(), before + 256);
+            allocator.dealloc(ptr, Layout::from_size_align(256, 8).unwrap());
+        }
+        assert_eq!(allocated(), before);
+    }
+
+    #[test]
+    pub fn test_human_bytes() {
+        assert_eq!(human_bytes(512), "512B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(3 << 30), "3.00G");
+    }
+
+    #[test]
+    pub fn test_memory_stats() {
+        let stats = MemoryStats {
+            used: 100,
+            rss: 150,
+            allocator: Some((100, 120, 140)),
+        };
+        assert_eq!(stats.fragmentation_ratio(), 1.5);
+        assert_eq!(stats.allocator_fragmentation_ratio(), Some(1.2));
+        assert!(MemoryStats::collect().rss > 0 || cfg!(not(target_os = "linux")));
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt::Write;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// upper bounds of the histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+// requests dequeued by the engine at every wakeup, up to MAX_BATCH
+const BATCH_BUCKETS: [f64; 8] = [1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0];
+
+// cumulative histogram with fixed buckets, updated without locks.
+// The sum is kept as an integer of value * scale: microseconds for latencies.
+pub struct Histogram {
+    bounds: &'static [f64],
+    scale: f64,
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    // latencies in seconds
+    pub fn new() -> Histogram {
+        Histogram::with_buckets(&LATENCY_BUCKETS, 1_000_000.0)
+    }
+
+    pub fn sizes() -> Histogram {
+        Histogram::with_buckets(&BATCH_BUCKETS, 1.0)
+    }
+
+    fn with_buckets(bounds: &'static [f64], scale: f64) -> Histogram {
+        Histogram {
+            bounds,
+            scale,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        self.record(elapsed.as_secs_f64());
+    }
+
+    pub fn record(&self, value: f64) {
+        if let Some(idx) = self.bounds.iter().position(|b| value <= *b) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum
+            .fetch_add((value * self.scale).round() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / self.scale;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+// shared between the engine, the connections and the admin endpoint
+pub struct Metrics {
+    pub commands_total: AtomicU64,
+    pub command_errors_total: AtomicU64,
+    pub keys: AtomicU64,
+    pub expires: AtomicU64,
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+    // time spent by the engine executing a single command
+    pub command_duration: Histogram,
+    // time between a request being read and the engine response, seen by the connection
+    pub request_duration: Histogram,
+    // requests handled by the engine at every wakeup
+    pub engine_batch_size: Histogram,
+}
+
+// values not owned by Metrics, collected when rendering
+pub struct Snapshot {
+    pub connected_clients: usize,
+    pub connections_total: usize,
+    // (command, quantile, microseconds) from INFO latencystats
+    pub command_latency: Vec<(String, f64, f64)>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            commands_total: AtomicU64::new(0),
+            command_errors_total: AtomicU64::new(0),
+            keys: AtomicU64::new(0),
+            expires: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            command_duration: Histogram::new(),
+            request_duration: Histogram::new(),
+            engine_batch_size: Histogram::sizes(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    // prometheus text exposition format
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::with_capacity(4096);
+        let counter = |out: &mut String, name: &str, help: &str, v: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, v);
+        };
+        let gauge = |out: &mut String, name: &str, help: &str, v: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, v);
+        };
+        counter(
+            &mut out,
+            "rdis_commands_total",
+            "Commands processed by the engine.",
+            self.commands_total.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "rdis_command_errors_total",
+            "Commands replied with an error.",
+            self.command_errors_total.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "rdis_net_input_bytes_total",
+            "Bytes of requests read from clients.",
+            self.net_input_bytes.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "rdis_net_output_bytes_total",
+            "Bytes of responses written to clients.",
+            self.net_output_bytes.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "rdis_connections_total",
+            "Connections accepted since startup.",
+            snapshot.connections_total as u64,
+        );
+        gauge(
+            &mut out,
+            "rdis_connected_clients",
+            "Currently open client connections.",
+            snapshot.connected_clients as u64,
+        );
+        gauge(
+            &mut out,
+            "rdis_keys",
+            "Keys in the keyspace.",
+            self.keys.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "rdis_expires",
+            "Keys with an expiration set.",
+            self.expires.load(Ordering::Relaxed),
+        );
+        if let Some(rss) = resident_memory() {
+            gauge(
+                &mut out,
+                "rdis_resident_memory_bytes",
+                "Resident set size of the process.",
+                rss,
+            );
+        }
+        self.command_duration.render(
+            &mut out,
+            "rdis_command_duration_seconds",
+            "Execution time of a single command in the engine.",
+        );
+        self.request_duration.render(
+            &mut out,
+            "rdis_request_duration_seconds",
+            "Time from a request being read to the engine response, pipelines included.",
+        );
+        self.engine_batch_size.render(
+            &mut out,
+            "rdis_engine_batch_size",
+            "Requests dequeued by the engine at every wakeup.",
+        );
+        if !snapshot.command_latency.is_empty() {
+            let name = "rdis_command_latency_microseconds";
+            let _ = writeln!(out, "# HELP {} Per-command latency percentiles.", name);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (command, quantile, usec) in snapshot.command_latency.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{command=\"{}\",quantile=\"{}\"}} {}",
+                    name, command, quantile, usec
+                );
+            }
+        }
+        out
+    }
+}
+
+// VmRSS from /proc, None on other platforms
+pub fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_histogram() {
+        let h = Histogram::new();
+        h.observe(Duration::from_micros(5));
+        h.observe(Duration::from_micros(70));
+        h.observe(Duration::from_secs(2));
+        let mut out = String::new();
+        h.render(&mut out, "lat", "help");
+        assert!(out.contains("lat_bucket{le=\"0.00001\"} 1\n"));
+        assert!(out.contains("lat_bucket{le=\"0.0001\"} 2\n"));
+        assert!(out.contains("lat_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("lat_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("lat_sum 2.000075\n"));
+        assert!(out.contains("lat_count 3\n"));
+
+        let sizes = Histogram::sizes();
+        sizes.record(1.0);
+        sizes.record(3.0);
+        let mut out = String::new();
+        sizes.render(&mut out, "batch", "help");
+        assert!(out.contains("batch_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("batch_bucket{le=\"4\"} 2\n"));
+        assert!(out.contains("batch_sum 4\n"));
+    }
+
+    #[test]
+    pub fn test_render() {
+        let metrics = Metrics::new();
+        metrics.commands_total.fetch_add(3, Ordering::Relaxed);
+        let out = metrics.render(&Snapshot {
+            connected_clients: 2,
+            connections_total: 5,
+            command_latency: vec![("get".to_owned(), 0.99, 1.5)],
+        });
+        assert!(out.contains("# TYPE rdis_commands_total counter\nrdis_commands_total 3\n"));
+        assert!(out.contains("rdis_connected_clients 2\n"));
+        assert!(out.contains("rdis_connections_total 5\n"));
+        assert!(out.contains(
+            "rdis_command_latency_microseconds{command=\"get\",quantile=\"0.99\"} 1.5\n"
+        ));
+    }
+}
@@ -1,4 +1,49 @@
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod affinity;
+pub mod audit;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod commands;
+pub mod config;
+pub mod convert;
+pub mod daemon;
+pub mod defrag;
 pub mod engine;
+pub mod error;
+pub mod events;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod glob;
+pub mod hotkeys;
+pub mod list;
+pub mod logging;
+#[cfg(feature = "memcached")]
+pub mod memcached;
+pub mod memory;
+pub mod metrics;
+pub mod module;
 pub mod parser;
+pub mod preload;
 pub mod protocol;
+pub mod radix;
+pub mod ratelimit;
+pub mod readcache;
+pub mod readthrough;
+pub mod recorder;
+pub mod registry;
+pub mod scan;
+pub mod server;
+pub mod session;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+pub mod systemd;
+pub mod tiered;
 pub mod types;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod wiretrace;
+pub mod writebehind;
+pub mod zset;
@@ -0,0 +1,232 @@
+use super::protocol::RESP;
+use super::storage::{Key, RawValue, Storage};
+use super::types::ResultT;
+use std::time::Instant;
+
+// a command added by an application embedding rdis, registered with
+// RdisServerBuilder::command. It runs on the engine loop like the builtin commands,
+// so execute must not block.
+pub trait CustomCommand: Send {
+    // case insensitive, it can't shadow a builtin command
+    fn name(&self) -> &str;
+    // args exclude the command name, inline arguments are converted to bulk strings
+    fn execute(&mut self, ctx: &mut CommandContext, args: &[RawValue]) -> RESP;
+}
+
+// keyspace access for custom commands, expiration is handled like for builtin commands
+pub struct CommandContext<'a> {
+    storage: &'a mut dyn Storage,
+    client: usize,
+    t: u64,
+    deadline: Option<Instant>,
+    // keys passed to the methods modifying the keyspace
+    touched: Vec<Key>,
+}
+
+impl<'a> CommandContext<'a> {
+    pub(crate) fn new(
+        storage: &'a mut dyn Storage,
+        client: usize,
+        t: u64,
+        deadline: Option<Instant>,
+    ) -> CommandContext<'a> {
+        CommandContext {
+            storage,
+            client,
+            t,
+            deadline,
+            touched: Vec::new(),
+        }
+    }
+
+    pub(crate) fn into_touched(self) -> Vec<Key> {
+        self.touched
+    }
+
+    // true once the command ran longer than command-time-budget. Long commands should check it
+    // between steps and reply with RdisError::Timeout.to_resp()
+    pub fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn client_id(&self) -> usize {
+        self.client
+    }
+
+    // current time in millis, as used for expiration
+    pub fn time(&self) -> u64 {
+        self.t
+    }
+
+    pub fn get(&mut self, k: &RawValue) -> ResultT<Option<RawValue>> {
+        self.storage.get(k, self.t)
+    }
+
+    pub fn set(&mut self, k: Key, v: RawValue) {
+        self.touched.push(k.clone());
+        self.storage.set(k, v, None)
+    }
+
+    pub fn incr_by(&mut self, k: Key, by: i64) -> ResultT<i64> {
+        self.touched.push(k.clone());
+        self.storage.incr_by(k, by, self.t)
+    }
+
+    pub fn del(&mut self, k: &RawValue) -> bool {
+        self.touched.push(k.clone());
+        self.storage.del(k, self.t)
+    }
+
+    pub fn l_push(&mut self, k: Key, v: RawValue) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.l_push(k, v, None, self.t)
+    }
+
+    pub fn r_push(&mut self, k: Key, v: RawValue) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.r_push(k, v, None, self.t)
+    }
+
+    pub fn l_pop(&mut self, k: &RawValue) -> ResultT<Option<RawValue>> {
+        self.touched.push(k.clone());
+        self.storage.l_pop(k, self.t)
+    }
+
+    pub fn r_pop(&mut self, k: &RawValue) -> ResultT<Option<RawValue>> {
+        self.touched.push(k.clone());
+        self.storage.r_pop(k, self.t)
+    }
+
+    pub fn l_range(&mut self, k: &RawValue, start: i64, stop: i64) -> ResultT<Vec<RawValue>> {
+        self.storage.l_range(k, start, stop, self.t)
+    }
+
+    pub fn h_set(&mut self, k: Key, pairs: Vec<(RawValue, RawValue)>) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.h_set(k, pairs, self.t)
+    }
+
+    pub fn h_get(&mut self, k: &RawValue, fields: &[RawValue]) -> ResultT<Vec<Option<RawValue>>> {
+        self.storage.h_get(k, fields, self.t)
+    }
+
+    pub fn h_del(&mut self, k: &RawValue, fields: &[RawValue]) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.h_del(k, fields, self.t)
+    }
+
+    pub fn h_get_all(&mut self, k: &RawValue) -> ResultT<Vec<(RawValue, RawValue)>> {
+        self.storage.h_get_all(k, self.t)
+    }
+
+    pub fn s_add(&mut self, k: Key, members: Vec<RawValue>) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.s_add(k, members, self.t)
+    }
+
+    pub fn s_rem(&mut self, k: &RawValue, members: &[RawValue]) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.s_rem(k, members, self.t)
+    }
+
+    pub fn s_members(&mut self, k: &RawValue) -> ResultT<Vec<RawValue>> {
+        self.storage.s_members(k, self.t)
+    }
+
+    pub fn s_is_member(&mut self, k: &RawValue, member: &RawValue) -> ResultT<bool> {
+        self.storage.s_is_member(k, member, self.t)
+    }
+
+    pub fn z_add(&mut self, k: Key, members: Vec<(f64, RawValue)>) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.z_add(k, members, self.t)
+    }
+
+    pub fn z_rem(&mut self, k: &RawValue, members: &[RawValue]) -> ResultT<usize> {
+        self.touched.push(k.clone());
+        self.storage.z_rem(k, members, self.t)
+    }
+
+    pub fn z_score(&mut self, k: &RawValue, member: &RawValue) -> ResultT<Option<f64>> {
+        self.storage.z_score(k, member, self.t)
+    }
+
+    pub fn z_range(
+        &mut self,
+        k: &RawValue,
+        start: i64,
+        stop: i64,
+    ) -> ResultT<Vec<(RawValue, f64)>> {
+        self.storage.z_range(k, start, stop, self.t)
+    }
+
+    pub fn keys_count(&self) -> usize {
+        self.storage.keys_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::client::RdisClient;
+    use crate::rdis::config::Config;
+    use crate::rdis::engine::RedisEngine;
+    use crate::rdis::metrics::Metrics;
+    use crate::rdis::registry::ClientRegistry;
+    use crate::rdis::storage::RedisData;
+    use crate::rdis::types::{RdisError, RedisEngineApi};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    // GETSET key value
+    struct GetSet;
+
+    impl CustomCommand for GetSet {
+        fn name(&self) -> &str {
+            "getset"
+        }
+
+        fn execute(&mut self, ctx: &mut CommandContext, args: &[RawValue]) -> RESP {
+            match args {
+                [k, v] => match ctx.get(k) {
+                    Ok(old) => {
+                        ctx.set(k.clone(), v.clone());
+                        old.map_or(RESP::Null, RESP::BulkString)
+                    }
+                    Err(err) => err.to_resp(),
+                },
+                _ => RdisError::WrongArity("getset".into()).to_resp(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_custom_command() -> ResultT<()> {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut engine = RedisEngine::new(
+            receiver,
+            Arc::new(ClientRegistry::new()),
+            Arc::new(Metrics::new()),
+            Box::new(RedisData::new()),
+            &Config::default(),
+        );
+        engine.register_command(Box::new(GetSet))?;
+        assert!(engine.register_command(Box::new(GetSet)).is_err());
+        let engine_handle = tokio::spawn(async move { engine.start_loop().await });
+        let client = RdisClient::new(Arc::new(RedisEngineApi::new(sender)));
+
+        assert_eq!(client.command(&["GETSET", "k", "1"]).await?, RESP::Null);
+        assert_eq!(
+            client.command(&["getset", "k", "2"]).await?,
+            RESP::BulkString(Bytes::from_static(b"1"))
+        );
+        assert_eq!(client.get("k").await?, Some(b"2".to_vec()));
+        assert!(client.command(&["GETSET", "k"]).await.is_err());
+
+        drop(client);
+        engine_handle.await?;
+        Ok(())
+    }
+}
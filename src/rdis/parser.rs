@@ -8,8 +8,8 @@ use nom::{
     multi::{count, separated_list1},
     sequence::{preceded, terminated, tuple},
 };
+use ::bytes::Bytes;
 use std::convert::TryInto;
-use std::sync::Arc;
 
 
 #[inline]
@@ -49,7 +49,7 @@ fn read_bulk(bytes: &[u8]) -> IResult<&[u8], RESP> {
     if size > 0 {
         let us: u64 = size.try_into().unwrap();
         terminated(
-            map(take(us), |b: &[u8]| RESP::BulkString(Arc::new(b.into()))),
+            map(take(us), |b: &[u8]| RESP::BulkString(Bytes::copy_from_slice(b))),
             crlf,
         )(rem)
     } else {
@@ -122,7 +122,6 @@ pub fn read(bytes: &[u8]) -> IResult<&[u8], RESP> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::sync::Arc;
     #[test]
     pub fn test_read_simple() {
         let res = read(b"+OK!! \r\n").unwrap();
@@ -134,7 +133,7 @@ mod test {
     pub fn test_read_bulk_easy() {
         let res = read(b"$5\r\nhello\r\n").unwrap();
         assert_eq!(res.0.len(), 0);
-        assert_eq!(RESP::BulkString(Arc::new("hello".into())), res.1);
+        assert_eq!(RESP::BulkString(Bytes::from_static(b"hello")), res.1);
     }
 
     #[test]
@@ -147,6 +146,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::single_match)]
     pub fn test_read_decimal_should_fail() {
         match read(b"c299") {
             Ok(_) => panic!("test failed"),
@@ -173,8 +173,8 @@ mod test {
     pub fn test_read_array() {
         assert_eq!(
             RESP::Array(vec![
-                RESP::BulkString(Arc::new("hello".into())),
-                RESP::BulkString(Arc::new("world".into()))
+                RESP::BulkString(Bytes::from_static(b"hello")),
+                RESP::BulkString(Bytes::from_static(b"world"))
             ]),
             read_array(b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
                 .unwrap()
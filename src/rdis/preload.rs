@@ -0,0 +1,168 @@
+use super::protocol::{read_reply, RESP};
+use super::types::{RdisError, ResultT};
+use bytes::Bytes;
+use serde_json::Value as Json;
+
+// fixtures run by the engine when the preload directive is set, before the listener accepts
+// any client, so tests and staging environments boot with known data. The file holds either
+//   - commands in RESP, like an appendonly file or a record-file, whose times are dropped
+//   - JSON: an array of commands, [["SET", "k", "v"], ["SADD", "s", "a", "b"]], or an object
+//     of keys, {"k": "v", "n": 1, "l": ["a", "b"], "h": {"f": "v"}}: strings and numbers are
+//     SET, arrays pushed with RPUSH and objects written with HSET
+// A file starting with [ or {, after the blanks, is JSON.
+pub async fn read_fixtures(path: &str) -> ResultT<Vec<Vec<RESP>>> {
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|e| RdisError::from(format!("Cannot read preload file {}: {}", path, e)))?;
+    let fixtures = match content.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[') | Some(b'{') => json_commands(&content),
+        _ => resp_commands(&content).await,
+    };
+    fixtures.map_err(|e| RdisError::from(format!("Invalid preload file {}: {}", path, e)))
+}
+
+async fn resp_commands(mut content: &[u8]) -> ResultT<Vec<Vec<RESP>>> {
+    let mut commands = Vec::new();
+    while !content.is_empty() {
+        match read_reply(&mut content).await? {
+            RESP::Array(mut command) => {
+                if let Some(RESP::Integer(_)) = command.first() {
+                    command.remove(0);
+                }
+                commands.push(command);
+            }
+            other => return Err(RdisError::from(format!("not a command: {:?}", other))),
+        }
+    }
+    Ok(commands)
+}
+
+fn json_commands(content: &[u8]) -> ResultT<Vec<Vec<RESP>>> {
+    // nesting deeper than 128 levels is an error, not a stack overflow
+    let fixtures: Json =
+        serde_json::from_slice(content).map_err(|e| RdisError::from(e.to_string()))?;
+    let bulk = |s: &str| RESP::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+    let mut commands = Vec::new();
+    match fixtures {
+        Json::Array(items) => {
+            for item in items {
+                match item {
+                    Json::Array(args) if !args.is_empty() => {
+                        let args = args.iter().map(|a| scalar(a).map(|a| bulk(&a)));
+                        commands.push(args.collect::<ResultT<_>>()?);
+                    }
+                    _ => return Err(RdisError::from("commands must be non empty arrays")),
+                }
+            }
+        }
+        Json::Object(keys) => {
+            for (k, value) in keys {
+                let mut command = match &value {
+                    Json::Array(_) => vec![bulk("RPUSH"), bulk(&k)],
+                    Json::Object(_) => vec![bulk("HSET"), bulk(&k)],
+                    _ => vec![bulk("SET"), bulk(&k), bulk(&scalar(&value)?)],
+                };
+                match value {
+                    Json::Array(items) => {
+                        for item in &items {
+                            command.push(bulk(&scalar(item)?));
+                        }
+                    }
+                    Json::Object(fields) => {
+                        for (field, v) in &fields {
+                            command.push(bulk(field));
+                            command.push(bulk(&scalar(v)?));
+                        }
+                    }
+                    _ => (),
+                }
+                // an empty list or hash is no key at all
+                if command.len() > 2 {
+                    commands.push(command);
+                }
+            }
+        }
+        _ => return Err(RdisError::from("expected an array or an object")),
+    }
+    Ok(commands)
+}
+
+// the bytes of a value: numbers keep the digits of the file, only an exponent gains its sign,
+// 1e3 is written 1e+3
+fn scalar(value: &Json) -> ResultT<String> {
+    match value {
+        Json::String(s) => Ok(s.clone()),
+        Json::Number(n) => Ok(n.to_string()),
+        other => Err(RdisError::from(format!(
+            "expected a string or a number, found {}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(fixtures: Vec<Vec<RESP>>) -> Vec<Vec<String>> {
+        let arg = |a: RESP| match a {
+            RESP::BulkString(b) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        let command = |c: Vec<RESP>| c.into_iter().map(arg).collect();
+        fixtures.into_iter().map(command).collect()
+    }
+
+    #[test]
+    pub fn test_json() -> ResultT<()> {
+        let fixtures = br#"
+            {"greeting": "h\u00e9llo \"x\"", "n": -1.5e3, "l": ["a", 2], "h": {"f": "v"}, "e": []}
+        "#;
+        assert_eq!(
+            commands(json_commands(fixtures)?),
+            vec![
+                vec!["SET", "greeting", "h\u{e9}llo \"x\""],
+                vec!["SET", "n", "-1.5e+3"],
+                vec!["RPUSH", "l", "a", "2"],
+                vec!["HSET", "h", "f", "v"],
+            ]
+        );
+        let fixtures = br#"[["SADD", "s", "a", "b"], ["set", "k", "\ud83d\ude00", 0.1]]"#;
+        assert_eq!(
+            commands(json_commands(fixtures)?),
+            vec![
+                vec!["SADD", "s", "a", "b"],
+                vec!["set", "k", "\u{1f600}", "0.1"]
+            ]
+        );
+        for invalid in [
+            &br#"{"k": "v""#[..],
+            br#"{"k": "v",}"#,
+            br#"{"k": true}"#,
+            br#"{"k": [["nested"]]}"#,
+            br#"[["SET", "k", null]]"#,
+            br#"[[]]"#,
+            br#"["SET"]"#,
+            br#"{"k": "\x"}"#,
+            br#"{"k": "\ud83d"}"#,
+            br#"{"k": 1} 2"#,
+            &[b'['; 10_000],
+        ] {
+            assert!(json_commands(invalid).is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_resp() -> ResultT<()> {
+        // an appendonly file entry and a record-file one
+        let fixtures = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n:1650000000000\r\n$4\r\nINCR\r\n$1\r\na\r\n";
+        assert_eq!(
+            commands(resp_commands(fixtures).await?),
+            vec![vec!["SET", "a", "1"], vec!["INCR", "a"]]
+        );
+        assert!(resp_commands(b":1\r\n").await.is_err());
+        assert!(resp_commands(b"*2\r\n$3\r\nGET\r\n").await.is_err());
+        Ok(())
+    }
+}
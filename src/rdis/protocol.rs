@@ -1,25 +1,133 @@
 use super::parser;
 use super::types::*;
 use async_recursion::async_recursion;
-use bytes::{Buf, BytesMut};
-use log::{warn};
+use bytes::{Buf, Bytes, BytesMut};
+use log::warn;
 use std::fmt::Debug;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter,
+};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 
+// the encodings of 0..SHARED_INTEGERS followed by CRLF, like the shared integers of redis.
+// Integer replies and the bulk string and array headers below the limit are written from
+// here instead of formatting a String each time.
+const SHARED_INTEGERS: usize = 10_000;
+
+struct SharedIntegers {
+    encodings: Vec<u8>,
+    // encoding of n at offsets[n]..offsets[n + 1]
+    offsets: Vec<usize>,
+}
+
+impl SharedIntegers {
+    fn new() -> SharedIntegers {
+        let mut encodings = Vec::new();
+        let mut offsets = Vec::with_capacity(SHARED_INTEGERS + 1);
+        for n in 0..SHARED_INTEGERS {
+            offsets.push(encodings.len());
+            encodings.extend_from_slice(n.to_string().as_bytes());
+            encodings.extend_from_slice(&CRLF);
+        }
+        offsets.push(encodings.len());
+        SharedIntegers { encodings, offsets }
+    }
+}
+
+fn shared_integer(int: i64) -> Option<&'static [u8]> {
+    static SHARED: OnceLock<SharedIntegers> = OnceLock::new();
+    if !(0..SHARED_INTEGERS as i64).contains(&int) {
+        return None;
+    }
+    let shared = SHARED.get_or_init(SharedIntegers::new);
+    let n = int as usize;
+    Some(&shared.encodings[shared.offsets[n]..shared.offsets[n + 1]])
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum RESP {
     SimpleString(Vec<u8>),
     Error(String, String),
     Integer(i64),
-    BulkString(Arc<Vec<u8>>),
+    BulkString(Bytes),
     Array(Vec<RESP>),
     Null,
+    // RESP3 attributes, (key, value) pairs of metadata sent ahead of the reply they describe.
+    // Only RESP3 clients receive them, see without_attributes
+    Attribute(Vec<(RESP, RESP)>, Box<RESP>),
 }
 
 impl RESP {
+    pub fn with_attributes(self, attributes: Vec<(RESP, RESP)>) -> RESP {
+        RESP::Attribute(attributes, Box::new(self))
+    }
+
+    // the reply as sent to a RESP2 client, attributes are dropped at any depth
+    pub fn without_attributes(self) -> RESP {
+        match self {
+            RESP::Attribute(_, reply) => reply.without_attributes(),
+            RESP::Array(items) if items.iter().any(RESP::has_attributes) => {
+                RESP::Array(items.into_iter().map(RESP::without_attributes).collect())
+            }
+            other => other,
+        }
+    }
+
+    fn has_attributes(&self) -> bool {
+        match self {
+            RESP::Attribute(_, _) => true,
+            RESP::Array(items) => items.iter().any(RESP::has_attributes),
+            _ => false,
+        }
+    }
+
+    // command name and number of arguments of a request, for logging and CLIENT LIST
+    pub fn describe_command(&self) -> (String, usize) {
+        let name = |n: &[u8]| String::from_utf8_lossy(n).to_uppercase();
+        match self {
+            RESP::Array(parts) => match parts.first() {
+                Some(RESP::BulkString(n)) => (name(n), parts.len() - 1),
+                Some(RESP::SimpleString(n)) => (name(n), parts.len() - 1),
+                _ => ("?".to_owned(), parts.len().saturating_sub(1)),
+            },
+            RESP::BulkString(n) => (name(n), 0),
+            RESP::SimpleString(n) => (name(n), 0),
+            _ => ("?".to_owned(), 0),
+        }
+    }
+
+    // bytes written by write_async
+    pub fn encoded_len(&self) -> usize {
+        let digits = |n: i64| match shared_integer(n) {
+            Some(encoded) => encoded.len() - CRLF.len(),
+            None => n.to_string().len(),
+        };
+        match self {
+            RESP::SimpleString(s) => s.len() + 3,
+            RESP::Error(err_type, err) => err_type.len() + err.len() + 4,
+            RESP::Integer(int) => digits(*int) + 3,
+            RESP::BulkString(s) => digits(s.len() as i64) + s.len() + 5,
+            RESP::Array(vec) => {
+                digits(vec.len() as i64) + 3 + vec.iter().map(RESP::encoded_len).sum::<usize>()
+            }
+            RESP::Null => NULL_MSG.len(),
+            RESP::Attribute(attributes, reply) => {
+                digits(attributes.len() as i64)
+                    + 3
+                    + attributes
+                        .iter()
+                        .map(|(k, v)| k.encoded_len() + v.encoded_len())
+                        .sum::<usize>()
+                    + reply.encoded_len()
+            }
+        }
+    }
+
     pub async fn write_end<W>(b: &mut W) -> ResultT<()>
     where
         W: AsyncWriteExt + Unpin,
@@ -30,6 +138,22 @@ impl RESP {
         Ok(())
     }
 
+    // integer replies and length headers, followed by CRLF
+    async fn write_integer<W>(writer: &mut W, prefix: u8, int: i64) -> ResultT<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        writer.write_u8(prefix).await?;
+        match shared_integer(int) {
+            Some(encoded) => writer.write_all(encoded).await?,
+            None => {
+                writer.write_all(int.to_string().as_bytes()).await?;
+                RESP::write_end(writer).await?;
+            }
+        }
+        Ok(())
+    }
+
     #[async_recursion]
     pub async fn write_async<W>(self, writer: &mut W, flush: bool) -> ResultT<()>
     where
@@ -38,38 +162,37 @@ impl RESP {
         match self {
             RESP::SimpleString(s) => {
                 writer.write_u8(b'+').await?;
-                writer.write_all(&s.as_slice()).await?;
+                writer.write_all(s.as_ref()).await?;
                 RESP::write_end(writer).await?;
             }
             RESP::Error(err_type, err) => {
                 writer.write_u8(b'-').await?;
-                writer.write_all(&err_type.as_bytes()).await?;
-                writer.write_all(&err.as_bytes()).await?;
-                RESP::write_end(writer).await?;
-            }
-            RESP::Integer(int) => {
-                let string_rep: String = int.to_string();
-                writer.write_u8(b':').await?;
-                writer.write_all(&string_rep.as_bytes()).await?;
+                writer.write_all(err_type.as_bytes()).await?;
+                writer.write_u8(b' ').await?;
+                writer.write_all(err.as_bytes()).await?;
                 RESP::write_end(writer).await?;
             }
+            RESP::Integer(int) => RESP::write_integer(writer, b':', int).await?,
             RESP::BulkString(s) => {
-                let len = s.len().to_string();
-                writer.write_u8(b'$').await?;
-                writer.write_all(&len.as_bytes()).await?;
-                RESP::write_end(writer).await?;
+                RESP::write_integer(writer, b'$', s.len() as i64).await?;
                 writer.write_all(&s).await?;
                 RESP::write_end(writer).await?;
             }
             RESP::Array(mut vec) => {
-                writer.write_u8(b'*').await?;
-                writer.write_all(&vec.len().to_string().as_bytes()).await?;
-                RESP::write_end(writer).await?;
+                RESP::write_integer(writer, b'*', vec.len() as i64).await?;
                 for el in vec.drain(0..vec.len()) {
                     el.write_async(writer, false).await?;
                 }
             }
             RESP::Null => writer.write_all(NULL_MSG).await?,
+            RESP::Attribute(attributes, reply) => {
+                RESP::write_integer(writer, b'|', attributes.len() as i64).await?;
+                for (k, v) in attributes {
+                    k.write_async(writer, false).await?;
+                    v.write_async(writer, false).await?;
+                }
+                reply.write_async(writer, false).await?;
+            }
         };
         if flush {
             writer.flush().await?;
@@ -85,16 +208,11 @@ pub struct RedisCmd<R, W> {
     // pub stream: TcpStream,
     writer: W,
     reader: R,
-    buff: BytesMut,
-    client_epoch: usize,
-    pipelined_request: Vec<RESP>,
+    decoder: FrameDecoder,
 }
 
-impl RedisCmd<OwnedReadHalf, BufWriter<OwnedWriteHalf>> {
-    pub fn from_stream(
-        stream: TcpStream,
-        client_epoch: usize,
-    ) -> RedisCmd<OwnedReadHalf, BufWriter<OwnedWriteHalf>> {
+impl TcpCmd {
+    pub fn from_stream(stream: TcpStream, client_epoch: usize) -> TcpCmd {
         let (reader, writer) = stream.into_split();
         RedisCmd::new(reader, BufWriter::new(writer), client_epoch)
     }
@@ -105,46 +223,213 @@ impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send + Debug> RedisCmd
         RedisCmd {
             writer: w,
             reader: r,
-            buff: BytesMut::with_capacity(4096),
-            client_epoch,
-            pipelined_request: Vec::with_capacity(1024),
+            decoder: FrameDecoder::new(client_epoch),
         }
     }
     // requests are read all togethere, in order to minimize write operations as well
     pub async fn read_async(&mut self) -> ResultT<ClientReq> {
         loop {
-            match self.parse_frame() {
-                Ok(resp) => {
-                    if let Some(r) = resp {
-                        self.pipelined_request.push(r);
-                    }
-                }
-                Err(_) => {
-                    if !self.pipelined_request.is_empty() {
-                        // info!("returning req #{}", self.pipelined_request.len());
-                        return Ok(self.fill_output_pipeline_req());
-                    } else {
-                        if self.buff.capacity() == 0 {
-                            self.buff.reserve(2 * self.buff.len());
-                            warn!("Expanding buffer to {}", self.buff.len());
-                        }
-                        // let previous_capacity = self.buff.capacity();
-                        let n = self.reader.read_buf(&mut self.buff).await?;
-                        // info!(
-                        //     "capacity = {}, Read {} bytes from socket from client {},",
-                        //     previous_capacity, n, self.client_epoch
-                        // );
-                        if n == 0 {
-                            // The remote closed the connection. For this to be
-                            // a clean shutdown, there should be no data in the
-                            // read buffer. If there is, this means that the
-                            // peer closed the socket while sending a frame.
-                            return Ok(self.fill_output_pipeline_req());
-                        }
-                    }
+            if let Some(req) = self.decoder.decode() {
+                return Ok(req);
+            }
+            let n = self.reader.read_buf(self.decoder.read_buffer()).await?;
+            if n == 0 {
+                // The remote closed the connection. For this to be
+                // a clean shutdown, there should be no data in the
+                // read buffer. If there is, this means that the
+                // peer closed the socket while sending a frame.
+                return Ok(self.decoder.finish());
+            }
+            self.decoder.check_limit()?;
+        }
+    }
+
+    pub async fn write_async(&mut self, resp: RESP, flush: bool) -> ResultT<()> {
+        resp.write_async(&mut self.writer, flush).await
+    }
+}
+
+// reads a single reply on the client side (tests, rdis-bench), requests are decoded by FrameDecoder
+#[async_recursion]
+pub async fn read_reply<R>(reader: &mut R) -> ResultT<RESP>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(RdisError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    let line = line.trim_end_matches("\r\n");
+    if line.is_empty() {
+        return Err(RdisError::Protocol("empty reply".to_owned()));
+    }
+    let (kind, rest) = line.split_at(1);
+    Ok(match kind {
+        "+" => RESP::SimpleString(rest.as_bytes().to_vec()),
+        "-" => {
+            let (kind, msg) = rest.split_once(' ').unwrap_or((rest, ""));
+            RESP::Error(kind.to_owned(), msg.to_owned())
+        }
+        ":" => RESP::Integer(rest.parse()?),
+        "$" => match rest.parse::<i64>()? {
+            -1 => RESP::Null,
+            len => {
+                let mut buf = vec![0; len as usize + 2];
+                reader.read_exact(&mut buf).await?;
+                buf.truncate(len as usize);
+                RESP::BulkString(Bytes::from(buf))
+            }
+        },
+        "*" => match rest.parse::<i64>()? {
+            -1 => RESP::Null,
+            len => {
+                let mut items = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    items.push(read_reply(reader).await?);
                 }
+                RESP::Array(items)
+            }
+        },
+        "|" => {
+            let len = rest.parse::<usize>()?;
+            let mut attributes = Vec::with_capacity(len);
+            for _ in 0..len {
+                attributes.push((read_reply(reader).await?, read_reply(reader).await?));
             }
+            read_reply(reader).await?.with_attributes(attributes)
         }
+        other => {
+            return Err(RdisError::Protocol(format!(
+                "unexpected reply type {}",
+                other
+            )))
+        }
+    })
+}
+
+// the socket side of a client connection
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    fn set_query_buffer_limit(&mut self, limit: usize);
+    // kept up to date with the bytes buffered by the decoder
+    fn set_query_buffer_gauge(&mut self, gauge: Arc<AtomicUsize>);
+    // bytes decoded since the last call
+    fn take_bytes_read(&mut self) -> u64;
+    async fn read_request(&mut self) -> ResultT<ClientReq>;
+    async fn write_response(&mut self, resp: RESP, flush: bool) -> ResultT<()>;
+}
+
+pub type TcpCmd = RedisCmd<OwnedReadHalf, BufWriter<OwnedWriteHalf>>;
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send + Debug> Transport
+    for RedisCmd<R, W>
+{
+    fn set_query_buffer_limit(&mut self, limit: usize) {
+        self.decoder.set_query_buffer_limit(limit);
+    }
+
+    fn set_query_buffer_gauge(&mut self, gauge: Arc<AtomicUsize>) {
+        self.decoder.set_query_buffer_gauge(gauge);
+    }
+
+    fn take_bytes_read(&mut self) -> u64 {
+        self.decoder.take_bytes_read()
+    }
+
+    async fn read_request(&mut self) -> ResultT<ClientReq> {
+        self.read_async().await
+    }
+
+    async fn write_response(&mut self, resp: RESP, flush: bool) -> ResultT<()> {
+        self.write_async(resp, flush).await
+    }
+}
+
+// accumulates the bytes read from a client and splits them in frames,
+// independently from how the bytes are read from the socket
+pub struct FrameDecoder {
+    buff: BytesMut,
+    client_epoch: usize,
+    pipelined_request: Vec<RESP>,
+    query_buffer_limit: usize,
+    bytes_read: u64,
+    // size of buff, read by maxmemory-clients
+    query_buffer_gauge: Option<Arc<AtomicUsize>>,
+}
+
+impl FrameDecoder {
+    pub fn new(client_epoch: usize) -> FrameDecoder {
+        FrameDecoder {
+            buff: BytesMut::with_capacity(4096),
+            client_epoch,
+            pipelined_request: Vec::with_capacity(1024),
+            query_buffer_limit: usize::MAX,
+            bytes_read: 0,
+            query_buffer_gauge: None,
+        }
+    }
+
+    pub fn take_bytes_read(&mut self) -> u64 {
+        std::mem::take(&mut self.bytes_read)
+    }
+
+    pub fn set_query_buffer_limit(&mut self, limit: usize) {
+        self.query_buffer_limit = limit;
+    }
+
+    pub fn set_query_buffer_gauge(&mut self, gauge: Arc<AtomicUsize>) {
+        self.query_buffer_gauge = Some(gauge);
+    }
+
+    fn update_gauge(&self) {
+        if let Some(gauge) = &self.query_buffer_gauge {
+            gauge.store(self.buff.len(), Ordering::Relaxed);
+        }
+    }
+
+    // every complete frame in the buffer, None if more data is needed
+    pub fn decode(&mut self) -> Option<ClientReq> {
+        let req = loop {
+            match self.parse_frame() {
+                Ok(Some(r)) => self.pipelined_request.push(r),
+                Ok(None) => (),
+                Err(_) if self.pipelined_request.is_empty() => break None,
+                Err(_) => break Some(self.fill_output_pipeline_req()),
+            }
+        };
+        self.update_gauge();
+        req
+    }
+
+    // buffer where new data from the socket must be appended
+    pub fn read_buffer(&mut self) -> &mut BytesMut {
+        if self.buff.capacity() == 0 {
+            self.buff.reserve(2 * self.buff.len());
+            warn!(
+                "Expanding buffer to {}, client={}",
+                self.buff.len(),
+                self.client_epoch
+            );
+        }
+        &mut self.buff
+    }
+
+    // called after every read
+    pub fn check_limit(&self) -> ResultT<()> {
+        self.update_gauge();
+        if self.buff.len() > self.query_buffer_limit {
+            return Err(RdisError::Protocol(format!(
+                "query buffer limit exceeded, {} bytes pending for client={}",
+                self.buff.len(),
+                self.client_epoch
+            )));
+        }
+        Ok(())
+    }
+
+    // the requests received before the connection was closed
+    pub fn finish(&mut self) -> ClientReq {
+        self.fill_output_pipeline_req()
     }
 
     fn fill_output_pipeline_req(&mut self) -> ClientReq {
@@ -162,19 +447,16 @@ impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send + Debug> RedisCmd
         }
     }
 
-    pub async fn write_async(&mut self, resp: RESP, flush: bool) -> ResultT<()> {
-        resp.write_async(&mut self.writer, flush).await
-    }
-
     fn parse_frame(&mut self) -> ResultT<Option<RESP>> {
         let slice = &self.buff;
         let size = slice.len();
         let (rem_size, resp) = match parser::read(slice) {
             Ok((rem, resp)) => Ok((rem.len(), Some(resp))),
             Err(nom::Err::Incomplete(_)) => Ok((0, None)),
-            Err(err) => Err(ErrorT::from(format!("Fatal parsing error {}", err))),
+            Err(err) => Err(RdisError::Protocol(err.to_string())),
         }?;
         let decoded_len = size - rem_size;
+        self.bytes_read += decoded_len as u64;
         self.buff.advance(decoded_len);
         // no more data, we can reset all the cursors
         if rem_size == 0 {
@@ -194,9 +476,9 @@ pub enum ClientReq {
 
 use ClientReq::*;
 
-impl Into<Vec<RESP>> for ClientReq {
-    fn into(self) -> Vec<RESP> {
-        match self {
+impl From<ClientReq> for Vec<RESP> {
+    fn from(req: ClientReq) -> Vec<RESP> {
+        match req {
             Single(r) => vec![r],
             Pipeline(v) => v,
         }
@@ -210,24 +492,36 @@ impl ClientReq {
             Pipeline(rs) => rs.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn last(&self) -> Option<&RESP> {
+        match self {
+            Single(r) => Some(r),
+            Pipeline(rs) => rs.last(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use bytes::{BytesMut};
+    use bytes::{Bytes, BytesMut};
 
     use super::super::types::*;
+    use super::read_reply;
     use super::RedisCmd;
+    use super::Transport;
     use super::RESP;
     use std::io::Cursor;
-    use std::sync::Arc;
     use tokio::io::AsyncWriteExt;
 
     #[test]
     pub fn bytes_mut_test() {
         let mut b = BytesMut::with_capacity(4096);
         b.extend_from_slice(vec![0; 128].as_slice());
-        assert_eq!(b.capacity() - b.len(), 4096 -128);
+        assert_eq!(b.capacity() - b.len(), 4096 - 128);
     }
 
     #[tokio::test]
@@ -235,32 +529,116 @@ mod tests {
         let mut req: Vec<(RESP, Vec<u8>)> = vec![
             (RESP::SimpleString("OK".into()), b"+OK\r\n".to_vec()),
             (RESP::Integer(129), b":129\r\n".to_vec()),
+            (RESP::Integer(0), b":0\r\n".to_vec()),
+            (RESP::Integer(9999), b":9999\r\n".to_vec()),
+            (RESP::Integer(10000), b":10000\r\n".to_vec()),
+            (RESP::Integer(-42), b":-42\r\n".to_vec()),
+            (
+                RESP::Error("ERR".into(), "unknown command".into()),
+                b"-ERR unknown command\r\n".to_vec(),
+            ),
             (
-                RESP::BulkString(Arc::new("foobar".into())),
+                RESP::BulkString(Bytes::from_static(b"foobar")),
                 b"$6\r\nfoobar\r\n".to_vec(),
             ),
             (RESP::Null, b"$-1\r\n".to_vec()),
             (
                 RESP::Array(vec![
-                    RESP::BulkString(Arc::new("foo".into())),
-                    RESP::BulkString(Arc::new("bar".into())),
+                    RESP::BulkString(Bytes::from_static(b"foo")),
+                    RESP::BulkString(Bytes::from_static(b"bar")),
                 ]),
                 b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n".to_vec(),
             ),
             (
-                RESP::Array(vec![1, 2, 3].iter().map(|i| RESP::Integer(*i)).collect()),
+                RESP::Array([1, 2, 3].iter().map(|i| RESP::Integer(*i)).collect()),
                 b"*3\r\n:1\r\n:2\r\n:3\r\n".to_vec(),
             ),
             (RESP::Null, b"$-1\r\n".to_vec()),
+            (
+                RESP::Integer(7).with_attributes(vec![(
+                    RESP::SimpleString("popularity".into()),
+                    RESP::Integer(10),
+                )]),
+                b"|1\r\n+popularity\r\n:10\r\n:7\r\n".to_vec(),
+            ),
         ];
         for (en, bytes) in req.drain(0..req.len()) {
             let mut b = Cursor::new(Vec::new());
+            assert_eq!(en.encoded_len(), bytes.len());
             en.write_async(&mut b, true).await?;
             assert_eq!(b.into_inner(), bytes);
         }
         Ok(())
     }
 
+    #[test]
+    pub fn test_describe_command() {
+        assert_eq!(
+            RESP::Array(vec![
+                RESP::BulkString(Bytes::from_static(b"set")),
+                RESP::BulkString(Bytes::from_static(b"k")),
+            ])
+            .describe_command(),
+            ("SET".to_owned(), 1)
+        );
+        assert_eq!(
+            RESP::SimpleString("ping".into()).describe_command(),
+            ("PING".to_owned(), 0)
+        );
+        assert_eq!(RESP::Array(vec![]).describe_command(), ("?".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    pub async fn test_read_reply() -> ResultT<()> {
+        let mut replies: &[u8] =
+            b"+OK\r\n-ERR bad thing\r\n:-3\r\n$0\r\n\r\n$-1\r\n*2\r\n$1\r\na\r\n*0\r\n";
+        assert_eq!(
+            read_reply(&mut replies).await?,
+            RESP::SimpleString("OK".into())
+        );
+        assert_eq!(
+            read_reply(&mut replies).await?,
+            RESP::Error("ERR".into(), "bad thing".into())
+        );
+        assert_eq!(read_reply(&mut replies).await?, RESP::Integer(-3));
+        assert_eq!(
+            read_reply(&mut replies).await?,
+            RESP::BulkString(Bytes::new())
+        );
+        assert_eq!(read_reply(&mut replies).await?, RESP::Null);
+        assert_eq!(
+            read_reply(&mut replies).await?,
+            RESP::Array(vec![
+                RESP::BulkString(Bytes::from_static(b"a")),
+                RESP::Array(vec![])
+            ])
+        );
+        assert!(read_reply(&mut replies).await.is_err());
+
+        let mut replies: &[u8] = b"|1\r\n+ttl\r\n:3\r\n*2\r\n:1\r\n:2\r\n";
+        let reply = read_reply(&mut replies).await?;
+        assert_eq!(
+            reply,
+            RESP::Array(vec![RESP::Integer(1), RESP::Integer(2)])
+                .with_attributes(vec![(RESP::SimpleString("ttl".into()), RESP::Integer(3))])
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_without_attributes() {
+        let attributes = || vec![(RESP::SimpleString("k".into()), RESP::Null)];
+        let reply = RESP::Array(vec![
+            RESP::Integer(1),
+            RESP::Integer(2).with_attributes(attributes()),
+        ])
+        .with_attributes(attributes());
+        assert_eq!(
+            reply.without_attributes(),
+            RESP::Array(vec![RESP::Integer(1), RESP::Integer(2)])
+        );
+    }
+
     #[tokio::test]
     pub async fn test_pipeline_req() -> ResultT<()> {
         let (client, server) = tokio::io::duplex(64);
@@ -277,6 +655,19 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    pub async fn test_query_buffer_limit() -> ResultT<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut cmd = RedisCmd::new(client, server, 0);
+        cmd.set_query_buffer_limit(16);
+        // an unterminated bulk string is never parsed and keeps growing the buffer
+        cmd.writer
+            .write_all(b"$100\r\naaaaaaaaaaaaaaaaaaaaaaaa")
+            .await?;
+        assert!(cmd.read_async().await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     pub async fn test_pipeline_req_benchmark() -> ResultT<()> {
         let (client, server) = tokio::io::duplex(1024);
@@ -287,6 +678,8 @@ mod tests {
         // it's an array because it uses the compact form
         let sent_msg = RESP::Array(vec![RESP::SimpleString("PING".into())]);
         assert_eq!(resp.len(), 3);
+        assert_eq!(cmd.take_bytes_read(), pipeline_reqs.len() as u64);
+        assert_eq!(cmd.take_bytes_read(), 0);
         for r in resp.drain(0..) {
             assert_eq!(r, sent_msg)
         }
@@ -0,0 +1,208 @@
+use super::storage::Key;
+use bytes::Bytes;
+
+// index of the keys by prefix, enabled by keyspace-prefix-index. A radix tree: every node
+// holds the bytes shared by the keys below it, so listing the keys starting with a prefix
+// walks the length of the prefix and then only the matching keys, instead of the whole
+// keyspace. Children are sorted by their first byte.
+#[derive(Debug, Default)]
+pub struct RadixTree {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    label: Vec<u8>,
+    // a key ends here
+    terminal: bool,
+    children: Vec<Node>,
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+impl Node {
+    fn leaf(label: &[u8]) -> Node {
+        Node {
+            label: label.to_vec(),
+            terminal: true,
+            children: Vec::new(),
+        }
+    }
+
+    // Ok(index) of the child starting with byte, Err(index) where it would be inserted
+    fn child(&self, byte: u8) -> Result<usize, usize> {
+        self.children.binary_search_by_key(&byte, |c| c.label[0])
+    }
+
+    fn insert(&mut self, key: &[u8]) -> bool {
+        if key.is_empty() {
+            return !std::mem::replace(&mut self.terminal, true);
+        }
+        let i = match self.child(key[0]) {
+            Ok(i) => i,
+            Err(i) => {
+                self.children.insert(i, Node::leaf(key));
+                return true;
+            }
+        };
+        let child = &mut self.children[i];
+        let common = common_prefix(&child.label, key);
+        if common < child.label.len() {
+            // the child is split where the key diverges
+            let suffix = child.label.split_off(common);
+            let mut tail = std::mem::replace(child, Node::leaf(&key[..common]));
+            tail.label = suffix;
+            child.terminal = common == key.len();
+            child.children.push(tail);
+            if common < key.len() {
+                return child.insert(&key[common..]);
+            }
+            return true;
+        }
+        child.insert(&key[common..])
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if key.is_empty() {
+            return std::mem::replace(&mut self.terminal, false);
+        }
+        let i = match self.child(key[0]) {
+            Ok(i) => i,
+            Err(_) => return false,
+        };
+        let child = &mut self.children[i];
+        if !key.starts_with(&child.label) {
+            return false;
+        }
+        let label_len = child.label.len();
+        if !child.remove(&key[label_len..]) {
+            return false;
+        }
+        // nodes without keys are removed, the ones with a single child merged with it
+        if !child.terminal {
+            match child.children.len() {
+                0 => {
+                    self.children.remove(i);
+                }
+                1 => {
+                    let grandchild = child.children.pop().unwrap();
+                    child.label.extend_from_slice(&grandchild.label);
+                    child.terminal = grandchild.terminal;
+                    child.children = grandchild.children;
+                }
+                _ => (),
+            }
+        }
+        true
+    }
+
+    fn collect(&self, path: &mut Vec<u8>, keys: &mut Vec<Key>) {
+        if self.terminal {
+            keys.push(Bytes::copy_from_slice(path));
+        }
+        for child in self.children.iter() {
+            path.extend_from_slice(&child.label);
+            child.collect(path, keys);
+            path.truncate(path.len() - child.label.len());
+        }
+    }
+}
+
+impl RadixTree {
+    pub fn new() -> RadixTree {
+        RadixTree::default()
+    }
+
+    // false if the key was already indexed
+    pub fn insert(&mut self, key: &[u8]) -> bool {
+        let inserted = self.root.insert(key);
+        self.len += inserted as usize;
+        inserted
+    }
+
+    // false if the key wasn't indexed
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        let removed = self.root.remove(key);
+        self.len -= removed as usize;
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the keys starting with prefix, in lexicographic order
+    pub fn with_prefix(&self, prefix: &[u8]) -> Vec<Key> {
+        let mut node = &self.root;
+        let mut path = Vec::with_capacity(prefix.len());
+        let mut rest = prefix;
+        while !rest.is_empty() {
+            let child = match node.child(rest[0]) {
+                Ok(i) => &node.children[i],
+                Err(_) => return Vec::new(),
+            };
+            let common = common_prefix(&child.label, rest);
+            if common < rest.len() && common < child.label.len() {
+                return Vec::new();
+            }
+            path.extend_from_slice(&child.label);
+            rest = &rest[common..];
+            node = child;
+        }
+        let mut keys = Vec::new();
+        node.collect(&mut path, &mut keys);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(tree: &RadixTree, prefix: &str) -> Vec<String> {
+        tree.with_prefix(prefix.as_bytes())
+            .iter()
+            .map(|k| String::from_utf8_lossy(k).into_owned())
+            .collect()
+    }
+
+    #[test]
+    pub fn test_radix_tree() {
+        let mut tree = RadixTree::new();
+        for key in ["user:1", "user:10", "user:2", "users", "session:1", "u", ""] {
+            assert!(tree.insert(key.as_bytes()));
+        }
+        assert!(!tree.insert(b"user:1"));
+        assert_eq!(tree.len(), 7);
+        assert_eq!(keys(&tree, "user:"), vec!["user:1", "user:10", "user:2"]);
+        assert_eq!(keys(&tree, "user:1"), vec!["user:1", "user:10"]);
+        assert_eq!(
+            keys(&tree, "use"),
+            vec!["user:1", "user:10", "user:2", "users"]
+        );
+        assert_eq!(keys(&tree, "user:3"), Vec::<String>::new());
+        assert_eq!(keys(&tree, "user:100"), Vec::<String>::new());
+        assert_eq!(keys(&tree, "").len(), 7);
+
+        assert!(tree.remove(b"user:1"));
+        assert!(!tree.remove(b"user:1"));
+        assert!(!tree.remove(b"user:"));
+        assert!(!tree.remove(b"missing"));
+        assert_eq!(keys(&tree, "user:1"), vec!["user:10"]);
+        assert!(tree.remove(b"u"));
+        assert!(tree.remove(b""));
+        assert_eq!(keys(&tree, "u"), vec!["user:10", "user:2", "users"]);
+        for key in ["user:10", "user:2", "users", "session:1"] {
+            assert!(tree.remove(key.as_bytes()));
+        }
+        assert!(tree.is_empty());
+        assert!(tree.root.children.is_empty());
+    }
+}
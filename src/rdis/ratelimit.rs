@@ -0,0 +1,82 @@
+// approximate sliding window of RATELIMIT key limit window-ms [COST n]: requests are counted in
+// fixed windows, the count of the sliding window ending now is the count of the current window
+// plus the share of the previous one still overlapping it, like the hot keys. Two counters per
+// key instead of a sorted set holding every request.
+
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    // requests of cost 1 still allowed in the sliding window
+    pub remaining: u64,
+    // ms until the request would be allowed, 0 if it is
+    pub retry_after: u64,
+}
+
+// previous and current are the counts of the fixed windows, elapsed the time since the start of
+// the current one. window > elapsed
+pub fn check(
+    limit: u64,
+    window: u64,
+    elapsed: u64,
+    previous: u64,
+    current: u64,
+    cost: u64,
+) -> Decision {
+    // counts are scaled by window, so the share of the previous window stays an integer
+    let (limit, window, elapsed) = (limit as u128, window as u128, elapsed as u128);
+    let (previous, current, cost) = (previous as u128, current as u128, cost as u128);
+    let used = current * window + previous * (window - elapsed);
+    let allowed = used + cost * window <= limit * window;
+    let left = (limit * window).saturating_sub(used + if allowed { cost * window } else { 0 });
+    let retry_after = if allowed {
+        0
+    } else if current + cost <= limit && previous > 0 {
+        // the share of the previous window shrinks enough before the end of the current one
+        let overlap = (limit - current - cost) * window / previous;
+        window - overlap - elapsed
+    } else {
+        window - elapsed
+    };
+    Decision {
+        allowed,
+        remaining: (left / window) as u64,
+        retry_after: retry_after as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_check() {
+        let allowed = |remaining| Decision {
+            allowed: true,
+            remaining,
+            retry_after: 0,
+        };
+        assert_eq!(check(3, 1000, 0, 0, 0, 1), allowed(2));
+        assert_eq!(check(3, 1000, 500, 0, 2, 1), allowed(0));
+        assert_eq!(
+            check(3, 1000, 500, 0, 3, 1),
+            Decision {
+                allowed: false,
+                remaining: 0,
+                retry_after: 500
+            }
+        );
+        // half of the 4 requests of the previous window still count
+        assert_eq!(check(4, 1000, 500, 4, 1, 1), allowed(0));
+        // a quarter of them at 750
+        assert_eq!(
+            check(4, 1000, 500, 4, 2, 1),
+            Decision {
+                allowed: false,
+                remaining: 0,
+                retry_after: 250
+            }
+        );
+        assert_eq!(check(4, 1000, 750, 4, 2, 1), allowed(0));
+        assert!(!check(4, 1000, 0, 0, 0, 5).allowed);
+    }
+}
@@ -0,0 +1,141 @@
+use super::clock::{Clock, SystemClock};
+use super::protocol::{ClientReq, RESP};
+use super::scan::scan_hash;
+use super::storage::{Key, RawValue};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+const SHARDS: usize = 16;
+
+// key -> (value, evict_at)
+type Shard = RwLock<HashMap<Key, (RawValue, Option<u64>)>>;
+
+// read fast path, enabled by read-fast-path yes: GETs are replied by the connection task
+// without waiting for the engine loop. The cache holds the strings written by SET and MSET,
+// whose expiration is known, along with that expiration. The engine updates it before replying
+// to the writes and drops the keys modified by any other command, deleted or expired, so a
+// client reads its own writes. Anything else, misses included, goes through the engine.
+// Reads served here aren't counted by total_commands_processed, see read_fast_path_hits.
+#[derive(Debug)]
+pub struct ReadCache {
+    shards: Vec<Shard>,
+    hits: AtomicU64,
+}
+
+impl Default for ReadCache {
+    fn default() -> Self {
+        ReadCache::new()
+    }
+}
+
+impl ReadCache {
+    pub fn new() -> ReadCache {
+        ReadCache {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, k: &[u8]) -> &Shard {
+        &self.shards[scan_hash(k) as usize % SHARDS]
+    }
+
+    pub fn insert(&self, k: Key, v: RawValue, evict_at: Option<u64>) {
+        self.shard(&k).write().unwrap().insert(k, (v, evict_at));
+    }
+
+    pub fn remove(&self, k: &[u8]) {
+        self.shard(k).write().unwrap().remove(k);
+    }
+
+    // None when the key isn't cached or has expired at t
+    pub fn get(&self, k: &[u8], t: u64) -> Option<RawValue> {
+        match self.shard(k).read().unwrap().get(k) {
+            Some((v, evict_at)) if evict_at.is_none_or(|evict_at| t < evict_at) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // the replies to a request made only of GETs of cached keys, None when the request must
+    // go through the engine
+    pub fn serve(&self, req: &ClientReq) -> Option<ClientReq> {
+        let t = SystemClock.now_millis();
+        let resp = match req {
+            ClientReq::Single(r) => ClientReq::Single(self.serve_command(r, t)?),
+            ClientReq::Pipeline(rs) => ClientReq::Pipeline(
+                rs.iter()
+                    .map(|r| self.serve_command(r, t))
+                    .collect::<Option<_>>()?,
+            ),
+        };
+        self.hits.fetch_add(req.len() as u64, Ordering::Relaxed);
+        Some(resp)
+    }
+
+    fn serve_command(&self, r: &RESP, t: u64) -> Option<RESP> {
+        match r {
+            RESP::Array(parts) => match parts.as_slice() {
+                [RESP::BulkString(name), RESP::BulkString(k)]
+                    if name.eq_ignore_ascii_case(b"GET") =>
+                {
+                    self.get(k, t).map(RESP::BulkString)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn get(key: &str) -> RESP {
+        RESP::Array(vec![RESP::from("GET"), RESP::from(key)])
+    }
+
+    #[test]
+    pub fn test_serve() {
+        let cache = ReadCache::new();
+        let now = SystemClock.now_millis();
+        cache.insert(Bytes::from_static(b"a"), Bytes::from_static(b"1"), None);
+        cache.insert(
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"2"),
+            Some(now + 60_000),
+        );
+        cache.insert(
+            Bytes::from_static(b"old"),
+            Bytes::from_static(b"3"),
+            Some(now),
+        );
+        assert_eq!(
+            cache.serve(&ClientReq::Single(get("a"))),
+            Some(ClientReq::Single(RESP::from("1")))
+        );
+        assert_eq!(
+            cache.serve(&ClientReq::Pipeline(vec![get("b"), get("a")])),
+            Some(ClientReq::Pipeline(vec![RESP::from("2"), RESP::from("1")]))
+        );
+        assert_eq!(cache.hits(), 3);
+        // misses, expired keys and other commands go to the engine
+        assert_eq!(cache.serve(&ClientReq::Single(get("missing"))), None);
+        assert_eq!(cache.serve(&ClientReq::Single(get("old"))), None);
+        assert_eq!(
+            cache.serve(&ClientReq::Pipeline(vec![get("a"), get("c")])),
+            None
+        );
+        let set = RESP::Array(vec![RESP::from("SET"), RESP::from("a"), RESP::from("2")]);
+        assert_eq!(cache.serve(&ClientReq::Single(set)), None);
+        cache.remove(b"a");
+        assert_eq!(cache.serve(&ClientReq::Single(get("a"))), None);
+        assert_eq!(cache.hits(), 3);
+    }
+}
@@ -0,0 +1,107 @@
+use super::session::ConnectionState;
+use super::storage::{Key, RawValue};
+use super::types::{ResponseSender, ResultT};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+// turns rdis into a read-through cache with a Loader set by RdisServerBuilder::read_through:
+// a GET missing a key with one of the prefixes is parked while the loader fetches the value on
+// the runtime that built the server, never on the engine thread. The value is stored with the
+// ttl, unless the key was written in the meantime, then the GET is replied with the key. The
+// GETs of a key being loaded wait for the same load.
+//
+// Only GETs sent on their own are parked, in a pipeline or a transaction a miss is nil. Loaded
+// values are not recorded, published nor mirrored to the write-behind sink: they come from the
+// source of truth.
+pub type LoadFuture = Pin<Box<dyn Future<Output = ResultT<Option<RawValue>>> + Send>>;
+
+pub trait Loader: Send + Sync {
+    // None when the source has no value for the key, an error is replied to the GET
+    fn load(&self, key: Key) -> LoadFuture;
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadThroughOptions {
+    // the empty prefix loads every key
+    pub prefixes: Vec<Vec<u8>>,
+    pub ttl: Duration,
+}
+
+impl Default for ReadThroughOptions {
+    fn default() -> Self {
+        ReadThroughOptions {
+            prefixes: vec![Vec::new()],
+            ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+// a parked GET: its sequence number, the state of the connection and where to reply
+pub type Waiter = (u64, ConnectionState, ResponseSender);
+
+pub type Loaded = (Key, ResultT<Option<RawValue>>);
+
+pub struct ReadThrough {
+    loader: Arc<dyn Loader>,
+    options: ReadThroughOptions,
+    runtime: Handle,
+    sender: mpsc::UnboundedSender<Loaded>,
+    receiver: mpsc::UnboundedReceiver<Loaded>,
+    waiting: HashMap<Key, Vec<Waiter>>,
+}
+
+impl ReadThrough {
+    // loads run on the runtime calling new
+    pub fn new(loader: Arc<dyn Loader>, options: ReadThroughOptions) -> ReadThrough {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        ReadThrough {
+            loader,
+            options,
+            runtime: Handle::current(),
+            sender,
+            receiver,
+            waiting: HashMap::new(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.options.ttl
+    }
+
+    pub fn loads(&self, k: &[u8]) -> bool {
+        self.options.prefixes.iter().any(|p| k.starts_with(p))
+    }
+
+    // starts a load unless the key is being loaded already
+    pub fn park(&mut self, k: Key, waiter: Waiter) {
+        let waiting = self.waiting.entry(k.clone()).or_default();
+        waiting.push(waiter);
+        if waiting.len() > 1 {
+            return;
+        }
+        let load = self.loader.load(k.clone());
+        let sender = self.sender.clone();
+        self.runtime.spawn(async move {
+            // the receiver is gone once the engine is dropped
+            let _ = sender.send((k, load.await));
+        });
+    }
+
+    // never completes while nothing is loading
+    pub async fn next(&mut self) -> Loaded {
+        match self.receiver.recv().await {
+            Some(loaded) => loaded,
+            // a sender is kept in self
+            None => unreachable!(),
+        }
+    }
+
+    pub fn waiters(&mut self, k: &[u8]) -> Vec<Waiter> {
+        self.waiting.remove(k).unwrap_or_default()
+    }
+}
@@ -0,0 +1,115 @@
+use super::protocol::{read_reply, RESP};
+use super::types::{RdisError, ResultT};
+use bytes::Bytes;
+use log::*;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// log of the commands modifying the keyspace, enabled by the record-file directive and
+// replayed by rdis-replay. Every entry is a RESP array holding the engine time in millis,
+// the resolved command name and its arguments:
+//
+//     *4\r\n:1650000000000\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n
+//
+// The engine hands entries to a task writing the file, the file is flushed whenever the
+// queue is empty and when the engine stops.
+pub struct Recorder {
+    sender: mpsc::UnboundedSender<RESP>,
+}
+
+impl Recorder {
+    // appends to the file if it exists, the handle completes once the engine is dropped
+    pub async fn open(path: &str) -> ResultT<(Recorder, JoinHandle<()>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let path = path.to_owned();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = write_entries(file, receiver).await {
+                error!("Recording to {} stopped: {}", path, e);
+            }
+        });
+        Ok((Recorder { sender }, handle))
+    }
+
+    pub fn record(&self, t: u64, cmd: &[u8], args: &[RESP]) {
+        let mut entry = Vec::with_capacity(args.len() + 2);
+        entry.push(RESP::Integer(t as i64));
+        entry.push(RESP::BulkString(Bytes::copy_from_slice(cmd)));
+        entry.extend_from_slice(args);
+        // the writer is gone only after an io error, already logged
+        let _ = self.sender.send(RESP::Array(entry));
+    }
+}
+
+async fn write_entries(file: File, mut receiver: mpsc::UnboundedReceiver<RESP>) -> ResultT<()> {
+    let mut writer = BufWriter::new(file);
+    while let Some(entry) = receiver.recv().await {
+        entry.write_async(&mut writer, false).await?;
+        if receiver.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// (time in millis, command), None at the end of the log
+pub async fn read_entry<R>(reader: &mut R) -> ResultT<Option<(u64, Vec<RESP>)>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    if reader.fill_buf().await?.is_empty() {
+        return Ok(None);
+    }
+    match read_reply(reader).await? {
+        RESP::Array(mut entry) if entry.len() >= 2 => match entry.remove(0) {
+            RESP::Integer(t) if t >= 0 => Ok(Some((t as u64, entry))),
+            _ => Err(invalid_entry()),
+        },
+        _ => Err(invalid_entry()),
+    }
+}
+
+fn invalid_entry() -> RdisError {
+    RdisError::Protocol("invalid entry in the record file".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    pub async fn test_record_and_read() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-recorder-{}.resp", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (recorder, handle) = Recorder::open(path).await?;
+        recorder.record(10, b"SET", &[RESP::from("k"), RESP::from("v")]);
+        recorder.record(25, b"DEL", &[RESP::from("k")]);
+        drop(recorder);
+        handle.await?;
+
+        let mut reader = BufReader::new(File::open(path).await?);
+        let (t, entry) = read_entry(&mut reader).await?.unwrap();
+        assert_eq!(t, 10);
+        assert_eq!(
+            entry,
+            vec![RESP::from("SET"), RESP::from("k"), RESP::from("v")]
+        );
+        let (t, entry) = read_entry(&mut reader).await?.unwrap();
+        assert_eq!(t, 25);
+        assert_eq!(entry, vec![RESP::from("DEL"), RESP::from("k")]);
+        assert!(read_entry(&mut reader).await?.is_none());
+        tokio::fs::remove_file(path).await?;
+
+        let mut garbage: &[u8] = b"$1\r\nx\r\n";
+        assert!(read_entry(&mut garbage).await.is_err());
+        Ok(())
+    }
+}
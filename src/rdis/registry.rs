@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::events::{Event, EventBus};
+use log::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: usize,
+    pub addr: SocketAddr,
+    // the address the connection was accepted on
+    pub laddr: Option<SocketAddr>,
+    pub connected_at: Instant,
+    pub stats: Arc<ClientStats>,
+}
+
+impl ClientInfo {
+    // one line of the CLIENT LIST output
+    pub fn describe(&self) -> String {
+        let age = self.connected_at.elapsed();
+        let last_interaction = self.stats.last_interaction.load(Ordering::Relaxed);
+        let idle = (age.as_millis() as u64).saturating_sub(last_interaction) / 1000;
+        format!(
+            "id={} addr={} laddr={} age={} idle={} qbuf={} omem={} tot-net-in={} tot-net-out={} tot-cmds={} cmd={}",
+            self.id,
+            self.addr,
+            self.laddr.map_or(String::new(), |laddr| laddr.to_string()),
+            age.as_secs(),
+            idle,
+            self.stats.query_buffer.load(Ordering::Relaxed),
+            self.stats.output_buffer.load(Ordering::Relaxed),
+            self.stats.net_input_bytes.load(Ordering::Relaxed),
+            self.stats.net_output_bytes.load(Ordering::Relaxed),
+            self.stats.commands.load(Ordering::Relaxed),
+            self.stats.last_command.lock().unwrap()
+        )
+    }
+
+    // bytes held in the query and output buffers
+    pub fn memory(&self) -> usize {
+        self.stats.query_buffer.load(Ordering::Relaxed)
+            + self.stats.output_buffer.load(Ordering::Relaxed)
+    }
+
+    pub fn kind(&self) -> ClientKind {
+        match self.stats.subscribed.load(Ordering::Relaxed) {
+            true => ClientKind::Pubsub,
+            false => ClientKind::Normal,
+        }
+    }
+}
+
+// CLIENT KILL TYPE, there is no replication: no client is a replica
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientKind {
+    Normal,
+    Pubsub,
+    Replica,
+}
+
+impl ClientKind {
+    // master and slave are the redis aliases of replica
+    pub fn parse(name: &str) -> Option<ClientKind> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(ClientKind::Normal),
+            "pubsub" => Some(ClientKind::Pubsub),
+            "replica" | "slave" | "master" => Some(ClientKind::Replica),
+            _ => None,
+        }
+    }
+}
+
+// the filters of CLIENT KILL, a client is killed when it matches all of them
+#[derive(Debug, Default)]
+pub struct KillFilter {
+    pub id: Option<usize>,
+    pub addr: Option<String>,
+    pub laddr: Option<String>,
+    pub kind: Option<ClientKind>,
+    // connected for longer than max_age
+    pub max_age: Option<Duration>,
+    // the client sending the command, spared
+    pub skip: Option<usize>,
+}
+
+impl KillFilter {
+    pub fn matches(&self, info: &ClientInfo) -> bool {
+        let laddr = info.laddr.map(|laddr| laddr.to_string());
+        self.id.is_none_or(|id| info.id == id)
+            && self
+                .addr
+                .as_ref()
+                .is_none_or(|addr| info.addr.to_string() == *addr)
+            && self
+                .laddr
+                .as_ref()
+                .is_none_or(|expected| laddr.as_ref() == Some(expected))
+            && self.kind.is_none_or(|kind| info.kind() == kind)
+            && self
+                .max_age
+                .is_none_or(|max_age| info.connected_at.elapsed() > max_age)
+            && self.skip != Some(info.id)
+    }
+}
+
+// updated by the connection, reported by CLIENT LIST and CLIENT INFO
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+    pub commands: AtomicU64,
+    // bytes of responses not flushed to the socket yet
+    pub output_buffer: AtomicUsize,
+    // bytes read and not parsed yet, updated by the decoder of the connection
+    pub query_buffer: Arc<AtomicUsize>,
+    // millis from connected_at to the last request
+    pub last_interaction: AtomicU64,
+    pub last_command: Mutex<String>,
+    // the connection has subscriptions
+    pub subscribed: AtomicBool,
+}
+
+impl ClientStats {
+    pub fn record_request(&self, commands: u64, last_command: String, connected_at: Instant) {
+        self.commands.fetch_add(commands, Ordering::Relaxed);
+        let elapsed = connected_at.elapsed().as_millis() as u64;
+        self.last_interaction.store(elapsed, Ordering::Relaxed);
+        *self.last_command.lock().unwrap() = last_command;
+    }
+}
+
+struct ClientEntry {
+    info: ClientInfo,
+    handle: Option<JoinHandle<()>>,
+}
+
+// keeps track of every live connection, entries are removed by the ClientGuard
+// owned by the connection itself, so completed handles never pile up
+pub struct ClientRegistry {
+    clients: Mutex<HashMap<usize, ClientEntry>>,
+    client_epoch: AtomicUsize,
+    events: EventBus,
+    // set by shutdown, handles attached afterwards are aborted right away
+    closed: AtomicBool,
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        ClientRegistry::new()
+    }
+}
+
+impl ClientRegistry {
+    pub fn new() -> ClientRegistry {
+        ClientRegistry::with_events(EventBus::default())
+    }
+
+    // connections and disconnections are published on the bus
+    pub fn with_events(events: EventBus) -> ClientRegistry {
+        ClientRegistry {
+            clients: Mutex::new(HashMap::with_capacity(1024)),
+            client_epoch: AtomicUsize::new(0),
+            events,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn register(self: &Arc<Self>, addr: SocketAddr, laddr: Option<SocketAddr>) -> ClientGuard {
+        let id = self.client_epoch.fetch_add(1, Ordering::SeqCst);
+        let stats = Arc::new(ClientStats {
+            last_command: Mutex::new("NULL".to_owned()),
+            ..ClientStats::default()
+        });
+        let connected_at = Instant::now();
+        let info = ClientInfo {
+            id,
+            addr,
+            laddr,
+            connected_at,
+            stats: stats.clone(),
+        };
+        let mut lock = self.clients.lock().unwrap();
+        lock.insert(id, ClientEntry { info, handle: None });
+        self.events.publish(|| Event::ClientConnected { id, addr });
+        ClientGuard {
+            id,
+            connected_at,
+            stats,
+            registry: self.clone(),
+        }
+    }
+
+    // the task may already be completed when the handle is attached,
+    // in that case the handle is simply dropped
+    pub fn attach_handle(&self, id: usize, handle: JoinHandle<()>) {
+        let mut lock = self.clients.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            handle.abort();
+        } else if let Some(entry) = lock.get_mut(&id) {
+            entry.handle = Some(handle);
+        }
+    }
+
+    fn remove(&self, id: usize) {
+        let mut lock = self.clients.lock().unwrap();
+        lock.remove(&id);
+        self.events.publish(|| Event::ClientDisconnected { id });
+        debug!("Client {} removed from registry, {} left", id, lock.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    // connections accepted since startup
+    pub fn total_connections(&self) -> usize {
+        self.client_epoch.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let lock = self.clients.lock().unwrap();
+        let mut infos: Vec<ClientInfo> = lock.values().map(|e| e.info.clone()).collect();
+        infos.sort_by_key(|i| i.id);
+        infos
+    }
+
+    pub fn info(&self, id: usize) -> Option<ClientInfo> {
+        let lock = self.clients.lock().unwrap();
+        lock.get(&id).map(|e| e.info.clone())
+    }
+
+    pub fn kill_id(&self, id: usize) -> bool {
+        self.kill_matching(|info| info.id == id) > 0
+    }
+
+    pub fn kill_addr(&self, addr: &str) -> bool {
+        self.kill_matching(|info| info.addr.to_string() == addr) > 0
+    }
+
+    // the number of clients killed
+    pub fn kill(&self, filter: &KillFilter) -> usize {
+        self.kill_matching(|info| filter.matches(info))
+    }
+
+    // bytes held by the buffers of every client
+    pub fn clients_memory(&self) -> usize {
+        let lock = self.clients.lock().unwrap();
+        lock.values().map(|e| e.info.memory()).sum()
+    }
+
+    // maxmemory-clients: while the buffers of the clients hold more than limit bytes, the
+    // clients holding the most are killed. Returns the number of clients killed
+    pub fn evict_clients(&self, limit: usize) -> usize {
+        let lock = self.clients.lock().unwrap();
+        let mut clients: Vec<(usize, &ClientEntry)> =
+            lock.values().map(|e| (e.info.memory(), e)).collect();
+        let mut total: usize = clients.iter().map(|(memory, _)| memory).sum();
+        if total <= limit {
+            return 0;
+        }
+        clients.sort_by_key(|(memory, _)| std::cmp::Reverse(*memory));
+        let mut evicted = 0;
+        for (memory, entry) in clients {
+            if total <= limit {
+                break;
+            }
+            if let Some(h) = &entry.handle {
+                warn!(
+                    "Evicting client {} holding {} bytes, clients use {} bytes over maxmemory-clients {}",
+                    entry.info.id, memory, total, limit
+                );
+                h.abort();
+                total -= memory;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    // aborting the task drops the connection and its guard, which removes the entry
+    fn kill_matching<F: Fn(&ClientInfo) -> bool>(&self, f: F) -> usize {
+        let lock = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for entry in lock.values().filter(|e| f(&e.info)) {
+            if let Some(h) = &entry.handle {
+                h.abort();
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    // stops every connection and waits for the tasks to be completed
+    pub async fn shutdown(&self) {
+        let handles: Vec<JoinHandle<()>> = {
+            let mut lock = self.clients.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            lock.values_mut().filter_map(|e| e.handle.take()).collect()
+        };
+        for h in handles.iter() {
+            h.abort();
+        }
+        for h in handles {
+            if let Err(err) = h.await {
+                if !err.is_cancelled() {
+                    warn!("Connection task failed during shutdown {}", err);
+                }
+            }
+        }
+    }
+}
+
+pub struct ClientGuard {
+    pub id: usize,
+    pub connected_at: Instant,
+    pub stats: Arc<ClientStats>,
+    registry: Arc<ClientRegistry>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.registry.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn test_guard_removes_entry() {
+        let registry = Arc::new(ClientRegistry::new());
+        let guard = registry.register(addr(1000), None);
+        let other = registry.register(addr(1001), None);
+        assert_eq!(registry.len(), 2);
+        drop(guard);
+        let left = registry.list();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, other.id);
+    }
+
+    #[tokio::test]
+    pub async fn test_client_stats() {
+        let registry = Arc::new(ClientRegistry::new());
+        let guard = registry.register(addr(1000), None);
+        assert!(registry
+            .info(guard.id)
+            .unwrap()
+            .describe()
+            .ends_with("cmd=NULL"));
+        guard.stats.net_input_bytes.fetch_add(14, Ordering::Relaxed);
+        guard
+            .stats
+            .record_request(2, "get".to_owned(), guard.connected_at);
+        let line = registry.info(guard.id).unwrap().describe();
+        assert!(line.contains(" idle=0 "), "{}", line);
+        assert!(line.contains(" tot-net-in=14 "), "{}", line);
+        assert!(line.ends_with(" tot-cmds=2 cmd=get"), "{}", line);
+        assert!(registry.info(guard.id + 1).is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_kill_and_shutdown() {
+        let registry = Arc::new(ClientRegistry::new());
+        for port in 0..3 {
+            let guard = registry.register(addr(2000 + port), Some(addr(6379 + port)));
+            let id = guard.id;
+            let handle = tokio::spawn(async move {
+                let _guard = guard;
+                std::future::pending::<()>().await
+            });
+            registry.attach_handle(id, handle);
+        }
+        assert!(registry.kill_addr("127.0.0.1:2000"));
+        assert!(!registry.kill_addr("127.0.0.1:3000"));
+        let filter = |laddr: &str| KillFilter {
+            laddr: Some(laddr.to_owned()),
+            kind: Some(ClientKind::Normal),
+            ..KillFilter::default()
+        };
+        assert_eq!(registry.kill(&filter("127.0.0.1:6381")), 1);
+        assert_eq!(registry.kill(&filter("127.0.0.1:9999")), 0);
+        let replicas = KillFilter {
+            kind: Some(ClientKind::Replica),
+            ..KillFilter::default()
+        };
+        assert_eq!(registry.kill(&replicas), 0);
+        registry.shutdown().await;
+        assert!(registry.is_empty());
+        // connections accepted by another listener after the shutdown are stopped as well
+        let guard = registry.register(addr(2003), None);
+        let id = guard.id;
+        let handle = tokio::spawn(async move {
+            let _guard = guard;
+            std::future::pending::<()>().await
+        });
+        registry.attach_handle(id, handle);
+        tokio::task::yield_now().await;
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_evict_clients() {
+        let registry = Arc::new(ClientRegistry::new());
+        let mut stats = Vec::new();
+        for (port, buffered) in [(3000, 100), (3001, 300), (3002, 50)] {
+            let guard = registry.register(addr(port), None);
+            guard.stats.query_buffer.store(buffered, Ordering::Relaxed);
+            stats.push(guard.stats.clone());
+            let id = guard.id;
+            let handle = tokio::spawn(async move {
+                let _guard = guard;
+                std::future::pending::<()>().await
+            });
+            registry.attach_handle(id, handle);
+        }
+        stats[2].output_buffer.store(150, Ordering::Relaxed);
+        assert_eq!(registry.clients_memory(), 600);
+        assert_eq!(registry.evict_clients(1000), 0);
+        assert_eq!(registry.evict_clients(300), 1);
+        tokio::task::yield_now().await;
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.clients_memory(), 300);
+        // the client with 200 bytes goes first
+        assert_eq!(registry.evict_clients(0), 2);
+        registry.shutdown().await;
+    }
+}
@@ -0,0 +1,136 @@
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::Hasher;
+use std::iter::FromIterator;
+
+// cursors of SCAN and of HSCAN, SSCAN and ZSCAN.
+// Elements are visited in the order of a fixed hash of their bytes, which doesn't depend on
+// the layout of the HashMap holding them, and the cursor is the next hash to visit. Growing or
+// shrinking the map can't move an element behind the cursor, so every element present for the
+// whole iteration is returned at least once. Cursor 0 starts and ends the iteration.
+// A ScanIndex keeps the elements sorted by hash next to the map, a page seeks to the cursor and
+// walks COUNT elements: a whole iteration costs O(n log n), like the index itself.
+
+// DefaultHasher::new uses fixed keys, hashes are stable for the life of the process
+pub fn scan_hash(element: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(element);
+    hasher.finish()
+}
+
+#[derive(Debug, Default)]
+pub struct ScanIndex {
+    // the elements share their buffers with the map
+    elements: BTreeSet<(u64, Bytes)>,
+}
+
+impl ScanIndex {
+    pub fn insert(&mut self, element: &Bytes) {
+        self.elements.insert((scan_hash(element), element.clone()));
+    }
+
+    pub fn remove(&mut self, element: &Bytes) {
+        self.elements.remove(&(scan_hash(element), element.clone()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    // the count elements with hash >= cursor with the smallest hashes and the next cursor.
+    // Elements sharing the hash of the last one are all included, so a page may exceed count.
+    pub fn page(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let count = count.max(1);
+        let mut page = Vec::with_capacity(count.min(self.elements.len()));
+        let mut last = None;
+        for (hash, element) in self.elements.range((cursor, Bytes::new())..) {
+            // greater than the hash of the last one, so never 0
+            if page.len() >= count && last != Some(*hash) {
+                return (*hash, page);
+            }
+            last = Some(*hash);
+            page.push(element.clone());
+        }
+        (0, page)
+    }
+}
+
+impl<'a> FromIterator<&'a Bytes> for ScanIndex {
+    fn from_iter<I: IntoIterator<Item = &'a Bytes>>(elements: I) -> Self {
+        let elements = elements.into_iter().map(|e| (scan_hash(e), e.clone()));
+        ScanIndex {
+            elements: elements.collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn element(s: String) -> Bytes {
+        Bytes::from(s.into_bytes())
+    }
+
+    #[test]
+    pub fn test_page() {
+        let elements: Vec<Bytes> = (0..100u8).map(|i| Bytes::from(vec![i])).collect();
+        let index: ScanIndex = elements.iter().collect();
+        assert_eq!(index.len(), 100);
+        let (mut cursor, mut seen, mut calls) = (0, HashSet::new(), 0);
+        loop {
+            let (next, page) = index.page(cursor, 7);
+            assert!(page.len() <= 7);
+            seen.extend(page);
+            calls += 1;
+            if next == 0 {
+                break;
+            }
+            assert!(next > cursor);
+            cursor = next;
+        }
+        assert_eq!(seen.len(), 100);
+        assert_eq!(calls, 15);
+        assert_eq!(index.page(0, 1000).0, 0);
+        assert!(ScanIndex::default().page(0, 10).1.is_empty());
+    }
+
+    // elements come and go during the iteration
+    #[test]
+    pub fn test_page_while_growing_and_shrinking() {
+        let mut index = ScanIndex::default();
+        for i in 0..64u32 {
+            index.insert(&element(format!("stable:{}", i)));
+        }
+        let (mut cursor, mut seen, mut round) = (0, HashSet::new(), 0u32);
+        loop {
+            let (next, page) = index.page(cursor, 5);
+            seen.extend(page);
+            for i in 0..200 {
+                index.insert(&element(format!("new:{}:{}", round, i)));
+            }
+            if round % 3 == 2 {
+                for r in round - 2..=round {
+                    for i in 0..200 {
+                        index.remove(&element(format!("new:{}:{}", r, i)));
+                    }
+                }
+                assert_eq!(index.len(), 64);
+            }
+            round += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in 0..64u32 {
+            assert!(seen.contains(format!("stable:{}", i).as_bytes()));
+        }
+    }
+}
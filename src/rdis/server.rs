@@ -0,0 +1,673 @@
+use super::affinity::{format_cpu_list, pin_current_thread};
+use super::audit::AuditLog;
+use super::client::RdisClient;
+use super::clock::{Clock, SteppingClock};
+use super::config::{ClientLimits, Config, IoBackend};
+use super::engine::RedisEngine;
+use super::events::{Event, EventBus};
+#[cfg(feature = "fault-injection")]
+use super::faults::Faults;
+use super::metrics::Metrics;
+use super::module::CustomCommand;
+use super::preload;
+use super::readcache::ReadCache;
+use super::readthrough::{Loader, ReadThrough, ReadThroughOptions};
+use super::recorder::Recorder;
+use super::registry::ClientRegistry;
+use super::storage::{RedisData, Storage};
+use super::systemd::{self, Supervised};
+use super::tiered::Tiering;
+use super::types::*;
+use super::writebehind::{WriteBehind, WriteBehindOptions, WriteSink};
+use log::{error, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::runtime;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+
+// configures an rdis server, the binary uses it as well as applications embedding rdis
+//
+//     let server = RdisServerBuilder::new().port(0).build().await?;
+//     let addr = server.local_addr()?;
+//     let shutdown = server.shutdown_handle();
+//     tokio::spawn(server.serve());
+pub struct RdisServerBuilder {
+    config: Config,
+    storage: Option<Box<dyn Storage>>,
+    clock: Option<Box<dyn Clock>>,
+    custom_commands: Vec<Box<dyn CustomCommand>>,
+    write_behind: Option<(Box<dyn WriteSink>, WriteBehindOptions)>,
+    read_through: Option<(Arc<dyn Loader>, ReadThroughOptions)>,
+}
+
+impl Default for RdisServerBuilder {
+    fn default() -> Self {
+        RdisServerBuilder::new()
+    }
+}
+
+impl RdisServerBuilder {
+    pub fn new() -> RdisServerBuilder {
+        RdisServerBuilder::from_config(Config::default())
+    }
+
+    pub fn from_config(config: Config) -> RdisServerBuilder {
+        RdisServerBuilder {
+            config,
+            storage: None,
+            clock: None,
+            custom_commands: Vec::new(),
+            write_behind: None,
+            read_through: None,
+        }
+    }
+
+    pub fn bind(mut self, host: &str) -> Self {
+        self.config.bind = host.to_owned();
+        self
+    }
+
+    // 0 binds an ephemeral port, see RdisServer::local_addr
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    // http admin and metrics endpoint, disabled when 0
+    pub fn admin_port(mut self, port: u16) -> Self {
+        self.config.admin_port = port;
+        self
+    }
+
+    // memcached text protocol listener, disabled when 0
+    pub fn memcached_port(mut self, port: u16) -> Self {
+        self.config.memcached_port = port;
+        self
+    }
+
+    // appends the commands modifying the keyspace to the file, see rdis-replay
+    pub fn record_file(mut self, path: &str) -> Self {
+        self.config.record_file = Some(path.to_owned());
+        self
+    }
+
+    // runs the commands of the file before accepting clients, see preload
+    pub fn preload(mut self, path: &str) -> Self {
+        self.config.preload = Some(path.to_owned());
+        self
+    }
+
+    // an empty name disables the command
+    pub fn rename_command(mut self, from: &str, to: &str) -> Self {
+        self.config.rename_commands.push((
+            from.to_uppercase().into_bytes(),
+            to.to_uppercase().into_bytes(),
+        ));
+        self
+    }
+
+    pub fn limits(mut self, limits: ClientLimits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn supervised(mut self, mode: Supervised) -> Self {
+        self.config.supervised = mode;
+        self
+    }
+
+    pub fn io_backend(mut self, backend: IoBackend) -> Self {
+        self.config.io_backend = backend;
+        self
+    }
+
+    // keyspace implementation, RedisData by default
+    pub fn storage<S: Storage + 'static>(mut self, storage: S) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    // GETs of strings written by SET or MSET are replied without going through the engine
+    pub fn read_fast_path(mut self) -> Self {
+        self.config.read_fast_path = true;
+        self
+    }
+
+    // the default storage indexes the keys by prefix, see RedisData::with_prefix_index
+    pub fn prefix_index(mut self) -> Self {
+        self.config.keyspace_prefix_index = true;
+        self
+    }
+
+    // runs the engine on a thread of its own, pinned to the CPUs when not empty
+    pub fn engine_thread(mut self, cpus: Vec<usize>) -> Self {
+        self.config.runtime.engine_thread = true;
+        self.config.runtime.engine_cpus = cpus;
+        self
+    }
+
+    // engine clock starting at the seed and moving one millisecond per batch, unless a clock
+    // is set. The caller picks the runtime, rdis uses a current thread one with the directive
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.config.deterministic_seed = Some(seed);
+        self.config.runtime.current_thread = true;
+        self
+    }
+
+    // time source used for expiration, SystemClock by default
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    // adds a command implemented outside of rdis, build fails if the name is already taken
+    pub fn command<C: CustomCommand + 'static>(mut self, command: C) -> Self {
+        self.custom_commands.push(Box::new(command));
+        self
+    }
+
+    // the changes of the keys are mirrored into the sink, see WriteSink
+    pub fn write_behind<S: WriteSink + 'static>(
+        mut self,
+        sink: S,
+        options: WriteBehindOptions,
+    ) -> Self {
+        self.write_behind = Some((Box::new(sink), options));
+        self
+    }
+
+    // GET misses of keys with one of the prefixes are loaded from the loader, see Loader
+    pub fn read_through<L: Loader + 'static>(
+        mut self,
+        loader: L,
+        options: ReadThroughOptions,
+    ) -> Self {
+        self.read_through = Some((Arc::new(loader), options));
+        self
+    }
+
+    // binds the sockets and starts the engine, connections are accepted by RdisServer::serve
+    pub async fn build(self) -> ResultT<RdisServer> {
+        let config = self.config;
+        let supervised = config.supervised;
+        let io_backend = config.io_backend;
+        let storage: Box<dyn Storage> = match self.storage {
+            Some(storage) => {
+                if config.tiering.file.is_some() {
+                    warn!("tiering-file is ignored with a custom storage");
+                }
+                storage
+            }
+            None => {
+                let mut data = if config.keyspace_prefix_index {
+                    RedisData::with_prefix_index()
+                } else {
+                    RedisData::new()
+                };
+                if let Some(tiering) = Tiering::open(&config.tiering)? {
+                    data.set_tiering(tiering);
+                }
+                Box::new(data)
+            }
+        };
+        let listener = match systemd::listen_fds()? {
+            Some(listener) => {
+                info!("Using socket activated listener {}", listener.local_addr()?);
+                listener
+            }
+            None => {
+                let addr = config.addr().parse()?;
+                let socket = TcpSocket::new_v4()?;
+
+                socket.set_reuseaddr(true)?;
+                socket.bind(addr)?;
+                info!("Bound socket to addr {}", socket.local_addr()?);
+
+                socket.listen(1024)?
+            }
+        };
+
+        let events = EventBus::default();
+        let registry = Arc::new(ClientRegistry::with_events(events.clone()));
+        let metrics = Arc::new(Metrics::new());
+        let mut server = RedisServer::new(registry.clone(), config.limits, metrics.clone());
+        server.set_wire_trace(config.log.wire_trace);
+        let (sender, receiver) = mpsc::channel(4096);
+        let api = Arc::new(RedisEngineApi::new(sender));
+        let mut engine = RedisEngine::new(
+            receiver,
+            registry.clone(),
+            metrics.clone(),
+            storage,
+            &config,
+        );
+        engine.set_events(events.clone());
+        #[cfg(feature = "fault-injection")]
+        let faults = Faults::default();
+        #[cfg(feature = "fault-injection")]
+        {
+            server.set_faults(faults.clone());
+            engine.set_faults(faults.clone());
+        }
+        if config.read_fast_path {
+            // the cache reads the system clock and answers GET by its name
+            let get_renamed = config
+                .rename_commands
+                .iter()
+                .any(|(from, _)| from == b"GET");
+            if self.clock.is_some() || config.deterministic_seed.is_some() || get_renamed {
+                warn!("read-fast-path is disabled with a custom clock or a renamed GET");
+            } else {
+                let cache = Arc::new(ReadCache::new());
+                server.set_read_cache(cache.clone());
+                engine.set_read_cache(cache);
+            }
+        }
+        match (self.clock, config.deterministic_seed) {
+            (Some(clock), _) => engine.set_clock(clock),
+            (None, Some(seed)) => engine.set_clock(Box::new(SteppingClock::new(seed))),
+            (None, None) => (),
+        }
+        for command in self.custom_commands {
+            engine.register_command(command)?;
+        }
+        // before the recorder and the sink, the fixtures are not recorded nor mirrored
+        if let Some(path) = &config.preload {
+            let commands = preload::read_fixtures(path).await?;
+            info!("Preloading {} commands from {}", commands.len(), path);
+            engine.preload(commands)?;
+        }
+        let recorder_handle = match &config.record_file {
+            Some(path) => {
+                let (recorder, handle) = Recorder::open(path).await?;
+                info!("Recording writes to {}", path);
+                engine.set_recorder(recorder);
+                Some(handle)
+            }
+            None => None,
+        };
+        if let Some((loader, options)) = self.read_through {
+            engine.set_read_through(ReadThrough::new(loader, options));
+        }
+        let write_behind_handle = self.write_behind.map(|(sink, options)| {
+            let (write_behind, handle) = WriteBehind::spawn(sink, options);
+            engine.set_write_behind(write_behind);
+            handle
+        });
+
+        let audit_handle = match (&config.audit_log, &config.audit_log_key) {
+            (Some(path), Some(key)) => {
+                let (audit, handle) = AuditLog::open(path, key.as_bytes()).await?;
+                info!("Auditing administrative commands to {}", path);
+                engine.set_audit_log(audit);
+                Some(handle)
+            }
+            // an unkeyed chain can be recomputed by whoever edits the file
+            (Some(_), None) => return Err(ErrorT::from("audit-log requires audit-log-key")),
+            (None, _) => None,
+        };
+
+        let (admin_addr, admin_handle) = match config.admin_addr() {
+            Some(addr) => {
+                let (addr, handle) = spawn_admin(&addr, &api, &registry, &metrics).await?;
+                (Some(addr), Some(handle))
+            }
+            None => (None, None),
+        };
+        let (memcached_addr, memcached_handle) = match config.memcached_addr() {
+            Some(addr) => {
+                let (addr, handle) = spawn_memcached(&addr, &api, &registry).await?;
+                (Some(addr), Some(handle))
+            }
+            None => (None, None),
+        };
+        let engine_handle = if config.dedicated_engine_thread() {
+            spawn_engine_thread(engine, config.runtime.engine_cpus.clone())?
+        } else {
+            tokio::spawn(async move { engine.start_loop().await })
+        };
+
+        Ok(RdisServer {
+            listener,
+            server,
+            api,
+            supervised,
+            io_backend,
+            admin_addr,
+            admin_handle,
+            memcached_addr,
+            memcached_handle,
+            engine_handle,
+            recorder_handle,
+            write_behind_handle,
+            audit_handle,
+            events,
+            #[cfg(feature = "fault-injection")]
+            faults,
+            shutdown: ShutdownHandle::default(),
+        })
+    }
+}
+
+pub struct RdisServer {
+    listener: TcpListener,
+    server: RedisServer,
+    api: Arc<RedisEngineApi>,
+    supervised: Supervised,
+    io_backend: IoBackend,
+    admin_addr: Option<SocketAddr>,
+    admin_handle: Option<JoinHandle<()>>,
+    memcached_addr: Option<SocketAddr>,
+    memcached_handle: Option<JoinHandle<()>>,
+    engine_handle: JoinHandle<()>,
+    recorder_handle: Option<JoinHandle<()>>,
+    write_behind_handle: Option<JoinHandle<()>>,
+    audit_handle: Option<JoinHandle<()>>,
+    events: EventBus,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
+    shutdown: ShutdownHandle,
+}
+
+impl RdisServer {
+    pub fn local_addr(&self) -> ResultT<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    pub fn memcached_addr(&self) -> Option<SocketAddr> {
+        self.memcached_addr
+    }
+
+    // in-process client, it can be used before serve is called.
+    // The engine stops, and serve returns, only once every client is dropped.
+    pub fn client(&self) -> RdisClient {
+        RdisClient::new(self.api.clone())
+    }
+
+    // server events from now on, see EventBus
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    // injected faults, the same rules of DEBUG FAULT
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // accepts connections until the shutdown handle is triggered, then closes the connections
+    // and waits for the engine to terminate
+    pub async fn serve(self) -> ResultT<()> {
+        let RdisServer {
+            listener,
+            server,
+            api,
+            supervised,
+            io_backend,
+            admin_handle,
+            memcached_handle,
+            engine_handle,
+            recorder_handle,
+            write_behind_handle,
+            audit_handle,
+            shutdown,
+            ..
+        } = self;
+        systemd::notify(supervised, "READY=1")?;
+        match io_backend {
+            IoBackend::Tokio => serve_tokio(listener, server, api, &shutdown).await,
+            IoBackend::Uring => serve_uring(listener, server, api, &shutdown).await?,
+        }
+        systemd::notify(supervised, "STOPPING=1")?;
+        // memcached connections are closed with the others by the registry
+        for handle in admin_handle.into_iter().chain(memcached_handle) {
+            handle.abort();
+            let _ = handle.await;
+        }
+        // every sender is dropped at this point, the engine loop terminates
+        engine_handle.await?;
+        // the recorder and the audit log flush their files and the sink receives the last changes
+        // once the engine is dropped
+        let handles = recorder_handle.into_iter().chain(write_behind_handle);
+        for handle in handles.chain(audit_handle) {
+            handle.await?;
+        }
+        Ok(())
+    }
+}
+
+// stops RdisServer::serve, it can be triggered before serve is called
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+
+    async fn wait(&self) {
+        self.notify.notified().await
+    }
+}
+
+// runs the engine on a current thread runtime of its own, pinned to the CPUs when not empty.
+// The handle completes when the thread exits and panics if the engine did.
+fn spawn_engine_thread(mut engine: RedisEngine, cpus: Vec<usize>) -> ResultT<JoinHandle<()>> {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let thread = std::thread::Builder::new()
+        .name("rdis-engine".to_owned())
+        .spawn(move || {
+            if !cpus.is_empty() {
+                match pin_current_thread(&cpus) {
+                    Ok(()) => info!("Engine pinned to CPUs {}", format_cpu_list(&cpus)),
+                    Err(e) => error!(
+                        "Failed to pin engine to CPUs {}: {}",
+                        format_cpu_list(&cpus),
+                        e
+                    ),
+                }
+            }
+            runtime.block_on(engine.start_loop())
+        })?;
+    Ok(tokio::task::spawn_blocking(move || {
+        if let Err(panic) = thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }))
+}
+
+async fn serve_tokio(
+    listener: TcpListener,
+    server: RedisServer,
+    api: Arc<RedisEngineApi>,
+    shutdown: &ShutdownHandle,
+) {
+    tokio::select! {
+        _ = accept_connections(&listener, &server, api) => (),
+        _ = shutdown.wait() => info!("Shutting down"),
+    }
+    server.shutdown().await;
+}
+
+#[cfg(feature = "admin")]
+async fn spawn_admin(
+    addr: &str,
+    api: &Arc<RedisEngineApi>,
+    registry: &Arc<ClientRegistry>,
+    metrics: &Arc<Metrics>,
+) -> ResultT<(SocketAddr, JoinHandle<()>)> {
+    use super::admin::{self, AdminState};
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let state = AdminState {
+        api: api.clone(),
+        registry: registry.clone(),
+        metrics: metrics.clone(),
+    };
+    Ok((addr, tokio::spawn(admin::serve(listener, state))))
+}
+
+// admin-port is rejected by the config parser when the feature is disabled
+#[cfg(not(feature = "admin"))]
+async fn spawn_admin(
+    _addr: &str,
+    _api: &Arc<RedisEngineApi>,
+    _registry: &Arc<ClientRegistry>,
+    _metrics: &Arc<Metrics>,
+) -> ResultT<(SocketAddr, JoinHandle<()>)> {
+    Err(ErrorT::from("rdis was built without the admin feature"))
+}
+
+#[cfg(feature = "memcached")]
+async fn spawn_memcached(
+    addr: &str,
+    api: &Arc<RedisEngineApi>,
+    registry: &Arc<ClientRegistry>,
+) -> ResultT<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let handle = tokio::spawn(super::memcached::serve(
+        listener,
+        api.clone(),
+        registry.clone(),
+    ));
+    Ok((addr, handle))
+}
+
+// memcached-port is rejected by the config parser when the feature is disabled
+#[cfg(not(feature = "memcached"))]
+async fn spawn_memcached(
+    _addr: &str,
+    _api: &Arc<RedisEngineApi>,
+    _registry: &Arc<ClientRegistry>,
+) -> ResultT<(SocketAddr, JoinHandle<()>)> {
+    Err(ErrorT::from("rdis was built without the memcached feature"))
+}
+
+#[cfg(feature = "io-uring")]
+async fn serve_uring(
+    listener: TcpListener,
+    server: RedisServer,
+    api: Arc<RedisEngineApi>,
+    shutdown: &ShutdownHandle,
+) -> ResultT<()> {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let handle = super::uring::spawn(listener.into_std()?, server, api, receiver)?;
+    shutdown.wait().await;
+    info!("Shutting down");
+    let _ = sender.send(());
+    tokio::task::spawn_blocking(move || handle.join())
+        .await?
+        .map_err(|_| ErrorT::from("io_uring thread panicked"))
+}
+
+// the config parser rejects the uring backend when the feature is disabled
+#[cfg(not(feature = "io-uring"))]
+async fn serve_uring(
+    _listener: TcpListener,
+    _server: RedisServer,
+    _api: Arc<RedisEngineApi>,
+    _shutdown: &ShutdownHandle,
+) -> ResultT<()> {
+    unreachable!("rdis was built without the io-uring feature")
+}
+
+async fn accept_connections(
+    listener: &TcpListener,
+    server: &RedisServer,
+    api: Arc<RedisEngineApi>,
+) {
+    while let Ok((stream, addr)) = listener.accept().await {
+        let connection = server.client_connection(api.clone(), stream, addr);
+        let client_epoch = connection.client_epoch();
+        server.add_handle(client_epoch, tokio::spawn(connection.start_loop()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    pub async fn test_serve_and_shutdown() -> ResultT<()> {
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await?;
+        let addr = server.local_addr()?;
+        let shutdown = server.shutdown_handle();
+        let server_client = server.client();
+        let handle = tokio::spawn(server.serve());
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"PING\r\n").await?;
+        let mut buf = [0; 7];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+PONG\r\n");
+
+        let client = server_client;
+        assert_eq!(client.get("k").await?, None);
+        drop(client);
+
+        shutdown.shutdown();
+        handle.await??;
+        // the connection is closed by the shutdown
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_events() -> ResultT<()> {
+        let server = RdisServerBuilder::new()
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await?;
+        let addr = server.local_addr()?;
+        let shutdown = server.shutdown_handle();
+        let mut events = server.subscribe();
+        let handle = tokio::spawn(server.serve());
+
+        let mut stream = TcpStream::connect(addr).await?;
+        // the pop of a missing key doesn't publish anything
+        stream.write_all(b"LPOP l\r\nSET k v\r\n").await?;
+        let mut buf = [0; 10];
+        stream.read_exact(&mut buf).await?;
+        drop(stream);
+
+        let id = match events.recv().await? {
+            Event::ClientConnected { id, .. } => id,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            events.recv().await?,
+            Event::KeyWritten {
+                key: Bytes::from_static(b"k"),
+                command: "set".to_owned()
+            }
+        );
+        assert_eq!(events.recv().await?, Event::ClientDisconnected { id });
+
+        shutdown.shutdown();
+        handle.await??;
+        Ok(())
+    }
+}
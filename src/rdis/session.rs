@@ -0,0 +1,55 @@
+use super::protocol::RESP;
+use std::collections::HashSet;
+
+// per-connection state, owned by the connection and moved to the engine with every request.
+// The engine updates it (CLIENT SETNAME, SELECT, MULTI...) and hands it back with the response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionState {
+    pub client_id: usize,
+    pub db: usize,
+    pub name: Option<String>,
+    pub authenticated: bool,
+    // 2 or 3, negotiated with HELLO
+    pub resp_version: u8,
+    // commands queued after MULTI, None outside of a transaction
+    pub multi: Option<Vec<RESP>>,
+    // a command was refused while queueing, EXEC discards the transaction
+    pub multi_failed: bool,
+    // channels and patterns the client is subscribed to
+    pub subscriptions: HashSet<Vec<u8>>,
+    pub reply_mode: ReplyMode,
+    // frames are logged by the connection, see wiretrace
+    pub wire_trace: bool,
+}
+
+// CLIENT REPLY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    Skip,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::new(0)
+    }
+}
+
+impl ConnectionState {
+    pub fn new(client_id: usize) -> ConnectionState {
+        ConnectionState {
+            client_id,
+            db: 0,
+            name: None,
+            // there is no authentication yet
+            authenticated: true,
+            resp_version: 2,
+            multi: None,
+            multi_failed: false,
+            subscriptions: HashSet::new(),
+            reply_mode: ReplyMode::On,
+            wire_trace: false,
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// samples averaged by the instantaneous metrics, like STATS_METRIC_SAMPLES in redis
+const METRIC_SAMPLES: usize = 16;
+
+// percentiles reported by INFO latencystats
+pub const LATENCY_PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+// counters maintained by the engine and reported by INFO
+pub struct Stats {
+    pub started_at: Instant,
+    pub total_commands_processed: u64,
+    // keyed by the lowercase command name, sorted for INFO
+    pub commands: BTreeMap<String, CommandStats>,
+    // error replies by error type (the first word of the error)
+    pub errors: BTreeMap<String, u64>,
+    pub total_error_replies: u64,
+    // killed by maxmemory-clients
+    pub evicted_clients: u64,
+    pub expired_keys: u64,
+    // GETs finding a value or not, expired keys are misses
+    pub keyspace_hits: u64,
+    pub keyspace_misses: u64,
+    pub instantaneous_ops: InstantaneousMetric,
+    pub instantaneous_input: InstantaneousMetric,
+    pub instantaneous_output: InstantaneousMetric,
+}
+
+#[derive(Default)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub usec: u64,
+    pub failed_calls: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl CommandStats {
+    // calls=2,usec=10,usec_per_call=5.00,failed_calls=0
+    pub fn describe(&self) -> String {
+        let per_call = if self.calls == 0 {
+            0.0
+        } else {
+            self.usec as f64 / self.calls as f64
+        };
+        format!(
+            "calls={},usec={},usec_per_call={:.2},failed_calls={}",
+            self.calls, self.usec, per_call, self.failed_calls
+        )
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started_at: Instant::now(),
+            total_commands_processed: 0,
+            commands: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            total_error_replies: 0,
+            evicted_clients: 0,
+            expired_keys: 0,
+            keyspace_hits: 0,
+            keyspace_misses: 0,
+            instantaneous_ops: InstantaneousMetric::default(),
+            instantaneous_input: InstantaneousMetric::default(),
+            instantaneous_output: InstantaneousMetric::default(),
+        }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    pub fn record_command(&mut self, cmd: &[u8], elapsed: Duration, failed: bool) {
+        let name = String::from_utf8_lossy(cmd).to_lowercase();
+        let stats = self.commands.entry(name).or_default();
+        stats.calls += 1;
+        stats.usec += elapsed.as_micros() as u64;
+        if failed {
+            stats.failed_calls += 1;
+        }
+        stats.latency.record(elapsed.as_nanos() as u64);
+    }
+
+    pub fn record_error(&mut self, error_type: &str) {
+        self.total_error_replies += 1;
+        *self.errors.entry(error_type.to_owned()).or_default() += 1;
+    }
+}
+
+// rate of a monotonic counter, averaged over the last METRIC_SAMPLES samples
+#[derive(Default)]
+pub struct InstantaneousMetric {
+    samples: [f64; METRIC_SAMPLES],
+    idx: usize,
+    last: Option<(Instant, u64)>,
+}
+
+impl InstantaneousMetric {
+    pub fn sample(&mut self, value: u64, now: Instant) {
+        if let Some((last_time, last_value)) = self.last {
+            let elapsed = now.saturating_duration_since(last_time).as_secs_f64();
+            if elapsed > 0.0 {
+                let delta = value.saturating_sub(last_value) as f64;
+                self.samples[self.idx] = delta / elapsed;
+                self.idx = (self.idx + 1) % METRIC_SAMPLES;
+            }
+        }
+        self.last = Some((now, value));
+    }
+
+    // per second
+    pub fn rate(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / METRIC_SAMPLES as f64
+    }
+}
+
+// log-linear histogram of nanoseconds: every power of two is split in SUB_BUCKETS linear
+// buckets, so recorded values keep a relative error below 1/SUB_BUCKETS like an HDR histogram
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+#[derive(Default)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, value: u64) {
+        let idx = LatencyHistogram::index(value);
+        if idx >= self.counts.len() {
+            self.counts.resize(idx + 1, 0);
+        }
+        self.counts[idx] += 1;
+        self.total += 1;
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.total += other.total;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // highest value equivalent to the one at the percentile, 0 when empty
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let target = ((percentile / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return LatencyHistogram::highest_value(idx);
+            }
+        }
+        0
+    }
+
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+        let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+        let sub = (value >> shift) - SUB_BUCKETS;
+        ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize
+    }
+
+    fn highest_value(idx: usize) -> u64 {
+        let idx = idx as u64;
+        if idx < SUB_BUCKETS {
+            return idx;
+        }
+        let shift = idx / SUB_BUCKETS - 1;
+        let sub = idx % SUB_BUCKETS;
+        ((SUB_BUCKETS + sub) << shift) + ((1 << shift) - 1)
+    }
+}
+
+// p50=1.003,p99=2.015,p99.9=3.071 in microseconds
+pub fn format_percentiles(histogram: &LatencyHistogram) -> String {
+    let mut out = String::new();
+    for (idx, p) in LATENCY_PERCENTILES.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let usec = histogram.percentile(*p) as f64 / 1000.0;
+        let _ = write!(out, "p{}={:.3}", p, usec);
+    }
+    out
+}
+
+// renders the INFO reply, "# Section" headers followed by key:value lines.
+// Without a filter the default sections are included.
+pub struct InfoBuilder {
+    out: String,
+    filter: Option<String>,
+    include_section: bool,
+}
+
+impl InfoBuilder {
+    pub fn new(filter: Option<&[u8]>) -> InfoBuilder {
+        InfoBuilder {
+            out: String::new(),
+            filter: filter.map(|f| String::from_utf8_lossy(f).to_lowercase()),
+            include_section: false,
+        }
+    }
+
+    // returns false if the section is filtered out, fields are then ignored
+    pub fn section(&mut self, name: &str) -> bool {
+        self.include_section = match self.filter.as_deref() {
+            None | Some("all") | Some("default") | Some("everything") => true,
+            Some(f) => f == name.to_lowercase(),
+        };
+        if self.include_section {
+            if !self.out.is_empty() {
+                self.out.push_str("\r\n");
+            }
+            let _ = write!(self.out, "# {}\r\n", name);
+        }
+        self.include_section
+    }
+
+    pub fn field<V: Display>(&mut self, key: &str, value: V) {
+        if self.include_section {
+            let _ = write!(self.out, "{}:{}\r\n", key, value);
+        }
+    }
+
+    pub fn build(self) -> String {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_info_builder() {
+        let mut info = InfoBuilder::new(None);
+        info.section("Server");
+        info.field("redis_version", "6.0.0");
+        info.section("Clients");
+        info.field("connected_clients", 2);
+        assert_eq!(
+            info.build(),
+            "# Server\r\nredis_version:6.0.0\r\n\r\n# Clients\r\nconnected_clients:2\r\n"
+        );
+    }
+
+    #[test]
+    pub fn test_latency_histogram() {
+        let mut h = LatencyHistogram::default();
+        assert_eq!(h.percentile(50.0), 0);
+        for v in 1..=1000 {
+            h.record(v * 1000);
+        }
+        assert_eq!(h.total, 1000);
+        let p50 = h.percentile(50.0);
+        assert!((500_000..=500_000 * 17 / 16).contains(&p50), "{}", p50);
+        let p99 = h.percentile(99.0);
+        assert!((990_000..=990_000 * 17 / 16).contains(&p99), "{}", p99);
+        assert!(h.percentile(100.0) >= 1_000_000);
+        for v in 0..64 {
+            assert!(LatencyHistogram::highest_value(LatencyHistogram::index(v)) >= v);
+        }
+    }
+
+    #[test]
+    pub fn test_latency_histogram_merge() {
+        let mut a = LatencyHistogram::default();
+        let mut b = LatencyHistogram::default();
+        a.record(10);
+        b.record(1_000_000);
+        b.record(2_000_000);
+        a.merge(&b);
+        assert_eq!(a.total(), 3);
+        assert_eq!(a.percentile(10.0), 10);
+        assert!(a.percentile(100.0) >= 2_000_000);
+    }
+
+    #[test]
+    pub fn test_command_stats() {
+        let mut stats = Stats::new();
+        stats.record_command(b"GET", Duration::from_micros(3), false);
+        stats.record_command(b"GET", Duration::from_micros(4), true);
+        stats.record_error("ERR");
+        stats.record_error("ERR");
+        assert_eq!(
+            stats.commands["get"].describe(),
+            "calls=2,usec=7,usec_per_call=3.50,failed_calls=1"
+        );
+        assert_eq!(stats.errors["ERR"], 2);
+        assert_eq!(stats.total_error_replies, 2);
+    }
+
+    #[test]
+    pub fn test_instantaneous_metric() {
+        let mut metric = InstantaneousMetric::default();
+        let start = Instant::now();
+        metric.sample(0, start);
+        assert_eq!(metric.rate(), 0.0);
+        for i in 1..=METRIC_SAMPLES as u64 {
+            metric.sample(i * 10, start + Duration::from_millis(100 * i));
+        }
+        assert!((metric.rate() - 100.0).abs() < 0.001);
+    }
+
+    #[test]
+    pub fn test_format_percentiles() {
+        let mut h = LatencyHistogram::default();
+        h.record(10);
+        assert_eq!(format_percentiles(&h), "p50=0.010,p99=0.010,p99.9=0.010");
+    }
+
+    #[test]
+    pub fn test_info_builder_filter() {
+        let mut info = InfoBuilder::new(Some(b"clients"));
+        assert!(!info.section("Server"));
+        info.field("redis_version", "6.0.0");
+        assert!(info.section("Clients"));
+        info.field("connected_clients", 2);
+        assert_eq!(info.build(), "# Clients\r\nconnected_clients:2\r\n");
+    }
+}
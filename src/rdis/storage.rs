@@ -0,0 +1,1751 @@
+use super::glob;
+use super::list::List;
+use super::radix::RadixTree;
+use super::scan::ScanIndex;
+use super::stream::{NewId, Stream, StreamEntry, StreamId};
+use super::tiered::{Spilled, Tiering, TieringStats};
+use super::types::{RdisError, ResultT};
+use super::zset::SortedSet;
+use bytes::Bytes;
+use log::*;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::mem::size_of;
+
+pub type RawValue = Bytes;
+pub type Key = Bytes;
+
+// keyspace operations executed by the engine, t is the current time in millis and it's used
+// to expire the keys. Implementations are owned by the engine loop, no locking is needed.
+// Operations against a key holding another kind of value fail with RdisError::WrongType.
+pub trait Storage: Send {
+    // replaces the key whatever its kind, together with its expiration
+    fn set(&mut self, k: Key, v: RawValue, evict_at: Option<u64>);
+    fn get(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>>;
+    // a missing key counts as 0, the expiration is kept
+    fn incr_by(&mut self, k: Key, by: i64, t: u64) -> ResultT<i64>;
+    // true if the key existed
+    fn del(&mut self, k: &RawValue, t: u64) -> bool;
+    // the key expires at evict_at, a time not after t removes it. False for a missing key
+    fn expire(&mut self, k: &RawValue, evict_at: u64, t: u64) -> bool;
+    // false for a missing key or one without expiration
+    fn persist(&mut self, k: &RawValue, t: u64) -> bool;
+    // None for a missing key, the time it expires at otherwise
+    fn expires_at(&mut self, k: &RawValue, t: u64) -> Option<Option<u64>>;
+    // pushes return the length of the list
+    fn l_push(&mut self, k: Key, v: RawValue, evict_at: Option<u64>, t: u64) -> ResultT<usize>;
+    fn r_push(&mut self, k: Key, v: RawValue, evict_at: Option<u64>, t: u64) -> ResultT<usize>;
+    fn l_pop(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>>;
+    fn r_pop(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>>;
+    // inclusive range, negative indexes count from the end of the list like in LRANGE
+    fn l_range(&mut self, k: &RawValue, start: i64, stop: i64, t: u64) -> ResultT<Vec<RawValue>>;
+    // returns the number of fields added, the expiration is kept
+    fn h_set(&mut self, k: Key, pairs: Vec<(RawValue, RawValue)>, t: u64) -> ResultT<usize>;
+    // the value of every field, None for the missing ones
+    fn h_get(
+        &mut self,
+        k: &RawValue,
+        fields: &[RawValue],
+        t: u64,
+    ) -> ResultT<Vec<Option<RawValue>>>;
+    // returns the number of fields removed, the key is removed with its last field
+    fn h_del(&mut self, k: &RawValue, fields: &[RawValue], t: u64) -> ResultT<usize>;
+    fn h_get_all(&mut self, k: &RawValue, t: u64) -> ResultT<Vec<(RawValue, RawValue)>>;
+    // returns the number of members added, the expiration is kept
+    fn s_add(&mut self, k: Key, members: Vec<RawValue>, t: u64) -> ResultT<usize>;
+    // returns the number of members removed, the key is removed with its last member
+    fn s_rem(&mut self, k: &RawValue, members: &[RawValue], t: u64) -> ResultT<usize>;
+    fn s_members(&mut self, k: &RawValue, t: u64) -> ResultT<Vec<RawValue>>;
+    fn s_is_member(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<bool>;
+    // removes up to count members picked at random
+    fn s_pop(&mut self, k: &RawValue, count: usize, t: u64) -> ResultT<Vec<RawValue>>;
+    // returns the number of members added, the existing ones get the new score
+    fn z_add(&mut self, k: Key, members: Vec<(f64, RawValue)>, t: u64) -> ResultT<usize>;
+    // returns the number of members removed, the key is removed with its last member
+    fn z_rem(&mut self, k: &RawValue, members: &[RawValue], t: u64) -> ResultT<usize>;
+    fn z_score(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<Option<f64>>;
+    // 0 for the member with the lowest score
+    fn z_rank(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<Option<usize>>;
+    // returns the id of the new entry, the expiration is kept
+    fn x_add(
+        &mut self,
+        k: Key,
+        id: NewId,
+        fields: Vec<(RawValue, RawValue)>,
+        t: u64,
+    ) -> ResultT<StreamId>;
+    // entries from start to end included, at most count, the last ones first if rev
+    fn x_range(
+        &mut self,
+        k: &RawValue,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+        t: u64,
+    ) -> ResultT<Vec<StreamEntry>>;
+    // inclusive range of ranks, negative indexes count from the end like in ZRANGE
+    fn z_range(
+        &mut self,
+        k: &RawValue,
+        start: i64,
+        stop: i64,
+        t: u64,
+    ) -> ResultT<Vec<(RawValue, f64)>>;
+    // None for a missing key
+    fn info(&mut self, k: &RawValue, t: u64) -> Option<ValueInfo>;
+    // the largest keys by memory usage, at most count for every kind of value
+    fn big_keys(&mut self, count: usize, t: u64) -> Vec<(Key, ValueInfo)>;
+    // one SCAN page, pattern filters the keys with glob::matches and kind on the TYPE name of
+    // the values, see ScanIndex::page
+    fn scan(
+        &mut self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        kind: Option<&str>,
+        t: u64,
+    ) -> (u64, Vec<Key>);
+    // one HSCAN page of the fields and values of a hash, pattern filters the fields
+    fn h_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<(RawValue, RawValue)>)>;
+    // one SSCAN page of the members of a set
+    fn s_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<RawValue>)>;
+    // one ZSCAN page of the members of a sorted set and their scores
+    fn z_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<(RawValue, f64)>)>;
+    // the keys starting with prefix, None when the storage keeps no index of them
+    fn keys_with_prefix(&mut self, _prefix: &[u8], _t: u64) -> Option<Vec<Key>> {
+        None
+    }
+    fn keys_count(&self) -> usize;
+    fn expires_count(&self) -> usize;
+    // average time to live in ms of the keys with an expiration, 0 without any
+    fn avg_ttl(&self, _t: u64) -> u64 {
+        0
+    }
+    // keys past their expiration time not removed yet, keys are removed by the next command
+    fn expired_stale_count(&self, _t: u64) -> usize {
+        0
+    }
+    // keys removed by expiration since the last call
+    fn take_expired(&mut self) -> Vec<Key> {
+        Vec::new()
+    }
+    // the flags of a memcached item, dropped with the key and replaced by a SET. 0 for a
+    // missing key or one without flags
+    fn client_flags(&mut self, _k: &RawValue, _t: u64) -> u32 {
+        0
+    }
+    // false for a missing key
+    fn set_client_flags(&mut self, _k: &RawValue, _flags: u32, _t: u64) -> bool {
+        false
+    }
+    // one step of an active defrag pass, see defrag::ActiveDefrag: moves the values of count
+    // keys from cursor to new allocations, and shrinks the tables left sparse once the pass is
+    // over. Returns the next cursor, 0 at the end of the pass, and the allocations moved
+    fn defrag(&mut self, _cursor: u64, _count: usize) -> (u64, usize) {
+        (0, 0)
+    }
+    // periodic work, run by the engine cron
+    fn cron(&mut self, _t: u64) {}
+    // None when values are never moved out of memory
+    fn tiering_stats(&self) -> Option<TieringStats> {
+        None
+    }
+}
+
+pub enum Value {
+    String(RawValue),
+    List(List),
+    Hash(HashMap<RawValue, RawValue>),
+    Set(HashSet<RawValue>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+    // a string moved to disk, see Tiering
+    Spilled(Spilled),
+}
+
+impl Value {
+    // as reported by TYPE
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) | Value::Spilled(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // bytes of a string, elements of a list, fields of a hash, members of a set, entries of a
+    // stream
+    pub fn len(&self) -> usize {
+        match self {
+            Value::String(v) => v.len(),
+            Value::List(list) => list.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+            Value::Spilled(spilled) => spilled.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // estimate of the heap used by the value: buffers and reference counts, allocator
+    // overhead excluded
+    pub fn memory(&self) -> usize {
+        match self {
+            Value::String(v) => shared_bytes(v),
+            Value::List(list) => list.memory(),
+            Value::Hash(hash) => {
+                let slots = hash.capacity() * size_of::<(RawValue, RawValue)>();
+                let fields = hash.iter().map(|(f, v)| shared_bytes(f) + shared_bytes(v));
+                slots + fields.sum::<usize>()
+            }
+            Value::Set(set) => {
+                let slots = set.capacity() * size_of::<RawValue>();
+                slots + set.iter().map(shared_bytes).sum::<usize>()
+            }
+            Value::SortedSet(zset) => zset.memory(),
+            Value::Stream(stream) => stream.memory(),
+            Value::Spilled(_) => 0,
+        }
+    }
+
+    // allocations moved
+    pub fn defrag(&mut self) -> usize {
+        match self {
+            Value::String(v) => realloc_bytes(v),
+            Value::List(list) => list.defrag(),
+            // the fields can't be moved in place, only the values are
+            Value::Hash(hash) => hash.values_mut().map(realloc_bytes).sum(),
+            // the members are moved to a new table
+            Value::Set(set) => {
+                let mut moved = 1;
+                *set = set
+                    .drain()
+                    .map(|mut member| {
+                        moved += realloc_bytes(&mut member);
+                        member
+                    })
+                    .collect();
+                moved
+            }
+            Value::SortedSet(zset) => zset.defrag(),
+            Value::Stream(stream) => stream.defrag(),
+            Value::Spilled(_) => 0,
+        }
+    }
+}
+
+// positions of start..=stop in len elements, negative indexes count from the end like in
+// LRANGE. None when the range is empty: a stop before the first element selects nothing
+pub fn range_bounds(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if stop < 0 || start >= len || start > stop {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+// the buffer and the header Bytes allocates to share it: capacity, reference count and the
+// original pointer
+pub fn shared_bytes(v: &RawValue) -> usize {
+    3 * size_of::<usize>() + v.len()
+}
+
+// copies the buffer to a new allocation, unless it's shared: the copy would add to the
+// memory instead of replacing it. Returns the allocations moved
+pub fn realloc_bytes(v: &mut RawValue) -> usize {
+    if v.is_empty() || !v.is_unique() {
+        return 0;
+    }
+    *v = Bytes::copy_from_slice(v);
+    1
+}
+
+// reported by TYPE, STRLEN/LLEN and MEMORY USAGE
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueInfo {
+    pub kind: &'static str,
+    pub len: usize,
+    // key and value, including the slot of the keyspace
+    pub memory: usize,
+}
+
+// default in-memory storage, every key holds a single Value
+pub struct RedisData {
+    map: HashMap<Key, Value>,
+    eviction: BTreeMap<u64, HashSet<Key>>,
+    // eviction time of every key in eviction
+    expires: HashMap<Key, u64>,
+    // sum of the eviction times in expires, kept up to date for avg_ttl
+    expires_total: u128,
+    expired: Vec<Key>,
+    // see with_prefix_index
+    prefix_index: Option<RadixTree>,
+    // the keys by scan_hash, for SCAN
+    scan_index: ScanIndex,
+    // the members of the hashes, sets and sorted sets being scanned, built by the first page of
+    // HSCAN, SSCAN or ZSCAN and dropped by the last one, see scan_members
+    member_indexes: HashMap<Key, ScanIndex>,
+    // non zero flags of the memcached items
+    client_flags: HashMap<Key, u32>,
+    tiering: Option<Tiering>,
+}
+
+const DEFAULT_CAPACITY: usize = 4096;
+const DEFAULT_LIST_CAPACITY: usize = 8;
+// indexes of the members kept between the pages of HSCAN, SSCAN and ZSCAN
+const MAX_MEMBER_INDEXES: usize = 16;
+
+impl Default for RedisData {
+    fn default() -> Self {
+        RedisData::new()
+    }
+}
+
+impl RedisData {
+    pub fn new() -> RedisData {
+        RedisData {
+            map: HashMap::with_capacity(DEFAULT_CAPACITY),
+            eviction: BTreeMap::new(),
+            expires: HashMap::new(),
+            expires_total: 0,
+            expired: Vec::new(),
+            prefix_index: None,
+            scan_index: ScanIndex::default(),
+            member_indexes: HashMap::new(),
+            client_flags: HashMap::new(),
+            tiering: None,
+        }
+    }
+
+    // keeps the keys in a radix tree as well, so listing the keys with a prefix doesn't scan
+    // the whole keyspace, at the cost of a copy of the keys. Enabled by keyspace-prefix-index
+    pub fn with_prefix_index() -> RedisData {
+        RedisData {
+            prefix_index: Some(RadixTree::new()),
+            ..RedisData::new()
+        }
+    }
+
+    // idle strings are moved to disk, see Tiering
+    pub fn set_tiering(&mut self, tiering: Tiering) {
+        self.tiering = Some(tiering);
+    }
+
+    // every key added to or removed from map goes through these two
+    fn insert_key(&mut self, k: Key, v: Value) {
+        if let Some(tiering) = self.tiering.as_mut() {
+            match &v {
+                Value::String(_) => tiering.touch(&k),
+                _ => tiering.forget(&k),
+            }
+        }
+        // an overwritten key is already indexed, only its members may change
+        match self.map.insert(k.clone(), v) {
+            Some(replaced) => {
+                if !self.member_indexes.is_empty() {
+                    self.member_indexes.remove(&k);
+                }
+                self.free(Some(replaced));
+            }
+            None => {
+                if let Some(index) = self.prefix_index.as_mut() {
+                    index.insert(&k);
+                }
+                self.scan_index.insert(&k);
+            }
+        }
+    }
+
+    fn remove_key(&mut self, k: &RawValue) -> bool {
+        if let Some(index) = self.prefix_index.as_mut() {
+            index.remove(k);
+        }
+        self.scan_index.remove(k);
+        self.member_indexes.remove(k);
+        self.client_flags.remove(k);
+        if let Some(tiering) = self.tiering.as_mut() {
+            tiering.forget(k);
+        }
+        let removed = self.map.remove(k);
+        let existed = removed.is_some();
+        self.free(removed);
+        existed
+    }
+
+    fn free(&mut self, v: Option<Value>) {
+        if let (Some(tiering), Some(Value::Spilled(spilled))) = (self.tiering.as_mut(), v) {
+            tiering.free(spilled);
+        }
+    }
+
+    // moves the idle strings to disk and compacts the file when needed
+    fn spill_idle(&mut self, t: u64) {
+        let tiering = match self.tiering.as_mut() {
+            Some(tiering) => tiering,
+            None => return,
+        };
+        tiering.tick(t);
+        // every idle key is spilled or forgotten, the next ones come first at the next tick
+        for k in tiering.idle_keys() {
+            let value = match self.map.get_mut(&k) {
+                Some(value) => value,
+                None => {
+                    tiering.forget(&k);
+                    continue;
+                }
+            };
+            let spilled = match value {
+                Value::String(v) if tiering.spillable(v) => tiering.spill(&k, v),
+                _ => {
+                    tiering.forget(&k);
+                    continue;
+                }
+            };
+            match spilled {
+                Ok(spilled) => *value = Value::Spilled(spilled),
+                Err(err) => {
+                    warn!("Cannot spill values to disk: {}", err);
+                    break;
+                }
+            }
+        }
+        if tiering.needs_compaction() {
+            tiering.compact(self.map.values_mut().filter_map(|v| match v {
+                Value::Spilled(spilled) => Some(spilled),
+                _ => None,
+            }));
+        }
+    }
+
+    // keys are evicted when their eviction time is in the past
+    fn evict_if_needed(&mut self, t: u64) {
+        if self.eviction.range(..t).next().is_none() {
+            return;
+        }
+        let pending = self.eviction.split_off(&t);
+        let due = std::mem::replace(&mut self.eviction, pending);
+        for (t, keys) in due {
+            self.expires_total -= t as u128 * keys.len() as u128;
+            for k in keys {
+                self.expires.remove(&k);
+                if self.remove_key(&k) {
+                    self.expired.push(k);
+                }
+            }
+        }
+    }
+
+    fn insert_eviction(&mut self, k: Key, t: u64) {
+        self.remove_eviction(&k);
+        self.eviction.entry(t).or_default().insert(k.clone());
+        self.expires.insert(k, t);
+        self.expires_total += t as u128;
+    }
+
+    fn remove_eviction(&mut self, k: &RawValue) {
+        if let Some(t) = self.expires.remove(k) {
+            self.expires_total -= t as u128;
+            if let Some(keys) = self.eviction.get_mut(&t) {
+                keys.remove(k);
+                if keys.is_empty() {
+                    self.eviction.remove(&t);
+                }
+            }
+        }
+    }
+
+    fn value_info(&self, k: &Key, v: &Value) -> ValueInfo {
+        let mut memory = size_of::<(Key, Value)>() + shared_bytes(k) + v.memory();
+        if self.expires.contains_key(k) {
+            memory += 2 * size_of::<(Key, u64)>();
+        }
+        ValueInfo {
+            kind: v.kind(),
+            len: v.len(),
+            memory,
+        }
+    }
+
+    fn list(&mut self, k: Key) -> ResultT<&mut List> {
+        if !self.map.contains_key(&k) {
+            let list = List::with_capacity(DEFAULT_LIST_CAPACITY);
+            self.insert_key(k.clone(), Value::List(list));
+        }
+        match self.map.get_mut(&k) {
+            Some(Value::List(list)) => Ok(list),
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    fn hash(&mut self, k: Key) -> ResultT<&mut HashMap<RawValue, RawValue>> {
+        if !self.map.contains_key(&k) {
+            self.insert_key(k.clone(), Value::Hash(HashMap::new()));
+        }
+        match self.map.get_mut(&k) {
+            Some(Value::Hash(hash)) => Ok(hash),
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    fn get_hash(&mut self, k: &RawValue, t: u64) -> ResultT<Option<&HashMap<RawValue, RawValue>>> {
+        self.evict_if_needed(t);
+        match self.map.get(k) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(RdisError::WrongType),
+        }
+    }
+
+    fn set_entry(&mut self, k: Key) -> ResultT<&mut HashSet<RawValue>> {
+        if !self.map.contains_key(&k) {
+            self.insert_key(k.clone(), Value::Set(HashSet::new()));
+        }
+        match self.map.get_mut(&k) {
+            Some(Value::Set(set)) => Ok(set),
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    fn get_set(&mut self, k: &RawValue, t: u64) -> ResultT<Option<&mut HashSet<RawValue>>> {
+        self.evict_if_needed(t);
+        match self.map.get_mut(k) {
+            None => Ok(None),
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(RdisError::WrongType),
+        }
+    }
+
+    fn sorted_set(&mut self, k: Key) -> ResultT<&mut SortedSet> {
+        if !self.map.contains_key(&k) {
+            self.insert_key(k.clone(), Value::SortedSet(SortedSet::new()));
+        }
+        match self.map.get_mut(&k) {
+            Some(Value::SortedSet(zset)) => Ok(zset),
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    fn get_sorted_set(&mut self, k: &RawValue, t: u64) -> ResultT<Option<&SortedSet>> {
+        self.evict_if_needed(t);
+        match self.map.get(k) {
+            None => Ok(None),
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(RdisError::WrongType),
+        }
+    }
+
+    fn stream(&mut self, k: Key) -> ResultT<&mut Stream> {
+        if !self.map.contains_key(&k) {
+            self.insert_key(k.clone(), Value::Stream(Stream::default()));
+        }
+        match self.map.get_mut(&k) {
+            Some(Value::Stream(stream)) => Ok(stream),
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    // empty sets are removed like in redis
+    fn remove_if_empty_set(&mut self, k: &RawValue) {
+        if let Some(Value::Set(set)) = self.map.get(k) {
+            if set.is_empty() {
+                self.remove_key(k);
+                self.remove_eviction(k);
+            }
+        }
+    }
+
+    // keeps the index of the members of k in sync, if it was scanned
+    fn reindex_members(&mut self, k: &[u8], added: &[RawValue], removed: &[RawValue]) {
+        if let Some(index) = self.member_indexes.get_mut(k) {
+            added.iter().for_each(|member| index.insert(member));
+            removed.iter().for_each(|member| index.remove(member));
+        }
+    }
+
+    // one page of the fields of a hash or the members of a set or a sorted set. The index of the
+    // members is built by the first page and dropped by the last one. An index follows from the
+    // members alone, so the indexes of scans never completed are dropped past
+    // MAX_MEMBER_INDEXES: their next page builds them again
+    fn scan_members(
+        &mut self,
+        k: &RawValue,
+        kind: &str,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<RawValue>)> {
+        self.evict_if_needed(t);
+        let index = match (self.map.get(k), self.member_indexes.get(k)) {
+            (None, _) => return Ok((0, Vec::new())),
+            (Some(value), _) if value.kind() != kind => return Err(RdisError::WrongType),
+            (Some(_), Some(index)) => index,
+            (Some(value), None) => {
+                let index = match value {
+                    Value::Hash(hash) => hash.keys().collect(),
+                    Value::Set(set) => set.iter().collect(),
+                    Value::SortedSet(zset) => zset.members().collect(),
+                    _ => ScanIndex::default(),
+                };
+                if self.member_indexes.len() >= MAX_MEMBER_INDEXES {
+                    if let Some(dropped) = self.member_indexes.keys().next().cloned() {
+                        self.member_indexes.remove(&dropped);
+                    }
+                }
+                self.member_indexes.entry(k.clone()).or_insert(index)
+            }
+        };
+        let (next, members) = index.page(cursor, count);
+        if next == 0 {
+            self.member_indexes.remove(k);
+        }
+        let members = members
+            .into_iter()
+            .filter(|m| pattern.is_none_or(|pattern| glob::matches(pattern, m, false)))
+            .collect();
+        Ok((next, members))
+    }
+
+    // an expired list is replaced by a new one
+    fn push(
+        &mut self,
+        k: Key,
+        v: RawValue,
+        evict_at: Option<u64>,
+        front: bool,
+        t: u64,
+    ) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let list = self.list(k.clone())?;
+        if front {
+            list.push_front(v);
+        } else {
+            list.push_back(v);
+        }
+        let len = list.len();
+        if let Some(at) = evict_at {
+            self.insert_eviction(k, at)
+        }
+        Ok(len)
+    }
+
+    // empty lists are removed like in redis
+    fn pop(&mut self, k: &RawValue, front: bool, t: u64) -> ResultT<Option<RawValue>> {
+        self.evict_if_needed(t);
+        let list = match self.map.get_mut(k) {
+            None => return Ok(None),
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(RdisError::WrongType),
+        };
+        let popped = if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        };
+        if list.is_empty() {
+            self.remove_key(k);
+            self.remove_eviction(k);
+        }
+        Ok(popped)
+    }
+}
+
+// tables using less than a quarter of their capacity are halved at least
+fn shrink<K: Eq + std::hash::Hash, V>(table: &mut HashMap<K, V>) -> bool {
+    if table.capacity() <= DEFAULT_CAPACITY || table.len() * 4 > table.capacity() {
+        return false;
+    }
+    table.shrink_to(DEFAULT_CAPACITY.max(2 * table.len()));
+    true
+}
+
+impl Storage for RedisData {
+    fn set(&mut self, k: RawValue, v: RawValue, evict_at: Option<u64>) {
+        if !self.client_flags.is_empty() {
+            self.client_flags.remove(&k);
+        }
+        self.insert_key(k.clone(), Value::String(v));
+        match evict_at {
+            Some(t) => self.insert_eviction(k, t),
+            None => self.remove_eviction(&k),
+        }
+    }
+
+    fn get(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>> {
+        self.evict_if_needed(t);
+        let (key, value) = match self.map.get_key_value(k) {
+            None => return Ok(None),
+            Some((key, value)) => (key.clone(), value),
+        };
+        match (value, self.tiering.as_mut()) {
+            (Value::String(v), Some(tiering)) => {
+                tiering.touch(&key);
+                Ok(Some(v.clone()))
+            }
+            (Value::String(v), None) => Ok(Some(v.clone())),
+            (Value::Spilled(spilled), Some(tiering)) => {
+                let v = tiering
+                    .load(&key, *spilled)
+                    .map_err(|err| format!("cannot read the value from disk: {}", err))?;
+                self.map.insert(key, Value::String(v.clone()));
+                Ok(Some(v))
+            }
+            _ => Err(RdisError::WrongType),
+        }
+    }
+
+    fn incr_by(&mut self, k: Key, by: i64, t: u64) -> ResultT<i64> {
+        let current = match self.get(&k, t)? {
+            None => 0,
+            Some(int_raw) => std::str::from_utf8(&int_raw)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or(RdisError::NotInteger)?,
+        };
+        let value = current
+            .checked_add(by)
+            .ok_or_else(|| RdisError::from("increment or decrement would overflow"))?;
+        self.insert_key(k, Value::String(Bytes::from(value.to_string())));
+        Ok(value)
+    }
+
+    fn del(&mut self, k: &RawValue, t: u64) -> bool {
+        self.evict_if_needed(t);
+        self.remove_eviction(k);
+        self.remove_key(k)
+    }
+
+    fn expire(&mut self, k: &RawValue, evict_at: u64, t: u64) -> bool {
+        self.evict_if_needed(t);
+        let key = match self.map.get_key_value(k) {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        if evict_at <= t {
+            self.remove_eviction(k);
+            self.remove_key(k);
+        } else {
+            self.insert_eviction(key, evict_at);
+        }
+        true
+    }
+
+    fn persist(&mut self, k: &RawValue, t: u64) -> bool {
+        self.evict_if_needed(t);
+        let expires = self.expires.contains_key(k);
+        self.remove_eviction(k);
+        expires
+    }
+
+    fn expires_at(&mut self, k: &RawValue, t: u64) -> Option<Option<u64>> {
+        self.evict_if_needed(t);
+        if !self.map.contains_key(k) {
+            return None;
+        }
+        Some(self.expires.get(k).copied())
+    }
+
+    fn l_push(
+        &mut self,
+        k: RawValue,
+        v: RawValue,
+        evict_at: Option<u64>,
+        t: u64,
+    ) -> ResultT<usize> {
+        self.push(k, v, evict_at, true, t)
+    }
+
+    fn r_push(
+        &mut self,
+        k: RawValue,
+        v: RawValue,
+        evict_at: Option<u64>,
+        t: u64,
+    ) -> ResultT<usize> {
+        self.push(k, v, evict_at, false, t)
+    }
+
+    fn l_range(&mut self, k: &RawValue, start: i64, stop: i64, t: u64) -> ResultT<Vec<RawValue>> {
+        self.evict_if_needed(t);
+        let list = match self.map.get(k) {
+            None => return Ok(Vec::new()),
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(RdisError::WrongType),
+        };
+        match range_bounds(start, stop, list.len()) {
+            Some((start, stop)) => Ok(list.range(start, stop)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // an expired hash is replaced by a new one
+    fn h_set(&mut self, k: Key, pairs: Vec<(RawValue, RawValue)>, t: u64) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let indexed = self.member_indexes.contains_key(&k);
+        let hash = self.hash(k.clone())?;
+        let (mut added, mut new_fields) = (0, Vec::new());
+        for (field, v) in pairs {
+            if indexed && !hash.contains_key(&field) {
+                new_fields.push(field.clone());
+            }
+            if hash.insert(field, v).is_none() {
+                added += 1;
+            }
+        }
+        self.reindex_members(&k, &new_fields, &[]);
+        Ok(added)
+    }
+
+    fn h_get(
+        &mut self,
+        k: &RawValue,
+        fields: &[RawValue],
+        t: u64,
+    ) -> ResultT<Vec<Option<RawValue>>> {
+        let hash = self.get_hash(k, t)?;
+        Ok(fields
+            .iter()
+            .map(|field| hash.and_then(|hash| hash.get(field).cloned()))
+            .collect())
+    }
+
+    // empty hashes are removed like in redis
+    fn h_del(&mut self, k: &RawValue, fields: &[RawValue], t: u64) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let hash = match self.map.get_mut(k) {
+            None => return Ok(0),
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return Err(RdisError::WrongType),
+        };
+        let removed: Vec<RawValue> = fields
+            .iter()
+            .filter(|field| hash.remove(*field).is_some())
+            .cloned()
+            .collect();
+        let empty = hash.is_empty();
+        self.reindex_members(k, &[], &removed);
+        if empty {
+            self.remove_key(k);
+            self.remove_eviction(k);
+        }
+        Ok(removed.len())
+    }
+
+    fn h_get_all(&mut self, k: &RawValue, t: u64) -> ResultT<Vec<(RawValue, RawValue)>> {
+        let hash = self.get_hash(k, t)?;
+        Ok(hash
+            .map(|hash| hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    // an expired set is replaced by a new one
+    fn s_add(&mut self, k: Key, members: Vec<RawValue>, t: u64) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let set = self.set_entry(k.clone())?;
+        let added: Vec<RawValue> = members
+            .into_iter()
+            .filter(|member| set.insert(member.clone()))
+            .collect();
+        self.reindex_members(&k, &added, &[]);
+        Ok(added.len())
+    }
+
+    fn s_rem(&mut self, k: &RawValue, members: &[RawValue], t: u64) -> ResultT<usize> {
+        let removed: Vec<RawValue> = match self.get_set(k, t)? {
+            None => return Ok(0),
+            Some(set) => members
+                .iter()
+                .filter(|member| set.remove(*member))
+                .cloned()
+                .collect(),
+        };
+        self.reindex_members(k, &[], &removed);
+        self.remove_if_empty_set(k);
+        Ok(removed.len())
+    }
+
+    fn s_members(&mut self, k: &RawValue, t: u64) -> ResultT<Vec<RawValue>> {
+        let set = self.get_set(k, t)?;
+        Ok(set
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn s_is_member(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<bool> {
+        let set = self.get_set(k, t)?;
+        Ok(set.is_some_and(|set| set.contains(member)))
+    }
+
+    // reservoir sampling: one pass over the members keeps count of them, without copying the set
+    fn s_pop(&mut self, k: &RawValue, count: usize, t: u64) -> ResultT<Vec<RawValue>> {
+        let set = match self.get_set(k, t)? {
+            None => return Ok(Vec::new()),
+            Some(set) => set,
+        };
+        let popped = if count >= set.len() {
+            set.drain().collect()
+        } else {
+            let random = RandomState::new();
+            let mut popped: Vec<RawValue> = Vec::with_capacity(count);
+            for (seen, member) in set.iter().enumerate() {
+                if seen < count {
+                    popped.push(member.clone());
+                    continue;
+                }
+                let at = random.hash_one(seen) as usize % (seen + 1);
+                if at < count {
+                    popped[at] = member.clone();
+                }
+            }
+            for member in &popped {
+                set.remove(member);
+            }
+            popped
+        };
+        self.reindex_members(k, &[], &popped);
+        self.remove_if_empty_set(k);
+        Ok(popped)
+    }
+
+    // an expired sorted set is replaced by a new one
+    fn z_add(&mut self, k: Key, members: Vec<(f64, RawValue)>, t: u64) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let zset = self.sorted_set(k.clone())?;
+        let added: Vec<RawValue> = members
+            .into_iter()
+            .filter(|(score, member)| zset.insert(member.clone(), *score))
+            .map(|(_, member)| member)
+            .collect();
+        self.reindex_members(&k, &added, &[]);
+        Ok(added.len())
+    }
+
+    // empty sorted sets are removed like in redis
+    fn z_rem(&mut self, k: &RawValue, members: &[RawValue], t: u64) -> ResultT<usize> {
+        self.evict_if_needed(t);
+        let zset = match self.map.get_mut(k) {
+            None => return Ok(0),
+            Some(Value::SortedSet(zset)) => zset,
+            Some(_) => return Err(RdisError::WrongType),
+        };
+        let removed: Vec<RawValue> = members.iter().filter(|m| zset.remove(m)).cloned().collect();
+        let empty = zset.is_empty();
+        self.reindex_members(k, &[], &removed);
+        if empty {
+            self.remove_key(k);
+            self.remove_eviction(k);
+        }
+        Ok(removed.len())
+    }
+
+    // an expired stream is replaced by a new one, a stream created for an entry with an invalid
+    // id is removed
+    fn x_add(
+        &mut self,
+        k: Key,
+        id: NewId,
+        fields: Vec<(RawValue, RawValue)>,
+        t: u64,
+    ) -> ResultT<StreamId> {
+        self.evict_if_needed(t);
+        let stream = self.stream(k.clone())?;
+        let added = stream.add(id, fields, t);
+        if stream.is_empty() {
+            self.remove_key(&k);
+        }
+        added
+    }
+
+    fn x_range(
+        &mut self,
+        k: &RawValue,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+        t: u64,
+    ) -> ResultT<Vec<StreamEntry>> {
+        self.evict_if_needed(t);
+        match self.map.get(k) {
+            None => Ok(Vec::new()),
+            Some(Value::Stream(stream)) => Ok(stream.range(start, end, count, rev)),
+            Some(_) => Err(RdisError::WrongType),
+        }
+    }
+
+    fn z_score(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<Option<f64>> {
+        let zset = self.get_sorted_set(k, t)?;
+        Ok(zset.and_then(|zset| zset.score(member)))
+    }
+
+    fn z_rank(&mut self, k: &RawValue, member: &RawValue, t: u64) -> ResultT<Option<usize>> {
+        let zset = self.get_sorted_set(k, t)?;
+        Ok(zset.and_then(|zset| zset.rank(member)))
+    }
+
+    fn z_range(
+        &mut self,
+        k: &RawValue,
+        start: i64,
+        stop: i64,
+        t: u64,
+    ) -> ResultT<Vec<(RawValue, f64)>> {
+        let zset = match self.get_sorted_set(k, t)? {
+            None => return Ok(Vec::new()),
+            Some(zset) => zset,
+        };
+        match range_bounds(start, stop, zset.len()) {
+            Some((start, stop)) => Ok(zset.range(start, stop)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn info(&mut self, k: &RawValue, t: u64) -> Option<ValueInfo> {
+        self.evict_if_needed(t);
+        let (key, value) = self.map.get_key_value(k)?;
+        Some(self.value_info(key, value))
+    }
+
+    fn big_keys(&mut self, count: usize, t: u64) -> Vec<(Key, ValueInfo)> {
+        self.evict_if_needed(t);
+        let mut by_kind: BTreeMap<&'static str, Vec<(Key, ValueInfo)>> = BTreeMap::new();
+        for (k, v) in self.map.iter() {
+            let biggest = by_kind.entry(v.kind()).or_default();
+            biggest.push((k.clone(), self.value_info(k, v)));
+            // keeps the memory bounded by count, sorting only once in a while
+            if biggest.len() >= 2 * count.max(1) {
+                biggest.sort_by_key(|(_, info)| std::cmp::Reverse(info.memory));
+                biggest.truncate(count);
+            }
+        }
+        let mut keys = Vec::new();
+        for (_, mut biggest) in by_kind {
+            biggest.sort_by_key(|(_, info)| std::cmp::Reverse(info.memory));
+            biggest.truncate(count);
+            keys.extend(biggest);
+        }
+        keys
+    }
+
+    fn scan(
+        &mut self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        kind: Option<&str>,
+        t: u64,
+    ) -> (u64, Vec<Key>) {
+        self.evict_if_needed(t);
+        let (next, keys) = self.scan_index.page(cursor, count);
+        // the filters are applied to the page like in redis, so it may be empty
+        let keys = keys
+            .into_iter()
+            .filter(|k| pattern.is_none_or(|pattern| glob::matches(pattern, k, false)))
+            .filter(|k| kind.is_none_or(|kind| self.map.get(k).is_some_and(|v| v.kind() == kind)))
+            .collect();
+        (next, keys)
+    }
+
+    fn h_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<(RawValue, RawValue)>)> {
+        let (next, fields) = self.scan_members(k, "hash", cursor, count, pattern, t)?;
+        let pairs = match self.map.get(k) {
+            Some(Value::Hash(hash)) => fields
+                .into_iter()
+                .filter_map(|f| hash.get(&f).map(|v| (f, v.clone())))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok((next, pairs))
+    }
+
+    fn s_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<RawValue>)> {
+        self.scan_members(k, "set", cursor, count, pattern, t)
+    }
+
+    fn z_scan(
+        &mut self,
+        k: &RawValue,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        t: u64,
+    ) -> ResultT<(u64, Vec<(RawValue, f64)>)> {
+        let (next, members) = self.scan_members(k, "zset", cursor, count, pattern, t)?;
+        let pairs = match self.map.get(k) {
+            Some(Value::SortedSet(zset)) => members
+                .into_iter()
+                .filter_map(|m| zset.score(&m).map(|score| (m, score)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok((next, pairs))
+    }
+
+    fn keys_with_prefix(&mut self, prefix: &[u8], t: u64) -> Option<Vec<Key>> {
+        self.evict_if_needed(t);
+        Some(self.prefix_index.as_ref()?.with_prefix(prefix))
+    }
+
+    fn keys_count(&self) -> usize {
+        self.map.len()
+    }
+
+    fn expires_count(&self) -> usize {
+        self.expires.len()
+    }
+
+    // keys past their expiration lower the average until they're removed
+    fn avg_ttl(&self, t: u64) -> u64 {
+        let n = self.expires.len() as u128;
+        match self.expires_total.checked_div(n) {
+            Some(avg) => avg.saturating_sub(t as u128) as u64,
+            None => 0,
+        }
+    }
+
+    fn expired_stale_count(&self, t: u64) -> usize {
+        self.eviction.range(..t).map(|(_, keys)| keys.len()).sum()
+    }
+
+    fn take_expired(&mut self) -> Vec<Key> {
+        std::mem::take(&mut self.expired)
+    }
+
+    fn client_flags(&mut self, k: &RawValue, t: u64) -> u32 {
+        self.evict_if_needed(t);
+        self.client_flags.get(k).copied().unwrap_or(0)
+    }
+
+    fn set_client_flags(&mut self, k: &RawValue, flags: u32, t: u64) -> bool {
+        self.evict_if_needed(t);
+        let key = match self.map.get_key_value(k) {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        if flags == 0 {
+            self.client_flags.remove(k);
+        } else {
+            self.client_flags.insert(key, flags);
+        }
+        true
+    }
+
+    fn cron(&mut self, t: u64) {
+        self.spill_idle(t);
+    }
+
+    fn tiering_stats(&self) -> Option<TieringStats> {
+        self.tiering.as_ref().map(|tiering| tiering.stats.clone())
+    }
+
+    // keys are visited by their position in the map, one resized in between may make a pass
+    // skip or revisit some of them, which is harmless
+    fn defrag(&mut self, cursor: u64, count: usize) -> (u64, usize) {
+        let mut moved = 0;
+        for v in self.map.values_mut().skip(cursor as usize).take(count) {
+            moved += v.defrag();
+        }
+        let next = cursor as usize + count;
+        if next < self.map.len() {
+            return (next as u64, moved);
+        }
+        if shrink(&mut self.map) {
+            moved += 1;
+        }
+        if shrink(&mut self.expires) {
+            moved += 1;
+        }
+        (0, moved)
+    }
+
+    fn l_pop(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>> {
+        self.pop(k, true, t)
+    }
+
+    fn r_pop(&mut self, k: &RawValue, t: u64) -> ResultT<Option<RawValue>> {
+        self.pop(k, false, t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(s: &str) -> RawValue {
+        Bytes::copy_from_slice(s.as_bytes())
+    }
+
+    #[test]
+    pub fn test_expiration() {
+        let mut data = RedisData::new();
+        data.set(raw("k"), raw("v"), Some(10));
+        data.set(raw("p"), raw("v"), None);
+        assert_eq!(data.expires_count(), 1);
+        assert_eq!(data.get(&raw("k"), 5).unwrap(), Some(raw("v")));
+        assert_eq!(data.get(&raw("k"), 11).unwrap(), None);
+        assert_eq!(data.take_expired(), vec![raw("k")]);
+        assert!(data.take_expired().is_empty());
+        assert_eq!(data.get(&raw("p"), 11).unwrap(), Some(raw("v")));
+        assert_eq!(data.keys_count(), 1);
+    }
+
+    #[test]
+    pub fn test_defrag() -> ResultT<()> {
+        let mut data = RedisData::new();
+        for i in 0..10_000 {
+            data.set(raw(&format!("k{}", i)), raw("v"), None);
+        }
+        data.r_push(raw("l"), raw("a"), None, 0)?;
+        for i in 100..10_000 {
+            data.del(&raw(&format!("k{}", i)), 0);
+        }
+        let capacity = data.map.capacity();
+        let before = match data.map.get(&raw("k1")) {
+            Some(Value::String(v)) => v.as_ptr(),
+            _ => panic!("k1 is missing"),
+        };
+        let (mut cursor, mut moved, mut steps) = (0, 0, 0);
+        loop {
+            let (next, n) = data.defrag(cursor, 30);
+            moved += n;
+            steps += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(steps, 4);
+        // 100 strings, the list and its element, the keys table
+        assert_eq!(moved, 103);
+        assert!(data.map.capacity() < capacity);
+        match data.map.get(&raw("k1")) {
+            Some(Value::String(v)) => assert!(v.as_ptr() != before && v == &raw("v")),
+            _ => panic!("k1 is missing"),
+        }
+        assert_eq!(data.l_range(&raw("l"), 0, -1, 0)?, vec![raw("a")]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_expired_lists() -> ResultT<()> {
+        let mut data = RedisData::new();
+        data.r_push(raw("l"), raw("a"), Some(10), 0)?;
+        data.r_push(raw("l"), raw("b"), None, 5)?;
+        assert_eq!(data.l_pop(&raw("l"), 11)?, None);
+        data.r_push(raw("m"), raw("a"), Some(10), 0)?;
+        // pushing to an expired list starts a new one, without the expiration
+        assert_eq!(data.l_push(raw("m"), raw("b"), None, 11)?, 1);
+        assert_eq!(data.r_pop(&raw("m"), 11)?, Some(raw("b")));
+        assert_eq!(data.take_expired(), vec![raw("l"), raw("m")]);
+        assert_eq!(data.expires_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_hashes() -> ResultT<()> {
+        let mut data: Box<dyn Storage> = Box::new(RedisData::new());
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(f, v)| (raw(f), raw(v))).collect();
+        assert_eq!(
+            data.h_set(raw("h"), pairs(&[("a", "1"), ("b", "2")]), 0)?,
+            2
+        );
+        assert_eq!(
+            data.h_set(raw("h"), pairs(&[("a", "3"), ("c", "4")]), 0)?,
+            1
+        );
+        let fields = [raw("a"), raw("missing"), raw("c")];
+        assert_eq!(
+            data.h_get(&raw("h"), &fields, 0)?,
+            vec![Some(raw("3")), None, Some(raw("4"))]
+        );
+        assert_eq!(data.h_get(&raw("none"), &fields[..1], 0)?, vec![None]);
+        let mut all = data.h_get_all(&raw("h"), 0)?;
+        all.sort();
+        assert_eq!(all, pairs(&[("a", "3"), ("b", "2"), ("c", "4")]));
+        assert_eq!(data.info(&raw("h"), 0).map(|info| info.len), Some(3));
+        assert_eq!(data.h_del(&raw("h"), &[raw("a"), raw("missing")], 0)?, 1);
+        // the key goes with its last field
+        assert_eq!(data.h_del(&raw("h"), &[raw("b"), raw("c")], 0)?, 2);
+        assert_eq!(data.keys_count(), 0);
+        assert!(data.h_get_all(&raw("h"), 0)?.is_empty());
+
+        data.set(raw("s"), raw("v"), None);
+        assert!(data.h_set(raw("s"), pairs(&[("a", "1")]), 0).is_err());
+        assert!(data.h_get(&raw("s"), &fields, 0).is_err());
+        assert!(data.h_del(&raw("s"), &fields, 0).is_err());
+        assert_eq!(data.get(&raw("s"), 0)?, Some(raw("v")));
+
+        // an expired key is replaced by a new hash, without the expiration
+        data.set(raw("x"), raw("v"), Some(10));
+        assert_eq!(data.h_set(raw("x"), pairs(&[("b", "2")]), 11)?, 1);
+        assert_eq!(data.h_get_all(&raw("x"), 11)?, pairs(&[("b", "2")]));
+        assert_eq!(data.expires_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_sets() -> ResultT<()> {
+        let mut data: Box<dyn Storage> = Box::new(RedisData::new());
+        let members = |members: &[&str]| members.iter().map(|m| raw(m)).collect::<Vec<_>>();
+        assert_eq!(data.s_add(raw("s"), members(&["a", "b", "a"]), 0)?, 2);
+        assert_eq!(data.s_add(raw("s"), members(&["b", "c"]), 0)?, 1);
+        assert!(data.s_is_member(&raw("s"), &raw("c"), 0)?);
+        assert!(!data.s_is_member(&raw("s"), &raw("d"), 0)?);
+        assert!(!data.s_is_member(&raw("none"), &raw("d"), 0)?);
+        let mut all = data.s_members(&raw("s"), 0)?;
+        all.sort();
+        assert_eq!(all, members(&["a", "b", "c"]));
+        assert_eq!(data.info(&raw("s"), 0).map(|info| info.len), Some(3));
+        assert_eq!(data.s_rem(&raw("s"), &members(&["a", "d"]), 0)?, 1);
+        let popped = data.s_pop(&raw("s"), 1, 0)?;
+        assert_eq!(popped.len(), 1);
+        assert!(!data.s_is_member(&raw("s"), &popped[0], 0)?);
+        // the key goes with its last member
+        assert_eq!(data.s_pop(&raw("s"), 5, 0)?.len(), 1);
+        assert_eq!(data.keys_count(), 0);
+        assert!(data.s_pop(&raw("s"), 1, 0)?.is_empty());
+
+        let many: Vec<RawValue> = (0..100).map(|i| raw(&i.to_string())).collect();
+        data.s_add(raw("m"), many.clone(), 0)?;
+        let mut popped = data.s_pop(&raw("m"), 60, 0)?;
+        assert_eq!(popped.len(), 60);
+        assert_eq!(data.info(&raw("m"), 0).map(|info| info.len), Some(40));
+        popped.extend(data.s_members(&raw("m"), 0)?);
+        popped.sort();
+        let mut expected = many;
+        expected.sort();
+        assert_eq!(popped, expected);
+
+        data.set(raw("str"), raw("v"), None);
+        assert!(data.s_add(raw("str"), members(&["a"]), 0).is_err());
+        assert!(data.s_members(&raw("str"), 0).is_err());
+        assert!(data.s_pop(&raw("str"), 1, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_sorted_sets() -> ResultT<()> {
+        let mut data: Box<dyn Storage> = Box::new(RedisData::new());
+        let members =
+            |pairs: &[(f64, &str)]| pairs.iter().map(|(s, m)| (*s, raw(m))).collect::<Vec<_>>();
+        let added = data.z_add(raw("z"), members(&[(2.0, "b"), (1.0, "a"), (3.0, "a")]), 0)?;
+        assert_eq!(added, 2);
+        assert_eq!(data.z_add(raw("z"), members(&[(0.5, "c")]), 0)?, 1);
+        assert_eq!(data.z_score(&raw("z"), &raw("a"), 0)?, Some(3.0));
+        assert_eq!(data.z_score(&raw("z"), &raw("d"), 0)?, None);
+        assert_eq!(data.z_rank(&raw("z"), &raw("b"), 0)?, Some(1));
+        assert_eq!(data.z_rank(&raw("none"), &raw("b"), 0)?, None);
+        assert_eq!(
+            data.z_range(&raw("z"), 1, -1, 0)?,
+            vec![(raw("b"), 2.0), (raw("a"), 3.0)]
+        );
+        assert_eq!(data.z_range(&raw("z"), -10, 0, 0)?, vec![(raw("c"), 0.5)]);
+        assert!(data.z_range(&raw("z"), 2, 1, 0)?.is_empty());
+        // a negative stop before the first member selects nothing
+        assert!(data.z_range(&raw("z"), 0, -100, 0)?.is_empty());
+        assert_eq!(data.info(&raw("z"), 0).map(|info| info.kind), Some("zset"));
+        // the key goes with its last member
+        assert_eq!(data.z_rem(&raw("z"), &[raw("a"), raw("d")], 0)?, 1);
+        assert_eq!(data.z_rem(&raw("z"), &[raw("b"), raw("c")], 0)?, 2);
+        assert_eq!(data.keys_count(), 0);
+
+        data.set(raw("str"), raw("v"), None);
+        assert!(data.z_add(raw("str"), members(&[(1.0, "a")]), 0).is_err());
+        assert!(data.z_range(&raw("str"), 0, -1, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_lists() -> ResultT<()> {
+        let mut data: Box<dyn Storage> = Box::new(RedisData::new());
+        data.r_push(raw("l"), raw("a"), None, 0)?;
+        data.r_push(raw("l"), raw("b"), None, 0)?;
+        data.l_push(raw("l"), raw("c"), None, 0)?;
+        assert_eq!(data.l_pop(&raw("l"), 0)?, Some(raw("c")));
+        assert_eq!(data.r_pop(&raw("l"), 0)?, Some(raw("b")));
+        assert_eq!(data.r_pop(&raw("missing"), 0)?, None);
+        assert_eq!(data.keys_count(), 1);
+        assert_eq!(data.l_pop(&raw("l"), 0)?, Some(raw("a")));
+        assert_eq!(data.keys_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_l_range() -> ResultT<()> {
+        let mut data = RedisData::new();
+        for (idx, v) in ["a", "b", "c", "d"].iter().enumerate() {
+            assert_eq!(data.r_push(raw("l"), raw(v), None, 0)?, idx + 1);
+        }
+        assert_eq!(data.l_range(&raw("l"), 0, 1, 0)?, vec![raw("a"), raw("b")]);
+        assert_eq!(
+            data.l_range(&raw("l"), -2, 100, 0)?,
+            vec![raw("c"), raw("d")]
+        );
+        assert_eq!(data.l_range(&raw("l"), -100, 0, 0)?, vec![raw("a")]);
+        assert!(data.l_range(&raw("l"), 3, 1, 0)?.is_empty());
+        assert!(data.l_range(&raw("l"), 5, 10, 0)?.is_empty());
+        // a negative stop past the head selects nothing
+        assert!(data.l_range(&raw("l"), 0, -100, 0)?.is_empty());
+        assert!(data.l_range(&raw("l"), -100, -5, 0)?.is_empty());
+        assert_eq!(data.l_range(&raw("l"), -100, -4, 0)?, vec![raw("a")]);
+        assert!(data.l_range(&raw("missing"), 0, -1, 0)?.is_empty());
+        data.set(raw("s"), raw("v"), None);
+        assert!(matches!(
+            data.l_range(&raw("s"), 0, -1, 0),
+            Err(RdisError::WrongType)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_wrong_type() -> ResultT<()> {
+        let mut data = RedisData::new();
+        data.set(raw("s"), raw("1"), None);
+        data.r_push(raw("l"), raw("a"), None, 0)?;
+        assert!(matches!(data.get(&raw("l"), 0), Err(RdisError::WrongType)));
+        assert!(matches!(
+            data.incr_by(raw("l"), 1, 0),
+            Err(RdisError::WrongType)
+        ));
+        assert!(matches!(
+            data.l_push(raw("s"), raw("a"), None, 0),
+            Err(RdisError::WrongType)
+        ));
+        assert!(matches!(
+            data.r_pop(&raw("s"), 0),
+            Err(RdisError::WrongType)
+        ));
+        assert_eq!(data.keys_count(), 2);
+        // SET replaces a key of any kind
+        data.set(raw("l"), raw("v"), None);
+        assert_eq!(data.get(&raw("l"), 0)?, Some(raw("v")));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_overwrite_clears_expiration() {
+        let mut data = RedisData::new();
+        data.set(raw("k"), raw("v"), Some(10));
+        data.set(raw("k"), raw("w"), None);
+        assert_eq!(data.expires_count(), 0);
+        assert_eq!(data.get(&raw("k"), 20).unwrap(), Some(raw("w")));
+        // an eviction time already in the past expires the key on the next access
+        data.set(raw("k"), raw("v"), Some(5));
+        assert_eq!(data.get(&raw("k"), 20).unwrap(), None);
+        data.set(raw("d"), raw("v"), Some(30));
+        assert!(data.del(&raw("d"), 20));
+        assert!(!data.del(&raw("d"), 20));
+        assert_eq!(data.expires_count(), 0);
+    }
+
+    // flags go with the key: expiring, deleting or setting it again drops them
+    #[test]
+    pub fn test_client_flags() {
+        let mut data = RedisData::new();
+        assert!(!data.set_client_flags(&raw("k"), 7, 0));
+        assert_eq!(data.client_flags(&raw("k"), 0), 0);
+        data.set(raw("k"), raw("v"), Some(10));
+        assert!(data.set_client_flags(&raw("k"), 7, 0));
+        assert_eq!(data.client_flags(&raw("k"), 0), 7);
+        assert_eq!(data.client_flags(&raw("k"), 20), 0);
+        assert!(data.client_flags.is_empty());
+        data.set(raw("k"), raw("v"), None);
+        data.set_client_flags(&raw("k"), 7, 0);
+        data.set(raw("k"), raw("w"), None);
+        assert_eq!(data.client_flags(&raw("k"), 0), 0);
+        data.set_client_flags(&raw("k"), 7, 0);
+        data.del(&raw("k"), 0);
+        assert!(data.client_flags.is_empty());
+    }
+
+    #[test]
+    pub fn test_expire_and_persist() -> ResultT<()> {
+        let mut data = RedisData::new();
+        assert!(!data.expire(&raw("k"), 100, 0));
+        assert_eq!(data.expires_at(&raw("k"), 0), None);
+        data.r_push(raw("k"), raw("v"), None, 0)?;
+        assert_eq!(data.expires_at(&raw("k"), 0), Some(None));
+        assert!(data.expire(&raw("k"), 100, 0));
+        assert!(data.expire(&raw("k"), 50, 0));
+        assert_eq!(data.expires_at(&raw("k"), 0), Some(Some(50)));
+        assert_eq!(data.avg_ttl(0), 50);
+        assert!(data.persist(&raw("k"), 0));
+        assert!(!data.persist(&raw("k"), 0));
+        assert_eq!(data.expires_count(), 0);
+        assert_eq!(data.l_range(&raw("k"), 0, -1, 200)?, vec![raw("v")]);
+        // a time not in the future removes the key
+        assert!(data.expire(&raw("k"), 200, 200));
+        assert_eq!(data.keys_count(), 0);
+        data.set(raw("k"), raw("v"), Some(10));
+        assert_eq!(data.expires_at(&raw("k"), 20), None);
+        assert!(data.take_expired().contains(&raw("k")));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_avg_ttl() {
+        let mut data = RedisData::new();
+        assert_eq!(data.avg_ttl(0), 0);
+        data.set(raw("a"), raw("v"), Some(100));
+        data.set(raw("b"), raw("v"), Some(300));
+        data.set(raw("p"), raw("v"), None);
+        assert_eq!(data.avg_ttl(0), 200);
+        assert_eq!(data.avg_ttl(150), 50);
+        data.set(raw("b"), raw("w"), Some(500));
+        assert_eq!(data.avg_ttl(0), 300);
+        data.set(raw("b"), raw("w"), None);
+        assert_eq!(data.avg_ttl(0), 100);
+        // evicted keys no longer count
+        data.set(raw("c"), raw("v"), Some(1_000));
+        assert_eq!(data.get(&raw("a"), 200).unwrap(), None);
+        assert_eq!((data.expires_count(), data.avg_ttl(200)), (1, 800));
+        assert!(data.del(&raw("c"), 200));
+        assert_eq!(data.avg_ttl(200), 0);
+    }
+
+    #[test]
+    pub fn test_incr_by() -> ResultT<()> {
+        let mut data = RedisData::new();
+        assert_eq!(data.incr_by(raw("n"), 1, 0)?, 1);
+        assert_eq!(data.incr_by(raw("n"), -5, 0)?, -4);
+        assert_eq!(data.get(&raw("n"), 0)?, Some(raw("-4")));
+        data.set(raw("n"), raw(&i64::MAX.to_string()), Some(10));
+        assert!(data.incr_by(raw("n"), 1, 0).is_err());
+        assert_eq!(data.incr_by(raw("n"), -1, 0)?, i64::MAX - 1);
+        assert_eq!(data.expires_count(), 1);
+        data.set(raw("s"), raw("x"), None);
+        assert!(matches!(
+            data.incr_by(raw("s"), 1, 0),
+            Err(RdisError::NotInteger)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_info_and_big_keys() -> ResultT<()> {
+        let mut data = RedisData::new();
+        assert_eq!(data.info(&raw("missing"), 0), None);
+        data.set(raw("small"), raw("v"), None);
+        data.set(raw("big"), raw(&"v".repeat(1000)), None);
+        data.set(raw("medium"), raw(&"v".repeat(100)), Some(10));
+        for _ in 0..3 {
+            data.r_push(raw("l"), raw("element"), None, 0)?;
+        }
+        let big = data.info(&raw("big"), 0).unwrap();
+        assert_eq!((big.kind, big.len), ("string", 1000));
+        assert!(big.memory > 1000);
+        let list = data.info(&raw("l"), 0).unwrap();
+        assert_eq!((list.kind, list.len), ("list", 3));
+
+        let keys: Vec<(Key, &str)> = data
+            .big_keys(2, 0)
+            .into_iter()
+            .map(|(k, info)| (k, info.kind))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (raw("l"), "list"),
+                (raw("big"), "string"),
+                (raw("medium"), "string")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_keys_with_prefix() -> ResultT<()> {
+        assert_eq!(RedisData::new().keys_with_prefix(b"", 0), None);
+        let mut data = RedisData::with_prefix_index();
+        data.set(raw("user:1"), raw("v"), None);
+        data.set(raw("user:2"), raw("v"), Some(10));
+        data.incr_by(raw("user:3"), 1, 0)?;
+        data.r_push(raw("users"), raw("a"), None, 0)?;
+        data.set(raw("session:1"), raw("v"), None);
+        assert_eq!(
+            data.keys_with_prefix(b"user:", 0),
+            Some(vec![raw("user:1"), raw("user:2"), raw("user:3")])
+        );
+        // deleted, popped and expired keys leave the index
+        data.del(&raw("user:1"), 0);
+        data.r_pop(&raw("users"), 0)?;
+        assert_eq!(
+            data.keys_with_prefix(b"user", 20),
+            Some(vec![raw("user:3")])
+        );
+        assert_eq!(data.keys_with_prefix(b"", 20).map(|k| k.len()), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_scan_match() -> ResultT<()> {
+        for mut data in [RedisData::new(), RedisData::with_prefix_index()] {
+            for i in 0..50 {
+                data.set(raw(&format!("user:{}", i)), raw("v"), None);
+                data.set(raw(&format!("session:{}", i)), raw("v"), None);
+            }
+            data.r_push(raw("user:list"), raw("v"), None, 0)?;
+            // overwrites keep the key indexed once
+            data.set(raw("user:1"), raw("w"), None);
+            data.incr_by(raw("n"), 1, 0)?;
+            data.incr_by(raw("n"), 1, 0)?;
+            assert_eq!(data.scan_index.len(), data.keys_count());
+            let mut scan_all = |pattern: &str, kind: Option<&str>| {
+                let (mut cursor, mut keys) = (0, Vec::new());
+                loop {
+                    let (next, page) = data.scan(cursor, 7, Some(pattern.as_bytes()), kind, 0);
+                    keys.extend(page);
+                    if next == 0 {
+                        return keys.len();
+                    }
+                    cursor = next;
+                }
+            };
+            assert_eq!(scan_all("user:*", None), 51);
+            assert_eq!(scan_all("user:?", None), 10);
+            assert_eq!(scan_all("*:1?", None), 20);
+            assert_eq!(scan_all("user:*", Some("list")), 1);
+            assert_eq!(scan_all("nothing*", None), 0);
+        }
+        Ok(())
+    }
+
+    type ScanPage = fn(&mut RedisData, u64) -> ResultT<(u64, Vec<RawValue>)>;
+    type Remove = fn(&mut RedisData, &[RawValue]) -> ResultT<usize>;
+
+    #[test]
+    pub fn test_scan_members() -> ResultT<()> {
+        let members: Vec<RawValue> = (0..100).map(|i| raw(&format!("m{}", i))).collect();
+        let mut data = RedisData::new();
+        data.s_add(raw("s"), members.clone(), 0)?;
+        let pairs = members.iter().map(|m| (m.clone(), raw("v"))).collect();
+        data.h_set(raw("h"), pairs, 0)?;
+        let scored = members.iter().map(|m| (1.5, m.clone())).collect();
+        data.z_add(raw("z"), scored, 0)?;
+        let scans: [(ScanPage, Remove); 3] = [
+            (
+                |data, cursor| data.s_scan(&raw("s"), cursor, 10, None, 0),
+                |data, members| data.s_rem(&raw("s"), members, 0),
+            ),
+            (
+                |data, cursor| {
+                    let (next, pairs) = data.h_scan(&raw("h"), cursor, 10, None, 0)?;
+                    assert!(pairs.iter().all(|(_, v)| v == &raw("v")));
+                    Ok((next, pairs.into_iter().map(|(f, _)| f).collect()))
+                },
+                |data, fields| data.h_del(&raw("h"), fields, 0),
+            ),
+            (
+                |data, cursor| {
+                    let (next, pairs) = data.z_scan(&raw("z"), cursor, 10, None, 0)?;
+                    assert!(pairs.iter().all(|(_, score)| *score == 1.5));
+                    Ok((next, pairs.into_iter().map(|(m, _)| m).collect()))
+                },
+                |data, members| data.z_rem(&raw("z"), members, 0),
+            ),
+        ];
+        for (scan, remove) in scans {
+            // the second half of the members not returned by the first page is removed, the
+            // following pages never return them
+            let (mut cursor, first) = scan(&mut data, 0)?;
+            assert!(first.len() >= 10 && cursor != 0);
+            let removed: Vec<RawValue> = members[50..]
+                .iter()
+                .filter(|m| !first.contains(m))
+                .cloned()
+                .collect();
+            remove(&mut data, &removed)?;
+            let mut seen: HashSet<RawValue> = first.into_iter().collect();
+            while cursor != 0 {
+                let (next, page) = scan(&mut data, cursor)?;
+                assert!(page.iter().all(|m| !removed.contains(m)));
+                seen.extend(page);
+                cursor = next;
+            }
+            assert!(members[..50].iter().all(|m| seen.contains(m)));
+        }
+        // completed scans drop their index
+        assert!(data.member_indexes.is_empty());
+
+        let (next, page) = data.s_scan(&raw("s"), 0, 100, Some(b"m1?"), 0)?;
+        assert_eq!((next, page.len()), (0, 10));
+        assert_eq!(
+            data.s_scan(&raw("missing"), 0, 10, None, 0)?,
+            (0, Vec::new())
+        );
+        assert!(matches!(
+            data.h_scan(&raw("s"), 0, 10, None, 0),
+            Err(RdisError::WrongType)
+        ));
+        // members added during a scan are indexed, the index goes with the key
+        let len = data.s_members(&raw("s"), 0)?.len();
+        data.s_scan(&raw("s"), 0, 1, None, 0)?;
+        data.s_add(raw("s"), vec![raw("late")], 0)?;
+        assert_eq!(data.member_indexes[&raw("s")].len(), len + 1);
+        data.del(&raw("s"), 0);
+        assert!(data.member_indexes.is_empty());
+
+        // abandoned scans keep at most MAX_MEMBER_INDEXES indexes
+        for i in 0..MAX_MEMBER_INDEXES * 2 {
+            let k = raw(&format!("s{}", i));
+            data.s_add(k.clone(), members.clone(), 0)?;
+            data.s_scan(&k, 0, 1, None, 0)?;
+        }
+        assert_eq!(data.member_indexes.len(), MAX_MEMBER_INDEXES);
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use super::storage::{realloc_bytes, shared_bytes, RawValue};
+use super::types::{RdisError, ResultT};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem::size_of;
+
+// values of the stream keys: entries of field value pairs in the order of their ids, which
+// only grow. An id is the time in ms the entry was added at and a sequence number among the
+// entries of the same ms, written ms-seq. Entries are never removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+pub type StreamEntry = (StreamId, Vec<(RawValue, RawValue)>);
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    // ms-seq, or ms alone with default_seq
+    pub fn parse(raw: &[u8], default_seq: u64) -> Option<StreamId> {
+        let s = std::str::from_utf8(raw).ok()?;
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (s, default_seq),
+        };
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq,
+        })
+    }
+
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self
+                .ms
+                .checked_sub(1)
+                .map(|ms| StreamId { ms, seq: u64::MAX }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// the id of a new entry: * generates it from the current time, ms-* only the sequence number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    Auto,
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    pub fn parse(raw: &[u8]) -> Option<NewId> {
+        if raw == b"*" {
+            return Some(NewId::Auto);
+        }
+        match raw.strip_suffix(b"-*") {
+            Some(ms) => {
+                let ms = std::str::from_utf8(ms).ok()?.parse().ok()?;
+                Some(NewId::AutoSeq(ms))
+            }
+            None => StreamId::parse(raw, 0).map(NewId::Explicit),
+        }
+    }
+}
+
+// a bound of XRANGE: - and + for the first and the last entry, an id, or ms alone covering
+// all its sequence numbers. ( makes the bound exclusive
+pub fn parse_bound(raw: &[u8], start: bool) -> Option<StreamId> {
+    let default_seq = if start { 0 } else { u64::MAX };
+    match raw {
+        b"-" => Some(StreamId::MIN),
+        b"+" => Some(StreamId::MAX),
+        _ => match raw.strip_prefix(b"(") {
+            Some(id) if start => StreamId::parse(id, default_seq)?.next(),
+            Some(id) => StreamId::parse(id, default_seq)?.prev(),
+            None => StreamId::parse(raw, default_seq),
+        },
+    }
+}
+
+#[derive(Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Vec<(RawValue, RawValue)>>,
+    last_id: StreamId,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // ids always grow: a generated id follows the last one when the clock didn't move forward
+    pub fn add(
+        &mut self,
+        id: NewId,
+        fields: Vec<(RawValue, RawValue)>,
+        t: u64,
+    ) -> ResultT<StreamId> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if t > last.ms => Some(StreamId { ms: t, seq: 0 }),
+            NewId::Auto => last.next(),
+            NewId::AutoSeq(ms) if ms == last.ms => last.next(),
+            NewId::AutoSeq(ms) => Some(StreamId { ms, seq: 0 }),
+            NewId::Explicit(id) => Some(id),
+        };
+        let id = id.ok_or_else(|| {
+            RdisError::from(
+                "The stream has exhausted the last possible ID, unable to add more items",
+            )
+        })?;
+        if id == StreamId::MIN {
+            return Err(RdisError::from(
+                "The ID specified in XADD must be greater than 0-0",
+            ));
+        }
+        if id <= last {
+            return Err(RdisError::from(
+                "The ID specified in XADD is equal or smaller than the target stream top item",
+            ));
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    // entries from start to end included, the last ones first if rev
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<StreamEntry> {
+        if start > end {
+            return Vec::new();
+        }
+        let count = count.unwrap_or(usize::MAX);
+        let range = self.entries.range(start..=end);
+        let entry = |(id, fields): (&StreamId, &Vec<_>)| (*id, fields.clone());
+        if rev {
+            range.rev().take(count).map(entry).collect()
+        } else {
+            range.take(count).map(entry).collect()
+        }
+    }
+
+    // estimate of the heap used by the entries, see Value::memory
+    pub fn memory(&self) -> usize {
+        let entry = |fields: &Vec<(RawValue, RawValue)>| {
+            let pairs = fields
+                .iter()
+                .map(|(f, v)| shared_bytes(f) + shared_bytes(v));
+            size_of::<StreamEntry>()
+                + fields.capacity() * size_of::<(RawValue, RawValue)>()
+                + pairs.sum::<usize>()
+        };
+        self.entries.values().map(entry).sum()
+    }
+
+    // allocations moved
+    pub fn defrag(&mut self) -> usize {
+        self.entries
+            .values_mut()
+            .flat_map(|fields| fields.iter_mut())
+            .map(|(f, v)| realloc_bytes(f) + realloc_bytes(v))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn fields(v: &str) -> Vec<(RawValue, RawValue)> {
+        vec![(
+            Bytes::from_static(b"f"),
+            Bytes::copy_from_slice(v.as_bytes()),
+        )]
+    }
+
+    #[test]
+    pub fn test_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse(b"5", 7), Some(id(5, 7)));
+        assert_eq!(StreamId::parse(b"5-x", 0), None);
+        assert_eq!(StreamId::parse(b"-1", 0), None);
+        assert_eq!(id(5, u64::MAX).next(), Some(id(6, 0)));
+        assert_eq!(id(5, 0).prev(), Some(id(4, u64::MAX)));
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(id(5, 3).to_string(), "5-3");
+
+        assert_eq!(NewId::parse(b"*"), Some(NewId::Auto));
+        assert_eq!(NewId::parse(b"5-*"), Some(NewId::AutoSeq(5)));
+        assert_eq!(NewId::parse(b"5"), Some(NewId::Explicit(id(5, 0))));
+        assert_eq!(NewId::parse(b"x-*"), None);
+
+        assert_eq!(parse_bound(b"-", true), Some(StreamId::MIN));
+        assert_eq!(parse_bound(b"+", false), Some(StreamId::MAX));
+        assert_eq!(parse_bound(b"5", false), Some(id(5, u64::MAX)));
+        assert_eq!(parse_bound(b"(5-3", true), Some(id(5, 4)));
+        assert_eq!(parse_bound(b"(5", false), Some(id(5, u64::MAX - 1)));
+        assert_eq!(parse_bound(b"(5-0", false), Some(id(4, u64::MAX)));
+        assert_eq!(parse_bound(b"(0-0", false), None);
+    }
+
+    #[test]
+    pub fn test_add_and_range() -> ResultT<()> {
+        let mut stream = Stream::default();
+        assert!(stream
+            .add(NewId::Explicit(id(0, 0)), fields("a"), 10)
+            .is_err());
+        assert_eq!(stream.add(NewId::Auto, fields("a"), 10)?, id(10, 0));
+        // the clock didn't move, or went back
+        assert_eq!(stream.add(NewId::Auto, fields("b"), 10)?, id(10, 1));
+        assert_eq!(stream.add(NewId::Auto, fields("c"), 5)?, id(10, 2));
+        assert_eq!(stream.add(NewId::AutoSeq(10), fields("d"), 0)?, id(10, 3));
+        assert_eq!(stream.add(NewId::AutoSeq(20), fields("e"), 0)?, id(20, 0));
+        assert!(stream
+            .add(NewId::Explicit(id(20, 0)), fields("f"), 0)
+            .is_err());
+        assert!(stream.add(NewId::AutoSeq(15), fields("f"), 0).is_err());
+        assert_eq!(
+            stream.add(NewId::Explicit(id(30, 5)), fields("f"), 0)?,
+            id(30, 5)
+        );
+        assert_eq!(stream.len(), 6);
+
+        let ids =
+            |entries: Vec<StreamEntry>| entries.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        let all = stream.range(StreamId::MIN, StreamId::MAX, None, false);
+        assert_eq!(all[1], (id(10, 1), fields("b")));
+        assert_eq!(
+            ids(stream.range(id(10, 2), id(20, u64::MAX), None, false)),
+            vec![id(10, 2), id(10, 3), id(20, 0)]
+        );
+        assert_eq!(
+            ids(stream.range(StreamId::MIN, StreamId::MAX, Some(2), true)),
+            vec![id(30, 5), id(20, 0)]
+        );
+        assert!(stream.range(id(30, 0), id(20, 0), None, false).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use super::types::{ErrorT, ResultT};
+use log::{debug, warn};
+use std::env;
+use std::net;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use tokio::net::TcpListener;
+
+// first file descriptor passed by systemd, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Supervised {
+    No,
+    Systemd,
+    // notify only if NOTIFY_SOCKET is set
+    Auto,
+}
+
+impl Supervised {
+    pub fn parse(value: &str) -> ResultT<Supervised> {
+        match value.to_lowercase().as_str() {
+            "no" => Ok(Supervised::No),
+            "systemd" => Ok(Supervised::Systemd),
+            "auto" => Ok(Supervised::Auto),
+            other => Err(ErrorT::from(format!("Invalid supervised mode {}", other))),
+        }
+    }
+}
+
+// returns the listener passed by systemd socket activation, if any.
+// Only the first descriptor is used, rdis listens on a single socket.
+pub fn listen_fds() -> ResultT<Option<TcpListener>> {
+    let fds = match (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) {
+        (Ok(pid), Ok(fds)) if pid.parse::<u32>()? == std::process::id() => fds.parse::<i32>()?,
+        _ => return Ok(None),
+    };
+    // the descriptors must not be inherited by children
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(
+            "Received {} sockets from systemd, using only the first",
+            fds
+        );
+    }
+    // safety: systemd guarantees the descriptor is open and owned by this process
+    let listener = unsafe { net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    // fails if the descriptor is not a socket
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+// sends a state update (READY=1, STOPPING=1, ...) to the service manager, see sd_notify(3)
+pub fn notify(mode: Supervised, state: &str) -> ResultT<bool> {
+    let path = match (mode, env::var("NOTIFY_SOCKET")) {
+        (Supervised::No, _) => return Ok(false),
+        (Supervised::Systemd, Err(_)) => {
+            warn!("systemd supervision requested but NOTIFY_SOCKET is not set");
+            return Ok(false);
+        }
+        (Supervised::Auto, Err(_)) => return Ok(false),
+        (_, Ok(path)) => path,
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        Some(name) => send_abstract(&socket, name, state)?,
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    debug!("Sent {} to systemd", state);
+    Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> ResultT<()> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, name: &str, _state: &str) -> ResultT<()> {
+    Err(ErrorT::from(format!(
+        "Abstract notify socket @{} is only supported on linux",
+        name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_supervised() -> ResultT<()> {
+        assert_eq!(Supervised::parse("SystemD")?, Supervised::Systemd);
+        assert_eq!(Supervised::parse("no")?, Supervised::No);
+        assert!(Supervised::parse("upstart").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_notify() -> ResultT<()> {
+        let dir = env::temp_dir().join(format!("rdis-notify-{}", std::process::id()));
+        let receiver = UnixDatagram::bind(&dir)?;
+        env::set_var("NOTIFY_SOCKET", &dir);
+        assert!(!notify(Supervised::No, "READY=1")?);
+        assert!(notify(Supervised::Auto, "READY=1")?);
+        env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&dir)?;
+        Ok(())
+    }
+}
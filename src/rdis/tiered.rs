@@ -0,0 +1,310 @@
+use super::config::TieringConfig;
+use super::storage::{Key, RawValue};
+use super::types::ResultT;
+use bytes::Bytes;
+use log::*;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+
+// idle strings spilled at every cron tick
+const KEYS_PER_STEP: usize = 1000;
+// the file is compacted once it holds more garbage than this and than live values
+const COMPACT_MIN_GARBAGE: u64 = 1024 * 1024;
+
+// tiered storage, enabled by tiering-file: strings not accessed for tiering-idle-seconds are
+// moved to an append-only file, RedisData keeps the key, its expiration and a Spilled in the
+// place of the value. Reading the key pages the value back in, blocking the engine loop for
+// the time of the read. Values are lost with the process, the file is truncated at startup and
+// removed on shutdown. Overwritten and deleted values leave garbage in the file until it's
+// compacted by the cron. Access times are those of the last cron tick.
+#[derive(Debug)]
+pub struct Tiering {
+    path: String,
+    file: File,
+    idle_ms: u64,
+    min_value_size: usize,
+    // time of the last cron tick
+    now: u64,
+    // strings in memory -> last access
+    accessed: HashMap<Key, u64>,
+    // the same (last access, key), the least recently accessed first
+    by_access: BTreeSet<(u64, Key)>,
+    end: u64,
+    garbage: u64,
+    pub stats: TieringStats,
+}
+
+// reported by INFO memory as tiered_*
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TieringStats {
+    pub keys: u64,
+    pub bytes: u64,
+    pub file_bytes: u64,
+    pub spills: u64,
+    pub loads: u64,
+    pub compactions: u64,
+}
+
+// where a value was written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spilled {
+    offset: u64,
+    pub len: usize,
+}
+
+fn create(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+impl Tiering {
+    pub fn open(config: &TieringConfig) -> ResultT<Option<Tiering>> {
+        let path = match &config.file {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+        let file = create(&path).map_err(|err| format!("Cannot open {}: {}", path, err))?;
+        Ok(Some(Tiering {
+            path,
+            file,
+            idle_ms: config.idle.as_millis() as u64,
+            min_value_size: config.min_value_size,
+            now: 0,
+            accessed: HashMap::new(),
+            by_access: BTreeSet::new(),
+            end: 0,
+            garbage: 0,
+            stats: TieringStats::default(),
+        }))
+    }
+
+    pub fn tick(&mut self, t: u64) {
+        // the keys written before the first tick were accessed at 0
+        if self.now == 0 {
+            self.accessed.values_mut().for_each(|at| *at = t);
+            self.by_access = self.accessed.keys().map(|k| (t, k.clone())).collect();
+        }
+        self.now = t;
+    }
+
+    // the string at k was written or read
+    pub fn touch(&mut self, k: &Key) {
+        match self.accessed.get_mut(k) {
+            // already accessed during this tick
+            Some(at) if *at == self.now => (),
+            Some(at) => {
+                self.by_access.remove(&(*at, k.clone()));
+                *at = self.now;
+                self.by_access.insert((self.now, k.clone()));
+            }
+            None => {
+                self.accessed.insert(k.clone(), self.now);
+                self.by_access.insert((self.now, k.clone()));
+            }
+        }
+    }
+
+    // the key was removed or holds something else than a string in memory
+    pub fn forget(&mut self, k: &[u8]) {
+        if let Some((key, at)) = self.accessed.remove_entry(k) {
+            self.by_access.remove(&(at, key));
+        }
+    }
+
+    pub fn spillable(&self, v: &RawValue) -> bool {
+        v.len() >= self.min_value_size
+    }
+
+    // up to KEYS_PER_STEP keys idle for longer than tiering-idle-seconds, the least recently
+    // accessed first. They stay tracked until they are spilled or forgotten
+    pub fn idle_keys(&self) -> Vec<Key> {
+        self.by_access
+            .iter()
+            .take_while(|(at, _)| self.now.saturating_sub(*at) >= self.idle_ms)
+            .take(KEYS_PER_STEP)
+            .map(|(_, k)| k.clone())
+            .collect()
+    }
+
+    pub fn spill(&mut self, k: &[u8], v: &[u8]) -> io::Result<Spilled> {
+        self.file.write_all_at(v, self.end)?;
+        let spilled = Spilled {
+            offset: self.end,
+            len: v.len(),
+        };
+        self.end += v.len() as u64;
+        self.forget(k);
+        self.stats.spills += 1;
+        self.stats.keys += 1;
+        self.stats.bytes += v.len() as u64;
+        self.stats.file_bytes = self.end;
+        Ok(spilled)
+    }
+
+    // the value is back in memory, its place in the file becomes garbage
+    pub fn load(&mut self, k: &Key, spilled: Spilled) -> io::Result<RawValue> {
+        let mut v = vec![0; spilled.len];
+        self.file.read_exact_at(&mut v, spilled.offset)?;
+        self.free(spilled);
+        self.stats.loads += 1;
+        self.touch(k);
+        Ok(Bytes::from(v))
+    }
+
+    // the spilled value was overwritten or deleted
+    pub fn free(&mut self, spilled: Spilled) {
+        self.garbage += spilled.len as u64;
+        self.stats.keys -= 1;
+        self.stats.bytes -= spilled.len as u64;
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.garbage > COMPACT_MIN_GARBAGE && self.garbage > self.stats.bytes
+    }
+
+    // copies the live values to a new file replacing the current one, the Spilled are only
+    // updated once it succeeds
+    pub fn compact<'a, I: Iterator<Item = &'a mut Spilled>>(&mut self, spilled: I) {
+        let tmp = format!("{}.compact", self.path);
+        let mut moved = Vec::new();
+        let copied = create(&tmp).and_then(|file| {
+            let mut end = 0;
+            for s in spilled {
+                let mut v = vec![0; s.len];
+                self.file.read_exact_at(&mut v, s.offset)?;
+                file.write_all_at(&v, end)?;
+                moved.push((s, end));
+                end += v.len() as u64;
+            }
+            fs::rename(&tmp, &self.path)?;
+            Ok((file, end))
+        });
+        match copied {
+            Ok((file, end)) => {
+                for (s, offset) in moved {
+                    s.offset = offset;
+                }
+                self.file = file;
+                self.end = end;
+                self.garbage = 0;
+                self.stats.file_bytes = end;
+                self.stats.compactions += 1;
+            }
+            Err(err) => {
+                warn!("Cannot compact {}: {}", self.path, err);
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+    }
+}
+
+impl Drop for Tiering {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::storage::{RedisData, Storage};
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn raw(s: &str) -> RawValue {
+        Bytes::copy_from_slice(s.as_bytes())
+    }
+
+    #[test]
+    pub fn test_spill_and_load() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-tiering-{}.dat", std::process::id()));
+        let config = TieringConfig {
+            file: Some(path.to_string_lossy().into_owned()),
+            idle: Duration::from_secs(10),
+            min_value_size: 4,
+        };
+        let mut data = RedisData::new();
+        data.set_tiering(Tiering::open(&config)?.unwrap());
+        let stats = |data: &RedisData| data.tiering_stats().unwrap();
+        let big = "x".repeat(1000);
+        data.set(raw("big"), raw(&big), Some(1_000_000));
+        data.set(raw("small"), raw("v"), None);
+        data.r_push(raw("l"), raw(&big), None, 0)?;
+        data.cron(1_000);
+        data.cron(5_000);
+        assert_eq!(stats(&data).keys, 0);
+        // small strings and lists stay in memory
+        data.cron(11_000);
+        assert_eq!((stats(&data).keys, stats(&data).bytes), (1, 1000));
+        let info = data.info(&raw("big"), 11_000).unwrap();
+        assert_eq!((info.kind, info.len), ("string", 1000));
+        assert_eq!(data.expires_count(), 1);
+        assert_eq!(data.get(&raw("big"), 11_000)?, Some(raw(&big)));
+        assert_eq!((stats(&data).keys, stats(&data).loads), (0, 1));
+        data.cron(30_000);
+        assert_eq!(stats(&data).spills, 2);
+        // overwritten and deleted values are freed
+        data.set(raw("big"), raw("new"), None);
+        assert_eq!((stats(&data).keys, stats(&data).bytes), (0, 0));
+        assert_eq!(data.get(&raw("big"), 30_000)?, Some(raw("new")));
+
+        for i in 0..30 {
+            let v = i.to_string().repeat(100_000);
+            data.set(raw(&format!("k{}", i)), raw(&v), None);
+        }
+        data.cron(50_000);
+        assert_eq!(stats(&data).keys, 30);
+        for i in 0..20 {
+            assert!(data.del(&raw(&format!("k{}", i)), 50_000));
+        }
+        data.cron(50_100);
+        let compacted = stats(&data);
+        assert_eq!(compacted.compactions, 1);
+        assert_eq!(compacted.bytes, compacted.file_bytes);
+        for i in 20..30 {
+            let v = i.to_string().repeat(100_000);
+            assert_eq!(data.get(&raw(&format!("k{}", i)), 50_100)?, Some(raw(&v)));
+        }
+        drop(data);
+        assert!(!Path::new(&path).exists());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_idle_keys() -> ResultT<()> {
+        let path = std::env::temp_dir().join(format!("rdis-idle-{}.dat", std::process::id()));
+        let config = TieringConfig {
+            file: Some(path.to_string_lossy().into_owned()),
+            idle: Duration::from_secs(10),
+            min_value_size: 0,
+        };
+        let mut tiering = Tiering::open(&config)?.unwrap();
+        tiering.tick(1_000);
+        for i in 0..KEYS_PER_STEP + 10 {
+            tiering.touch(&raw(&i.to_string()));
+        }
+        tiering.tick(2_000);
+        tiering.touch(&raw("late"));
+        tiering.touch(&raw("0"));
+        tiering.tick(11_000);
+        // the least recently accessed first, a step at a time
+        let idle = tiering.idle_keys();
+        assert_eq!(idle.len(), KEYS_PER_STEP);
+        assert!(!idle.contains(&raw("0")));
+        for k in &idle {
+            tiering.forget(k);
+        }
+        assert_eq!(tiering.idle_keys().len(), 9);
+        tiering.tick(12_000);
+        let idle = tiering.idle_keys();
+        assert_eq!(idle.len(), 11);
+        assert!(idle.contains(&raw("late")) && idle.contains(&raw("0")));
+        Ok(())
+    }
+}
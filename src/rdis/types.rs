@@ -1,88 +1,227 @@
 use std::fmt::Display;
 use std::fmt::Formatter;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
-};
-use tokio::io::BufWriter;
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::tcp::OwnedWriteHalf;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::time::Instant;
 
-use log::{debug, error, info};
-use std::error::Error;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, oneshot};
+use log::{debug, error, info, trace};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-pub type ErrorT = Box<dyn Error + Sync + Send>;
+pub use super::error::RdisError;
+pub type ErrorT = RdisError;
 pub type ResultT<A> = Result<A, ErrorT>;
 
+// requests sent to the engine with their sequence number and the state of the sender,
+// the state is handed back with the response on the queue of the sender
+pub type EngineRequest = (u64, ClientReq, ConnectionState, ResponseSender);
+
+pub type ResponseSender = mpsc::UnboundedSender<EngineResponse>;
+
+#[derive(Debug)]
+pub struct EngineResponse {
+    pub seq: u64,
+    pub resp: ClientReq,
+    pub state: ConnectionState,
+}
+
+// the responses of the engine to a client. A connection creates its queue once and hands a
+// clone of the sender with every request, which costs no allocation unlike a oneshot channel;
+// responses are matched to requests by sequence number.
+pub struct ResponseQueue {
+    sender: ResponseSender,
+    receiver: mpsc::UnboundedReceiver<EngineResponse>,
+    next_seq: u64,
+}
+
+impl Default for ResponseQueue {
+    fn default() -> Self {
+        ResponseQueue::new()
+    }
+}
+
+impl ResponseQueue {
+    pub fn new() -> ResponseQueue {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        ResponseQueue {
+            sender,
+            receiver,
+            next_seq: 0,
+        }
+    }
+}
+
+use super::config::{ClientLimits, WireTraceConfig};
+#[cfg(feature = "fault-injection")]
+use super::faults::Faults;
+use super::metrics::Metrics;
 use super::protocol::*;
+use super::readcache::ReadCache;
+use super::registry::{ClientGuard, ClientRegistry};
+use super::session::ConnectionState;
+use super::wiretrace;
 
 pub struct RedisServer {
-    pub listener: TcpListener,
-    open_handles: Mutex<Vec<JoinHandle<()>>>,
-    client_epoch: AtomicUsize,
+    pub registry: Arc<ClientRegistry>,
+    limits: ClientLimits,
+    metrics: Arc<Metrics>,
+    read_cache: Option<Arc<ReadCache>>,
+    wire_trace: WireTraceConfig,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
 }
 
 impl RedisServer {
-    pub fn new(listener: TcpListener) -> RedisServer {
+    pub fn new(
+        registry: Arc<ClientRegistry>,
+        limits: ClientLimits,
+        metrics: Arc<Metrics>,
+    ) -> RedisServer {
         RedisServer {
-            listener,
-            open_handles: Mutex::new(Vec::with_capacity(1024)),
-            client_epoch: AtomicUsize::new(0),
+            registry,
+            limits,
+            metrics,
+            read_cache: None,
+            wire_trace: WireTraceConfig::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Faults::default(),
         }
     }
 
+    // GETs hitting the cache are replied by the connections, see ReadCache
+    pub fn set_read_cache(&mut self, cache: Arc<ReadCache>) {
+        self.read_cache = Some(cache);
+    }
+
+    pub fn set_wire_trace(&mut self, wire_trace: WireTraceConfig) {
+        self.wire_trace = wire_trace;
+    }
+
+    // the rules are checked for every request read by the connections
+    #[cfg(feature = "fault-injection")]
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+    }
+
     pub fn client_connection(
         &self,
         engine: Arc<RedisEngineApi>,
         stream: TcpStream,
-    ) -> ClientConnection {
-        let client_epoch = self.client_epoch.fetch_add(1, Ordering::SeqCst);
+        addr: SocketAddr,
+    ) -> ClientConnection<TcpCmd> {
+        let guard = self.registry.register(addr, stream.local_addr().ok());
+        let redis_cmd = RedisCmd::from_stream(stream, guard.id);
+        self.connection(engine, redis_cmd, guard)
+    }
+
+    // the guard must be registered before the transport is created, it provides the client_epoch
+    pub fn connection<T: Transport>(
+        &self,
+        engine: Arc<RedisEngineApi>,
+        mut transport: T,
+        guard: ClientGuard,
+    ) -> ClientConnection<T> {
+        transport.set_query_buffer_limit(self.limits.query_buffer_limit);
+        transport.set_query_buffer_gauge(guard.stats.query_buffer.clone());
+        let mut state = ConnectionState::new(guard.id);
+        state.wire_trace = self.wire_trace.enabled;
         ClientConnection {
-            redis_cmd: RedisCmd::from_stream(stream, client_epoch),
+            transport,
             engine,
-            client_epoch,
+            client_epoch: guard.id,
+            state,
+            responses: ResponseQueue::new(),
+            rate_limiter: RateLimiter::new(self.limits.max_requests_per_sec),
+            handshake_timeout: self.limits.handshake_timeout,
+            metrics: self.metrics.clone(),
+            read_cache: self.read_cache.clone(),
+            wire_trace_max_bytes: self.wire_trace.max_bytes,
+            #[cfg(feature = "fault-injection")]
+            faults: self.faults.clone(),
+            guard,
         }
     }
 
-    pub fn add_handle(&self, handle: JoinHandle<()>) -> Option<()> {
-        let mut lock = self.open_handles.lock().unwrap();
-        (*lock).push(handle);
-        Some(())
+    pub fn add_handle(&self, client_epoch: usize, handle: JoinHandle<()>) {
+        self.registry.attach_handle(client_epoch, handle);
+    }
+
+    pub async fn shutdown(&self) {
+        if !self.registry.is_empty() {
+            info!("Shutting down {} connections", self.registry.len());
+        }
+        self.registry.shutdown().await;
     }
 }
 
 pub struct RedisEngineApi {
-    sender: mpsc::Sender<(ClientReq, oneshot::Sender<ClientReq>)>,
+    sender: mpsc::Sender<EngineRequest>,
 }
 impl RedisEngineApi {
-    pub fn new(sender: mpsc::Sender<(ClientReq, oneshot::Sender<ClientReq>)>) -> RedisEngineApi {
-        RedisEngineApi {
-            sender,
-        }
+    pub fn new(sender: mpsc::Sender<EngineRequest>) -> RedisEngineApi {
+        RedisEngineApi { sender }
     }
 
-    pub async fn request(&self, req: ClientReq) -> ResultT<ClientReq> {
-        let (tx, rx) = oneshot::channel();
-        // fix this
-        self.sender.send((req, tx)).await.unwrap();
-        match rx.await {
-            Ok(e) => Ok(e),
-            Err(err) => Err(Box::new(err)),
+    // a request with a fresh state and queue, for clients without a connection (admin, embedded)
+    pub async fn request(&self, client_epoch: usize, req: ClientReq) -> ResultT<ClientReq> {
+        let state = ConnectionState::new(client_epoch);
+        let mut queue = ResponseQueue::new();
+        Ok(self.request_with_state(&mut queue, req, state).await?.0)
+    }
+
+    pub async fn request_with_state(
+        &self,
+        queue: &mut ResponseQueue,
+        req: ClientReq,
+        state: ConnectionState,
+    ) -> ResultT<(ClientReq, ConnectionState)> {
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        self.sender
+            .send((seq, req, state, queue.sender.clone()))
+            .await
+            .map_err(|_| RdisError::Engine("loop terminated".to_owned()))?;
+        loop {
+            // the queue holds a sender, the channel is never closed: the request is lost if the
+            // engine is dropped without replying
+            let response = tokio::select! {
+                biased;
+                response = queue.receiver.recv() => response,
+                _ = self.sender.closed() => None,
+            };
+            match response {
+                Some(response) if response.seq == seq => {
+                    return Ok((response.resp, response.state));
+                }
+                Some(response) => debug!("Discarding stale response {}", response.seq),
+                None => return Err(RdisError::Engine("request dropped".to_owned())),
+            }
         }
     }
 }
 
-pub struct ClientConnection {
-    redis_cmd: RedisCmd<OwnedReadHalf, BufWriter<OwnedWriteHalf>>,
+pub struct ClientConnection<T> {
+    transport: T,
     engine: Arc<RedisEngineApi>,
     client_epoch: usize,
+    state: ConnectionState,
+    responses: ResponseQueue,
+    rate_limiter: RateLimiter,
+    // taken by the first read, see client-handshake-timeout
+    handshake_timeout: Option<Duration>,
+    metrics: Arc<Metrics>,
+    read_cache: Option<Arc<ReadCache>>,
+    wire_trace_max_bytes: usize,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
+    // removes the connection from the registry when dropped, even if the task is aborted
+    guard: ClientGuard,
 }
 
-impl Display for ClientConnection {
+impl<T> Display for ClientConnection<T> {
     fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), std::fmt::Error> {
         f.write_fmt(format_args!(
             "ClientConnection{{client_epoch: {} }}",
@@ -91,30 +230,141 @@ impl Display for ClientConnection {
     }
 }
 
-impl ClientConnection {
+impl<T: Transport> ClientConnection<T> {
+    pub fn client_epoch(&self) -> usize {
+        self.client_epoch
+    }
+
     pub async fn start_loop(mut self) {
         info!("Connection received {}", self);
         loop {
             let before_read = Instant::now();
-            let cmd = self.redis_cmd.read_async().await;
+            let cmd = match self.handshake_timeout.take() {
+                // a connection trickling bytes without completing a request holds a socket
+                Some(timeout) => {
+                    match tokio::time::timeout(timeout, self.transport.read_request()).await {
+                        Ok(cmd) => cmd,
+                        Err(_) => {
+                            info!("No request within {:?} from {}, closing", timeout, self);
+                            break;
+                        }
+                    }
+                }
+                None => self.transport.read_request().await,
+            };
             let read_delta = before_read.elapsed().as_micros();
             debug!("Time for read {}, client={}", read_delta, self.client_epoch);
+            let bytes_read = self.transport.take_bytes_read();
+            self.metrics
+                .net_input_bytes
+                .fetch_add(bytes_read, Ordering::Relaxed);
+            let stats = self.guard.stats.clone();
+            stats
+                .net_input_bytes
+                .fetch_add(bytes_read, Ordering::Relaxed);
             match cmd {
                 Ok(commands) => {
                     let len = commands.len();
+                    if let Some(last) = commands.last() {
+                        let (name, _) = last.describe_command();
+                        stats.record_request(
+                            len as u64,
+                            name.to_lowercase(),
+                            self.guard.connected_at,
+                        );
+                    }
                     if len > 0 {
-                        let responses = match self.engine.request(commands).await {
-                            Ok(resp) => resp,
-                            // not really correct
-                            Err(err) => ClientReq::Single(RESP::Error(
-                                "Unexpected".to_owned(),
-                                err.to_string(),
-                            )),
+                        if let Some(delay) = self.rate_limiter.acquire(len as u64, Instant::now()) {
+                            debug!("Throttling client={} for {:?}", self.client_epoch, delay);
+                            tokio::time::sleep(delay).await;
+                        }
+                        #[cfg(feature = "fault-injection")]
+                        let injected = self.faults.check(self.client_epoch, &commands);
+                        #[cfg(feature = "fault-injection")]
+                        {
+                            if injected.disconnect {
+                                info!("Injected disconnect client={}", self.client_epoch);
+                                break;
+                            }
+                            tokio::time::sleep(injected.latency).await;
+                        }
+                        if self.state.wire_trace {
+                            self.trace_request(&commands).await;
+                        }
+                        let before_request = Instant::now();
+                        let cached = match &self.read_cache {
+                            // subscribed clients can't run GET, transactions queue it
+                            Some(cache)
+                                if self.state.subscriptions.is_empty()
+                                    && self.state.multi.is_none() =>
+                            {
+                                cache.serve(&commands)
+                            }
+                            _ => None,
                         };
+                        let responses = match cached {
+                            Some(resp) => resp,
+                            None => {
+                                let state = std::mem::take(&mut self.state);
+                                match self
+                                    .engine
+                                    .request_with_state(&mut self.responses, commands, state)
+                                    .await
+                                {
+                                    Ok((resp, state)) => {
+                                        stats.subscribed.store(
+                                            !state.subscriptions.is_empty(),
+                                            Ordering::Relaxed,
+                                        );
+                                        self.state = state;
+                                        resp
+                                    }
+                                    Err(err) => {
+                                        error!(
+                                            "Engine request failed client={} {}",
+                                            self.client_epoch, err
+                                        );
+                                        break;
+                                    }
+                                }
+                            }
+                        };
+                        let request_delta = before_request.elapsed();
+                        self.metrics.request_duration.observe(request_delta);
+                        trace!(
+                            "client={} pipeline={} request_us={}",
+                            self.client_epoch,
+                            len,
+                            request_delta.as_micros()
+                        );
                         let mut resp_vec: Vec<_> = responses.into();
+                        #[cfg(feature = "fault-injection")]
+                        injected.drop_responses(&mut resp_vec);
+                        let len = resp_vec.len();
                         for (idx, response) in resp_vec.drain(0..).enumerate() {
                             debug!("Response is {:?}", response);
-                            match self.redis_cmd.write_async(response, idx == len - 1).await {
+                            if self.state.wire_trace {
+                                wiretrace::trace(
+                                    self.client_epoch,
+                                    "out",
+                                    &response,
+                                    self.wire_trace_max_bytes,
+                                )
+                                .await;
+                            }
+                            let bytes_written = response.encoded_len() as u64;
+                            self.metrics
+                                .net_output_bytes
+                                .fetch_add(bytes_written, Ordering::Relaxed);
+                            stats
+                                .net_output_bytes
+                                .fetch_add(bytes_written, Ordering::Relaxed);
+                            stats
+                                .output_buffer
+                                .fetch_add(bytes_written as usize, Ordering::Relaxed);
+                            let flush = idx == len - 1;
+                            match self.transport.write_response(response, flush).await {
+                                Ok(()) if flush => stats.output_buffer.store(0, Ordering::Relaxed),
                                 Ok(()) => (),
                                 Err(err) => {
                                     error!("Error when writing to client={}", err);
@@ -128,10 +378,86 @@ impl ClientConnection {
                 }
                 Err(err) => {
                     info!("Stopping loop, received error {}", err);
-                    break;
+                    if !matches!(err, RdisError::Io(_)) {
+                        let _ = self.transport.write_response(err.to_resp(), true).await;
+                    }
+                    if err.closes_connection() {
+                        break;
+                    }
                 }
             }
         }
         info!("Connection dropped {}", self);
     }
+
+    async fn trace_request(&self, commands: &ClientReq) {
+        let frames = match commands {
+            ClientReq::Single(r) => std::slice::from_ref(r),
+            ClientReq::Pipeline(rs) => rs.as_slice(),
+        };
+        for frame in frames {
+            wiretrace::trace(self.client_epoch, "in", frame, self.wire_trace_max_bytes).await;
+        }
+    }
+}
+
+// fixed one second window, requests over the limit are delayed until the next window
+struct RateLimiter {
+    max_per_sec: u64,
+    window_start: Instant,
+    count: u64,
+}
+
+impl RateLimiter {
+    fn new(max_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    fn acquire(&mut self, requests: u64, now: Instant) -> Option<Duration> {
+        if self.max_per_sec == 0 {
+            return None;
+        }
+        let window_end = self.window_start + Duration::from_secs(1);
+        if now >= window_end {
+            self.window_start = now;
+            self.count = requests;
+            None
+        } else if self.count + requests <= self.max_per_sec {
+            self.count += requests;
+            None
+        } else {
+            self.window_start = window_end;
+            self.count = requests;
+            Some(window_end - now)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(10);
+        let start = limiter.window_start;
+        assert_eq!(limiter.acquire(6, start), None);
+        assert_eq!(limiter.acquire(4, start), None);
+        let delay = limiter.acquire(3, start + Duration::from_millis(200));
+        assert_eq!(delay, Some(Duration::from_millis(800)));
+        // the delayed requests are accounted in the next window
+        assert_eq!(limiter.acquire(7, start + Duration::from_secs(1)), None);
+        assert!(limiter.acquire(1, start + Duration::from_secs(1)).is_some());
+        assert_eq!(limiter.acquire(100, start + Duration::from_secs(3)), None);
+    }
+
+    #[tokio::test]
+    pub async fn test_rate_limiter_disabled() {
+        let mut limiter = RateLimiter::new(0);
+        assert_eq!(limiter.acquire(u64::MAX, Instant::now()), None);
+    }
 }
@@ -0,0 +1,116 @@
+use super::protocol::{ClientReq, FrameDecoder, Transport, RESP};
+use super::types::{RedisEngineApi, RedisServer, ResultT};
+use log::info;
+use std::net;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::oneshot;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+// io_uring needs owned buffers, data is read in read_buff and then copied in the decoder
+pub struct UringCmd {
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    read_buff: Vec<u8>,
+    write_buff: Vec<u8>,
+}
+
+impl UringCmd {
+    pub fn new(stream: TcpStream, client_epoch: usize) -> UringCmd {
+        UringCmd {
+            stream,
+            decoder: FrameDecoder::new(client_epoch),
+            read_buff: Vec::with_capacity(READ_BUFFER_SIZE),
+            write_buff: Vec::with_capacity(READ_BUFFER_SIZE),
+        }
+    }
+}
+
+impl Transport for UringCmd {
+    fn set_query_buffer_limit(&mut self, limit: usize) {
+        self.decoder.set_query_buffer_limit(limit);
+    }
+
+    fn set_query_buffer_gauge(&mut self, gauge: Arc<AtomicUsize>) {
+        self.decoder.set_query_buffer_gauge(gauge);
+    }
+
+    fn take_bytes_read(&mut self) -> u64 {
+        self.decoder.take_bytes_read()
+    }
+
+    async fn read_request(&mut self) -> ResultT<ClientReq> {
+        loop {
+            if let Some(req) = self.decoder.decode() {
+                return Ok(req);
+            }
+            let buff = std::mem::take(&mut self.read_buff);
+            let (res, mut buff) = self.stream.read(buff).await;
+            let n = res?;
+            self.decoder.read_buffer().extend_from_slice(&buff[..n]);
+            buff.clear();
+            self.read_buff = buff;
+            if n == 0 {
+                return Ok(self.decoder.finish());
+            }
+            self.decoder.check_limit()?;
+        }
+    }
+
+    // responses are encoded in memory and written with a single submission on flush
+    async fn write_response(&mut self, resp: RESP, flush: bool) -> ResultT<()> {
+        resp.write_async(&mut self.write_buff, false).await?;
+        if flush {
+            let buff = std::mem::take(&mut self.write_buff);
+            let (res, mut buff) = self.stream.write_all(buff).await;
+            res?;
+            buff.clear();
+            self.write_buff = buff;
+        }
+        Ok(())
+    }
+}
+
+// runs the accept loop and the connections on a dedicated io_uring runtime thread,
+// the engine stays on the tokio runtime and it's reached through RedisEngineApi
+pub fn spawn(
+    listener: net::TcpListener,
+    server: RedisServer,
+    api: Arc<RedisEngineApi>,
+    shutdown: oneshot::Receiver<()>,
+) -> ResultT<thread::JoinHandle<()>> {
+    listener.set_nonblocking(false)?;
+    let handle = thread::Builder::new()
+        .name("rdis-uring".to_owned())
+        .spawn(move || {
+            tokio_uring::start(async move {
+                let listener = TcpListener::from_std(listener);
+                info!("Accepting connections with io_uring");
+                tokio::select! {
+                    _ = accept_connections(&listener, &server, api) => (),
+                    _ = shutdown => (),
+                }
+                server.shutdown().await;
+            })
+        })?;
+    Ok(handle)
+}
+
+async fn accept_connections(
+    listener: &TcpListener,
+    server: &RedisServer,
+    api: Arc<RedisEngineApi>,
+) {
+    // io_uring streams don't expose their local address, the one of the listener is used
+    let laddr = listener.local_addr().ok();
+    while let Ok((stream, addr)) = listener.accept().await {
+        let guard = server.registry.register(addr, laddr);
+        let cmd = UringCmd::new(stream, guard.id);
+        let connection = server.connection(api.clone(), cmd, guard);
+        let client_epoch = connection.client_epoch();
+        server.add_handle(client_epoch, tokio_uring::spawn(connection.start_loop()));
+    }
+}
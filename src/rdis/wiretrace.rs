@@ -0,0 +1,63 @@
+use super::protocol::RESP;
+use log::info;
+
+// frames exchanged with a client, logged at info level under the rdis::wiretrace module when
+// the client is traced (wire-trace yes or CLIENT TRACE ON). A line per frame:
+//
+//     client=3 in len=14 hex=2a310d0a24340d0a50494e470d0a decoded="*1\r\n$4\r\nPING\r\n"
+//
+// requests are logged as parsed, re-encoded: inline commands show as arrays. Only the first
+// wire-trace-max-bytes bytes are logged, followed by ... when the frame is longer.
+
+pub async fn trace(client: usize, direction: &str, resp: &RESP, max_bytes: usize) {
+    let mut frame = Vec::with_capacity(resp.encoded_len());
+    // writing to a Vec doesn't fail
+    let _ = resp.clone().write_async(&mut frame, false).await;
+    info!(
+        "client={} {} {}",
+        client,
+        direction,
+        describe_frame(&frame, max_bytes)
+    );
+}
+
+pub fn describe_frame(frame: &[u8], max_bytes: usize) -> String {
+    let logged = &frame[..frame.len().min(max_bytes)];
+    let hex: String = logged.iter().map(|b| format!("{:02x}", b)).collect();
+    let decoded: String = logged
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect();
+    let more = if logged.len() < frame.len() {
+        "..."
+    } else {
+        ""
+    };
+    format!(
+        "len={} hex={}{} decoded=\"{}{}\"",
+        frame.len(),
+        hex,
+        more,
+        decoded,
+        more
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_describe_frame() {
+        assert_eq!(
+            describe_frame(b"+OK\r\n", 256),
+            "len=5 hex=2b4f4b0d0a decoded=\"+OK\\r\\n\""
+        );
+        assert_eq!(
+            describe_frame(b"$2\r\n\"\xff\r\n", 6),
+            "len=8 hex=24320d0a22ff... decoded=\"$2\\r\\n\\\"\\xff...\""
+        );
+        assert_eq!(describe_frame(b"", 4), "len=0 hex= decoded=\"\"");
+    }
+}
@@ -0,0 +1,208 @@
+use super::storage::{Key, RawValue};
+use super::types::ResultT;
+use log::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// mirrors the changes made by the commands modifying the keyspace into a WriteSink set with
+// RdisServerBuilder::write_behind: a database, a topic... A task hands them to the sink in
+// batches of up to batch_size, a failed batch is retried after retry_delay, doubled at every
+// attempt, and dropped after max_retries. The engine never waits for the sink, the changes
+// arriving while max_pending of them are queued are dropped.
+//
+// The values are None for a missing key. Expired keys are mirrored as a del without the old
+// value, gone by then.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mutation {
+    pub key: Key,
+    // lowercase, as in Event::KeyWritten
+    pub command: String,
+    pub old: Option<MirroredValue>,
+    pub new: Option<MirroredValue>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MirroredValue {
+    String(RawValue),
+    // a list, a hash... only its type is mirrored, as in TYPE: copying a whole collection at
+    // every change would cost more than the change itself
+    Unsupported(&'static str),
+}
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = ResultT<()>> + Send + 'a>>;
+
+// implemented by the application embedding rdis:
+//
+//     impl WriteSink for Mirror {
+//         fn write<'a>(&'a mut self, batch: &'a [Mutation]) -> SinkFuture<'a> {
+//             Box::pin(async move { self.table.upsert(batch).await })
+//         }
+//     }
+pub trait WriteSink: Send {
+    // the changes in the order they were made, an error retries the whole batch
+    fn write<'a>(&'a mut self, batch: &'a [Mutation]) -> SinkFuture<'a>;
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteBehindOptions {
+    pub batch_size: usize,
+    pub max_pending: usize,
+    pub max_retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for WriteBehindOptions {
+    fn default() -> Self {
+        WriteBehindOptions {
+            batch_size: 128,
+            max_pending: 100_000,
+            max_retries: 5,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+pub struct WriteBehind {
+    sender: mpsc::Sender<Mutation>,
+    dropping: bool,
+}
+
+impl WriteBehind {
+    // the handle completes once the engine is dropped and the queued changes are written
+    pub fn spawn(
+        sink: Box<dyn WriteSink>,
+        options: WriteBehindOptions,
+    ) -> (WriteBehind, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(options.max_pending.max(1));
+        let handle = tokio::spawn(write_batches(sink, receiver, options));
+        let write_behind = WriteBehind {
+            sender,
+            dropping: false,
+        };
+        (write_behind, handle)
+    }
+
+    pub fn send(&mut self, mutation: Mutation) {
+        match self.sender.try_send(mutation) {
+            Ok(()) if self.dropping => {
+                info!("Write-behind queue drained, changes are mirrored again");
+                self.dropping = false;
+            }
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(_)) if !self.dropping => {
+                warn!("Write-behind queue full, dropping changes");
+                self.dropping = true;
+            }
+            // the task is gone only if the sink panicked
+            Err(_) => (),
+        }
+    }
+}
+
+async fn write_batches(
+    mut sink: Box<dyn WriteSink>,
+    mut receiver: mpsc::Receiver<Mutation>,
+    options: WriteBehindOptions,
+) {
+    let batch_size = options.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    while receiver.recv_many(&mut batch, batch_size).await > 0 {
+        let mut delay = options.retry_delay;
+        let mut retries = 0;
+        while let Err(e) = sink.write(&batch).await {
+            if retries == options.max_retries {
+                error!(
+                    "Dropping {} changes after {} retries of the write-behind sink: {}",
+                    batch.len(),
+                    retries,
+                    e
+                );
+                break;
+            }
+            warn!("Write-behind sink failed, retrying in {:?}: {}", delay, e);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            retries += 1;
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdis::types::RdisError;
+    use bytes::Bytes;
+    use std::sync::{Arc, Mutex};
+
+    // keeps the batches, the first failures ones fail
+    struct Collect {
+        batches: Arc<Mutex<Vec<Vec<Mutation>>>>,
+        failures: usize,
+    }
+
+    impl WriteSink for Collect {
+        fn write<'a>(&'a mut self, batch: &'a [Mutation]) -> SinkFuture<'a> {
+            Box::pin(async move {
+                if self.failures > 0 {
+                    self.failures -= 1;
+                    return Err(RdisError::from("unavailable"));
+                }
+                self.batches.lock().unwrap().push(batch.to_vec());
+                Ok(())
+            })
+        }
+    }
+
+    fn mutation(i: usize) -> Mutation {
+        Mutation {
+            key: Bytes::from(format!("k{}", i)),
+            command: "set".to_owned(),
+            old: None,
+            new: Some(MirroredValue::String(Bytes::from(i.to_string()))),
+        }
+    }
+
+    async fn run(failures: usize, max_retries: u32, count: usize) -> Vec<Vec<Mutation>> {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let sink = Collect {
+            batches: batches.clone(),
+            failures,
+        };
+        let options = WriteBehindOptions {
+            batch_size: 3,
+            max_retries,
+            retry_delay: Duration::from_millis(1),
+            ..WriteBehindOptions::default()
+        };
+        let (mut write_behind, handle) = WriteBehind::spawn(Box::new(sink), options);
+        for i in 0..count {
+            write_behind.send(mutation(i));
+        }
+        drop(write_behind);
+        handle.await.unwrap();
+        let batches = batches.lock().unwrap().clone();
+        batches
+    }
+
+    #[tokio::test]
+    pub async fn test_batches() {
+        let batches = run(0, 0, 7).await;
+        let sizes: Vec<usize> = batches.iter().map(|b| b.len()).collect();
+        assert_eq!(sizes, vec![3, 3, 1]);
+        assert_eq!(batches.concat(), (0..7).map(mutation).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    pub async fn test_retries() {
+        // the first batch succeeds at the third attempt
+        let batches = run(2, 2, 4).await;
+        assert_eq!(batches.concat(), (0..4).map(mutation).collect::<Vec<_>>());
+        // and is dropped after one retry
+        let batches = run(2, 1, 4).await;
+        assert_eq!(batches, vec![vec![mutation(3)]]);
+    }
+}
@@ -0,0 +1,382 @@
+use super::storage::{shared_bytes, RawValue};
+use bytes::Bytes;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+
+// values of the sorted set keys: a skip list ordered by score and then by member, and a map
+// from the members to their scores for ZSCORE and for updates. Every link keeps its span, the
+// number of nodes it moves forward, so the rank of a member and the member at a rank are found
+// in O(log n) like in redis. Nodes live in a Vec and are linked by index, the slots of the
+// removed ones are reused.
+const MAX_LEVEL: usize = 32;
+// the head holds no member, a link to it is the end of the list
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    next: usize,
+    // the links to the end span the nodes left
+    span: usize,
+}
+
+const END: Link = Link {
+    next: HEAD,
+    span: 0,
+};
+
+struct Node {
+    member: RawValue,
+    score: f64,
+    links: Vec<Link>,
+}
+
+pub struct SortedSet {
+    scores: HashMap<RawValue, f64>,
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    // xorshift state, picks the level of the new nodes
+    seed: u64,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet::new()
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            links: vec![END; MAX_LEVEL],
+        };
+        SortedSet {
+            scores: HashMap::new(),
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            seed: RandomState::new().hash_one(0) | 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // in no particular order
+    pub fn members(&self) -> impl Iterator<Item = &RawValue> {
+        self.scores.keys()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // true if the member was added, an existing member is moved to its new score
+    pub fn insert(&mut self, member: RawValue, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => false,
+            Some(old) => {
+                self.unlink(&member, old);
+                self.link(member, score);
+                false
+            }
+            None => {
+                self.link(member, score);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.unlink(member, score);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 0 for the member with the lowest score
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.next == HEAD || self.after(link.next, score, member) {
+                    break;
+                }
+                rank += link.span;
+                x = link.next;
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    // members and scores from start to stop included, both valid ranks
+    pub fn range(&self, start: usize, stop: usize) -> Vec<(RawValue, f64)> {
+        let mut x = self.at_rank(start + 1);
+        let mut range = Vec::with_capacity(stop - start + 1);
+        for _ in start..=stop {
+            let node = &self.nodes[x];
+            range.push((node.member.clone(), node.score));
+            x = node.links[0].next;
+        }
+        range
+    }
+
+    // estimate of the heap used by the members, see Value::memory. The members are shared by
+    // the map and the list
+    pub fn memory(&self) -> usize {
+        let slots = self.scores.capacity() * size_of::<(RawValue, f64)>()
+            + self.nodes.capacity() * size_of::<Node>();
+        let links: usize = self.nodes.iter().map(|node| node.links.capacity()).sum();
+        let members: usize = self.scores.keys().map(shared_bytes).sum();
+        slots + links * size_of::<Link>() + members
+    }
+
+    // the members are copied to a new list and map, returns the allocations moved
+    pub fn defrag(&mut self) -> usize {
+        let mut moved = SortedSet::new();
+        let mut x = self.nodes[HEAD].links[0].next;
+        while x != HEAD {
+            let node = &self.nodes[x];
+            moved.insert(Bytes::copy_from_slice(&node.member), node.score);
+            x = node.links[0].next;
+        }
+        *self = moved;
+        2 * self.len() + 2
+    }
+
+    // the node comes after score and member
+    fn after(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        node.score > score || (node.score == score && node.member.as_ref() > member)
+    }
+
+    fn before(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        node.score < score || (node.score == score && node.member.as_ref() < member)
+    }
+
+    // the node at rank, counting from 1, HEAD for 0
+    fn at_rank(&self, rank: usize) -> usize {
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.next == HEAD || traversed + link.span > rank {
+                    break;
+                }
+                traversed += link.span;
+                x = link.next;
+            }
+            if traversed == rank {
+                return x;
+            }
+        }
+        x
+    }
+
+    // every level is kept with probability 1/4
+    fn random_level(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (1 + self.seed.trailing_zeros() as usize / 2).min(MAX_LEVEL)
+    }
+
+    // nodes in the list
+    fn linked(&self) -> usize {
+        self.nodes.len() - 1 - self.free.len()
+    }
+
+    fn link(&mut self, member: RawValue, score: f64) {
+        // the last node before the new one at every level, and its rank
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 < self.level { rank[i + 1] } else { 0 };
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.next == HEAD || !self.before(link.next, score, &member) {
+                    break;
+                }
+                rank[i] += link.span;
+                x = link.next;
+            }
+            update[i] = x;
+        }
+        let level = self.random_level();
+        for i in self.level..level {
+            self.nodes[HEAD].links[i].span = self.linked();
+        }
+        self.level = self.level.max(level);
+
+        let node = Node {
+            member,
+            score,
+            links: vec![END; level],
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let prev = self.nodes[update[i]].links[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[idx].links[i] = Link {
+                next: prev.next,
+                span: prev.span - skipped,
+            };
+            self.nodes[update[i]].links[i] = Link {
+                next: idx,
+                span: skipped + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].links[i].span += 1;
+        }
+    }
+
+    fn unlink(&mut self, member: &[u8], score: f64) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.next == HEAD || !self.before(link.next, score, member) {
+                    break;
+                }
+                x = link.next;
+            }
+            update[i] = x;
+        }
+        let idx = self.nodes[x].links[0].next;
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[idx].links.get(i).copied();
+            let prev = &mut self.nodes[prev].links[i];
+            match removed {
+                Some(removed) if prev.next == idx => {
+                    prev.next = removed.next;
+                    prev.span = prev.span + removed.span - 1;
+                }
+                _ => prev.span -= 1,
+            }
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].next == HEAD {
+            self.level -= 1;
+        }
+        let node = &mut self.nodes[idx];
+        node.member = Bytes::new();
+        node.links = Vec::new();
+        self.free.push(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(s: &str) -> RawValue {
+        Bytes::copy_from_slice(s.as_bytes())
+    }
+
+    // the members in order with the list, the ranks with the spans
+    fn check(zset: &SortedSet, expected: &[(&str, f64)]) {
+        let all = if expected.is_empty() {
+            Vec::new()
+        } else {
+            zset.range(0, expected.len() - 1)
+        };
+        let expected: Vec<(RawValue, f64)> = expected.iter().map(|(m, s)| (raw(m), *s)).collect();
+        assert_eq!(all, expected);
+        assert_eq!(zset.len(), expected.len());
+        for (i, (member, score)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(i));
+            assert_eq!(zset.score(member), Some(*score));
+            assert_eq!(zset.range(i, i), vec![(member.clone(), *score)]);
+        }
+    }
+
+    #[test]
+    pub fn test_sorted_set() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert(raw("b"), 2.0));
+        assert!(zset.insert(raw("a"), 2.0));
+        assert!(zset.insert(raw("c"), 1.0));
+        assert!(zset.insert(raw("d"), f64::NEG_INFINITY));
+        check(
+            &zset,
+            &[("d", f64::NEG_INFINITY), ("c", 1.0), ("a", 2.0), ("b", 2.0)],
+        );
+        // updates move the member
+        assert!(!zset.insert(raw("d"), 3.0));
+        assert!(!zset.insert(raw("c"), 1.0));
+        check(&zset, &[("c", 1.0), ("a", 2.0), ("b", 2.0), ("d", 3.0)]);
+        assert!(zset.remove(b"a"));
+        assert!(!zset.remove(b"a"));
+        assert_eq!(zset.rank(b"a"), None);
+        check(&zset, &[("c", 1.0), ("b", 2.0), ("d", 3.0)]);
+        for m in ["b", "c", "d"] {
+            zset.remove(m.as_bytes());
+        }
+        check(&zset, &[]);
+        // d was moved to a new node
+        assert_eq!((zset.level, zset.free.len()), (1, 4));
+    }
+
+    #[test]
+    pub fn test_many_members() {
+        let mut zset = SortedSet::new();
+        // scores in a scrambled order, the members sort by score
+        let score = |i: u64| (i * 7919 % 1000) as f64;
+        for i in 0..1000 {
+            zset.insert(raw(&format!("m{}", i)), score(i));
+        }
+        for i in (0..1000).step_by(3) {
+            zset.remove(format!("m{}", i).as_bytes());
+        }
+        for i in (1..1000).step_by(3) {
+            zset.insert(raw(&format!("m{}", i)), -score(i));
+        }
+        let mut expected: Vec<(String, f64)> = (0..1000)
+            .filter(|i| i % 3 != 0)
+            .map(|i| {
+                let s = if i % 3 == 1 { -score(i) } else { score(i) };
+                (format!("m{}", i), s)
+            })
+            .collect();
+        expected.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        let expected: Vec<(&str, f64)> = expected.iter().map(|(m, s)| (m.as_str(), *s)).collect();
+        check(&zset, &expected);
+        assert_eq!(zset.range(10, 12).len(), 3);
+
+        let memory = zset.memory();
+        assert!(zset.defrag() > 0);
+        check(&zset, &expected);
+        assert!(zset.memory() <= memory);
+    }
+}
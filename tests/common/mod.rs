@@ -0,0 +1,106 @@
+// support for the integration tests: a full server bound to an ephemeral port and a minimal
+// RESP client, replies are read with the client side reader, not the server parser
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use rdis::rdis::protocol::read_reply;
+use rdis::rdis::systemd::Supervised;
+use rdis::{RdisServerBuilder, ResultT, ShutdownHandle, RESP};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    handle: JoinHandle<ResultT<()>>,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        TestServer::start_with(RdisServerBuilder::new()).await
+    }
+
+    // address and supervision are overridden, every server gets its own port
+    pub async fn start_with(builder: RdisServerBuilder) -> TestServer {
+        let server = builder
+            .bind("127.0.0.1")
+            .port(0)
+            .supervised(Supervised::No)
+            .build()
+            .await
+            .expect("server failed to start");
+        TestServer {
+            addr: server.local_addr().unwrap(),
+            shutdown: server.shutdown_handle(),
+            handle: tokio::spawn(server.serve()),
+        }
+    }
+
+    pub async fn connect(&self) -> TestClient {
+        TestClient::connect(self.addr).await
+    }
+
+    // waits for the connections to be closed and the engine to terminate
+    pub async fn stop(self) {
+        self.shutdown.shutdown();
+        self.handle.await.unwrap().unwrap();
+    }
+}
+
+pub struct TestClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> TestClient {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        TestClient {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    pub async fn cmd(&mut self, args: &[&str]) -> RESP {
+        self.send(args).await;
+        self.read_reply().await.unwrap()
+    }
+
+    // writes the command without waiting for the reply, for pipelines
+    pub async fn send(&mut self, args: &[&str]) {
+        self.writer.write_all(&encode(args)).await.unwrap();
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).await.unwrap();
+    }
+
+    pub async fn read_reply(&mut self) -> ResultT<RESP> {
+        read_reply(&mut self.reader).await
+    }
+
+    // true if the server closed the connection
+    pub async fn is_closed(&mut self) -> bool {
+        let mut buf = [0; 1];
+        matches!(self.reader.read(&mut buf).await, Ok(0) | Err(_))
+    }
+}
+
+pub fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    out
+}
+
+pub fn bulk(value: &str) -> RESP {
+    RESP::BulkString(Bytes::copy_from_slice(value.as_bytes()))
+}
+
+pub fn ok() -> RESP {
+    RESP::SimpleString(b"OK".to_vec())
+}
@@ -0,0 +1,675 @@
+// drives rdis with the redis-rs client. With RDIS_COMPARE_URL set to a real redis
+// (redis://127.0.0.1:6379) the same script runs against both servers and the replies are diffed.
+mod common;
+
+use common::TestServer;
+use redis::aio::MultiplexedConnection;
+use redis::{Cmd, Value};
+
+async fn connect(url: &str) -> MultiplexedConnection {
+    let client = redis::Client::open(url).unwrap();
+    client.get_multiplexed_tokio_connection().await.unwrap()
+}
+
+async fn rdis_connection(server: &TestServer) -> MultiplexedConnection {
+    connect(&format!("redis://{}", server.addr)).await
+}
+
+#[tokio::test]
+async fn test_strings() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let missing: Option<String> = redis::cmd("GET")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(missing, None);
+    let _: () = redis::cmd("SET")
+        .arg("k")
+        .arg("v")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let value: String = redis::cmd("GET")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(value, "v");
+    let binary = vec![0u8, 13, 10, 255];
+    let _: () = redis::cmd("SET")
+        .arg("b")
+        .arg(&binary)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let value: Vec<u8> = redis::cmd("GET")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(value, binary);
+    let _: () = redis::cmd("MSET")
+        .arg("a")
+        .arg("1")
+        .arg("c")
+        .arg("3")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let values: Vec<Option<String>> = redis::cmd("MGET")
+        .arg("a")
+        .arg("missing")
+        .arg("c")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(values, vec![Some("1".into()), None, Some("3".into())]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_lists() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    for v in ["a", "b", "c"].iter() {
+        let _: () = redis::cmd("RPUSH")
+            .arg("l")
+            .arg(*v)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+    }
+    let _: () = redis::cmd("LPUSH")
+        .arg("l")
+        .arg("z")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let first: String = redis::cmd("LPOP")
+        .arg("l")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let last: String = redis::cmd("RPOP")
+        .arg("l")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!((first.as_str(), last.as_str()), ("z", "c"));
+    let missing: Option<String> = redis::cmd("LPOP")
+        .arg("none")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(missing, None);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_hashes() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let added: i64 = redis::cmd("HSET")
+        .arg("h")
+        .arg("a")
+        .arg("1")
+        .arg("b")
+        .arg("2")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(added, 2);
+    let values: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg("h")
+        .arg("a")
+        .arg("c")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(
+        values,
+        vec![Some("1".to_owned()), None, Some("2".to_owned())]
+    );
+    let all: std::collections::HashMap<String, String> = redis::cmd("HGETALL")
+        .arg("h")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(all.len(), 2);
+    assert_eq!(all["b"], "2");
+    let removed: i64 = redis::cmd("HDEL")
+        .arg("h")
+        .arg("a")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(removed, 2);
+    let kind: String = redis::cmd("TYPE")
+        .arg("h")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(kind, "none");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_sets() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let added: i64 = redis::cmd("SADD")
+        .arg("s")
+        .arg("a")
+        .arg("b")
+        .arg("c")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(added, 3);
+    let is_member: bool = redis::cmd("SISMEMBER")
+        .arg("s")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert!(is_member);
+    let popped: Vec<String> = redis::cmd("SPOP")
+        .arg("s")
+        .arg(2)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(popped.len(), 2);
+    let mut members: std::collections::HashSet<String> = redis::cmd("SMEMBERS")
+        .arg("s")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    members.extend(popped);
+    assert_eq!(members.len(), 3);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_sorted_sets() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let added: i64 = redis::cmd("ZADD")
+        .arg("z")
+        .arg(3)
+        .arg("c")
+        .arg(1)
+        .arg("a")
+        .arg(2)
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(added, 3);
+    let range: Vec<(String, f64)> = redis::cmd("ZRANGE")
+        .arg("z")
+        .arg(0)
+        .arg(-1)
+        .arg("WITHSCORES")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(
+        range,
+        vec![("a".into(), 1.0), ("b".into(), 2.0), ("c".into(), 3.0)]
+    );
+    let rank: Option<i64> = redis::cmd("ZRANK")
+        .arg("z")
+        .arg("c")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(rank, Some(2));
+    let score: Option<f64> = redis::cmd("ZSCORE")
+        .arg("z")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(score, Some(2.0));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_streams() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let first: String = redis::cmd("XADD")
+        .arg("x")
+        .arg("*")
+        .arg("f")
+        .arg("a")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let second: String = redis::cmd("XADD")
+        .arg("x")
+        .arg("*")
+        .arg("f")
+        .arg("b")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    // redis-rs reads arrays of pairs as flat arrays, the replies are compared as they are
+    let bulk = |s: &str| Value::Data(s.as_bytes().to_vec());
+    let entry =
+        |id: &str, v: &str| Value::Bulk(vec![bulk(id), Value::Bulk(vec![bulk("f"), bulk(v)])]);
+    let range: Value = redis::cmd("XRANGE")
+        .arg("x")
+        .arg("-")
+        .arg("+")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(
+        range,
+        Value::Bulk(vec![entry(&first, "a"), entry(&second, "b")])
+    );
+    let read: Value = redis::cmd("XREAD")
+        .arg("STREAMS")
+        .arg("x")
+        .arg(&first)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let stream = Value::Bulk(vec![bulk("x"), Value::Bulk(vec![entry(&second, "b")])]);
+    assert_eq!(read, Value::Bulk(vec![stream]));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let mut pipe = redis::pipe();
+    for i in 0..100 {
+        pipe.cmd("SET").arg(format!("k{}", i)).arg(i).ignore();
+    }
+    for i in 0..100 {
+        pipe.cmd("GET").arg(format!("k{}", i));
+    }
+    let values: Vec<i64> = pipe.query_async(&mut con).await.unwrap();
+    assert_eq!(values, (0..100).collect::<Vec<i64>>());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_transaction() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let (value, len): (String, i64) = redis::pipe()
+        .atomic()
+        .cmd("SET")
+        .arg("k")
+        .arg("v")
+        .ignore()
+        .cmd("GET")
+        .arg("k")
+        .cmd("RPUSH")
+        .arg("l")
+        .arg("a")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!((value.as_str(), len), ("v", 1));
+    let aborted: redis::RedisResult<()> = redis::pipe()
+        .atomic()
+        .cmd("DEL")
+        .arg("k")
+        .cmd("SUBSCRIBE")
+        .arg("c")
+        .query_async(&mut con)
+        .await;
+    assert!(aborted.is_err());
+    let value: Option<String> = redis::cmd("GET")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(value.as_deref(), Some("v"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_expiry() {
+    let server = TestServer::start().await;
+    let mut con = rdis_connection(&server).await;
+    let _: () = redis::cmd("SET")
+        .arg("k")
+        .arg("v")
+        .arg("PX")
+        .arg(50)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let value: Option<String> = redis::cmd("GET")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(value, None);
+
+    // the expiration is removed before it fires
+    let _: () = redis::cmd("SET")
+        .arg("k")
+        .arg("v")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    let set: bool = redis::cmd("PEXPIRE")
+        .arg("k")
+        .arg(50)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert!(set);
+    let ttl: i64 = redis::cmd("PTTL")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert!(ttl > 0 && ttl <= 50);
+    let persisted: bool = redis::cmd("PERSIST")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert!(persisted);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let ttl: i64 = redis::cmd("TTL")
+        .arg("k")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(ttl, -1);
+    server.stop().await;
+}
+
+// commands replayed against both servers, keys are prefixed to avoid clashes with existing data
+fn script(prefix: &str) -> Vec<Cmd> {
+    let key = |k: &str| format!("{}{}", prefix, k);
+    let mut cmds = vec![
+        redis::cmd("PING"),
+        redis::cmd("GET").arg(key("s")).clone(),
+        redis::cmd("SET").arg(key("s")).arg("v").clone(),
+        redis::cmd("GET").arg(key("s")).clone(),
+        redis::cmd("SET").arg(key("n")).arg("10").clone(),
+        redis::cmd("INCR").arg(key("n")).clone(),
+        redis::cmd("GET").arg(key("n")).clone(),
+        redis::cmd("INCR").arg(key("s")).clone(),
+        redis::cmd("RPUSH").arg(key("l")).arg("a").clone(),
+        redis::cmd("LPUSH").arg(key("l")).arg("b").arg("c").clone(),
+        redis::cmd("LRANGE").arg(key("l")).arg(0).arg(-1).clone(),
+        redis::cmd("LRANGE").arg(key("l")).arg(0).arg(-100).clone(),
+        redis::cmd("MSET")
+            .arg(key("s"))
+            .arg("w")
+            .arg(key("m"))
+            .arg("x")
+            .clone(),
+        redis::cmd("GET").arg(key("m")).clone(),
+        redis::cmd("MGET")
+            .arg(key("s"))
+            .arg(key("missing"))
+            .arg(key("l"))
+            .arg(key("m"))
+            .clone(),
+        redis::cmd("RPOP").arg(key("l")).clone(),
+        redis::cmd("RPOP").arg(key("l")).clone(),
+        redis::cmd("LPOP").arg(key("l")).clone(),
+        redis::cmd("LPOP").arg(key("l")).clone(),
+        redis::cmd("GET").arg(key("l")).clone(),
+        redis::cmd("EXISTS")
+            .arg(key("s"))
+            .arg(key("l"))
+            .arg(key("s"))
+            .arg(key("missing"))
+            .clone(),
+        redis::cmd("TYPE").arg(key("s")).clone(),
+        redis::cmd("SET").arg(key("u")).arg("v").clone(),
+        redis::cmd("UNLINK")
+            .arg(key("u"))
+            .arg(key("missing"))
+            .clone(),
+        redis::cmd("TYPE").arg(key("u")).clone(),
+        redis::cmd("HSET")
+            .arg(key("h"))
+            .arg("f")
+            .arg("1")
+            .arg("g")
+            .arg("2")
+            .clone(),
+        redis::cmd("HSET").arg(key("h")).arg("f").arg("3").clone(),
+        redis::cmd("HGET").arg(key("h")).arg("f").clone(),
+        redis::cmd("HMGET")
+            .arg(key("h"))
+            .arg("g")
+            .arg("missing")
+            .clone(),
+        redis::cmd("HEXISTS").arg(key("h")).arg("g").clone(),
+        redis::cmd("HLEN").arg(key("h")).clone(),
+        redis::cmd("HDEL")
+            .arg(key("h"))
+            .arg("g")
+            .arg("missing")
+            .clone(),
+        redis::cmd("HGETALL").arg(key("h")).clone(),
+        redis::cmd("TYPE").arg(key("h")).clone(),
+        redis::cmd("HGET").arg(key("s")).arg("f").clone(),
+        redis::cmd("HDEL").arg(key("h")).arg("f").clone(),
+        redis::cmd("HLEN").arg(key("h")).clone(),
+        redis::cmd("SADD")
+            .arg(key("t"))
+            .arg("a")
+            .arg("b")
+            .arg("a")
+            .clone(),
+        redis::cmd("SCARD").arg(key("t")).clone(),
+        redis::cmd("SISMEMBER").arg(key("t")).arg("b").clone(),
+        redis::cmd("SISMEMBER").arg(key("t")).arg("c").clone(),
+        redis::cmd("SREM").arg(key("t")).arg("a").arg("c").clone(),
+        redis::cmd("SMEMBERS").arg(key("t")).clone(),
+        redis::cmd("TYPE").arg(key("t")).clone(),
+        redis::cmd("SADD").arg(key("s")).arg("a").clone(),
+        redis::cmd("SPOP").arg(key("t")).clone(),
+        redis::cmd("SPOP").arg(key("t")).clone(),
+        redis::cmd("SPOP").arg(key("t")).arg(2).clone(),
+        redis::cmd("SMEMBERS").arg(key("t")).clone(),
+        redis::cmd("SCARD").arg(key("t")).clone(),
+        redis::cmd("ZADD")
+            .arg(key("z"))
+            .arg(2)
+            .arg("b")
+            .arg(1)
+            .arg("a")
+            .arg("1.5")
+            .arg("c")
+            .clone(),
+        redis::cmd("ZADD").arg(key("z")).arg(0).arg("b").clone(),
+        redis::cmd("ZADD").arg(key("z")).arg("x").arg("d").clone(),
+        redis::cmd("ZRANGE").arg(key("z")).arg(0).arg(-1).clone(),
+        redis::cmd("ZRANGE").arg(key("z")).arg(0).arg(-100).clone(),
+        redis::cmd("ZRANGE")
+            .arg(key("z"))
+            .arg(1)
+            .arg(5)
+            .arg("WITHSCORES")
+            .clone(),
+        redis::cmd("ZSCORE").arg(key("z")).arg("c").clone(),
+        redis::cmd("ZSCORE").arg(key("z")).arg("d").clone(),
+        redis::cmd("ZRANK").arg(key("z")).arg("a").clone(),
+        redis::cmd("ZRANK").arg(key("z")).arg("d").clone(),
+        redis::cmd("ZREM").arg(key("z")).arg("a").arg("d").clone(),
+        redis::cmd("ZCARD").arg(key("z")).clone(),
+        redis::cmd("TYPE").arg(key("z")).clone(),
+        redis::cmd("ZADD").arg(key("s")).arg(1).arg("a").clone(),
+        redis::cmd("XADD")
+            .arg(key("x"))
+            .arg("5-1")
+            .arg("f")
+            .arg("a")
+            .clone(),
+        redis::cmd("XADD")
+            .arg(key("x"))
+            .arg("5-*")
+            .arg("f")
+            .arg("b")
+            .arg("g")
+            .arg("c")
+            .clone(),
+        redis::cmd("XADD")
+            .arg(key("x"))
+            .arg("5")
+            .arg("f")
+            .arg("d")
+            .clone(),
+        redis::cmd("XADD")
+            .arg(key("x"))
+            .arg("7")
+            .arg("f")
+            .arg("d")
+            .clone(),
+        redis::cmd("XLEN").arg(key("x")).clone(),
+        redis::cmd("XRANGE").arg(key("x")).arg("-").arg("+").clone(),
+        redis::cmd("XRANGE")
+            .arg(key("x"))
+            .arg("5")
+            .arg("(7")
+            .arg("COUNT")
+            .arg(1)
+            .clone(),
+        redis::cmd("XREVRANGE")
+            .arg(key("x"))
+            .arg("+")
+            .arg("-")
+            .clone(),
+        redis::cmd("XREAD")
+            .arg("STREAMS")
+            .arg(key("x"))
+            .arg("5-1")
+            .clone(),
+        redis::cmd("XREAD")
+            .arg("STREAMS")
+            .arg(key("x"))
+            .arg("7")
+            .clone(),
+        redis::cmd("TYPE").arg(key("x")).clone(),
+        redis::cmd("XADD")
+            .arg(key("s"))
+            .arg("*")
+            .arg("f")
+            .arg("a")
+            .clone(),
+        redis::cmd("SET").arg(key("e")).arg("v").clone(),
+        redis::cmd("TTL").arg(key("e")).clone(),
+        redis::cmd("EXPIRE")
+            .arg(key("e"))
+            .arg(100)
+            .arg("GT")
+            .clone(),
+        redis::cmd("EXPIRE").arg(key("e")).arg(100).clone(),
+        redis::cmd("TTL").arg(key("e")).clone(),
+        redis::cmd("EXPIRE")
+            .arg(key("e"))
+            .arg(200)
+            .arg("NX")
+            .clone(),
+        redis::cmd("PERSIST").arg(key("e")).clone(),
+        redis::cmd("PTTL").arg(key("e")).clone(),
+        redis::cmd("EXPIRE").arg(key("e")).arg(-1).clone(),
+        redis::cmd("GET").arg(key("e")).clone(),
+        redis::cmd("TTL").arg(key("e")).clone(),
+        redis::cmd("PEXPIRE").arg(key("e")).arg(100).clone(),
+        redis::cmd("SET").arg(key("e")).arg("a").arg("XX").clone(),
+        redis::cmd("SET")
+            .arg(key("e"))
+            .arg("a")
+            .arg("NX")
+            .arg("EX")
+            .arg(100)
+            .clone(),
+        redis::cmd("SET").arg(key("e")).arg("b").arg("NX").clone(),
+        redis::cmd("SET")
+            .arg(key("e"))
+            .arg("b")
+            .arg("KEEPTTL")
+            .arg("GET")
+            .clone(),
+        redis::cmd("TTL").arg(key("e")).clone(),
+        redis::cmd("SET")
+            .arg(key("e"))
+            .arg("c")
+            .arg("EX")
+            .arg(1)
+            .arg("KEEPTTL")
+            .clone(),
+        redis::cmd("SET").arg(key("l")).arg("a").arg("GET").clone(),
+    ];
+    cmds.push(redis::cmd("GET"));
+    cmds
+}
+
+async fn run_script(con: &mut MultiplexedConnection, cmds: &[Cmd]) -> Vec<String> {
+    let mut replies = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let reply: redis::RedisResult<Value> = cmd.query_async(con).await;
+        replies.push(match reply {
+            Ok(value) => format!("{:?}", value),
+            // only the error kind is compared, messages differ between servers
+            Err(err) => format!("error {:?}", err.kind()),
+        });
+    }
+    replies
+}
+
+#[tokio::test]
+async fn test_compare_with_redis() {
+    let url = match std::env::var("RDIS_COMPARE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("RDIS_COMPARE_URL is not set, skipping the comparison with redis");
+            return;
+        }
+    };
+    let prefix = format!("rdis-compat-{}:", std::process::id());
+    let cmds = script(&prefix);
+    let mut redis_con = connect(&url).await;
+    let expected = run_script(&mut redis_con, &cmds).await;
+    let mut cleanup = redis::cmd("DEL");
+    for k in ["s", "n", "l", "m", "h", "t", "z", "x", "e", "u"].iter() {
+        cleanup.arg(format!("{}{}", prefix, k));
+    }
+    let _: redis::RedisResult<()> = cleanup.query_async(&mut redis_con).await;
+
+    let server = TestServer::start().await;
+    let mut rdis_con = rdis_connection(&server).await;
+    let actual = run_script(&mut rdis_con, &cmds).await;
+    server.stop().await;
+
+    let diffs: Vec<String> = cmds
+        .iter()
+        .zip(expected.iter().zip(actual.iter()))
+        .filter(|(_, (e, a))| e != a)
+        .map(|(cmd, (e, a))| {
+            let args: Vec<String> = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    redis::Arg::Simple(a) => String::from_utf8_lossy(a).into_owned(),
+                    redis::Arg::Cursor => "<cursor>".to_owned(),
+                })
+                .collect();
+            format!("{}\n  redis: {}\n  rdis:  {}", args.join(" "), e, a)
+        })
+        .collect();
+    assert!(diffs.is_empty(), "replies differ:\n{}", diffs.join("\n"));
+}
@@ -0,0 +1,461 @@
+mod common;
+
+use bytes::Bytes;
+use common::{bulk, encode, ok, TestServer};
+use rdis::rdis::config::ClientLimits;
+use rdis::{LoadFuture, Loader, RdisError, RdisServerBuilder, ReadThroughOptions, RESP};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_commands() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    assert_eq!(
+        client.cmd(&["PING"]).await,
+        RESP::SimpleString(b"PONG".to_vec())
+    );
+    assert_eq!(client.cmd(&["GET", "k"]).await, RESP::Null);
+    assert_eq!(client.cmd(&["SET", "k", "v"]).await, ok());
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+    client.cmd(&["RPUSH", "l", "a"]).await;
+    client.cmd(&["RPUSH", "l", "b"]).await;
+    assert_eq!(client.cmd(&["LPOP", "l"]).await, bulk("a"));
+    assert!(matches!(client.cmd(&["NOPE"]).await, RESP::Error(_, _)));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_wrong_type() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.cmd(&["RPUSH", "l", "a"]).await;
+    client.cmd(&["SET", "s", "v"]).await;
+    for args in [
+        &["GET", "l"][..],
+        &["INCR", "l"],
+        &["LPUSH", "s", "a"],
+        &["RPOP", "s"],
+    ] {
+        match client.cmd(args).await {
+            RESP::Error(kind, msg) => {
+                assert_eq!(kind, "WRONGTYPE");
+                assert_eq!(
+                    msg,
+                    "Operation against a key holding the wrong kind of value"
+                );
+            }
+            other => panic!("unexpected {:?} for {:?}", other, args),
+        }
+    }
+    assert_eq!(client.cmd(&["LPOP", "l"]).await, bulk("a"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_inline_commands() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.send_raw(b"SET k v\r\nGET k\r\n").await;
+    assert_eq!(client.read_reply().await.unwrap(), ok());
+    assert_eq!(client.read_reply().await.unwrap(), bulk("v"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_pipelining() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    let mut pipeline = Vec::new();
+    for i in 0..500 {
+        let key = format!("k{}", i);
+        pipeline.extend(encode(&["SET", &key, &i.to_string()]));
+        pipeline.extend(encode(&["GET", &key]));
+    }
+    client.send_raw(&pipeline).await;
+    for i in 0..500 {
+        assert_eq!(client.read_reply().await.unwrap(), ok());
+        assert_eq!(client.read_reply().await.unwrap(), bulk(&i.to_string()));
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_concurrent_clients() {
+    let server = TestServer::start().await;
+    let mut tasks = Vec::new();
+    for c in 0..16 {
+        let mut client = server.connect().await;
+        tasks.push(tokio::spawn(async move {
+            for i in 0..50 {
+                let value = format!("{}-{}", c, i);
+                let reply = client.cmd(&["RPUSH", "l", &value]).await;
+                assert!(
+                    matches!(reply, RESP::Integer(len) if len > i),
+                    "{:?}",
+                    reply
+                );
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let mut client = server.connect().await;
+    let mut popped = 0;
+    while client.cmd(&["LPOP", "l"]).await != RESP::Null {
+        popped += 1;
+    }
+    assert_eq!(popped, 16 * 50);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_connections() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    assert_eq!(
+        client.cmd(&["PING"]).await,
+        RESP::SimpleString(b"PONG".to_vec())
+    );
+    server.stop().await;
+    assert!(client.is_closed().await);
+}
+
+#[tokio::test]
+async fn test_connection_state() {
+    let server = TestServer::start().await;
+    let mut first = server.connect().await;
+    let mut second = server.connect().await;
+    assert_eq!(first.cmd(&["CLIENT", "SETNAME", "first"]).await, ok());
+    // the state survives across requests and pipelines of the same connection
+    first.send(&["PING"]).await;
+    first.send(&["CLIENT", "GETNAME"]).await;
+    first.read_reply().await.unwrap();
+    assert_eq!(first.read_reply().await.unwrap(), bulk("first"));
+    assert_eq!(second.cmd(&["CLIENT", "GETNAME"]).await, RESP::Null);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_query_buffer_limit_replies_and_closes() {
+    let limits = ClientLimits {
+        query_buffer_limit: 64,
+        ..ClientLimits::default()
+    };
+    let server = TestServer::start_with(RdisServerBuilder::new().limits(limits)).await;
+    let mut client = server.connect().await;
+    // an incomplete bulk string, the server keeps buffering it
+    client.send_raw(b"*2\r\n$3\r\nSET\r\n$1000\r\n").await;
+    client.send_raw(&[b'x'; 128]).await;
+    match client.read_reply().await.unwrap() {
+        RESP::Error(kind, msg) => {
+            assert_eq!(kind, "ERR");
+            assert!(msg.starts_with("Protocol error: query buffer limit exceeded"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(client.is_closed().await);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let limits = ClientLimits {
+        handshake_timeout: Some(Duration::from_millis(100)),
+        ..ClientLimits::default()
+    };
+    let server = TestServer::start_with(RdisServerBuilder::new().limits(limits)).await;
+    let mut silent = server.connect().await;
+    let mut partial = server.connect().await;
+    partial.send_raw(b"*1\r\n$4\r\nPI").await;
+    let mut client = server.connect().await;
+    assert_eq!(
+        client.cmd(&["PING"]).await,
+        RESP::SimpleString(b"PONG".to_vec())
+    );
+    assert!(silent.is_closed().await);
+    assert!(partial.is_closed().await);
+    // the deadline only applies to the first request
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        client.cmd(&["PING"]).await,
+        RESP::SimpleString(b"PONG".to_vec())
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_maxmemory_clients() {
+    let limits = ClientLimits {
+        maxmemory_clients: 1024,
+        ..ClientLimits::default()
+    };
+    let server = TestServer::start_with(RdisServerBuilder::new().limits(limits)).await;
+    let mut idle = server.connect().await;
+    let mut client = server.connect().await;
+    // an incomplete bulk string stays in the query buffer until the cron evicts the client
+    client.send_raw(b"*2\r\n$3\r\nGET\r\n$4096\r\n").await;
+    client.send_raw(&[b'x'; 2048]).await;
+    assert!(client.is_closed().await);
+    let info = match idle.cmd(&["INFO", "stats"]).await {
+        RESP::BulkString(info) => String::from_utf8_lossy(&info).into_owned(),
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(info.contains("evicted_clients:1\r\n"), "{}", info);
+    server.stop().await;
+}
+
+#[cfg(feature = "fault-injection")]
+#[tokio::test]
+async fn test_fault_injection() {
+    use std::time::{Duration, Instant};
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    let mut admin = server.connect().await;
+    assert_eq!(
+        admin
+            .cmd(&["DEBUG", "FAULT", "LATENCY", "50", "COMMAND", "get"])
+            .await,
+        ok()
+    );
+    let start = Instant::now();
+    assert_eq!(client.cmd(&["GET", "k"]).await, RESP::Null);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // the command runs, only the reply is lost
+    admin.cmd(&["DEBUG", "FAULT", "RESET"]).await;
+    admin
+        .cmd(&["DEBUG", "FAULT", "DROP", "COMMAND", "set"])
+        .await;
+    client.send_raw(&encode(&["SET", "k", "v"])).await;
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+
+    admin
+        .cmd(&["DEBUG", "FAULT", "DISCONNECT", "COMMAND", "incr"])
+        .await;
+    client.send_raw(&encode(&["INCR", "n"])).await;
+    assert!(client.read_reply().await.is_err());
+    assert_eq!(admin.cmd(&["GET", "n"]).await, RESP::Null);
+    assert!(matches!(
+        admin.cmd(&["DEBUG", "FAULT", "EXPLODE"]).await,
+        RESP::Error(_, _)
+    ));
+    server.stop().await;
+}
+
+// the same requests get the same replies, expiration included
+#[tokio::test]
+async fn test_deterministic_runs() {
+    async fn run() -> Vec<RESP> {
+        let server = TestServer::start_with(RdisServerBuilder::new().deterministic(1000)).await;
+        let mut client = server.connect().await;
+        let mut replies = Vec::new();
+        replies.push(client.cmd(&["SET", "k", "v", "PX", "3"]).await);
+        replies.push(client.cmd(&["SET", "t", "v", "PXAT", "1005"]).await);
+        for _ in 0..4 {
+            replies.push(client.cmd(&["GET", "k"]).await);
+            replies.push(client.cmd(&["DBSIZE"]).await);
+        }
+        server.stop().await;
+        replies
+    }
+    let replies = run().await;
+    assert_eq!(replies, run().await);
+    // SET k runs at 1000, every request moves the clock by one millisecond
+    assert_eq!(replies[2], bulk("v"));
+    assert_eq!(replies[4], RESP::Null);
+    assert_eq!(replies[9], RESP::Integer(0));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_engine_thread() {
+    use rdis::rdis::affinity::current_thread_cpus;
+    let cpu = current_thread_cpus().unwrap()[0];
+    let server = TestServer::start_with(RdisServerBuilder::new().engine_thread(vec![cpu])).await;
+    let mut client = server.connect().await;
+    assert_eq!(client.cmd(&["SET", "k", "v"]).await, ok());
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+    let info = match client.cmd(&["INFO", "server"]).await {
+        RESP::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(info.contains("engine_thread:yes\r\n"));
+    assert!(info.contains(&format!("engine_cpulist:{}\r\n", cpu)));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_read_fast_path() {
+    let server = TestServer::start_with(RdisServerBuilder::new().read_fast_path()).await;
+    let mut client = server.connect().await;
+    let mut other = server.connect().await;
+    assert_eq!(client.cmd(&["SET", "k", "v"]).await, ok());
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(other.cmd(&["GET", "k"]).await, bulk("v"));
+    // every write is visible to the next read
+    assert_eq!(other.cmd(&["MSET", "k", "w", "j", "x"]).await, ok());
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("w"));
+    assert_eq!(client.cmd(&["DEL", "k"]).await, RESP::Integer(1));
+    assert_eq!(client.cmd(&["GET", "k"]).await, RESP::Null);
+    assert!(matches!(
+        client.cmd(&["INCR", "j"]).await,
+        RESP::Error(_, _)
+    ));
+    assert_eq!(client.cmd(&["SET", "n", "1"]).await, ok());
+    assert_eq!(client.cmd(&["INCR", "n"]).await, RESP::Integer(2));
+    assert_eq!(client.cmd(&["GET", "n"]).await, bulk("2"));
+    // a SET not setting the key leaves the value, KEEPTTL the expiration
+    assert_eq!(client.cmd(&["SET", "n", "3", "NX"]).await, RESP::Null);
+    assert_eq!(client.cmd(&["GET", "n"]).await, bulk("2"));
+    assert_eq!(client.cmd(&["SET", "t", "v", "PX", "20"]).await, ok());
+    assert_eq!(client.cmd(&["SET", "t", "w", "KEEPTTL"]).await, ok());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.cmd(&["GET", "t"]).await, RESP::Null);
+    let info = match client.cmd(&["INFO", "stats"]).await {
+        RESP::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(info.contains("read_fast_path_hits:3\r\n"), "{}", info);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_client_kill_filters() {
+    let server = TestServer::start().await;
+    let mut first = server.connect().await;
+    let mut second = server.connect().await;
+    assert_eq!(
+        first.cmd(&["PING"]).await,
+        RESP::SimpleString(b"PONG".to_vec())
+    );
+    assert_eq!(
+        second.cmd(&["CLIENT", "KILL", "MAXAGE", "3600"]).await,
+        RESP::Integer(0)
+    );
+    assert!(matches!(
+        second.cmd(&["CLIENT", "KILL", "TYPE", "bogus"]).await,
+        RESP::Error(_, _)
+    ));
+    assert!(matches!(
+        second.cmd(&["CLIENT", "KILL", "TYPE"]).await,
+        RESP::Error(_, _)
+    ));
+    // the caller is skipped
+    let laddr = server.addr.to_string();
+    assert_eq!(
+        second
+            .cmd(&["CLIENT", "KILL", "TYPE", "normal", "LADDR", &laddr])
+            .await,
+        RESP::Integer(1)
+    );
+    assert!(first.is_closed().await);
+    assert_eq!(
+        second.cmd(&["CLIENT", "KILL", "TYPE", "pubsub"]).await,
+        RESP::Integer(0)
+    );
+    server.stop().await;
+}
+
+struct Users {
+    loads: Arc<AtomicUsize>,
+}
+
+impl Loader for Users {
+    fn load(&self, key: Bytes) -> LoadFuture {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            match key.as_ref() {
+                b"user:1" => Ok(Some(Bytes::from_static(b"alice"))),
+                b"user:down" => Err(RdisError::from("database down")),
+                _ => Ok(None),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_read_through() {
+    let loads = Arc::new(AtomicUsize::new(0));
+    let options = ReadThroughOptions {
+        prefixes: vec![b"user:".to_vec()],
+        ttl: Duration::from_secs(10),
+    };
+    let users = Users {
+        loads: loads.clone(),
+    };
+    let server =
+        TestServer::start_with(RdisServerBuilder::new().read_through(users, options)).await;
+    let mut client = server.connect().await;
+    let mut other = server.connect().await;
+    // both GETs wait for the same load
+    client.send(&["GET", "user:1"]).await;
+    other.send(&["GET", "user:1"]).await;
+    assert_eq!(client.read_reply().await.unwrap(), bulk("alice"));
+    assert_eq!(other.read_reply().await.unwrap(), bulk("alice"));
+    assert_eq!(client.cmd(&["GET", "user:1"]).await, bulk("alice"));
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    match client.cmd(&["PTTL", "user:1"]).await {
+        RESP::Integer(ttl) => assert!(ttl > 9_000 && ttl <= 10_000, "{}", ttl),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.cmd(&["GET", "team:1"]).await, RESP::Null);
+    assert_eq!(client.cmd(&["GET", "user:2"]).await, RESP::Null);
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+    assert!(matches!(
+        client.cmd(&["GET", "user:down"]).await,
+        RESP::Error(_, _)
+    ));
+    // a miss in a pipeline is not loaded
+    let pipeline = [encode(&["GET", "user:3"]), encode(&["PING"])].concat();
+    client.send_raw(&pipeline).await;
+    assert_eq!(client.read_reply().await.unwrap(), RESP::Null);
+    assert_eq!(
+        client.read_reply().await.unwrap(),
+        RESP::SimpleString("PONG".into())
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 3);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_preload() {
+    let path = |name: &str| {
+        let path = std::env::temp_dir().join(format!("rdis-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_owned()
+    };
+    let json = path("fixtures.json");
+    std::fs::write(
+        &json,
+        r#"{"k": "v", "n": 1, "l": ["a", "b"], "h": {"f": "v"}}"#,
+    )
+    .unwrap();
+    let server = TestServer::start_with(RdisServerBuilder::new().preload(&json)).await;
+    let mut client = server.connect().await;
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(client.cmd(&["INCR", "n"]).await, RESP::Integer(2));
+    assert_eq!(client.cmd(&["LPOP", "l"]).await, bulk("a"));
+    assert_eq!(client.cmd(&["HGET", "h", "f"]).await, bulk("v"));
+    server.stop().await;
+
+    let resp = path("fixtures.resp");
+    std::fs::write(&resp, encode(&["SET", "k", "v"])).unwrap();
+    let server = TestServer::start_with(RdisServerBuilder::new().preload(&resp)).await;
+    let mut client = server.connect().await;
+    assert_eq!(client.cmd(&["GET", "k"]).await, bulk("v"));
+    server.stop().await;
+
+    // a failing command stops the startup
+    std::fs::write(&json, r#"[["SET", "k", "v"], ["INCR", "k"]]"#).unwrap();
+    let result = RdisServerBuilder::new()
+        .port(0)
+        .preload(&json)
+        .build()
+        .await;
+    assert!(result.is_err());
+    std::fs::remove_file(&json).unwrap();
+    std::fs::remove_file(&resp).unwrap();
+}